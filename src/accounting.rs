@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+//club specific values of the SEWOBE export, configured in the [accounting] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingProfile {
+    pub bill_name: String,
+    pub description_prefix: String,
+    pub billkeeping_account: String,
+    pub subaccount: String,
+    pub tax_key: String,
+    //gross prices are split with this rate unless the category or the item overrides it
+    pub vat_rate_percent: f64,
+    pub payment_target_days: u32,
+    pub late_after_days: u32,
+    //keyed by item category, e.g. [accounting.categories.Food]
+    pub categories: HashMap<String, CategoryAccounting>,
+    //keyed by item id, e.g. [accounting.items.12]
+    pub items: HashMap<String, ItemAccounting>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CategoryAccounting {
+    pub billkeeping_account: Option<String>,
+    pub subaccount: Option<String>,
+    pub tax_key: Option<String>,
+    pub vat_rate_percent: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ItemAccounting {
+    pub vat_rate_percent: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountAssignment {
+    pub billkeeping_account: String,
+    pub subaccount: String,
+    pub tax_key: String,
+}
+
+impl Default for AccountingProfile {
+    fn default() -> Self {
+        return AccountingProfile {
+            bill_name: "Kantinenabrechnung".to_string(),
+            description_prefix: "KA".to_string(),
+            billkeeping_account: "1112".to_string(),
+            subaccount: "8293".to_string(),
+            tax_key: "1".to_string(),
+            vat_rate_percent: 0.0,
+            payment_target_days: 30,
+            late_after_days: 14,
+            categories: HashMap::new(),
+            items: HashMap::new(),
+        };
+    }
+}
+
+impl AccountingProfile {
+    //positions without category (specials, budget transfers) always use the profile defaults
+    pub fn assignment_for(&self, category: Option<&str>) -> AccountAssignment {
+        let overrides = category.and_then(|c| self.categories.get(c));
+        let pick = |overridden: Option<&Option<String>>, fallback: &String| -> String {
+            return match overridden {
+                Some(&Some(ref value)) => value.to_string(),
+                _ => fallback.to_string(),
+            };
+        };
+        return AccountAssignment {
+            billkeeping_account: pick(
+                overrides.map(|o| &o.billkeeping_account),
+                &self.billkeeping_account,
+            ),
+            subaccount: pick(overrides.map(|o| &o.subaccount), &self.subaccount),
+            tax_key: pick(overrides.map(|o| &o.tax_key), &self.tax_key),
+        };
+    }
+
+    //item override wins over category override wins over the profile rate
+    pub fn vat_basis_points(&self, item_id: Option<u32>, category: Option<&str>) -> u32 {
+        let item_rate = item_id
+            .and_then(|id| self.items.get(&id.to_string()))
+            .and_then(|i| i.vat_rate_percent);
+        let category_rate = category
+            .and_then(|c| self.categories.get(c))
+            .and_then(|c| c.vat_rate_percent);
+        return percent_to_basis_points(
+            item_rate
+                .or(category_rate)
+                .unwrap_or(self.vat_rate_percent),
+        );
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.bill_name.trim().is_empty() {
+            problems.push("accounting.bill_name must not be empty".to_string());
+        }
+        if self.billkeeping_account.trim().is_empty() {
+            problems.push("accounting.billkeeping_account must not be empty".to_string());
+        }
+        if self.tax_key.trim().is_empty() {
+            problems.push("accounting.tax_key must not be empty".to_string());
+        }
+        let mut rates: Vec<(String, f64)> = vec![("accounting".to_string(), self.vat_rate_percent)];
+        for (name, category) in &self.categories {
+            if let Some(rate) = category.vat_rate_percent {
+                rates.push((format!("accounting.categories.{}", name), rate));
+            }
+        }
+        for (id, item) in &self.items {
+            if id.parse::<u32>().is_err() {
+                problems.push(format!("accounting.items.{} is not an item id", id));
+            }
+            if let Some(rate) = item.vat_rate_percent {
+                rates.push((format!("accounting.items.{}", id), rate));
+            }
+        }
+        for (name, rate) in rates {
+            if !(rate >= 0.0 && rate <= 100.0) {
+                problems.push(format!("{}.vat_rate_percent must be between 0 and 100", name));
+            }
+        }
+        return problems;
+    }
+}
+
+//basis points keep the tax arithmetic in integers, 19% = 1900
+pub fn percent_to_basis_points(percent: f64) -> u32 {
+    return (percent * 100.0).round() as u32;
+}
+
+//german notation like the other amounts in the exports, 1900 => "19", 550 => "5,5"
+pub fn format_vat_rate(basis_points: u32) -> String {
+    let whole = basis_points / 100;
+    let fraction = basis_points % 100;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:02}", fraction);
+    return format!("{},{}", whole, fraction.trim_right_matches('0'));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VatSplit {
+    pub gross_cents: i64,
+    pub net_cents: i64,
+    pub tax_cents: i64,
+}
+
+impl VatSplit {
+    //prices are gross prices, the tax is the contained share rounded half away from zero
+    pub fn of_gross(gross_cents: i64, basis_points: u32) -> VatSplit {
+        let divisor = 10000i64 + basis_points as i64;
+        let contained = (gross_cents.abs() * basis_points as i64 * 2 + divisor) / (2 * divisor);
+        let tax_cents = if gross_cents < 0 { -contained } else { contained };
+        return VatSplit {
+            gross_cents: gross_cents,
+            net_cents: gross_cents - tax_cents,
+            tax_cents: tax_cents,
+        };
+    }
+
+    pub fn add(&mut self, other: &VatSplit) {
+        self.gross_cents += other.gross_cents;
+        self.net_cents += other.net_cents;
+        self.tax_cents += other.tax_cents;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use accounting::*;
+
+    #[test]
+    fn category_overrides_fall_back_to_profile_defaults() {
+        let mut profile = AccountingProfile::default();
+        profile.categories.insert(
+            "Food".to_string(),
+            CategoryAccounting {
+                billkeeping_account: Some("4400".to_string()),
+                subaccount: None,
+                tax_key: Some("2".to_string()),
+                vat_rate_percent: None,
+            },
+        );
+
+        let food = profile.assignment_for(Some("Food"));
+        assert_eq!(food.billkeeping_account, "4400");
+        assert_eq!(food.subaccount, "8293");
+        assert_eq!(food.tax_key, "2");
+
+        assert_eq!(profile.assignment_for(Some("Drinks")), profile.assignment_for(None));
+        assert_eq!(profile.assignment_for(None).tax_key, "1");
+    }
+
+    #[test]
+    fn vat_rate_is_taken_from_item_then_category_then_profile() {
+        let mut profile = AccountingProfile::default();
+        profile.vat_rate_percent = 19.0;
+        profile.categories.insert(
+            "Food".to_string(),
+            CategoryAccounting {
+                vat_rate_percent: Some(7.0),
+                ..CategoryAccounting::default()
+            },
+        );
+        profile.items.insert(
+            "3".to_string(),
+            ItemAccounting {
+                vat_rate_percent: Some(5.5),
+            },
+        );
+
+        assert_eq!(profile.vat_basis_points(Some(1), None), 1900);
+        assert_eq!(profile.vat_basis_points(Some(1), Some("Food")), 700);
+        assert_eq!(profile.vat_basis_points(Some(3), Some("Food")), 550);
+        assert_eq!(profile.vat_basis_points(None, None), 1900);
+        assert_eq!(format_vat_rate(550), "5,5");
+        assert_eq!(format_vat_rate(1900), "19");
+        assert_eq!(format_vat_rate(725), "7,25");
+
+        profile.items.insert("beer".to_string(), ItemAccounting::default());
+        profile.vat_rate_percent = 120.0;
+        assert_eq!(profile.problems().len(), 2);
+    }
+
+    #[test]
+    fn gross_amounts_are_split_into_net_and_tax() {
+        assert_eq!(
+            VatSplit::of_gross(119, 1900),
+            VatSplit {
+                gross_cents: 119,
+                net_cents: 100,
+                tax_cents: 19,
+            }
+        );
+        assert_eq!(VatSplit::of_gross(95, 1900).tax_cents, 15);
+        assert_eq!(VatSplit::of_gross(-95, 1900).tax_cents, -15);
+        assert_eq!(VatSplit::of_gross(95, 0).net_cents, 95);
+
+        let mut total = VatSplit::default();
+        total.add(&VatSplit::of_gross(107, 700));
+        total.add(&VatSplit::of_gross(119, 1900));
+        assert_eq!(total.tax_cents, 26);
+        assert_eq!(total.net_cents, 200);
+    }
+}
@@ -0,0 +1,372 @@
+use inventory::{Inventory, InventorySettings, LowStockWarning, StockMovementKind};
+use mailqueue::{MailDraft, MailQueue};
+use reqwest;
+use rustix_bl;
+use rustix_bl::datastore::Item;
+use rustix_bl::rustix_event_shop::BLEvents;
+use serde_json;
+use server::Backend;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use typescriptify::TypeScriptifyTrait;
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+//configured in the [alerts] table of the config file, alerts go to the admins and never to members
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertSettings {
+    pub enabled: bool,
+    pub mail_recipient: Option<String>,
+    //receives the alert as json POST body
+    pub webhook_url: Option<String>,
+    //consumption of this many days is used to estimate what will be sold until the next delivery
+    pub consumption_window_days: i64,
+    pub reorder_coverage_days: i64,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        return AlertSettings {
+            enabled: false,
+            mail_recipient: None,
+            webhook_url: None,
+            consumption_window_days: 14,
+            reorder_coverage_days: 14,
+        };
+    }
+}
+
+impl AlertSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.enabled && self.mail_recipient.is_none() && self.webhook_url.is_none() {
+            problems.push("alerts are enabled, but neither mail_recipient nor webhook_url is set".to_string());
+        }
+        if self.consumption_window_days <= 0 {
+            problems.push("alerts.consumption_window_days must be positive".to_string());
+        }
+        if self.reorder_coverage_days < 0 {
+            problems.push("alerts.reorder_coverage_days must not be negative".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub enum AlertReason {
+    LowStock {
+        item_id: u32,
+        name: String,
+        quantity: i64,
+        low_stock_threshold: i64,
+    },
+    FreeForAllExhausted {
+        ffa_id: u64,
+        donor: String,
+        text_message: String,
+    },
+}
+
+impl AlertReason {
+    pub fn describe(&self) -> String {
+        return match self {
+            &AlertReason::LowStock {
+                ref name,
+                quantity,
+                low_stock_threshold,
+                ..
+            } => format!(
+                "{} is running low: {} left, threshold is {}",
+                name, quantity, low_stock_threshold
+            ),
+            &AlertReason::FreeForAllExhausted {
+                ref donor,
+                ref text_message,
+                ..
+            } => format!("The giveout of {} is used up: \"{}\"", donor, text_message),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct ReorderLine {
+    pub item_id: u32,
+    pub name: String,
+    pub quantity: i64,
+    pub low_stock_threshold: i64,
+    pub sold_in_window: i64,
+    pub suggested_quantity: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct StockAlert {
+    pub timestamp_epoch_millis: i64,
+    pub reasons: Vec<AlertReason>,
+    pub reorder_list: Vec<ReorderLine>,
+}
+
+//only items that just crossed their threshold, so every further sale does not alert again
+pub fn newly_low(before: &[LowStockWarning], after: &[LowStockWarning]) -> Vec<AlertReason> {
+    return after
+        .iter()
+        .filter(|a| !before.iter().any(|b| b.item_id == a.item_id))
+        .map(|a| AlertReason::LowStock {
+            item_id: a.item_id,
+            name: a.name.to_string(),
+            quantity: a.quantity,
+            low_stock_threshold: a.low_stock_threshold,
+        })
+        .collect();
+}
+
+//has to run before the event is applied, afterwards the giveout is no longer open
+pub fn exhausted_ffa(backend: &Backend, event: &BLEvents) -> Option<AlertReason> {
+    let ffa_id = match event {
+        &BLEvents::MakeFreeForAllPurchase { ffa_id, .. } => ffa_id,
+        _ => return None,
+    };
+    for freeby in &backend.datastore.open_ffa {
+        if let &rustix_bl::datastore::Freeby::FFA {
+            id,
+            allowed_number_total,
+            allowed_number_used,
+            ref text_message,
+            donor,
+            ..
+        } = freeby
+        {
+            if id == ffa_id && allowed_number_used + 1 >= allowed_number_total {
+                return Some(AlertReason::FreeForAllExhausted {
+                    ffa_id: id,
+                    donor: backend
+                        .datastore
+                        .users
+                        .get(&donor)
+                        .map(|u| u.username.to_string())
+                        .unwrap_or(String::new()),
+                    text_message: text_message.to_string(),
+                });
+            }
+        }
+    }
+    return None;
+}
+
+pub fn sold_since(inventory: &Inventory, item_id: u32, since_epoch_millis: i64) -> i64 {
+    return -inventory
+        .movements
+        .iter()
+        .filter(|m| m.item_id == item_id && m.timestamp_epoch_millis >= since_epoch_millis)
+        .filter(|m| m.kind == StockMovementKind::Sale || m.kind == StockMovementKind::Undo)
+        .map(|m| m.quantity_change)
+        .sum::<i64>();
+}
+
+//enough to last the coverage period at the recent rate, and to get back above the threshold
+pub fn reorder_list(
+    inventory: &Inventory,
+    items: &HashMap<u32, Item>,
+    inventory_settings: &InventorySettings,
+    settings: &AlertSettings,
+    now_epoch_millis: i64,
+) -> Vec<ReorderLine> {
+    let window_days = if settings.consumption_window_days > 0 {
+        settings.consumption_window_days
+    } else {
+        1
+    };
+    let since = now_epoch_millis - window_days * MILLIS_PER_DAY;
+    return inventory
+        .low_stock_warnings(items, inventory_settings)
+        .into_iter()
+        .map(|w| {
+            let sold = sold_since(inventory, w.item_id, since).max(0);
+            let expected_demand = (sold * settings.reorder_coverage_days + window_days - 1) / window_days;
+            ReorderLine {
+                item_id: w.item_id,
+                name: w.name,
+                quantity: w.quantity,
+                low_stock_threshold: w.low_stock_threshold,
+                sold_in_window: sold,
+                suggested_quantity: (expected_demand + w.low_stock_threshold + 1 - w.quantity).max(0),
+            }
+        })
+        .collect();
+}
+
+pub fn format_reorder_list_as_csv(lines: &[ReorderLine]) -> String {
+    let mut rows: Vec<String> =
+        vec!["item_id;name;quantity;low_stock_threshold;sold_in_window;suggested_quantity".to_string()];
+    for line in lines {
+        rows.push(format!(
+            "{};{};{};{};{};{}",
+            line.item_id,
+            line.name,
+            line.quantity,
+            line.low_stock_threshold,
+            line.sold_in_window,
+            line.suggested_quantity
+        ));
+    }
+    return rows.join("\n");
+}
+
+pub fn alert_mail(alert: &StockAlert, receiver_email: &str) -> MailDraft {
+    let mut body_lines: Vec<String> = alert.reasons.iter().map(|r| r.describe()).collect();
+    body_lines.push(String::new());
+    if alert.reorder_list.is_empty() {
+        body_lines.push("Nothing needs to be reordered right now.".to_string());
+    } else {
+        body_lines.push("Suggested reorder:".to_string());
+        for line in &alert.reorder_list {
+            body_lines.push(format!("{}x {}", line.suggested_quantity, line.name));
+        }
+    }
+
+    let mut attachments: HashMap<String, String> = HashMap::new();
+    attachments.insert(
+        "reorder_list.csv".to_string(),
+        format_reorder_list_as_csv(&alert.reorder_list),
+    );
+    return MailDraft {
+        receiver_email: receiver_email.to_string(),
+        subject: "Cervisia stock alert".to_string(),
+        body: body_lines.join("\n"),
+        attachments: attachments,
+        binary_attachments: HashMap::new(),
+        zipfilename: format!("reorder_{}", alert.timestamp_epoch_millis),
+    };
+}
+
+pub fn post_webhook(url: &str, alert: &StockAlert) -> Result<(), String> {
+    let client = reqwest::Client::new();
+    let res = client
+        .post(url)
+        .body(serde_json::to_string(alert).map_err(|e| e.to_string())?)
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("webhook answered with status {}", res.status()));
+    }
+    return Ok(());
+}
+
+//mails go through the queue and are retried, the webhook is called once from its own thread
+pub fn dispatch(alert: StockAlert, settings: &AlertSettings, queue: &Arc<RwLock<MailQueue>>, now_epoch_seconds: i64) {
+    info!("Stock alert: {:?}", alert.reasons);
+    if let Some(ref receiver) = settings.mail_recipient {
+        let mut queue = queue.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.enqueue(alert_mail(&alert, receiver), now_epoch_seconds);
+        queue.save();
+    }
+    if let Some(url) = settings.webhook_url.clone() {
+        thread::spawn(move || {
+            if let Err(e) = post_webhook(&url, &alert) {
+                error!("Could not deliver stock alert to {}: {}", url, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alerts::*;
+    use httpstub;
+    use inventory::{Inventory, InventorySettings, Restock};
+    use rustix_bl::datastore::Item;
+    use serde_json;
+    use std::collections::HashMap;
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    fn items() -> HashMap<u32, Item> {
+        let mut items = HashMap::new();
+        items.insert(
+            0,
+            Item {
+                name: "beer".to_string(),
+                item_id: 0,
+                category: None,
+                cost_cents: 95,
+                deleted: false,
+            },
+        );
+        return items;
+    }
+
+    fn inventory_with_sales() -> Inventory {
+        let mut inventory = Inventory::default();
+        inventory
+            .restock(
+                Restock {
+                    item_id: 0,
+                    quantity: 30,
+                    purchase_price_cents: 60,
+                    comment: String::new(),
+                },
+                0,
+            )
+            .unwrap();
+        //older sales do not count for the consumption rate
+        for _ in 0..10 {
+            inventory.record_sales(&[(0, -1)], DAY);
+        }
+        for _ in 0..16 {
+            inventory.record_sales(&[(0, -1)], 20 * DAY);
+        }
+        return inventory;
+    }
+
+    #[test]
+    fn reorder_list_is_based_on_recent_consumption() {
+        let inventory = inventory_with_sales();
+        let settings = AlertSettings {
+            consumption_window_days: 7,
+            reorder_coverage_days: 14,
+            ..AlertSettings::default()
+        };
+        let list = reorder_list(&inventory, &items(), &InventorySettings::default(), &settings, 21 * DAY);
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].quantity, 4);
+        assert_eq!(list[0].sold_in_window, 16);
+        //32 for two weeks, plus getting from 4 back above the threshold of 5
+        assert_eq!(list[0].suggested_quantity, 32 + 6 - 4);
+
+        let before = Vec::new();
+        let after = inventory.low_stock_warnings(&items(), &InventorySettings::default());
+        assert_eq!(newly_low(&before, &after).len(), 1);
+        assert!(newly_low(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn alerts_are_posted_to_the_webhook() {
+        let (url, receiver) = httpstub::serve_one_request("/alerts");
+
+        let inventory = inventory_with_sales();
+        let alert = StockAlert {
+            timestamp_epoch_millis: 21 * DAY,
+            reasons: vec![AlertReason::FreeForAllExhausted {
+                ffa_id: 3,
+                donor: "alice".to_string(),
+                text_message: "Happy birthday".to_string(),
+            }],
+            reorder_list: reorder_list(
+                &inventory,
+                &items(),
+                &InventorySettings::default(),
+                &AlertSettings::default(),
+                21 * DAY,
+            ),
+        };
+        post_webhook(&url, &alert).unwrap();
+
+        let received: StockAlert = serde_json::from_str(httpstub::body_of(&receiver.recv().unwrap())).unwrap();
+        assert_eq!(received, alert);
+
+        let mail = alert_mail(&alert, "bar@example.org");
+        assert!(mail.body.contains("The giveout of alice is used up"));
+        assert!(mail.attachments["reorder_list.csv"].contains("0;beer;4;5;16;"));
+    }
+}
@@ -0,0 +1,500 @@
+use chrono::prelude::*;
+use configuration::ServerConfig;
+use iron;
+use iron::headers::{Authorization, Bearer};
+use iron::prelude::*;
+use iron::BeforeMiddleware;
+use jwt::{decode, encode, Header, TokenData, Validation};
+use params::{Params, Value};
+use persistent::State;
+use rand;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_json;
+use errors::ServerError;
+use server::SecretKey;
+use sidecar;
+use std;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+pub const ADMIN_TOKEN_SUBJECT: &str = "admin-session";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TicketPurpose {
+    AdminSession,
+    BillDownload,
+    BillMail,
+}
+
+impl TicketPurpose {
+    pub fn lifetime_seconds(&self, config: &ServerConfig) -> i64 {
+        return match *self {
+            TicketPurpose::AdminSession => config.admin_token_lifetime_seconds,
+            TicketPurpose::BillDownload => config.bill_download_ticket_lifetime_seconds,
+            TicketPurpose::BillMail => config.bill_mail_ticket_lifetime_seconds,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SigningKey {
+    pub secret: String,
+    pub created_epoch_seconds: i64,
+    //set once the key got replaced, tokens signed with it are accepted until then
+    pub valid_until_epoch_seconds: Option<i64>,
+}
+
+//the last key is used for signing, older keys are only used for verification during their grace period
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Keyring {
+    pub keys: Vec<SigningKey>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+pub fn random_secret() -> String {
+    let mut rng = rand::thread_rng();
+    let bytes: [u8; 32] = rng.gen();
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+impl Keyring {
+    pub fn from_secret(secret: &str) -> Keyring {
+        return Keyring {
+            keys: vec![SigningKey {
+                secret: secret.to_string(),
+                created_epoch_seconds: Utc::now().timestamp(),
+                valid_until_epoch_seconds: None,
+            }],
+            path: None,
+        };
+    }
+
+    pub fn generate() -> Keyring {
+        return Keyring::from_secret(&random_secret());
+    }
+
+    pub fn current_secret(&self) -> &str {
+        return match self.keys.last() {
+            Some(key) => &key.secret,
+            None => "",
+        };
+    }
+
+    pub fn verification_secrets(&self, now_epoch_seconds: i64) -> Vec<&str> {
+        return self
+            .keys
+            .iter()
+            .rev()
+            .filter(|k| k.valid_until_epoch_seconds.map(|t| t > now_epoch_seconds).unwrap_or(true))
+            .map(|k| k.secret.as_str())
+            .collect();
+    }
+
+    pub fn rotate(&mut self, now_epoch_seconds: i64, grace_seconds: i64) {
+        for key in self.keys.iter_mut() {
+            if key.valid_until_epoch_seconds.is_none() {
+                key.valid_until_epoch_seconds = Some(now_epoch_seconds + grace_seconds);
+            }
+        }
+        self.keys
+            .retain(|k| k.valid_until_epoch_seconds.map(|t| t > now_epoch_seconds).unwrap_or(true));
+        self.keys.push(SigningKey {
+            secret: random_secret(),
+            created_epoch_seconds: now_epoch_seconds,
+            valid_until_epoch_seconds: None,
+        });
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        return match self.path {
+            Some(ref path) => sidecar::save_json(path, self),
+            None => Ok(()),
+        };
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        return encode(&Header::default(), claims, self.current_secret().as_bytes())
+            .unwrap_or("invalid-token-generation".to_owned());
+    }
+
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<TokenData<T>, String> {
+        let mut last_error = "no signing key available".to_string();
+        for secret in self.verification_secrets(Utc::now().timestamp()) {
+            match decode::<T>(token, secret.as_bytes(), validation) {
+                Ok(data) => return Ok(data),
+                Err(e) => last_error = format!("{:?}", e),
+            }
+        }
+        return Err(last_error);
+    }
+}
+
+//precedence: explicit secret from env > secret file > file next to the persistence directory > random secret for this run
+pub fn load_keyring(config: &ServerConfig) -> Keyring {
+    if let Some(ref secret) = config.jwt_secret {
+        info!("Using JWT secret from configuration");
+        return Keyring::from_secret(secret);
+    }
+
+    let path: Option<PathBuf> = match config.jwt_secret_file {
+        Some(ref file) => Some(PathBuf::from(file)),
+        None => sidecar::sidecar_path(config, "jwtkeys"),
+    };
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            info!("No persistence configured, JWT secret is only valid until restart");
+            return Keyring::generate();
+        }
+    };
+
+    //a secret file may either contain a whole keyring or just the plain secret
+    let mut content = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        //the file exists, replacing it would invalidate every issued token
+        Err(e) => {
+            error!("Could not read JWT keyring from {:?}, using a key valid until restart: {:?}", path, e);
+            return Keyring::generate();
+        }
+    }
+    if !content.trim().is_empty() {
+        let mut keyring: Keyring = match serde_json::from_str(&content) {
+            Ok(keyring) => keyring,
+            Err(_) => Keyring::from_secret(content.trim()),
+        };
+        keyring.path = Some(path.clone());
+        if !keyring.keys.is_empty() {
+            info!("Loaded JWT keyring from {:?}", path);
+            return keyring;
+        }
+    }
+
+    let mut keyring = Keyring::generate();
+    keyring.path = Some(path.clone());
+    match keyring.save() {
+        Ok(()) => info!("Generated new JWT keyring in {:?}", path),
+        Err(e) => error!("Could not persist JWT keyring in {:?}: {:?}", path, e),
+    }
+    return keyring;
+}
+
+//routes (relative to the /api/ mount) which may only be called with a valid admin token
+pub const ADMIN_ONLY_PATHS: &[&str] = &[
+    "/users/update",
+    "/users/delete",
+    "/items",
+    "/items/update",
+    "/items/delete",
+    "/purchases/undo/admin",
+    "/purchases/special/setprice",
+    "/backup/snapshot",
+    "/database/export/string",
+    "/bill/create",
+    "/bill/update",
+    "/bill/delete",
+    "/bill/finalize",
+    "/bill/export",
+    "/bill/download",
+    "/bill/download/list",
+    "/bill/download/secure",
+    "/bill/download/requestjwt",
+    "/bill/download/sepa",
+    "/admin/jwt/rotate",
+    "/admin/mails",
+    "/admin/mails/resend",
+    "/admin/sepa/mandates",
+    "/admin/sepa/mandates/delete",
+    "/payments",
+    "/payments/update",
+    "/payments/delete",
+    "/payments/balances",
+    "/admin/dunning",
+    "/admin/dunning/contact",
+    "/admin/dunning/pause",
+    "/prepaid",
+    "/prepaid/topup",
+    "/prepaid/limit",
+    "/inventory",
+    "/inventory/restock",
+    "/inventory/stocktaking",
+    "/inventory/threshold",
+    "/inventory/movements",
+    "/inventory/report",
+    "/inventory/reorder",
+    "/notifications/preferences",
+    "/admin/webhooks",
+    "/admin/webhooks/delete",
+    "/admin/webhooks/test",
+    "/admin/webhooks/deliveries",
+    "/admin/devices",
+    "/admin/devices/approve",
+    "/admin/devices/revoke",
+    "/admin/credentials",
+    "/admin/credentials/pin",
+    "/admin/credentials/cards",
+    "/admin/credentials/cards/delete",
+    "/admin/undos",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminJwtClaims {
+    sub: String,
+    exp: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct AdminToken {
+    pub token: String,
+    pub expires_epoch_seconds: i64,
+}
+
+pub fn issue_admin_token(keyring: &Keyring, lifetime_seconds: i64) -> AdminToken {
+    let expiration_epoch_seconds = Utc::now().timestamp() + lifetime_seconds;
+    let claims = AdminJwtClaims {
+        sub: ADMIN_TOKEN_SUBJECT.to_string(),
+        exp: expiration_epoch_seconds,
+    };
+    return AdminToken {
+        token: keyring.sign(&claims),
+        expires_epoch_seconds: expiration_epoch_seconds,
+    };
+}
+
+pub fn is_valid_admin_token(keyring: &Keyring, token: &str) -> bool {
+    let validation = Validation {
+        sub: Some(ADMIN_TOKEN_SUBJECT.to_string()),
+        ..Validation::default()
+    };
+    return keyring.verify::<AdminJwtClaims>(token, &validation).is_ok();
+}
+
+//an empty configured password disables the admin login instead of letting everybody in
+pub fn is_admin_password(config: &ServerConfig, candidate: &str) -> bool {
+    let expected = config.admin_password.trim();
+    if expected.is_empty() {
+        return false;
+    }
+    return constant_time_eq(expected.as_bytes(), candidate.trim().as_bytes());
+}
+
+//the admin password has no second factor, so guesses are limited per client like pins are
+pub const ADMIN_LOGIN_MAX_FAILED_ATTEMPTS: u32 = 5;
+pub const ADMIN_LOGIN_LOCKOUT_SECONDS: i64 = 300;
+
+#[derive(Debug, Clone, Default)]
+struct LoginFailures {
+    failed_attempts: u32,
+    locked_until_epoch_seconds: i64,
+}
+
+//failed admin password checks per client address, only counted in memory
+#[derive(Debug, Clone, Default)]
+pub struct LoginThrottle {
+    failures: HashMap<String, LoginFailures>,
+}
+
+impl LoginThrottle {
+    pub fn check_password(
+        &mut self,
+        config: &ServerConfig,
+        client: &str,
+        candidate: &str,
+        now_epoch_seconds: i64,
+    ) -> Result<bool, ServerError> {
+        self.failures
+            .retain(|_, f| f.failed_attempts > 0 || f.locked_until_epoch_seconds > now_epoch_seconds);
+        if let Some(state) = self.failures.get(client) {
+            if state.locked_until_epoch_seconds > now_epoch_seconds {
+                return Err(ServerError::TooManyRequests(format!(
+                    "Too many wrong passwords, try again in {} seconds",
+                    state.locked_until_epoch_seconds - now_epoch_seconds
+                )));
+            }
+        }
+        if is_admin_password(config, candidate) {
+            self.failures.remove(client);
+            return Ok(true);
+        }
+        let state = self.failures.entry(client.to_string()).or_insert_with(LoginFailures::default);
+        state.failed_attempts += 1;
+        if state.failed_attempts >= ADMIN_LOGIN_MAX_FAILED_ATTEMPTS {
+            warn!("Locking admin login for {} after {} wrong passwords", client, state.failed_attempts);
+            state.failed_attempts = 0;
+            state.locked_until_epoch_seconds = now_epoch_seconds + ADMIN_LOGIN_LOCKOUT_SECONDS;
+        }
+        return Ok(false);
+    }
+}
+
+//looks at every byte, so the time taken does not tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+pub fn requires_admin(path: &[&str]) -> bool {
+    let segments: Vec<&str> = path.iter().filter(|s| !s.is_empty()).map(|s| *s).collect();
+    let joined = format!("/{}", segments.join("/"));
+    return ADMIN_ONLY_PATHS.contains(&joined.as_str());
+}
+
+//the token is expected as 'Authorization: Bearer <token>', plain links (e.g. bill downloads) may use the query parameter admin_token instead
+pub fn extract_admin_token(req: &mut iron::request::Request) -> Option<String> {
+    let from_header = req
+        .headers
+        .get::<Authorization<Bearer>>()
+        .map(|auth| auth.0.token.to_string());
+    if from_header.is_some() {
+        return from_header;
+    }
+    return match req.get_ref::<Params>() {
+        Ok(map) => match map.find(&["admin_token"]) {
+            Some(&Value::String(ref token)) => Some(token.to_string()),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+}
+
+#[derive(Debug)]
+pub struct NotAuthorizedError {
+    pub path: String,
+}
+
+impl std::fmt::Display for NotAuthorizedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NotAuthorizedError({})", self.path)
+    }
+}
+
+impl std::error::Error for NotAuthorizedError {
+    fn description(&self) -> &str {
+        return "admin token missing or invalid";
+    }
+}
+
+pub struct AdminAuthentication;
+
+impl BeforeMiddleware for AdminAuthentication {
+    fn before(&self, req: &mut iron::request::Request) -> IronResult<()> {
+        if !requires_admin(&req.url.path()) {
+            return Ok(());
+        }
+
+        let path = req.url.path().join("/");
+        let token = extract_admin_token(req);
+        let is_valid = match (token, req.get::<State<SecretKey>>()) {
+            (Some(token), Ok(keyholder)) => match keyholder.read() {
+                Ok(keyring) => is_valid_admin_token(&keyring, &token),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+
+        if is_valid {
+            return Ok(());
+        }
+
+        warn!("Rejected unauthenticated call to admin route /{}", path);
+        let body = serde_json::to_string(
+            &ServerError::Unauthorized(
+                "This route requires an admin token, obtain one via /api/admin/login".to_string(),
+            ).to_write_result(),
+        ).unwrap_or(String::new());
+        return Err(IronError::new(
+            NotAuthorizedError { path: path },
+            (iron::status::Unauthorized, body),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use auth::*;
+    use chrono::prelude::*;
+
+    #[test]
+    fn admin_paths_are_detected() {
+        assert!(requires_admin(&["bill", "finalize"]));
+        assert!(requires_admin(&["users", "delete", ""]));
+        assert!(requires_admin(&["notifications", "preferences"]));
+        assert!(!requires_admin(&["users", "all"]));
+        assert!(!requires_admin(&["purchases"]));
+        assert!(!requires_admin(&["public", "ticket"]));
+    }
+
+    #[test]
+    fn empty_admin_password_never_matches() {
+        let mut config = ServerConfig::default();
+        config.admin_password = "".to_string();
+        assert!(!is_admin_password(&config, ""));
+        assert!(!is_admin_password(&config, "  "));
+
+        config.admin_password = "s3cret".to_string();
+        assert!(is_admin_password(&config, "s3cret\n"));
+        assert!(!is_admin_password(&config, "s3cre"));
+        assert!(!is_admin_password(&config, "s3creT"));
+    }
+
+    #[test]
+    fn wrong_admin_passwords_lock_out_the_client() {
+        let mut config = ServerConfig::default();
+        config.admin_password = "s3cret".to_string();
+        let mut throttle = LoginThrottle::default();
+        for _ in 0..ADMIN_LOGIN_MAX_FAILED_ATTEMPTS {
+            assert_eq!(throttle.check_password(&config, "10.0.0.2", "guess", 100).unwrap(), false);
+        }
+        //locked, even the right password is refused until the lockout is over
+        assert_eq!(
+            throttle.check_password(&config, "10.0.0.2", "s3cret", 101).unwrap_err().error_code(),
+            "too_many_requests"
+        );
+        assert!(throttle.check_password(&config, "10.0.0.3", "s3cret", 101).unwrap());
+        assert!(throttle
+            .check_password(&config, "10.0.0.2", "s3cret", 100 + ADMIN_LOGIN_LOCKOUT_SECONDS)
+            .unwrap());
+    }
+
+    #[test]
+    fn issued_admin_token_is_only_valid_with_same_secret() {
+        let first = Keyring::from_secret("first-secret");
+        let second = Keyring::from_secret("second-secret");
+        let token = issue_admin_token(&first, 60);
+        assert!(is_valid_admin_token(&first, &token.token));
+        assert!(!is_valid_admin_token(&second, &token.token));
+        assert!(!is_valid_admin_token(&first, "garbage"));
+    }
+
+    #[test]
+    fn rotated_keys_are_accepted_during_grace_period() {
+        let mut keyring = Keyring::from_secret("old-secret");
+        let token = issue_admin_token(&keyring, 60);
+
+        keyring.rotate(1000, 500);
+        assert_ne!(keyring.current_secret(), "old-secret");
+        assert_eq!(keyring.verification_secrets(1200).len(), 2);
+        assert_eq!(keyring.verification_secrets(1600).len(), 1);
+
+        //grace period ends far in the past relative to now
+        assert!(!is_valid_admin_token(&keyring, &token.token));
+
+        let mut keyring = Keyring::from_secret("old-secret");
+        keyring.rotate(Utc::now().timestamp(), 500);
+        assert!(is_valid_admin_token(&keyring, &token.token));
+    }
+}
@@ -0,0 +1,182 @@
+use configuration::ServerConfig;
+use errors::ServerError;
+use rustix_bl::datastore::Item;
+use sidecar;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+//EAN-8, UPC-A and EAN-13, UPC-A codes are stored as EAN-13 with a leading zero like scanners often report them
+pub fn normalize_barcode(raw: &str) -> Result<String, String> {
+    let code: String = raw.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Barcode '{}' may only contain digits", raw.trim()));
+    }
+    let code = match code.len() {
+        8 | 13 => code,
+        12 => format!("0{}", code),
+        _ => return Err(format!("Barcode '{}' is no EAN-8, UPC-A or EAN-13", raw.trim())),
+    };
+    if !has_valid_check_digit(&code) {
+        return Err(format!("Barcode '{}' has a wrong check digit", raw.trim()));
+    }
+    return Ok(code);
+}
+
+//digits are weighted 3 and 1 alternating from the right, the check digit rounds the sum up to a multiple of 10
+fn has_valid_check_digit(code: &str) -> bool {
+    let digits: Vec<u32> = code.chars().filter_map(|c| c.to_digit(10)).collect();
+    let (check, payload) = match digits.split_last() {
+        Some((check, payload)) => (*check, payload),
+        None => return false,
+    };
+    let sum: u32 = payload
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 0 { d * 3 } else { *d })
+        .sum();
+    return (10 - sum % 10) % 10 == check;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BarcodeBook {
+    //codes of deleted items are kept, but may be reused by other items
+    pub items: BTreeMap<u32, Vec<String>>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl BarcodeBook {
+    pub fn load(config: &ServerConfig) -> BarcodeBook {
+        let path = match sidecar::sidecar_path(config, "barcodes") {
+            Some(path) => path,
+            None => return BarcodeBook::default(),
+        };
+        let mut book: BarcodeBook = sidecar::load_json_or_default(&path, "barcodes");
+        book.path = Some(path);
+        return book;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist barcodes to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn barcodes_of(&self, item_id: u32) -> Vec<String> {
+        return self.items.get(&item_id).cloned().unwrap_or(Vec::new());
+    }
+
+    //for the items which are shown, keyed like the datastore
+    pub fn barcodes_of_items(&self, item_ids: &[u32]) -> BTreeMap<u32, Vec<String>> {
+        return item_ids
+            .iter()
+            .filter_map(|id| self.items.get(id).map(|codes| (*id, codes.clone())))
+            .filter(|&(_, ref codes)| !codes.is_empty())
+            .collect();
+    }
+
+    //item_id is None for items which do not exist yet
+    pub fn validate(
+        &self,
+        item_id: Option<u32>,
+        raw_codes: &[String],
+        items: &HashMap<u32, Item>,
+    ) -> Result<Vec<String>, ServerError> {
+        let mut codes: Vec<String> = Vec::new();
+        for raw in raw_codes {
+            let code = normalize_barcode(raw).map_err(ServerError::BadRequest)?;
+            if !codes.contains(&code) {
+                codes.push(code);
+            }
+        }
+        for code in &codes {
+            match self.item_for(code, items) {
+                Some(owner) if Some(owner) != item_id => {
+                    return Err(ServerError::Conflict(format!(
+                        "Barcode {} already belongs to item {}",
+                        code, owner
+                    )))
+                }
+                _ => {}
+            }
+        }
+        return Ok(codes);
+    }
+
+    pub fn set(&mut self, item_id: u32, codes: Vec<String>) -> bool {
+        if self.barcodes_of(item_id) == codes {
+            return false;
+        }
+        if codes.is_empty() {
+            self.items.remove(&item_id);
+        } else {
+            self.items.insert(item_id, codes);
+        }
+        return true;
+    }
+
+    pub fn item_for(&self, raw_code: &str, items: &HashMap<u32, Item>) -> Option<u32> {
+        let code = normalize_barcode(raw_code).ok()?;
+        return self
+            .items
+            .iter()
+            .filter(|&(id, _)| items.get(id).map(|i| !i.deleted).unwrap_or(false))
+            .find(|&(_, codes)| codes.contains(&code))
+            .map(|(id, _)| *id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use barcodes::*;
+    use rustix_bl::datastore::Item;
+    use std::collections::HashMap;
+
+    fn item(item_id: u32, deleted: bool) -> Item {
+        return Item {
+            name: format!("item {}", item_id),
+            item_id: item_id,
+            category: None,
+            cost_cents: 100,
+            deleted: deleted,
+        };
+    }
+
+    #[test]
+    fn check_digits_are_validated_and_upc_is_stored_as_ean() {
+        assert_eq!(normalize_barcode("4006381333931"), Ok("4006381333931".to_string()));
+        assert_eq!(normalize_barcode(" 036000 291452 "), Ok("0036000291452".to_string()));
+        assert_eq!(normalize_barcode("96385074"), Ok("96385074".to_string()));
+        assert!(normalize_barcode("4006381333932").is_err());
+        assert!(normalize_barcode("40063813339").is_err());
+        assert!(normalize_barcode("40063813339a1").is_err());
+    }
+
+    #[test]
+    fn barcodes_are_unique_among_items_which_are_not_deleted() {
+        let mut items: HashMap<u32, Item> = HashMap::new();
+        items.insert(1, item(1, false));
+        items.insert(2, item(2, false));
+        let mut book = BarcodeBook::default();
+
+        let codes = book
+            .validate(Some(1), &vec!["036000291452".to_string(), "0036000291452".to_string()], &items)
+            .unwrap();
+        assert_eq!(codes.len(), 1);
+        assert!(book.set(1, codes.clone()));
+        assert!(!book.set(1, codes));
+
+        assert_eq!(book.item_for("036000291452", &items), Some(1));
+        assert!(book.validate(Some(1), &vec!["0036000291452".to_string()], &items).is_ok());
+        assert!(book.validate(Some(2), &vec!["0036000291452".to_string()], &items).is_err());
+        assert!(book.validate(None, &vec!["0036000291452".to_string()], &items).is_err());
+
+        //once the item is deleted, its code is free again
+        items.insert(1, item(1, true));
+        assert_eq!(book.item_for("0036000291452", &items), None);
+        assert!(book.validate(Some(2), &vec!["0036000291452".to_string()], &items).is_ok());
+    }
+}
@@ -1,27 +1,50 @@
+use accounting::{format_vat_rate, AccountingProfile, VatSplit};
 use chrono::prelude::*;
 use rustix_bl;
 use rustix_bl::datastore::Bill;
+use sepa;
+use sepa::{SepaMandates, SepaSettings};
 use std;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::Hasher;
 use time;
 
 pub trait BillFormatting {
     //takes configuration and outputs a sewobe csv string
-    fn format_as_sewobe_csv(&self, date_today: i64) -> Vec<Vec<String>>;
+    fn format_as_sewobe_csv(&self, date_today: i64, profile: &AccountingProfile) -> Vec<Vec<String>>;
 
-    //outputs reduced bill string for one specific person
-    fn format_as_personalized_documentation(&self, user_id: &u32) -> Vec<Vec<String>>;
+    //outputs reduced bill string for one specific person, closed by a row with the gross/net/tax totals
+    fn format_as_personalized_documentation(
+        &self,
+        user_id: &u32,
+        profile: &AccountingProfile,
+    ) -> Vec<Vec<String>>;
+
+    //gross/net/tax per vat rate over all billed positions, for the treasurer
+    fn vat_summary(&self, profile: &AccountingProfile) -> Vec<Vec<String>>;
+
+    //pain.008 direct debit file with one transaction per SEPA user, or the reasons it cannot be created
+    fn format_as_sepa_direct_debit(
+        &self,
+        date_today: i64,
+        profile: &AccountingProfile,
+        settings: &SepaSettings,
+        mandates: &SepaMandates,
+    ) -> Result<String, Vec<String>>;
 
     fn sewobe_header(&self) -> Vec<String>;
     fn documentation_header(&self) -> Vec<String>;
 
-    //outputs bill for everyone in the bill (ordered alphabetically by name)
-    fn format_as_documentation(&self) -> Vec<Vec<String>> {
-        self.list_of_user_ids()
+    //outputs bill for everyone in the bill (ordered alphabetically by name), followed by the vat summary
+    fn format_as_documentation(&self, profile: &AccountingProfile) -> Vec<Vec<String>> {
+        let mut result: Vec<Vec<String>> = self
+            .list_of_user_ids()
             .iter()
-            .flat_map(|id| self.format_as_personalized_documentation(id))
-            .collect()
+            .flat_map(|id| self.format_as_personalized_documentation(id, profile))
+            .collect();
+        result.extend(self.vat_summary(profile));
+        return result;
     }
 
     fn list_of_user_ids(&self) -> Vec<u32>;
@@ -38,6 +61,11 @@ fn cents_to_currency_string(cents: i32) -> String {
     return format!("{},{}{}", before, after1, after2);
 }
 
+pub fn cents_to_currency_string_i64(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    return format!("{}{},{:02}", sign, cents.abs() / 100, cents.abs() % 100);
+}
+
 fn cents_to_currency_string_u32(cents: u32) -> String {
     let before = cents / 100;
     let after2 = cents % 10;
@@ -334,6 +362,12 @@ fn ms_to_day_month_str(timestamp_from: i64) -> String {
     return utc_timestamp.format(DATE_FORMAT_STRING_VERY_SHORT).to_string();
 }
 
+//the SEWOBE bill number, also printed on the pdf invoices
+pub fn bill_external_id(date_today: i64, external_user_id: &str) -> String {
+    let billing_creation_date = Utc.timestamp(date_today / 1000, 0);
+    return billing_creation_date.format("%y%m%d%S").to_string() + external_user_id;
+}
+
 pub fn get_date_today() -> i64 {
     let millis: i64 = time::get_time().sec as i64 * 1000;
     return millis;
@@ -353,6 +387,9 @@ impl SewobeCSVLine {
         position_price_per_unit: u32,
         date_today: i64,
         is_sepa: bool,
+        profile: &AccountingProfile,
+        category: Option<&str>,
+        vat_basis_points: u32,
     ) -> Self {
         let utc_timestamp_from = Utc.timestamp(timestamp_from / 1000, 0);
         let utc_timestamp_to = Utc.timestamp(timestamp_to / 1000, 0);
@@ -361,15 +398,19 @@ impl SewobeCSVLine {
         //bill is due based on export day
         let billing_creation_date = utc_timestamp_today.clone();
 
-        let bill_id: String =
-            billing_creation_date.format("%y%m%d%S").to_string() + external_user_id;
+        let bill_id: String = bill_external_id(date_today, external_user_id);
+
+        let assignment = profile.assignment_for(category);
 
         return SewobeCSVLine {
             external_user_id: external_user_id.to_string(),
             use_r_vs_g: true,
             bill_external_id: bill_id,
-            bill_name: "Kantinenabrechnung ".to_string()
-                + &billing_creation_date.format("%m/%y").to_string(),
+            bill_name: format!(
+                "{} {}",
+                profile.bill_name,
+                billing_creation_date.format("%m/%y")
+            ),
             bill_date: billing_creation_date,
             position_index: position_index,
             position_name: position_name.to_string(),
@@ -378,14 +419,15 @@ impl SewobeCSVLine {
             price_per_unit_cents: position_price_per_unit,
             use_invoice: is_sepa,
             receive_mail: true,
-            payment_target_days: 30,
+            payment_target_days: profile.payment_target_days,
             sepa_interval: 0,
             bill_date_sent: billing_creation_date,
-            bill_date_late: billing_creation_date + time::Duration::seconds(14 * 24 * 60 * 60),
+            bill_date_late: billing_creation_date
+                + time::Duration::days(profile.late_after_days as i64),
             position_ends_date: billing_creation_date
                 + time::Duration::seconds(100 * 365 * 24 * 60 * 60),
-            tax_rate: "0".to_string(),
-            description: "KA ".to_string()
+            tax_rate: format_vat_rate(vat_basis_points),
+            description: profile.description_prefix.to_string() + " "
                 + &utc_timestamp_from
                     .format(DATE_FORMAT_STRING_SHORT)
                     .to_string() + "-"
@@ -394,9 +436,9 @@ impl SewobeCSVLine {
                     .to_string(),
             is_not_donation: true,
             donation_remark: "".to_string(),
-            billkeeping_account: "1112".to_string(),
-            tax_key: "1".to_string(),
-            subaccount: "8293".to_string(),
+            billkeeping_account: assignment.billkeeping_account,
+            tax_key: assignment.tax_key,
+            subaccount: assignment.subaccount,
         };
     }
 
@@ -442,14 +484,14 @@ impl SewobeCSVLine {
             },
             self.donation_remark.to_string(),
             self.billkeeping_account.to_string(),
-            self.tax_rate.to_string(),
+            self.tax_key.to_string(),
             self.subaccount.to_string(),
         ]
     }
 }
 
 static DATE_FORMAT_STRING_VERY_SHORT: &'static str = "%d.%m.";
-static DATE_FORMAT_STRING: &'static str = "%d.%m.%Y";
+pub static DATE_FORMAT_STRING: &'static str = "%d.%m.%Y";
 static DATE_FORMAT_STRING_SHORT: &'static str = "%d.%m.%y";
 
 pub trait InOrderableu32 {
@@ -476,7 +518,7 @@ impl<T> InOrderableusize for std::collections::HashMap<usize, T> {
 }
 
 impl BillFormatting for Bill {
-    fn format_as_sewobe_csv(&self, date_today: i64) -> Vec<Vec<String>> {
+    fn format_as_sewobe_csv(&self, date_today: i64, profile: &AccountingProfile) -> Vec<Vec<String>> {
         let mut result: Vec<Vec<String>> = Vec::new();
         let timestamp_to: i64 = self.timestamp_to;
         let timestamp_from: i64 = self.timestamp_from;
@@ -536,6 +578,9 @@ impl BillFormatting for Bill {
                                 item.cost_cents,
                                 date_today,
                                 is_sepa,
+                                profile,
+                                category_of(&item),
+                                vat_of_item(profile, &item),
                             ).fmt(),
                         );
                         position_index += 1;
@@ -553,6 +598,9 @@ impl BillFormatting for Bill {
                                 special.price,
                                 date_today,
                                 is_sepa,
+                                profile,
+                                None,
+                                profile.vat_basis_points(None, None),
                             ).fmt(),
                         );
                         position_index += 1;
@@ -574,6 +622,9 @@ impl BillFormatting for Bill {
                                 item.cost_cents,
                                 date_today,
                                 is_sepa,
+                                profile,
+                                category_of(&item),
+                                vat_of_item(profile, &item),
                             ).fmt(),
                         );
                         position_index += 1;
@@ -605,6 +656,9 @@ impl BillFormatting for Bill {
                                     budget_given as u32,
                                     date_today,
                                     is_sepa,
+                                    profile,
+                                    None,
+                                    UNTAXED,
                                 ).fmt(),
                             );
                             position_index += 1;
@@ -625,6 +679,9 @@ impl BillFormatting for Bill {
                                     0,
                                     date_today,
                                     is_sepa,
+                                    profile,
+                                    None,
+                                    UNTAXED,
                                 ).fmt(),
                             );
                             position_index += 1;
@@ -649,6 +706,9 @@ impl BillFormatting for Bill {
                                     item.cost_cents,
                                     date_today,
                                     is_sepa,
+                                    profile,
+                                    category_of(&item),
+                                    vat_of_item(profile, &item),
                                 ).fmt(),
                             );
                             position_index += 1;
@@ -664,84 +724,326 @@ impl BillFormatting for Bill {
         return result;
     }
 
-    fn format_as_personalized_documentation(&self, user_id: &u32) -> Vec<Vec<String>> {
+    fn format_as_personalized_documentation(
+        &self,
+        user_id: &u32,
+        profile: &AccountingProfile,
+    ) -> Vec<Vec<String>> {
+        let mut positions = documented_positions(self, user_id, profile);
+        positions.sort_by(|a, b| hash_vec(&a.line.fmt()).cmp(&hash_vec(&b.line.fmt())));
+
+        let mut result: Vec<Vec<String>> = positions.iter().map(|p| p.fmt()).collect();
+
+        if let Some(first) = positions.first() {
+            let mut total = VatSplit::default();
+            for position in &positions {
+                total.add(&position.vat_split());
+            }
+            let prefix = vec![
+                first.line.username.to_string(),
+                first.line.user_id.to_string(),
+                first.line.is_billed.to_string(),
+                String::new(),
+                "total".to_string(),
+            ];
+            result.push(summary_row(prefix, String::new(), &total));
+        }
+
+        return result;
+    }
+
+    //only billed positions count, budget transfers between members are no sales
+    fn vat_summary(&self, profile: &AccountingProfile) -> Vec<Vec<String>> {
+        let mut per_rate: BTreeMap<u32, VatSplit> = BTreeMap::new();
+        for user_id in &self.finalized_data.all_users.in_order_keys() {
+            for position in documented_positions(self, user_id, profile) {
+                if !position.line.is_billed {
+                    continue;
+                }
+                if let Some(basis_points) = position.vat_basis_points {
+                    per_rate
+                        .entry(basis_points)
+                        .or_insert(VatSplit::default())
+                        .add(&position.vat_split());
+                }
+            }
+        }
+
         let mut result: Vec<Vec<String>> = Vec::new();
-        let _timestamp_to: i64 = self.timestamp_to;
-        let timestamp_from: i64 = self.timestamp_from;
+        let mut total = VatSplit::default();
+        for (basis_points, split) in &per_rate {
+            total.add(split);
+            result.push(summary_row(
+                vec![String::new(), String::new(), String::new(), String::new(), "vat summary".to_string()],
+                format_vat_rate(*basis_points),
+                split,
+            ));
+        }
+        if !per_rate.is_empty() {
+            result.push(summary_row(
+                vec![String::new(), String::new(), String::new(), String::new(), "vat summary total".to_string()],
+                String::new(),
+                &total,
+            ));
+        }
+        return result;
+    }
 
-        //for every user
-        let items = self.finalized_data.all_items.clone();
-        let users = self.finalized_data.all_users.clone();
+    fn format_as_sepa_direct_debit(
+        &self,
+        date_today: i64,
+        profile: &AccountingProfile,
+        settings: &SepaSettings,
+        mandates: &SepaMandates,
+    ) -> Result<String, Vec<String>> {
+        return sepa::pain008_document(self, date_today, profile, settings, mandates);
+    }
 
-        //filter out unbilled user_ids
-        let is_billed: bool = users.get(user_id).is_some()
-            && users.get(user_id).unwrap().external_user_id.is_some()
-            && users.get(user_id).unwrap().is_billed
-            && !self.users_that_will_not_be_billed.contains(user_id);
-        let consumption_opt = self.finalized_data.user_consumption.get(user_id);
-
-        if consumption_opt.is_none() {
-            return result;
+    fn list_of_user_ids(&self) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        for (key, _) in &self.finalized_data.all_users {
+            v.push(*key);
         }
+        return v;
+    }
+    fn sewobe_header(&self) -> Vec<String> {
+        let raw_header = "Mitgliedsnummer;R vs G;Rechnungsnr.;Rechnungsname;Rechnungsdatum;Positionsnr.;Positionsname;Positionsbeschreibung;Anzahl;Preis pro Einheit;2 == Lastschrift und 1 == Ueberweisung;Empfang per Mail;Zahlungsziel in Tagen;SEPA Intervall (0 fuer einmalig);Datum Rechnungsstellung;Datum Faelligkeit;Datum Positionsende;Mehrwertsteuersatz;Beschreibung; Spendenfähig;Spende;Buchhaltungskonto;Steuerschluessel;Unterkonto Kantine";
+        return raw_header.split(";").map(|s| s.to_string()).collect();
+    }
+
+    fn documentation_header(&self) -> Vec<String> {
+        let raw_header = "username;user_id;is_billed;day;item_name;item_count;item_cost_per_unit;budget_cents_outgoing;donor;donor_id;recipient;recipient_id;is_special;is_giveout;is_count;is_budget;is_incoming_donation;is_ffa;vat_rate;total_gross;total_net;total_tax";
+        return raw_header.split(";").map(|s| s.to_string()).collect();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionKind {
+    Purchase,
+    Special,
+    FfaGiveout,
+    CountGiveout,
+    BudgetGiven,
+    BudgetReceived,
+}
+
+//a documentation line together with the vat rate it is taxed with, None for budget transfers
+pub struct DocumentedPosition {
+    pub kind: PositionKind,
+    pub line: OversightCSVLine,
+    pub vat_basis_points: Option<u32>,
+}
+
+impl DocumentedPosition {
+    pub fn vat_split(&self) -> VatSplit {
+        let gross_cents = self.line.item_cost_cents as i64 * self.line.item_count as i64;
+        return VatSplit::of_gross(gross_cents, self.vat_basis_points.unwrap_or(0));
+    }
+
+    fn fmt(&self) -> Vec<String> {
+        let mut cells = self.line.fmt();
+        cells.push(self.vat_basis_points.map(format_vat_rate).unwrap_or(String::new()));
+        cells.extend(fmt_vat_split(&self.vat_split()));
+        return cells;
+    }
+}
+
+//budget transfers between members are no sales
+const UNTAXED: u32 = 0;
+
+//number of columns of OversightCSVLine, summary rows leave the unused ones empty
+const OVERSIGHT_COLUMNS: usize = 18;
+
+fn summary_row(prefix: Vec<String>, vat_rate: String, split: &VatSplit) -> Vec<String> {
+    let mut cells = prefix;
+    while cells.len() < OVERSIGHT_COLUMNS {
+        cells.push(String::new());
+    }
+    cells.push(vat_rate);
+    cells.extend(fmt_vat_split(split));
+    return cells;
+}
+
+fn fmt_vat_split(split: &VatSplit) -> Vec<String> {
+    return vec![
+        cents_to_currency_string_i64(split.gross_cents),
+        cents_to_currency_string_i64(split.net_cents),
+        cents_to_currency_string_i64(split.tax_cents),
+    ];
+}
+
+fn category_of(item: &rustix_bl::datastore::Item) -> Option<&str> {
+    return item.category.as_ref().map(|c| c.as_str());
+}
+
+fn vat_of_item(profile: &AccountingProfile, item: &rustix_bl::datastore::Item) -> u32 {
+    return profile.vat_basis_points(Some(item.item_id), category_of(item));
+}
+
+//what the user is charged for this bill, budget transfers included; zero for users not billed
+pub fn billed_total_cents(bill: &Bill, user_id: &u32, profile: &AccountingProfile) -> i64 {
+    return documented_positions(bill, user_id, profile)
+        .iter()
+        .filter(|p| p.line.is_billed)
+        .map(|p| p.vat_split().gross_cents)
+        .sum();
+}
+
+//all positions of one user as they appear in the documentation, in bill order
+pub fn documented_positions(
+    bill: &Bill,
+    user_id: &u32,
+    profile: &AccountingProfile,
+) -> Vec<DocumentedPosition> {
+    let mut result: Vec<DocumentedPosition> = Vec::new();
+    let timestamp_from: i64 = bill.timestamp_from;
+
+    //for every user
+    let items = bill.finalized_data.all_items.clone();
+    let users = bill.finalized_data.all_users.clone();
+
+    //filter out unbilled user_ids
+    let is_billed: bool = users.get(user_id).is_some()
+        && users.get(user_id).unwrap().external_user_id.is_some()
+        && users.get(user_id).unwrap().is_billed
+        && !bill.users_that_will_not_be_billed.contains(user_id);
+    let consumption_opt = bill.finalized_data.user_consumption.get(user_id);
+
+    if consumption_opt.is_none() {
+        return result;
+    }
+
+    let consumption = consumption_opt.unwrap();
+
+    if users.get(user_id).is_some() && users.get(user_id).unwrap().external_user_id.is_some() {
+        let external_user_id: String = users
+            .get(user_id)
+            .unwrap()
+            .clone()
+            .external_user_id
+            .unwrap()
+            .to_string();
+        let mut _position_index = 0u16;
+        for day in &consumption.per_day.in_order_keys() {
+            let daycontent = consumption.per_day.get(day).unwrap();
+
+            let day_timestamp: DateTime<Utc> = Utc.timestamp(timestamp_from / 1000, 0)
+                + time::Duration::seconds((60i64 * 60i64 * 24i64) * (*day as i64));
+
+            //for every item
+
+            for item_id_purchase in &daycontent.personally_consumed.in_order_keys() {
+                let count = daycontent
+                    .personally_consumed
+                    .get(item_id_purchase)
+                    .unwrap();
+                let item: rustix_bl::datastore::Item =
+                    items.get(item_id_purchase).unwrap().clone();
+                let vat_basis_points = Some(vat_of_item(profile, &item));
+                result.push(DocumentedPosition {
+                    kind: PositionKind::Purchase,
+                    line: OversightCSVLine::normal_purchase(
+                        users.get(user_id).unwrap().username.to_string(),
+                        external_user_id.to_string(),
+                        is_billed,
+                        item.name,
+                        *count,
+                        item.cost_cents as i32,
+                        day_timestamp,
+                    ),
+                    vat_basis_points: vat_basis_points,
+                });
+                _position_index += 1;
+            }
+            for special in &daycontent.specials_consumed {
+                result.push(DocumentedPosition {
+                    kind: PositionKind::Special,
+                    line: OversightCSVLine::special_purchase(
+                        users.get(user_id).unwrap().username.to_string(),
+                        external_user_id.to_string(),
+                        is_billed,
+                        special.name.to_string(),
+                        (special.price) as i32,
+                        day_timestamp,
+                    ),
+                    vat_basis_points: Some(profile.vat_basis_points(None, None)),
+                });
+                _position_index += 1;
+            }
+
+            for item_id_ffa in &daycontent.ffa_giveouts.in_order_keys() {
+                let count = daycontent.ffa_giveouts.get(item_id_ffa).unwrap();
+                let item: rustix_bl::datastore::Item = items.get(item_id_ffa).unwrap().clone();
+                let vat_basis_points = Some(vat_of_item(profile, &item));
+                result.push(DocumentedPosition {
+                    kind: PositionKind::FfaGiveout,
+                    line: OversightCSVLine::ffa_giveout(
+                        users.get(user_id).unwrap().username.to_string(),
+                        external_user_id.to_string(),
+                        is_billed,
+                        item.name,
+                        *count,
+                        item.cost_cents as i32,
+                        day_timestamp,
+                    ),
+                    vat_basis_points: vat_basis_points,
+                });
+                _position_index += 1;
+            }
+
+            for other_user_id in &daycontent.giveouts_to_user_id.in_order_keys() {
+                let paid_for = daycontent.giveouts_to_user_id.get(other_user_id).unwrap();
+
+                let other_user: rustix_bl::datastore::User =
+                    users.get(other_user_id).unwrap().clone();
+                let other_user_name: String = other_user.username;
+                let other_user_id: String =
+                    other_user.external_user_id.unwrap_or("".to_string());
+
+                let budget_given: u64 = paid_for.budget_given;
+                let budget_gotten: u64 = paid_for.budget_gotten;
 
-        let consumption = consumption_opt.unwrap();
-
-        if users.get(user_id).is_some() && users.get(user_id).unwrap().external_user_id.is_some() {
-            let external_user_id: String = users
-                .get(user_id)
-                .unwrap()
-                .clone()
-                .external_user_id
-                .unwrap()
-                .to_string();
-            let mut _position_index = 0u16;
-            for day in &consumption.per_day.in_order_keys() {
-                let daycontent = consumption.per_day.get(day).unwrap();
-
-                let day_timestamp: DateTime<Utc> = Utc.timestamp(timestamp_from / 1000, 0)
-                    + time::Duration::seconds((60i64 * 60i64 * 24i64) * (*day as i64));
-
-                //for every item
-
-                for item_id_purchase in &daycontent.personally_consumed.in_order_keys() {
-                    let count = daycontent
-                        .personally_consumed
-                        .get(item_id_purchase)
-                        .unwrap();
-                    let item: rustix_bl::datastore::Item =
-                        items.get(item_id_purchase).unwrap().clone();
-                    result.push(
-                        OversightCSVLine::normal_purchase(
+                //if budget given or gotten > 0, also add to bill
+                if budget_given > 0 {
+                    result.push(DocumentedPosition {
+                        kind: PositionKind::BudgetGiven,
+                        line: OversightCSVLine::budget_outgoing(
                             users.get(user_id).unwrap().username.to_string(),
                             external_user_id.to_string(),
                             is_billed,
-                            item.name,
-                            *count,
-                            item.cost_cents as i32,
+                            budget_given as i32,
                             day_timestamp,
-                        ).fmt(),
-                    );
+                            other_user_name.to_string(),
+                            other_user_id.to_string(),
+                        ),
+                        vat_basis_points: None,
+                    });
                     _position_index += 1;
                 }
-                for special in &daycontent.specials_consumed {
-                    result.push(
-                        OversightCSVLine::special_purchase(
+                if budget_gotten > 0 {
+                    result.push(DocumentedPosition {
+                        kind: PositionKind::BudgetReceived,
+                        line: OversightCSVLine::budget_incoming(
                             users.get(user_id).unwrap().username.to_string(),
                             external_user_id.to_string(),
                             is_billed,
-                            special.name.to_string(),
-                            (special.price) as i32,
+                            budget_gotten as i32,
                             day_timestamp,
-                        ).fmt(),
-                    );
+                            other_user_name.to_string(),
+                            other_user_id.to_string(),
+                        ),
+                        vat_basis_points: None,
+                    });
                     _position_index += 1;
                 }
 
-                for item_id_ffa in &daycontent.ffa_giveouts.in_order_keys() {
-                    let count = daycontent.ffa_giveouts.get(item_id_ffa).unwrap();
-                    let item: rustix_bl::datastore::Item = items.get(item_id_ffa).unwrap().clone();
-                    result.push(
-                        OversightCSVLine::ffa_giveout(
+                for item_id in &paid_for.count_giveouts_used.in_order_keys() {
+                    let count = paid_for.count_giveouts_used.get(item_id).unwrap();
+                    let item: rustix_bl::datastore::Item = items.get(&item_id).unwrap().clone();
+                    let vat_basis_points = Some(vat_of_item(profile, &item));
+                    result.push(DocumentedPosition {
+                        kind: PositionKind::CountGiveout,
+                        line: OversightCSVLine::count_giveout_outgoing(
                             users.get(user_id).unwrap().username.to_string(),
                             external_user_id.to_string(),
                             is_billed,
@@ -749,99 +1051,21 @@ impl BillFormatting for Bill {
                             *count,
                             item.cost_cents as i32,
                             day_timestamp,
-                        ).fmt(),
-                    );
+                            other_user_name.to_string(),
+                            other_user_id.to_string(),
+                        ),
+                        vat_basis_points: vat_basis_points,
+                    });
                     _position_index += 1;
                 }
-
-                for other_user_id in &daycontent.giveouts_to_user_id.in_order_keys() {
-                    let paid_for = daycontent.giveouts_to_user_id.get(other_user_id).unwrap();
-
-                    let other_user: rustix_bl::datastore::User =
-                        users.get(other_user_id).unwrap().clone();
-                    let other_user_name: String = other_user.username;
-                    let other_user_id: String =
-                        other_user.external_user_id.unwrap_or("".to_string());
-
-                    let budget_given: u64 = paid_for.budget_given;
-                    let budget_gotten: u64 = paid_for.budget_gotten;
-
-                    //if budget given or gotten > 0, also add to bill
-                    if budget_given > 0 {
-                        result.push(
-                            OversightCSVLine::budget_outgoing(
-                                users.get(user_id).unwrap().username.to_string(),
-                                external_user_id.to_string(),
-                                is_billed,
-                                budget_given as i32,
-                                day_timestamp,
-                                other_user_name.to_string(),
-                                other_user_id.to_string(),
-                            ).fmt(),
-                        );
-                        _position_index += 1;
-                    }
-                    if budget_gotten > 0 {
-                        result.push(
-                            OversightCSVLine::budget_incoming(
-                                users.get(user_id).unwrap().username.to_string(),
-                                external_user_id.to_string(),
-                                is_billed,
-                                budget_gotten as i32,
-                                day_timestamp,
-                                other_user_name.to_string(),
-                                other_user_id.to_string(),
-                            ).fmt(),
-                        );
-                        _position_index += 1;
-                    }
-
-                    for item_id in &paid_for.count_giveouts_used.in_order_keys() {
-                        let count = paid_for.count_giveouts_used.get(item_id).unwrap();
-                        let item: rustix_bl::datastore::Item = items.get(&item_id).unwrap().clone();
-                        result.push(
-                            OversightCSVLine::count_giveout_outgoing(
-                                users.get(user_id).unwrap().username.to_string(),
-                                external_user_id.to_string(),
-                                is_billed,
-                                item.name,
-                                *count,
-                                item.cost_cents as i32,
-                                day_timestamp,
-                                other_user_name.to_string(),
-                                other_user_id.to_string(),
-                            ).fmt(),
-                        );
-                        _position_index += 1;
-                    }
-                }
-
-                //list received (per donor), paid, donated (per recipient, or ffa)
-                //list amount of user budget ingoing and outgoing (per donor/recipient, but independent of item, as unique item position)
             }
-        }
 
-        result.sort_by(|a, b| hash_vec(a).cmp(&hash_vec(b)));
-
-        return result;
-    }
-
-    fn list_of_user_ids(&self) -> Vec<u32> {
-        let mut v: Vec<u32> = Vec::new();
-        for (key, _) in &self.finalized_data.all_users {
-            v.push(*key);
+            //list received (per donor), paid, donated (per recipient, or ffa)
+            //list amount of user budget ingoing and outgoing (per donor/recipient, but independent of item, as unique item position)
         }
-        return v;
-    }
-    fn sewobe_header(&self) -> Vec<String> {
-        let raw_header = "Mitgliedsnummer;R vs G;Rechnungsnr.;Rechnungsname;Rechnungsdatum;Positionsnr.;Positionsname;Positionsbeschreibung;Anzahl;Preis pro Einheit;2 == Lastschrift und 1 == Ueberweisung;Empfang per Mail;Zahlungsziel in Tagen;SEPA Intervall (0 fuer einmalig);Datum Rechnungsstellung;Datum Faelligkeit;Datum Positionsende;Mehrwertsteuersatz;Beschreibung; Spendenfähig;Spende;Buchhaltungskonto;Steuerschluessel;Unterkonto Kantine";
-        return raw_header.split(";").map(|s| s.to_string()).collect();
     }
 
-    fn documentation_header(&self) -> Vec<String> {
-        let raw_header = "username;user_id;is_billed;day;item_name;item_count;item_cost_per_unit;budget_cents_outgoing;donor;donor_id;recipient;recipient_id;is_special;is_giveout;is_count;is_budget;is_incoming_donation;is_ffa";
-        return raw_header.split(";").map(|s| s.to_string()).collect();
-    }
+    return result;
 }
 
 fn hash_vec(vec: &Vec<String>) -> u64 {
@@ -853,7 +1077,8 @@ fn hash_vec(vec: &Vec<String>) -> u64 {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use accounting::{AccountingProfile, CategoryAccounting};
     use billformatter::BillFormatting;
     use billformatter::DATE_FORMAT_STRING;
     use chrono;
@@ -871,9 +1096,9 @@ mod tests {
         assert_eq!(a, b);
     }
 
-    #[test]
-    fn simple_sewobe_csv_works() {
-        let bill: Bill = Bill {
+    //the soda is categorized, so category overrides of the accounting profile can be checked
+    pub fn simple_bill() -> Bill {
+        return Bill {
             timestamp_from: 1500000000,
             timestamp_to: 2000000000,
             comment: "No comment here".to_string(),
@@ -942,7 +1167,7 @@ mod tests {
                         rustix_bl::datastore::Item {
                             name: "soda".to_string(),
                             item_id: 1,
-                            category: None,
+                            category: Some("Softdrinks".to_string()),
                             cost_cents: 85,
                             deleted: false,
                         },
@@ -1050,6 +1275,11 @@ mod tests {
                 },
             },
         };
+    }
+
+    #[test]
+    fn simple_sewobe_csv_works() {
+        let bill: Bill = simple_bill();
 
         let should: Vec<Vec<String>> = vec![
             //header
@@ -1083,17 +1313,17 @@ mod tests {
                 .collect(),
         ];
 
-        let should_lines = vec!["ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;0;beer;Selbst gekauft 18.01.;3;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;1;soda;Selbst gekauft 18.01.;19;0,85;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;2;beer;Selbst gekauft 21.01.;99;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;3;Banana;Speziell abgestrichen 21.01.;1;123,45;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;4;beer;An alle ausgegeben 21.01.;9;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;5;soda;An alle ausgegeben 21.01.;1234;0,85;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;6;Guthaben erhalten von bob;Guthaben verbraucht: 25 Cents (intern verrechnet) 21.01.;1;0,00;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;7;Guthaben verschenkt an charlie;Guthaben verbraucht: 45 Cents (intern verrechnet) 21.01.;1;0,45;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293",
-                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;8;Guthaben erhalten von charlie;Guthaben verbraucht: 140 Cents (intern verrechnet) 21.01.;1;0,00;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;0;8293"];
-
-        let is_content = bill.format_as_sewobe_csv(1532886727279i64);
+        let should_lines = vec!["ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;0;beer;Selbst gekauft 18.01.;3;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;1;soda;Selbst gekauft 18.01.;19;0,85;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;2;beer;Selbst gekauft 21.01.;99;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;3;Banana;Speziell abgestrichen 21.01.;1;123,45;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;4;beer;An alle ausgegeben 21.01.;9;0,95;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;5;soda;An alle ausgegeben 21.01.;1234;0,85;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;6;Guthaben erhalten von bob;Guthaben verbraucht: 25 Cents (intern verrechnet) 21.01.;1;0,00;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;7;Guthaben verschenkt an charlie;Guthaben verbraucht: 45 Cents (intern verrechnet) 21.01.;1;0,45;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293",
+                                "ExternalUserId0;2;18072907ExternalUserId0;Kantinenabrechnung 07/18;29.07.2018;8;Guthaben erhalten von charlie;Guthaben verbraucht: 140 Cents (intern verrechnet) 21.01.;1;0,00;2;2;30;0;29.07.2018;12.08.2018;05.07.2118;0;KA 18.01.70-24.01.70;0;;1112;1;8293"];
+
+        let is_content = bill.format_as_sewobe_csv(1532886727279i64, &AccountingProfile::default());
         let is_header = bill.sewobe_header();
 
         let is_lines: Vec<String> = is_content.iter().map(|vec| vec.join(";")).collect();
@@ -1121,6 +1351,105 @@ mod tests {
         }
     }
 
+    fn assert_matches_golden_file(is_content: Vec<Vec<String>>, golden: &str) {
+        let is_lines: Vec<String> = is_content.iter().map(|vec| vec.join(";")).collect();
+        let should_lines: Vec<String> = golden.lines().map(|l| l.to_string()).collect();
+        assert_eq!(should_lines, is_lines);
+    }
+
+    #[test]
+    fn sewobe_csv_with_default_profile_matches_golden_file() {
+        assert_matches_golden_file(
+            simple_bill().format_as_sewobe_csv(1532886727279i64, &AccountingProfile::default()),
+            include_str!("testdata/sewobe_default_profile.csv"),
+        );
+    }
+
+    #[test]
+    fn sewobe_csv_with_custom_profile_matches_golden_file() {
+        let mut profile = AccountingProfile {
+            bill_name: "Getraenkeabrechnung".to_string(),
+            description_prefix: "GA".to_string(),
+            billkeeping_account: "4400".to_string(),
+            subaccount: "100".to_string(),
+            tax_key: "3".to_string(),
+            vat_rate_percent: 19.0,
+            payment_target_days: 14,
+            late_after_days: 7,
+            categories: HashMap::new(),
+            items: HashMap::new(),
+        };
+        profile.categories.insert(
+            "Softdrinks".to_string(),
+            CategoryAccounting {
+                billkeeping_account: Some("4410".to_string()),
+                subaccount: None,
+                tax_key: Some("9".to_string()),
+                vat_rate_percent: Some(7.0),
+            },
+        );
+
+        assert_matches_golden_file(
+            simple_bill().format_as_sewobe_csv(1532886727279i64, &profile),
+            include_str!("testdata/sewobe_custom_profile.csv"),
+        );
+    }
+
+    #[test]
+    fn documentation_carries_vat_totals_per_user_and_summary() {
+        let bill = simple_bill();
+        let mut profile = AccountingProfile::default();
+        profile.vat_rate_percent = 19.0;
+        profile.categories.insert(
+            "Softdrinks".to_string(),
+            CategoryAccounting {
+                vat_rate_percent: Some(7.0),
+                ..CategoryAccounting::default()
+            },
+        );
+
+        let alice = bill.format_as_personalized_documentation(&0, &profile);
+        let total = alice.last().unwrap();
+        assert_eq!(total[4], "total");
+        assert_eq!(total[19..].to_vec(), vec!["1292,75", "1186,51", "106,24"]);
+
+        let summary: Vec<Vec<String>> = bill
+            .vat_summary(&profile)
+            .iter()
+            .map(|row| row[18..].to_vec())
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                vec!["7", "1065,05", "995,37", "69,68"],
+                vec!["19", "228,90", "192,34", "36,56"],
+                vec!["", "1293,95", "1187,71", "106,24"],
+            ]
+        );
+
+        let documentation = bill.format_as_documentation(&profile);
+        assert_eq!(documentation.len(), alice.len() + 3);
+        for row in &documentation {
+            assert_eq!(row.len(), bill.documentation_header().len());
+        }
+    }
+
+    #[test]
+    fn sewobe_columns_follow_the_header() {
+        let bill = simple_bill();
+        let header = bill.sewobe_header();
+        let mut profile = AccountingProfile::default();
+        profile.vat_rate_percent = 7.0;
+        profile.tax_key = "5".to_string();
+        let line = &bill.format_as_sewobe_csv(1532886727279i64, &profile)[0];
+
+        let column = |name: &str| header.iter().position(|h| h == name).unwrap();
+        assert_eq!(line[column("Mehrwertsteuersatz")], "7");
+        assert_eq!(line[column("Steuerschluessel")], "5");
+        assert_eq!(line[column("Buchhaltungskonto")], "1112");
+        assert_eq!(line[column("Unterkonto Kantine")], "8293");
+    }
+
     #[test]
     fn date_format_works() {
         let day_timestamp: chrono::DateTime<chrono::Utc> =
@@ -1330,10 +1659,14 @@ mod tests {
             "is_budget",
             "is_incoming_donation",
             "is_ffa",
+            "vat_rate",
+            "total_gross",
+            "total_net",
+            "total_tax",
         ].iter()
             .map(|s| s.to_string())
             .collect();
-        let should_lines: Vec<Vec<String>> = vec![vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "0,2-5".to_string(), "-25".to_string(), "bob".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "true".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "soda".to_string(), "1234".to_string(), "0,85".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "true".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "beer".to_string(), "9".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "true".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "18.01.1970".to_string(), "soda".to_string(), "19".to_string(), "0,85".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "Banana".to_string(), "1".to_string(), "123,45".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "beer".to_string(), "99".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "0,45".to_string(), "45".to_string(), "".to_string(), "".to_string(), "charlie".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "-1,40".to_string(), "-140".to_string(), "charlie".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "true".to_string(), "false".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "18.01.1970".to_string(), "beer".to_string(), "3".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string()]]
+        let should_lines: Vec<Vec<String>> = vec![vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "0,2-5".to_string(), "-25".to_string(), "bob".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "true".to_string(), "false".to_string(), "".to_string(), "-0,25".to_string(), "-0,25".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "soda".to_string(), "1234".to_string(), "0,85".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "true".to_string(), "0".to_string(), "1048,90".to_string(), "1048,90".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "beer".to_string(), "9".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "true".to_string(), "0".to_string(), "8,55".to_string(), "8,55".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "18.01.1970".to_string(), "soda".to_string(), "19".to_string(), "0,85".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "0".to_string(), "16,15".to_string(), "16,15".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "Banana".to_string(), "1".to_string(), "123,45".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "0".to_string(), "123,45".to_string(), "123,45".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "beer".to_string(), "99".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "0".to_string(), "94,05".to_string(), "94,05".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "0,45".to_string(), "45".to_string(), "".to_string(), "".to_string(), "charlie".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "".to_string(), "0,45".to_string(), "0,45".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "-1,40".to_string(), "-140".to_string(), "charlie".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "true".to_string(), "false".to_string(), "".to_string(), "-1,40".to_string(), "-1,40".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "18.01.1970".to_string(), "beer".to_string(), "3".to_string(), "0,95".to_string(), "0".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "true".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "false".to_string(), "0".to_string(), "2,85".to_string(), "2,85".to_string(), "0,00".to_string()], vec!["alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "".to_string(), "total".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "1292,75".to_string(), "1292,75".to_string(), "0,00".to_string()], vec!["".to_string(), "".to_string(), "".to_string(), "".to_string(), "vat summary".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "0".to_string(), "1293,95".to_string(), "1293,95".to_string(), "0,00".to_string()], vec!["".to_string(), "".to_string(), "".to_string(), "".to_string(), "vat summary total".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "".to_string(), "1293,95".to_string(), "1293,95".to_string(), "0,00".to_string()]]
         /*vec![
             vec![
                 "alice".to_string(), "ExternalUserId0".to_string(), "true".to_string(), "21.01.1970".to_string(), "".to_string(), "1".to_string(), "0,2-5".to_string(), "-25".to_string(), "bob".to_string(), "".to_string(), "".to_string(), "".to_string(), "false".to_string(), "true".to_string(), "false".to_string(), "true".to_string(), "true".to_string(), "false".to_string()],
@@ -1352,7 +1685,7 @@ mod tests {
 
         assert_eq!(should_header, is_header);
 
-        let is_lines = bill.format_as_documentation();
+        let is_lines = bill.format_as_documentation(&AccountingProfile::default());
 
         assert_eq!(should_lines, is_lines);
 
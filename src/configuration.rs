@@ -1,11 +1,34 @@
+use accounting::AccountingProfile;
+use alerts::AlertSettings;
+use credentials::CredentialSettings;
+use devices::DeviceSettings;
+use dunning::DunningSettings;
+use idempotency::IdempotencySettings;
+use inventory::InventorySettings;
+use invoice::InvoiceSettings;
+use notifier::NotificationSettings;
+use prepaid::PrepaidSettings;
+use sepa::SepaSettings;
+use sync::SyncSettings;
+use undo::UndoSettings;
+use webhooks::WebhookSettings;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use std;
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::str::FromStr;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 use toml;
 
-#[derive(Debug, Deserialize, Clone)]
+//fields missing in the toml file keep their inline default, unknown fields are reported as errors
+#[derive(Deserialize, Clone)]
+#[serde(default = "ServerConfig::inline_default_config", deny_unknown_fields)]
 pub struct ServerConfig {
     pub top_items_per_user: u16,
     //default = 4
@@ -22,12 +45,103 @@ pub struct ServerConfig {
     pub smpt_credentials_loginname: String,
     pub smpt_credentials_password: String,
     pub smtp_port: u16,
+    pub sendmail_command: Option<String>,
+    //mails are written as files into this directory instead of being sent, meant for testing
+    pub mail_drop_directory: Option<String>,
+    pub mail_max_attempts: u32,
+    //delay before the first retry, doubled with every further attempt
+    pub mail_retry_base_seconds: i64,
+    //sent mails stay this long in the queue, so they can still be resent
+    pub mail_sent_retention_days: i64,
+    //every open live event stream (GET /events) keeps one worker thread of the server busy
+    pub live_events_max_streams: usize,
     pub use_mock_data: bool,
     pub admin_password: String,
     pub notification_enable: bool,
     pub notification_url: String,
     pub notification_api_key: String,
     pub notification_api_id: String,
+    pub jwt_secret: Option<String>,
+    pub jwt_secret_file: Option<String>,
+    pub jwt_rotation_grace_seconds: i64,
+    pub admin_token_lifetime_seconds: i64,
+    pub bill_download_ticket_lifetime_seconds: i64,
+    pub bill_mail_ticket_lifetime_seconds: i64,
+    pub accounting: AccountingProfile,
+    pub invoice: InvoiceSettings,
+    pub sepa: SepaSettings,
+    pub dunning: DunningSettings,
+    pub prepaid: PrepaidSettings,
+    pub inventory: InventorySettings,
+    pub alerts: AlertSettings,
+    pub notifications: NotificationSettings,
+    pub webhooks: WebhookSettings,
+    pub devices: DeviceSettings,
+    pub credentials: CredentialSettings,
+    pub idempotency: IdempotencySettings,
+    pub sync: SyncSettings,
+    pub undo: UndoSettings,
+}
+
+//printed into the log at startup, so passwords and secrets are only shown as set or not
+const HIDDEN: &str = "<hidden>";
+
+fn hidden(secret: &Option<String>) -> Option<&'static str> {
+    return secret.as_ref().map(|_| HIDDEN);
+}
+
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut notifications = self.notifications.clone();
+        notifications.webhook_secret = notifications.webhook_secret.map(|_| HIDDEN.to_string());
+        return f
+            .debug_struct("ServerConfig")
+            .field("top_items_per_user", &self.top_items_per_user)
+            .field("server_port", &self.server_port)
+            .field("host", &self.host)
+            .field("web_path", &self.web_path)
+            .field("use_persistence", &self.use_persistence)
+            .field("persistence_file_path", &self.persistence_file_path)
+            .field("use_sendmail_instead_of_smtp", &self.use_sendmail_instead_of_smtp)
+            .field("sender_email_address", &self.sender_email_address)
+            .field("smtp_host_address", &self.smtp_host_address)
+            .field("smpt_credentials_loginname", &self.smpt_credentials_loginname)
+            .field("smpt_credentials_password", &HIDDEN)
+            .field("smtp_port", &self.smtp_port)
+            .field("sendmail_command", &self.sendmail_command)
+            .field("mail_drop_directory", &self.mail_drop_directory)
+            .field("mail_max_attempts", &self.mail_max_attempts)
+            .field("mail_retry_base_seconds", &self.mail_retry_base_seconds)
+            .field("mail_sent_retention_days", &self.mail_sent_retention_days)
+            .field("live_events_max_streams", &self.live_events_max_streams)
+            .field("use_mock_data", &self.use_mock_data)
+            .field("admin_password", &HIDDEN)
+            .field("notification_enable", &self.notification_enable)
+            .field("notification_url", &self.notification_url)
+            .field("notification_api_key", &HIDDEN)
+            .field("notification_api_id", &self.notification_api_id)
+            .field("jwt_secret", &hidden(&self.jwt_secret))
+            .field("jwt_secret_file", &self.jwt_secret_file)
+            .field("jwt_rotation_grace_seconds", &self.jwt_rotation_grace_seconds)
+            .field("admin_token_lifetime_seconds", &self.admin_token_lifetime_seconds)
+            .field("bill_download_ticket_lifetime_seconds", &self.bill_download_ticket_lifetime_seconds)
+            .field("bill_mail_ticket_lifetime_seconds", &self.bill_mail_ticket_lifetime_seconds)
+            .field("accounting", &self.accounting)
+            .field("invoice", &self.invoice)
+            .field("sepa", &self.sepa)
+            .field("dunning", &self.dunning)
+            .field("prepaid", &self.prepaid)
+            .field("inventory", &self.inventory)
+            .field("alerts", &self.alerts)
+            .field("notifications", &notifications)
+            .field("webhooks", &self.webhooks)
+            .field("devices", &self.devices)
+            .field("credentials", &self.credentials)
+            .field("idempotency", &self.idempotency)
+            .field("sync", &self.sync)
+            .field("undo", &self.undo)
+            .finish();
+    }
 }
 
 impl ServerConfig {
@@ -45,75 +159,334 @@ impl ServerConfig {
             smpt_credentials_loginname: "username".to_string(),
             smpt_credentials_password: "s3cr3t_p@ssw0rd".to_string(),
             smtp_port: 587,
+            sendmail_command: None,
+            mail_drop_directory: None,
+            mail_max_attempts: 8,
+            mail_retry_base_seconds: 60,
+            mail_sent_retention_days: 30,
+            live_events_max_streams: 4,
             use_mock_data: true,
             admin_password: "".to_string(),
             notification_enable: false,
             notification_url: "http://localhost:1234/fcm/send-message".to_string(),
             notification_api_key: "123".to_string(),
             notification_api_id: "abc".to_string(),
+            jwt_secret: None,
+            jwt_secret_file: None,
+            jwt_rotation_grace_seconds: 7 * 24 * 60 * 60,
+            admin_token_lifetime_seconds: 12 * 60 * 60,
+            bill_download_ticket_lifetime_seconds: 180,
+            bill_mail_ticket_lifetime_seconds: 14 * 24 * 60 * 60,
+            accounting: AccountingProfile::default(),
+            invoice: InvoiceSettings::default(),
+            sepa: SepaSettings::default(),
+            dunning: DunningSettings::default(),
+            prepaid: PrepaidSettings::default(),
+            inventory: InventorySettings::default(),
+            alerts: AlertSettings::default(),
+            notifications: NotificationSettings::default(),
+            webhooks: WebhookSettings::default(),
+            devices: DeviceSettings::default(),
+            credentials: CredentialSettings::default(),
+            idempotency: IdempotencySettings::default(),
+            sync: SyncSettings::default(),
+            undo: UndoSettings::default(),
         };
     }
 
+    //env only configuration on top of the inline defaults, as used before the config file existed
     pub fn from_env() -> ServerConfig {
-        return ServerConfig {
-            top_items_per_user: get_env_u16("CERVISIA_NUMBER_OF_TOP_ITEMS", 4),
-            server_port: get_env_u16("CERVISIA_SERVER_PORT", 8080),
-            host: env::var("CERVISIA_SERVER_HOST").unwrap_or("localhost".to_string()),
-            web_path: env::var("CERVISIA_WEB_PATH").unwrap_or("dist/".to_string()),
-            use_persistence: !env::var("CERVISIA_PERSISTENCE_PATH").is_err(),
-            persistence_file_path: env::var("CERVISIA_PERSISTENCE_PATH")
-                .unwrap_or("./my-cervisia-lmdb.db".to_string()),
-            use_sendmail_instead_of_smtp: get_env_bool("CERVISIA_SMTP_USE_SENDMAIL", None),
-            sender_email_address: env::var("CERVISIA_SMTP_SENDER")
-                .unwrap_or("username@hostname.org".to_string()),
-            smtp_host_address: env::var("CERVISIA_SMTP_HOST")
-                .unwrap_or("smtp.hostname.org".to_string()),
-            smpt_credentials_loginname: env::var("CERVISIA_SMTP_USERNAME")
-                .unwrap_or("username".to_string()),
-            smpt_credentials_password: env::var("CERVISIA_SMTP_PASSWORD")
-                .unwrap_or("s3cr3t_p@ssw0rd".to_string()),
-            smtp_port: get_env_u16("CERVISIA_SMTP_PORT", 587),
-            use_mock_data: get_env_bool("CERVISIA_USE_MOCK_DATA", Some(true)).unwrap_or(true),
-            admin_password: env::var("CERVISIA_ADMIN_PASSWORD").unwrap_or("".to_string()),
-            notification_enable: env::var("CERVISIA_NOTIFICATION_URL").is_ok(),
-            notification_url: env::var("CERVISIA_NOTIFICATION_URL").unwrap_or("https://login.av-huette.de/fcm/send-message".to_string()),
-            notification_api_key: env::var("CERVISIA_NOTIFICATION_API_KEY").unwrap_or("123".to_string()),
-            notification_api_id: env::var("CERVISIA_NOTIFICATION_API_ID").unwrap_or("123".to_string()),
+        return ServerConfig::inline_default_config().apply_env();
+    }
+
+    //every variable which is set overrides the current value, unparsable values are ignored with a warning
+    pub fn apply_env(mut self) -> ServerConfig {
+        override_parsed(&mut self.top_items_per_user, "CERVISIA_NUMBER_OF_TOP_ITEMS");
+        override_parsed(&mut self.server_port, "CERVISIA_SERVER_PORT");
+        override_string(&mut self.host, "CERVISIA_SERVER_HOST");
+        override_string(&mut self.web_path, "CERVISIA_WEB_PATH");
+        if override_string(&mut self.persistence_file_path, "CERVISIA_PERSISTENCE_PATH") {
+            self.use_persistence = true;
+        }
+        let mut use_sendmail = false;
+        if override_parsed(&mut use_sendmail, "CERVISIA_SMTP_USE_SENDMAIL") {
+            self.use_sendmail_instead_of_smtp = Some(use_sendmail);
+        }
+        override_string(&mut self.sender_email_address, "CERVISIA_SMTP_SENDER");
+        override_string(&mut self.smtp_host_address, "CERVISIA_SMTP_HOST");
+        override_string(&mut self.smpt_credentials_loginname, "CERVISIA_SMTP_USERNAME");
+        override_string(&mut self.smpt_credentials_password, "CERVISIA_SMTP_PASSWORD");
+        override_parsed(&mut self.smtp_port, "CERVISIA_SMTP_PORT");
+        override_option(&mut self.sendmail_command, "CERVISIA_SENDMAIL_COMMAND");
+        override_option(&mut self.mail_drop_directory, "CERVISIA_MAIL_DROP_DIRECTORY");
+        override_parsed(&mut self.mail_max_attempts, "CERVISIA_MAIL_MAX_ATTEMPTS");
+        override_parsed(&mut self.mail_retry_base_seconds, "CERVISIA_MAIL_RETRY_BASE_SECONDS");
+        override_parsed(&mut self.mail_sent_retention_days, "CERVISIA_MAIL_SENT_RETENTION_DAYS");
+        override_parsed(&mut self.live_events_max_streams, "CERVISIA_LIVE_EVENTS_MAX_STREAMS");
+        override_parsed(&mut self.use_mock_data, "CERVISIA_USE_MOCK_DATA");
+        override_string(&mut self.admin_password, "CERVISIA_ADMIN_PASSWORD");
+        if override_string(&mut self.notification_url, "CERVISIA_NOTIFICATION_URL") {
+            self.notification_enable = true;
+        }
+        override_string(&mut self.notification_api_key, "CERVISIA_NOTIFICATION_API_KEY");
+        override_string(&mut self.notification_api_id, "CERVISIA_NOTIFICATION_API_ID");
+        override_option(&mut self.jwt_secret, "CERVISIA_JWT_SECRET");
+        override_option(&mut self.jwt_secret_file, "CERVISIA_JWT_SECRET_FILE");
+        override_parsed(&mut self.jwt_rotation_grace_seconds, "CERVISIA_JWT_ROTATION_GRACE_SECONDS");
+        override_parsed(&mut self.admin_token_lifetime_seconds, "CERVISIA_ADMIN_TOKEN_LIFETIME_SECONDS");
+        override_parsed(&mut self.bill_download_ticket_lifetime_seconds, "CERVISIA_BILL_DOWNLOAD_TICKET_LIFETIME_SECONDS");
+        override_parsed(&mut self.bill_mail_ticket_lifetime_seconds, "CERVISIA_BILL_MAIL_TICKET_LIFETIME_SECONDS");
+        return self;
+    }
+
+    //command line flags are the last layer, --config is handled by config_file_path()
+    pub fn apply_args(mut self, args: &[String]) -> Result<ServerConfig, ConfigError> {
+        let mut i = 0;
+        while i < args.len() {
+            let flag = args[i].as_str();
+            match flag {
+                "--no-mock-data" => self.use_mock_data = false,
+                "--mock-data" => self.use_mock_data = true,
+                "--config" | "--port" | "--host" | "--web-path" | "--persistence-path"
+                | "--top-items" => {
+                    let value = match args.get(i + 1) {
+                        Some(value) => value.to_string(),
+                        None => {
+                            return Err(ConfigError::Invalid(vec![format!(
+                                "{} requires a value",
+                                flag
+                            )]))
+                        }
+                    };
+                    i += 1;
+                    match flag {
+                        "--port" => self.server_port = parse_flag(flag, &value)?,
+                        "--host" => self.host = value,
+                        "--web-path" => self.web_path = value,
+                        "--persistence-path" => {
+                            self.use_persistence = true;
+                            self.persistence_file_path = value;
+                        }
+                        "--top-items" => self.top_items_per_user = parse_flag(flag, &value)?,
+                        _ => {}
+                    }
+                }
+                _ => {
+                    return Err(ConfigError::Invalid(vec![format!(
+                        "unknown command line flag {}",
+                        flag
+                    )]))
+                }
+            }
+            i += 1;
+        }
+        return Ok(self);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.server_port == 0 {
+            problems.push("server_port must not be 0".to_string());
+        }
+        if self.use_persistence && self.persistence_file_path.trim().is_empty() {
+            problems.push("persistence_file_path must be set when use_persistence is enabled".to_string());
+        }
+        if self.notification_enable && self.notification_url.trim().is_empty() {
+            problems.push("notification_url must be set when notifications are enabled".to_string());
+        }
+        if self.use_sendmail_instead_of_smtp.is_some() && self.sender_email_address.trim().is_empty() {
+            problems.push("sender_email_address must be set when mails are sent".to_string());
+        }
+        if self.mail_max_attempts == 0 {
+            problems.push("mail_max_attempts must be at least 1".to_string());
+        }
+        if self.mail_retry_base_seconds <= 0 {
+            problems.push("mail_retry_base_seconds must be positive".to_string());
+        }
+        if self.mail_sent_retention_days <= 0 {
+            problems.push("mail_sent_retention_days must be positive".to_string());
+        }
+        if self.live_events_max_streams == 0 {
+            problems.push("live_events_max_streams must be at least 1".to_string());
+        }
+        if self.admin_token_lifetime_seconds <= 0
+            || self.bill_download_ticket_lifetime_seconds <= 0
+            || self.bill_mail_ticket_lifetime_seconds <= 0
+        {
+            problems.push("token lifetimes must be positive".to_string());
+        }
+        if self.jwt_rotation_grace_seconds < 0 {
+            problems.push("jwt_rotation_grace_seconds must not be negative".to_string());
+        }
+        problems.extend(self.accounting.problems());
+        problems.extend(self.dunning.problems());
+        problems.extend(self.alerts.problems());
+        problems.extend(self.notifications.problems());
+        problems.extend(self.webhooks.problems());
+        problems.extend(self.credentials.problems());
+        problems.extend(self.idempotency.problems());
+        problems.extend(self.sync.problems());
+        problems.extend(self.undo.problems());
+        if problems.is_empty() {
+            return Ok(());
+        }
+        return Err(ConfigError::Invalid(problems));
+    }
+
+    //settings which are read per request or per mail and can therefore change while the listener keeps running
+    pub fn with_reloadable_settings_from(&self, newer: &ServerConfig) -> ServerConfig {
+        let mut merged = self.clone();
+        merged.top_items_per_user = newer.top_items_per_user;
+        merged.use_sendmail_instead_of_smtp = newer.use_sendmail_instead_of_smtp;
+        merged.sender_email_address = newer.sender_email_address.to_string();
+        merged.smtp_host_address = newer.smtp_host_address.to_string();
+        merged.smpt_credentials_loginname = newer.smpt_credentials_loginname.to_string();
+        merged.smpt_credentials_password = newer.smpt_credentials_password.to_string();
+        merged.smtp_port = newer.smtp_port;
+        merged.sendmail_command = newer.sendmail_command.clone();
+        merged.mail_drop_directory = newer.mail_drop_directory.clone();
+        merged.mail_max_attempts = newer.mail_max_attempts;
+        merged.mail_retry_base_seconds = newer.mail_retry_base_seconds;
+        merged.mail_sent_retention_days = newer.mail_sent_retention_days;
+        merged.notification_enable = newer.notification_enable;
+        merged.notification_url = newer.notification_url.to_string();
+        merged.notification_api_key = newer.notification_api_key.to_string();
+        merged.notification_api_id = newer.notification_api_id.to_string();
+        merged.accounting = newer.accounting.clone();
+        merged.invoice = newer.invoice.clone();
+        merged.sepa = newer.sepa.clone();
+        merged.dunning = newer.dunning.clone();
+        merged.prepaid = newer.prepaid.clone();
+        merged.inventory = newer.inventory.clone();
+        merged.alerts = newer.alerts.clone();
+        merged.notifications = newer.notifications.clone();
+        merged.webhooks = newer.webhooks.clone();
+        merged.devices = newer.devices.clone();
+        //the credential key is only read at startup, a new file would not be used until then
+        merged.credentials = CredentialSettings {
+            key_file: self.credentials.key_file.clone(),
+            ..newer.credentials.clone()
         };
+        merged.idempotency = newer.idempotency.clone();
+        merged.sync = newer.sync.clone();
+        merged.undo = newer.undo.clone();
+        return merged;
     }
-}
 
-fn get_env_u16(key: &str, def: u16) -> u16 {
-    match env::var(key) {
-        Ok(s) => {
-            let x = s.parse::<u16>();
-            return match x {
-                Ok(v) => v,
-                Err(_) => def,
-            };
+    pub fn settings_requiring_restart(&self, newer: &ServerConfig) -> Vec<&'static str> {
+        let mut changed: Vec<&'static str> = Vec::new();
+        if self.server_port != newer.server_port {
+            changed.push("server_port");
         }
-        Err(_) => {
-            return def;
+        if self.host != newer.host {
+            changed.push("host");
+        }
+        if self.web_path != newer.web_path {
+            changed.push("web_path");
+        }
+        if self.use_persistence != newer.use_persistence
+            || self.persistence_file_path != newer.persistence_file_path
+        {
+            changed.push("persistence");
         }
+        if self.use_mock_data != newer.use_mock_data {
+            changed.push("use_mock_data");
+        }
+        if self.admin_password != newer.admin_password {
+            changed.push("admin_password");
+        }
+        if self.jwt_secret != newer.jwt_secret || self.jwt_secret_file != newer.jwt_secret_file {
+            changed.push("jwt_secret");
+        }
+        if self.jwt_rotation_grace_seconds != newer.jwt_rotation_grace_seconds
+            || self.admin_token_lifetime_seconds != newer.admin_token_lifetime_seconds
+            || self.bill_download_ticket_lifetime_seconds != newer.bill_download_ticket_lifetime_seconds
+            || self.bill_mail_ticket_lifetime_seconds != newer.bill_mail_ticket_lifetime_seconds
+        {
+            changed.push("jwt lifetimes");
+        }
+        if self.credentials.key_file != newer.credentials.key_file {
+            changed.push("credentials.key_file");
+        }
+        return changed;
     }
 }
 
-fn get_env_bool(key: &str, def: Option<bool>) -> Option<bool> {
-    match env::var(key) {
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "could not read configuration: {}", e),
+            ConfigError::Parse(ref e) => write!(f, "could not parse configuration: {}", e),
+            ConfigError::Invalid(ref problems) => {
+                write!(f, "invalid configuration: {}", problems.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn description(&self) -> &str {
+        return "invalid configuration";
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        return ConfigError::Io(e);
+    }
+}
+
+fn override_string(target: &mut String, key: &str) -> bool {
+    return match env::var(key) {
         Ok(s) => {
-            let x = s.parse::<bool>();
-            return match x {
-                Ok(true) => Some(true),
-                Ok(false) => Some(false),
-                Err(_) => def,
-            };
+            *target = s;
+            true
+        }
+        Err(_) => false,
+    };
+}
+
+fn override_option(target: &mut Option<String>, key: &str) -> bool {
+    return match env::var(key) {
+        Ok(s) => {
+            *target = Some(s);
+            true
         }
+        Err(_) => false,
+    };
+}
+
+fn override_parsed<T: FromStr>(target: &mut T, key: &str) -> bool {
+    match env::var(key) {
+        Ok(s) => match s.parse::<T>() {
+            Ok(v) => {
+                *target = v;
+                return true;
+            }
+            Err(_) => {
+                warn!("Ignoring unparsable value {:?} of {}", s, key);
+                return false;
+            }
+        },
         Err(_) => {
-            return def;
+            return false;
         }
     }
 }
 
+fn parse_flag<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    return value
+        .parse::<T>()
+        .map_err(|_| ConfigError::Invalid(vec![format!("invalid value {:?} for {}", value, flag)]));
+}
+
 impl Default for ServerConfig {
     fn default() -> Self {
         return ServerConfig {
@@ -129,12 +502,38 @@ impl Default for ServerConfig {
             smpt_credentials_loginname: "username".to_string(),
             smpt_credentials_password: "s3cr3t_p@ssw0rd".to_string(),
             smtp_port: 587,
+            sendmail_command: None,
+            mail_drop_directory: None,
+            mail_max_attempts: 8,
+            mail_retry_base_seconds: 60,
+            mail_sent_retention_days: 30,
+            live_events_max_streams: 4,
             use_mock_data: true,
             admin_password: "".to_string(),
             notification_enable: false,
             notification_url: "http://localhost:1234/fcm/send-message".to_string(),
             notification_api_key: "123".to_string(),
-            notification_api_id: "abc".to_string(),       
+            notification_api_id: "abc".to_string(),
+            jwt_secret: None,
+            jwt_secret_file: None,
+            jwt_rotation_grace_seconds: 7 * 24 * 60 * 60,
+            admin_token_lifetime_seconds: 12 * 60 * 60,
+            bill_download_ticket_lifetime_seconds: 180,
+            bill_mail_ticket_lifetime_seconds: 14 * 24 * 60 * 60,
+            accounting: AccountingProfile::default(),
+            invoice: InvoiceSettings::default(),
+            sepa: SepaSettings::default(),
+            dunning: DunningSettings::default(),
+            prepaid: PrepaidSettings::default(),
+            inventory: InventorySettings::default(),
+            alerts: AlertSettings::default(),
+            notifications: NotificationSettings::default(),
+            webhooks: WebhookSettings::default(),
+            devices: DeviceSettings::default(),
+            credentials: CredentialSettings::default(),
+            idempotency: IdempotencySettings::default(),
+            sync: SyncSettings::default(),
+            undo: UndoSettings::default(),
         };
     }
 }
@@ -143,11 +542,12 @@ trait Loadable
 where
     Self: std::marker::Sized,
 {
-    fn from_path(path: &std::path::PathBuf) -> Result<Self, io::Error>;
+    fn from_path(path: &std::path::PathBuf) -> Result<Self, ConfigError>;
 }
 
 impl Loadable for ServerConfig {
-    fn from_path(path: &std::path::PathBuf) -> Result<Self, io::Error> {
+    //a missing file means defaults, a broken file is an error instead of silently falling back
+    fn from_path(path: &std::path::PathBuf) -> Result<Self, ConfigError> {
         let file_raw = File::open(path);
 
         if file_raw.is_err() {
@@ -158,11 +558,102 @@ impl Loadable for ServerConfig {
 
         let mut s = String::new();
         file.read_to_string(&mut s)?;
-        let decoded: ServerConfig = toml::from_str(&s).unwrap();
+        let decoded: ServerConfig =
+            toml::from_str(&s).map_err(|e| ConfigError::Parse(format!("{:?}: {}", path, e)))?;
         return Ok(decoded);
     }
 }
 
+//--config wins over CERVISIA_CONFIG_FILE, otherwise ~/.cervisia-server/Settings.toml is used
+pub fn config_file_path(args: &[String]) -> std::path::PathBuf {
+    if let Some(i) = args.iter().position(|a| a == "--config") {
+        if let Some(path) = args.get(i + 1) {
+            return std::path::PathBuf::from(path);
+        }
+    }
+    if let Ok(path) = env::var("CERVISIA_CONFIG_FILE") {
+        return std::path::PathBuf::from(path);
+    }
+    return path_to_config_file_and_mkdirs();
+}
+
+//defaults -> toml file -> env variables -> command line flags
+pub fn load_layered(
+    config_file: &std::path::PathBuf,
+    args: &[String],
+) -> Result<ServerConfig, ConfigError> {
+    let config = ServerConfig::from_path(config_file)?
+        .apply_env()
+        .apply_args(args)?;
+    config.validate()?;
+    return Ok(config);
+}
+
+pub type LiveConfig = Arc<RwLock<ServerConfig>>;
+
+pub fn live_config(config: ServerConfig) -> LiveConfig {
+    return Arc::new(RwLock::new(config));
+}
+
+pub fn current_config(live: &LiveConfig) -> ServerConfig {
+    return live
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+}
+
+pub fn reload_config(live: &LiveConfig, config_file: &std::path::PathBuf, args: &[String]) {
+    let newer = match load_layered(config_file, args) {
+        Ok(newer) => newer,
+        Err(e) => {
+            error!("Keeping the current configuration, reload failed: {}", e);
+            return;
+        }
+    };
+    let mut config = live.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let restart = config.settings_requiring_restart(&newer);
+    if !restart.is_empty() {
+        warn!("Changes of {} only take effect after a restart", restart.join(", "));
+    }
+    *config = config.with_reloadable_settings_from(&newer);
+    info!("Reloaded configuration from {:?}", config_file);
+}
+
+//watches the directory instead of the file, editors often replace the file on save
+pub fn watch_config_file(
+    live: LiveConfig,
+    config_file: std::path::PathBuf,
+    args: Vec<String>,
+) -> notify::Result<thread::JoinHandle<()>> {
+    let directory = match config_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
+    };
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, Duration::from_secs(2))?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    return Ok(thread::spawn(move || {
+        let _watcher = watcher;
+        let file_name = config_file.file_name().map(|n| n.to_os_string());
+        loop {
+            let changed_path = match rx.recv() {
+                Ok(DebouncedEvent::Write(path)) => path,
+                Ok(DebouncedEvent::Create(path)) => path,
+                Ok(DebouncedEvent::Rename(_, path)) => path,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("Stopped watching configuration: {:?}", e);
+                    return;
+                }
+            };
+            if changed_path.file_name().map(|n| n.to_os_string()) == file_name {
+                reload_config(&live, &config_file, &args);
+            }
+        }
+    }));
+}
+
 pub fn path_to_config_file_and_mkdirs() -> std::path::PathBuf {
     let mut path = std::env::home_dir().unwrap();
     path.push(".cervisia-server");
@@ -193,3 +684,128 @@ pub fn path_to_config_file_and_mkdirs() -> std::path::PathBuf {
     }
     return path2;
 }
+
+#[cfg(test)]
+mod tests {
+    use configuration::*;
+    use std::io::Write;
+    use uuid::Uuid;
+
+    fn write_config_file(content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("cervisia-config-{}.toml", Uuid::new_v4()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        return path;
+    }
+
+    fn args(xs: &[&str]) -> Vec<String> {
+        return xs.iter().map(|x| x.to_string()).collect();
+    }
+
+    #[test]
+    fn partial_config_file_keeps_defaults_and_flags_win() {
+        let path = write_config_file("server_port = 9000\nhost = \"0.0.0.0\"\n");
+        let config = ServerConfig::from_path(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.server_port, 9000);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.top_items_per_user, ServerConfig::inline_default_config().top_items_per_user);
+
+        let config = config
+            .apply_args(&args(&["--port", "9100", "--top-items", "7", "--no-mock-data"]))
+            .unwrap();
+        assert_eq!(config.server_port, 9100);
+        assert_eq!(config.top_items_per_user, 7);
+        assert!(!config.use_mock_data);
+    }
+
+    #[test]
+    fn accounting_profile_is_read_from_config_file() {
+        let path = write_config_file(
+            "[accounting]\nbill_name = \"Getraenkeabrechnung\"\nbillkeeping_account = \"4400\"\n\n[accounting.categories.Food]\ntax_key = \"2\"\n",
+        );
+        let config = ServerConfig::from_path(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.accounting.bill_name, "Getraenkeabrechnung");
+        assert_eq!(config.accounting.subaccount, "8293");
+        let food = config.accounting.assignment_for(Some("Food"));
+        assert_eq!(food.billkeeping_account, "4400");
+        assert_eq!(food.tax_key, "2");
+    }
+
+    #[test]
+    fn broken_config_is_reported_instead_of_panicking() {
+        let path = write_config_file("server_prot = 9000\n");
+        let unknown_key = ServerConfig::from_path(&path);
+        let _ = std::fs::remove_file(&path);
+        match unknown_key {
+            Err(ConfigError::Parse(_)) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        let path = write_config_file("server_port = 0\nmail_max_attempts = 0\n");
+        let invalid = load_layered(&path, &[]);
+        let _ = std::fs::remove_file(&path);
+        match invalid {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 2),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+
+        assert!(ServerConfig::default().apply_args(&args(&["--port"])).is_err());
+        assert!(ServerConfig::default().apply_args(&args(&["--port", "abc"])).is_err());
+        assert!(ServerConfig::default().apply_args(&args(&["--verbose"])).is_err());
+    }
+
+    #[test]
+    fn reload_only_takes_over_safe_settings() {
+        let running = ServerConfig::default();
+        let newer = ServerConfig {
+            server_port: running.server_port + 1,
+            admin_password: "changed".to_string(),
+            top_items_per_user: 9,
+            smtp_host_address: "mail.example.org".to_string(),
+            notification_enable: true,
+            ..ServerConfig::default()
+        };
+
+        let merged = running.with_reloadable_settings_from(&newer);
+        assert_eq!(merged.server_port, running.server_port);
+        assert_eq!(merged.admin_password, running.admin_password);
+        assert_eq!(merged.top_items_per_user, 9);
+        assert_eq!(merged.smtp_host_address, "mail.example.org");
+        assert!(merged.notification_enable);
+        assert_eq!(
+            running.settings_requiring_restart(&newer),
+            vec!["server_port", "admin_password"]
+        );
+    }
+
+    #[test]
+    fn the_credential_key_file_is_not_reloaded() {
+        let running = ServerConfig::default();
+        let mut newer = ServerConfig::default();
+        newer.credentials.key_file = Some("/etc/cervisia/credential-key".to_string());
+        newer.credentials.min_pin_length = 6;
+
+        let merged = running.with_reloadable_settings_from(&newer);
+        assert_eq!(merged.credentials.key_file, None);
+        assert_eq!(merged.credentials.min_pin_length, 6);
+        assert_eq!(running.settings_requiring_restart(&newer), vec!["credentials.key_file"]);
+    }
+
+    #[test]
+    fn secrets_are_not_printed() {
+        let config = ServerConfig {
+            admin_password: "admin-s3cret".to_string(),
+            jwt_secret: Some("jwt-s3cret".to_string()),
+            ..ServerConfig::default()
+        };
+        let printed = format!("{:?}", config);
+        assert!(printed.contains("server_port"));
+        assert!(!printed.contains("admin-s3cret"));
+        assert!(!printed.contains("jwt-s3cret"));
+        assert!(!printed.contains(&config.smpt_credentials_password));
+    }
+}
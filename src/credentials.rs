@@ -0,0 +1,656 @@
+use auth;
+use configuration::ServerConfig;
+use errors::ServerError;
+use notifier;
+use ring::{digest, pbkdf2};
+use sidecar;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use typescriptify::TypeScriptifyTrait;
+
+const PIN_HASH_SCHEME: &str = "pbkdf2-sha256";
+
+const PIN_HASH_LENGTH: usize = 32;
+
+//configured in the [credentials] table of the config file, the credentials themselves are managed via the admin api
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialSettings {
+    //purchases above this need the pin or a card of members who have one, None only asks members who opted in.
+    //carts with specials always count as above, their price is only set later
+    pub required_above_cents: Option<i64>,
+    pub min_pin_length: usize,
+    pub max_failed_attempts: u32,
+    pub lockout_seconds: i64,
+    //mixed into every pin and card hash, defaults to a file next to the database.
+    //it has to be backed up with the credentials, without it no pin or card matches anymore
+    pub key_file: Option<String>,
+    //pins only have a few digits, so every guess has to be expensive. stored with each hash, changing it only affects new pins
+    pub pin_hash_iterations: u32,
+}
+
+impl Default for CredentialSettings {
+    fn default() -> Self {
+        return CredentialSettings {
+            required_above_cents: None,
+            min_pin_length: 4,
+            max_failed_attempts: 5,
+            lockout_seconds: 300,
+            key_file: None,
+            pin_hash_iterations: 100_000,
+        };
+    }
+}
+
+impl CredentialSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.required_above_cents.map(|c| c < 0).unwrap_or(false) {
+            problems.push("credentials.required_above_cents must not be negative".to_string());
+        }
+        if self.min_pin_length < 1 {
+            problems.push("credentials.min_pin_length must be at least 1".to_string());
+        }
+        if self.max_failed_attempts < 1 {
+            problems.push("credentials.max_failed_attempts must be at least 1".to_string());
+        }
+        if self.lockout_seconds < 0 {
+            problems.push("credentials.lockout_seconds must not be negative".to_string());
+        }
+        if self.pin_hash_iterations < 1 {
+            problems.push("credentials.pin_hash_iterations must be at least 1".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserCredentials {
+    pub pin_hash: Option<String>,
+    pub card_hashes: Vec<String>,
+    //opted in by the member, every purchase then needs a credential regardless of its price
+    pub required: bool,
+}
+
+impl UserCredentials {
+    pub fn has_credential(&self) -> bool {
+        return self.pin_hash.is_some() || !self.card_hashes.is_empty();
+    }
+}
+
+//all that leaves the server, hashes are never sent
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct CredentialInfo {
+    pub user_id: u32,
+    pub has_pin: bool,
+    pub card_count: usize,
+    pub required: bool,
+}
+
+//sent along with a purchase, one of both is enough
+#[derive(Serialize, Deserialize, Debug, Clone, Default, TypeScriptify)]
+pub struct PurchaseCredential {
+    #[serde(default)]
+    pub pin: Option<String>,
+    #[serde(default)]
+    pub card_uid: Option<String>,
+}
+
+//without a pin the current pin is removed
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetPin {
+    pub user_id: u32,
+    pub pin: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct CardAssignment {
+    pub user_id: u32,
+    pub card_uid: String,
+}
+
+//changed by the member at the kiosk, so it needs the member's credential
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetCredentialRequired {
+    pub user_id: u32,
+    pub required: bool,
+    pub credential: PurchaseCredential,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct ResolveCard {
+    pub card_uid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct ResolvedCard {
+    pub user_id: u32,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct FailureState {
+    failed_attempts: u32,
+    locked_until_epoch_seconds: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialBook {
+    pub users: BTreeMap<u32, UserCredentials>,
+    //failed attempts are only counted in memory, a restart unlocks everyone
+    #[serde(skip)]
+    failures: HashMap<String, FailureState>,
+    //never stored in the credentials file, see CredentialSettings::key_file
+    #[serde(skip)]
+    pub key: String,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+//whether the credential sent with a request belongs to the member
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CredentialMatch {
+    Missing,
+    Matches,
+    Wrong,
+}
+
+//readers report uids in different notations, e.g. "04:a2:1f" and "04A21F" are the same card
+pub fn normalize_card_uid(card_uid: &str) -> String {
+    return card_uid
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase();
+}
+
+impl CredentialBook {
+    pub fn load(config: &ServerConfig) -> CredentialBook {
+        let path = sidecar::sidecar_path(config, "credentials");
+        let mut book: CredentialBook = match path {
+            Some(ref path) => sidecar::load_json_or_default(path, "user credentials"),
+            None => CredentialBook::default(),
+        };
+        book.path = path;
+        book.key = load_key(config);
+        return book;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist user credentials to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    //card uids have to be found by their hash, so they get a keyed hash instead of a salted one
+    fn card_hash(&self, card_uid: &str) -> Result<String, ServerError> {
+        return card_hash_with(&self.key, card_uid);
+    }
+
+    fn pin_hash_of(&self, user_id: u32) -> Option<String> {
+        return self.users.get(&user_id).and_then(|c| c.pin_hash.clone());
+    }
+
+    pub fn infos(&self) -> Vec<CredentialInfo> {
+        return self
+            .users
+            .iter()
+            .map(|(user_id, c)| CredentialInfo {
+                user_id: *user_id,
+                has_pin: c.pin_hash.is_some(),
+                card_count: c.card_hashes.len(),
+                required: c.required,
+            })
+            .collect();
+    }
+
+    pub fn set_pin(
+        &mut self,
+        user_id: u32,
+        pin: Option<String>,
+        settings: &CredentialSettings,
+    ) -> Result<(), ServerError> {
+        let pin_hash = match pin {
+            Some(pin) => {
+                let pin = pin.trim().to_string();
+                if pin.len() < settings.min_pin_length || !pin.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(ServerError::BadRequest(format!(
+                        "A PIN needs at least {} digits and nothing else",
+                        settings.min_pin_length
+                    )));
+                }
+                Some(hash_pin(&self.key, user_id, &pin, settings.pin_hash_iterations)?)
+            }
+            None => None,
+        };
+        self.users.entry(user_id).or_insert_with(UserCredentials::default).pin_hash = pin_hash;
+        return Ok(());
+    }
+
+    pub fn add_card(&mut self, user_id: u32, card_uid: &str) -> Result<(), ServerError> {
+        if normalize_card_uid(card_uid).is_empty() {
+            return Err(ServerError::BadRequest("card_uid is empty".to_string()));
+        }
+        match self.user_for_card(card_uid)? {
+            Some(owner) if owner == user_id => return Ok(()),
+            Some(owner) => {
+                return Err(ServerError::Conflict(format!(
+                    "This card is already assigned to user {}",
+                    owner
+                )))
+            }
+            None => {}
+        }
+        let card_hash = self.card_hash(card_uid)?;
+        self.users
+            .entry(user_id)
+            .or_insert_with(UserCredentials::default)
+            .card_hashes
+            .push(card_hash);
+        return Ok(());
+    }
+
+    pub fn remove_card(&mut self, user_id: u32, card_uid: &str) -> Result<(), ServerError> {
+        let card_hash = self.card_hash(card_uid)?;
+        let credentials = self
+            .users
+            .get_mut(&user_id)
+            .ok_or(ServerError::NotFound("card not found".to_string()))?;
+        let before = credentials.card_hashes.len();
+        credentials.card_hashes.retain(|h| *h != card_hash);
+        if credentials.card_hashes.len() == before {
+            return Err(ServerError::NotFound("card not found".to_string()));
+        }
+        return Ok(());
+    }
+
+    pub fn set_required(&mut self, user_id: u32, required: bool) -> Result<(), ServerError> {
+        let credentials = self.users.entry(user_id).or_insert_with(UserCredentials::default);
+        if required && !credentials.has_credential() {
+            return Err(ServerError::Conflict(
+                "Set a PIN or a card before requiring it for purchases".to_string(),
+            ));
+        }
+        credentials.required = required;
+        return Ok(());
+    }
+
+    pub fn user_for_card(&self, card_uid: &str) -> Result<Option<u32>, ServerError> {
+        let card_hash = self.card_hash(card_uid)?;
+        return Ok(self
+            .users
+            .iter()
+            .find(|&(_, c)| c.card_hashes.contains(&card_hash))
+            .map(|(user_id, _)| *user_id));
+    }
+
+    //members without any credential cannot prove anything, the threshold only protects those who have one
+    pub fn is_required(
+        &self,
+        user_id: u32,
+        cost_cents: i64,
+        has_unpriced_specials: bool,
+        settings: &CredentialSettings,
+    ) -> bool {
+        return match self.users.get(&user_id) {
+            Some(c) if c.has_credential() => {
+                c.required
+                    || settings
+                        .required_above_cents
+                        .map(|t| has_unpriced_specials || cost_cents > t)
+                        .unwrap_or(false)
+            }
+            _ => false,
+        };
+    }
+
+    fn is_locked(&self, key: &str, now_epoch_seconds: i64) -> bool {
+        return self
+            .failures
+            .get(key)
+            .map(|state| state.locked_until_epoch_seconds > now_epoch_seconds)
+            .unwrap_or(false);
+    }
+
+    fn check_not_locked(&self, key: &str, now_epoch_seconds: i64) -> Result<(), ServerError> {
+        return match self.failures.get(key) {
+            Some(state) if state.locked_until_epoch_seconds > now_epoch_seconds => {
+                Err(ServerError::TooManyRequests(format!(
+                    "Too many failed attempts, try again in {} seconds",
+                    state.locked_until_epoch_seconds - now_epoch_seconds
+                )))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    fn record_failure(&mut self, key: &str, settings: &CredentialSettings, now_epoch_seconds: i64) {
+        let state = self.failures.entry(key.to_string()).or_insert_with(FailureState::default);
+        state.failed_attempts += 1;
+        if state.failed_attempts >= settings.max_failed_attempts {
+            warn!("Locking {} for {} seconds after {} failed attempts", key, settings.lockout_seconds, state.failed_attempts);
+            state.failed_attempts = 0;
+            state.locked_until_epoch_seconds = now_epoch_seconds + settings.lockout_seconds;
+        }
+    }
+
+    //counts the outcome of match_credential against the lockout
+    pub fn verify(
+        &mut self,
+        user_id: u32,
+        matched: CredentialMatch,
+        settings: &CredentialSettings,
+        now_epoch_seconds: i64,
+    ) -> Result<(), ServerError> {
+        let key = user_lock_key(user_id);
+        self.check_not_locked(&key, now_epoch_seconds)?;
+        return match matched {
+            CredentialMatch::Missing => Err(ServerError::Unauthorized(
+                "A PIN or card is required for this purchase".to_string(),
+            )),
+            CredentialMatch::Matches => {
+                self.failures.remove(&key);
+                Ok(())
+            }
+            CredentialMatch::Wrong => {
+                self.record_failure(&key, settings, now_epoch_seconds);
+                Err(ServerError::Unauthorized("Wrong PIN or card".to_string()))
+            }
+        };
+    }
+
+    pub fn check_purchase(
+        &mut self,
+        user_id: u32,
+        cost_cents: i64,
+        has_unpriced_specials: bool,
+        matched: CredentialMatch,
+        settings: &CredentialSettings,
+        now_epoch_seconds: i64,
+    ) -> Result<(), ServerError> {
+        if !self.is_required(user_id, cost_cents, has_unpriced_specials, settings) {
+            return Ok(());
+        }
+        return self.verify(user_id, matched, settings, now_epoch_seconds);
+    }
+
+    //unknown cards count against the client, so uids cannot be guessed by trying
+    pub fn resolve_card(
+        &mut self,
+        card_uid: &str,
+        client: &str,
+        settings: &CredentialSettings,
+        now_epoch_seconds: i64,
+    ) -> Result<u32, ServerError> {
+        let key = format!("resolve:{}", client);
+        self.check_not_locked(&key, now_epoch_seconds)?;
+        return match self.user_for_card(card_uid)? {
+            Some(user_id) => Ok(user_id),
+            None => {
+                self.record_failure(&key, settings, now_epoch_seconds);
+                Err(ServerError::NotFound("Unknown card".to_string()))
+            }
+        };
+    }
+}
+
+fn user_lock_key(user_id: u32) -> String {
+    return format!("user:{}", user_id);
+}
+
+fn card_hash_with(secret: &str, card_uid: &str) -> Result<String, ServerError> {
+    return notifier::sign(&format!("card:{}", normalize_card_uid(card_uid)), secret)
+        .map_err(|e| ServerError::Internal(format!("Could not hash credential: {}", e)));
+}
+
+//the user id is part of the hash, so members with the same pin do not share a hash
+fn pin_secret(key: &str, user_id: u32, pin: &str) -> String {
+    return format!("{}:pin:{}:{}", key, user_id, pin.trim());
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return None;
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect();
+}
+
+//stored as pbkdf2-sha256$<iterations>$<salt>$<hash>, the salt is random per pin
+pub fn hash_pin(key: &str, user_id: u32, pin: &str, iterations: u32) -> Result<String, ServerError> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or(ServerError::Internal("credentials.pin_hash_iterations must be at least 1".to_string()))?;
+    let salt = auth::random_secret();
+    let mut hash = [0u8; PIN_HASH_LENGTH];
+    pbkdf2::derive(
+        &digest::SHA256,
+        iterations,
+        salt.as_bytes(),
+        pin_secret(key, user_id, pin).as_bytes(),
+        &mut hash,
+    );
+    return Ok(format!("{}${}${}${}", PIN_HASH_SCHEME, iterations, salt, to_hex(&hash)));
+}
+
+fn pin_matches(key: &str, user_id: u32, pin: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    if parts.len() != 4 || parts[0] != PIN_HASH_SCHEME {
+        return false;
+    }
+    let iterations = match parts[1].parse::<u32>().ok().and_then(NonZeroU32::new) {
+        Some(iterations) => iterations,
+        None => return false,
+    };
+    let expected = match from_hex(parts[3]) {
+        Some(expected) => expected,
+        None => return false,
+    };
+    return pbkdf2::verify(
+        &digest::SHA256,
+        iterations,
+        parts[2].as_bytes(),
+        pin_secret(key, user_id, pin).as_bytes(),
+        &expected,
+    )
+    .is_ok();
+}
+
+//the pin hash is slow on purpose, so it is computed without holding the lock.
+//callers do this before locking the backend and pass the outcome to verify or check_purchase
+pub fn match_credential(
+    book: &RwLock<CredentialBook>,
+    user_id: u32,
+    credential: Option<&PurchaseCredential>,
+    now_epoch_seconds: i64,
+) -> Result<CredentialMatch, ServerError> {
+    let credential = match credential {
+        Some(credential) => credential,
+        None => return Ok(CredentialMatch::Missing),
+    };
+    let (key, stored_pin, card_matches) = {
+        let book = book.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        //verify refuses locked members anyway, there is no need to spend time on their guesses
+        if book.is_locked(&user_lock_key(user_id), now_epoch_seconds) {
+            return Ok(CredentialMatch::Wrong);
+        }
+        let card_matches = match credential.card_uid {
+            Some(ref card_uid) => book.user_for_card(card_uid)? == Some(user_id),
+            None => false,
+        };
+        (book.key.to_string(), book.pin_hash_of(user_id), card_matches)
+    };
+    if card_matches {
+        return Ok(CredentialMatch::Matches);
+    }
+    let (pin, stored) = match (credential.pin.as_ref(), stored_pin) {
+        (Some(pin), Some(stored)) => (pin, stored),
+        _ => return Ok(CredentialMatch::Wrong),
+    };
+    if !pin_matches(&key, user_id, pin, &stored) {
+        return Ok(CredentialMatch::Wrong);
+    }
+    return Ok(CredentialMatch::Matches);
+}
+
+//a copy of the credentials file alone is not enough to try pins or card uids, the key lives in its own file
+pub fn load_key(config: &ServerConfig) -> String {
+    let path: Option<PathBuf> = match config.credentials.key_file {
+        Some(ref file) => Some(PathBuf::from(file)),
+        None => sidecar::sidecar_path(config, "credential-key"),
+    };
+    let path = match path {
+        Some(path) => path,
+        None => {
+            info!("No persistence configured, credentials are only valid until restart");
+            return auth::random_secret();
+        }
+    };
+    match sidecar::load_json::<String>(&path) {
+        Ok(Some(ref key)) if !key.is_empty() => return key.to_string(),
+        Ok(_) => {}
+        Err(e) => {
+            //a new key would silently invalidate every credential, so the broken file is left alone
+            error!("Could not read the credential key from {:?}, no PIN or card will match: {:?}", path, e);
+            return auth::random_secret();
+        }
+    }
+    let key = auth::random_secret();
+    match sidecar::save_json(&path, &key) {
+        Ok(()) => info!("Generated new credential key in {:?}", path),
+        Err(e) => error!("Could not persist the credential key in {:?}: {:?}", path, e),
+    }
+    return key;
+}
+
+#[cfg(test)]
+mod tests {
+    use credentials::*;
+    use serde_json;
+    use std;
+    use std::sync::RwLock;
+
+    fn book() -> CredentialBook {
+        let mut book = CredentialBook::default();
+        book.key = "test-key".to_string();
+        return book;
+    }
+
+    //a handful of iterations, the real number would make the tests slow
+    fn settings() -> CredentialSettings {
+        return CredentialSettings {
+            pin_hash_iterations: 10,
+            ..CredentialSettings::default()
+        };
+    }
+
+    fn matched(book: &mut CredentialBook, user_id: u32, credential: Option<&PurchaseCredential>, now: i64) -> CredentialMatch {
+        let holder = RwLock::new(std::mem::replace(book, CredentialBook::default()));
+        let result = match_credential(&holder, user_id, credential, now).unwrap();
+        *book = holder.into_inner().unwrap();
+        return result;
+    }
+
+    fn pin(pin: &str) -> PurchaseCredential {
+        return PurchaseCredential {
+            pin: Some(pin.to_string()),
+            card_uid: None,
+        };
+    }
+
+    #[test]
+    fn pins_and_cards_are_hashed_and_verified() {
+        let settings = settings();
+        let mut book = book();
+        assert!(book.set_pin(1, Some("12".to_string()), &settings).is_err());
+        assert!(book.set_pin(1, Some("12ab".to_string()), &settings).is_err());
+        book.set_pin(1, Some("1234".to_string()), &settings).unwrap();
+        book.set_pin(2, Some("1234".to_string()), &settings).unwrap();
+        book.add_card(1, "04:a2:1f:9c").unwrap();
+        assert!(book.add_card(2, "04A21F9C").is_err());
+
+        let stored = serde_json::to_string(&book).unwrap();
+        assert!(!stored.contains("1234"));
+        assert!(!stored.contains("04A21F9C"));
+        assert!(!stored.contains("test-key"));
+        assert_ne!(book.users[&1].pin_hash, book.users[&2].pin_hash);
+        assert!(book.users[&1].pin_hash.as_ref().unwrap().starts_with("pbkdf2-sha256$10$"));
+
+        let card = PurchaseCredential {
+            pin: None,
+            card_uid: Some("04-A2-1F-9C".to_string()),
+        };
+        assert_eq!(matched(&mut book, 1, Some(&pin("1234")), 0), CredentialMatch::Matches);
+        assert_eq!(matched(&mut book, 1, Some(&pin("1235")), 0), CredentialMatch::Wrong);
+        assert_eq!(matched(&mut book, 1, Some(&card), 0), CredentialMatch::Matches);
+        assert_eq!(matched(&mut book, 2, Some(&card), 0), CredentialMatch::Wrong);
+        assert_eq!(matched(&mut book, 1, None, 0), CredentialMatch::Missing);
+        assert_eq!(book.user_for_card("04a21f9c").unwrap(), Some(1));
+
+        //without the key, the credentials file is worthless
+        book.key = "another-key".to_string();
+        assert_eq!(matched(&mut book, 1, Some(&pin("1234")), 0), CredentialMatch::Wrong);
+        assert_eq!(book.user_for_card("04a21f9c").unwrap(), None);
+        book.key = "test-key".to_string();
+
+        book.remove_card(1, "04a21f9c").unwrap();
+        assert_eq!(book.user_for_card("04a21f9c").unwrap(), None);
+    }
+
+    #[test]
+    fn policy_and_lockout() {
+        let settings = CredentialSettings {
+            required_above_cents: Some(500),
+            max_failed_attempts: 2,
+            lockout_seconds: 60,
+            ..settings()
+        };
+        let mut book = book();
+        //members without credentials are never asked
+        assert!(!book.is_required(1, 1000, false, &settings));
+        assert!(book.set_required(1, true).is_err());
+
+        book.set_pin(1, Some("4711".to_string()), &settings).unwrap();
+        assert!(!book.is_required(1, 500, false, &settings));
+        assert!(book.is_required(1, 501, false, &settings));
+        //specials are priced later and may cost anything
+        assert!(book.is_required(1, 0, true, &settings));
+        book.set_required(1, true).unwrap();
+        assert!(book.is_required(1, 0, false, &settings));
+
+        assert_eq!(
+            book.check_purchase(1, 100, false, CredentialMatch::Missing, &settings, 0)
+                .unwrap_err()
+                .error_code(),
+            "unauthorized"
+        );
+        for _ in 0..2 {
+            let wrong = matched(&mut book, 1, Some(&pin("0000")), 0);
+            assert!(book.check_purchase(1, 100, false, wrong, &settings, 0).is_err());
+        }
+        //locked, even the right pin is refused until the lockout is over
+        let right = matched(&mut book, 1, Some(&pin("4711")), 30);
+        assert_eq!(
+            book.check_purchase(1, 100, false, right, &settings, 30).unwrap_err().error_code(),
+            "too_many_requests"
+        );
+        let right = matched(&mut book, 1, Some(&pin("4711")), 61);
+        assert!(book.check_purchase(1, 100, false, right, &settings, 61).is_ok());
+
+        assert!(book.resolve_card("DEADBEEF", "10.0.0.1", &settings, 0).is_err());
+        assert!(book.resolve_card("DEADBEEF", "10.0.0.1", &settings, 0).is_err());
+        assert_eq!(
+            book.resolve_card("DEADBEEF", "10.0.0.1", &settings, 1).unwrap_err().error_code(),
+            "too_many_requests"
+        );
+    }
+}
@@ -0,0 +1,705 @@
+use auth;
+use auth::NotAuthorizedError;
+use billformatter;
+use chrono::prelude::*;
+use configuration::{current_config, ServerConfig};
+use errors::ServerError;
+use iron;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::BeforeMiddleware;
+use notifier;
+use persistent::State;
+use rustix_bl::datastore::{Bill, BillState};
+use serde_json;
+use server::{Backend, SharedConfig, SharedDevices};
+use sidecar;
+use std;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+use url::form_urlencoded;
+
+pub const DEVICE_KEY_HEADER: &str = "X-Device-Key";
+
+//only the hash of a device key is stored, the context keeps it apart from webhook signatures
+const KEY_HASH_CONTEXT: &str = "cervisia-device-key";
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+//purchase terminals may read everything that is not admin only, but only write these
+const TERMINAL_WRITE_PATHS: &[&str] = &[
+    "/purchases",
+    "/purchases/cart",
+    "/purchases/ffa",
+    "/purchases/sync",
+    "/purchases/undo/user",
+    "/credentials/required",
+    "/credentials/resolve",
+];
+
+//reachable without a device key even if only registered devices are accepted, otherwise no device could ever be approved
+const UNREGISTERED_PATHS: &[&str] = &[
+    "/devices/register",
+    "/admin/login",
+    "/admin/checkpassword",
+    "/admin/devices",
+    "/admin/devices/approve",
+    "/admin/devices/revoke",
+    "/bill/download",
+    "/bill/download/secure",
+    "/bill/download/sepa",
+];
+
+//configured in the [devices] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceSettings {
+    //off by default, so existing single kiosk setups keep working without a key
+    pub require_registered_device: bool,
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        return DeviceSettings {
+            require_registered_device: false,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum DeviceRole {
+    PurchaseTerminal,
+    AdminConsole,
+    ReadOnlyDisplay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum DeviceStatus {
+    Pending,
+    Approved,
+    Revoked,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Device {
+    pub id: u64,
+    pub name: String,
+    pub role: DeviceRole,
+    pub status: DeviceStatus,
+    //the key itself is only shown once, when the device gets approved
+    pub api_key_hash: Option<String>,
+    pub registered_epoch_seconds: i64,
+    pub approved_epoch_seconds: Option<i64>,
+    pub last_seen_epoch_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct DeviceInfo {
+    pub id: u64,
+    pub name: String,
+    pub role: DeviceRole,
+    pub status: DeviceStatus,
+    pub registered_epoch_seconds: i64,
+    pub approved_epoch_seconds: Option<i64>,
+    pub last_seen_epoch_seconds: Option<i64>,
+}
+
+impl<'a> From<&'a Device> for DeviceInfo {
+    fn from(device: &'a Device) -> Self {
+        return DeviceInfo {
+            id: device.id,
+            name: device.name.to_string(),
+            role: device.role,
+            status: device.status,
+            registered_epoch_seconds: device.registered_epoch_seconds,
+            approved_epoch_seconds: device.approved_epoch_seconds,
+            last_seen_epoch_seconds: device.last_seen_epoch_seconds,
+        };
+    }
+}
+
+//sent by the kiosk itself, the role is only a wish until an admin approves it
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct RegisterDevice {
+    pub name: String,
+    pub role: DeviceRole,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct ApproveDevice {
+    pub id: u64,
+    pub role: DeviceRole,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct DeviceId {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct IssuedDeviceKey {
+    pub id: u64,
+    pub role: DeviceRole,
+    //has to be entered on the device, it cannot be shown again
+    pub api_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PurchaseOrigin {
+    pub purchase_id: u64,
+    pub device_id: u64,
+    pub user_id: u32,
+    //item or special name, the oversight csv matches positions by it
+    pub item_name: String,
+    pub timestamp_epoch_millis: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeviceBook {
+    pub next_id: u64,
+    pub devices: Vec<Device>,
+    //only purchases which are not part of a finalized bill, all of them are in the origin log
+    #[serde(skip)]
+    pub origins: Vec<PurchaseOrigin>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    //one line per purchase, only ever appended to
+    #[serde(skip)]
+    pub origins_path: Option<PathBuf>,
+}
+
+//what an export needs for the device column, the origin log is read once the device lock is released
+#[derive(Debug, Clone)]
+pub struct DeviceColumn {
+    pub names: HashMap<u64, String>,
+    pub origins: Vec<PurchaseOrigin>,
+    timestamp_from: i64,
+    timestamp_to: i64,
+    origins_path: Option<PathBuf>,
+}
+
+impl DeviceColumn {
+    pub fn load_logged_origins(&mut self) {
+        let path = match self.origins_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        match sidecar::load_json_lines::<PurchaseOrigin>(path) {
+            Ok(origins) => {
+                let (from, to) = (self.timestamp_from, self.timestamp_to);
+                self.origins = origins
+                    .into_iter()
+                    .filter(|o| from <= o.timestamp_epoch_millis && o.timestamp_epoch_millis <= to)
+                    .collect();
+            }
+            Err(e) => error!("Could not read purchase origins from {:?}: {:?}", path, e),
+        }
+    }
+}
+
+fn hash_key(api_key: &str) -> Option<String> {
+    return notifier::sign(api_key, KEY_HASH_CONTEXT).ok();
+}
+
+impl DeviceBook {
+    pub fn load(config: &ServerConfig) -> DeviceBook {
+        let path = match sidecar::sidecar_path(config, "devices") {
+            Some(path) => path,
+            None => return DeviceBook::default(),
+        };
+        let mut book: DeviceBook = sidecar::load_json_or_default(&path, "devices");
+        book.path = Some(path);
+        let origins_path = match sidecar::sidecar_log_path(config, "device-origins") {
+            Some(path) => path,
+            None => return book,
+        };
+        book.origins = match sidecar::load_json_lines(&origins_path) {
+            Ok(origins) => origins,
+            Err(e) => {
+                error!("Could not read purchase origins from {:?}: {:?}", origins_path, e);
+                Vec::new()
+            }
+        };
+        book.origins_path = Some(origins_path);
+        return book;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist devices to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn infos(&self) -> Vec<DeviceInfo> {
+        return self.devices.iter().map(DeviceInfo::from).collect();
+    }
+
+    pub fn register(&mut self, request: RegisterDevice, now_epoch_seconds: i64) -> Result<DeviceInfo, ServerError> {
+        let name = request.name.trim().to_string();
+        if name.is_empty() {
+            return Err(ServerError::BadRequest("A device needs a name".to_string()));
+        }
+        self.next_id += 1;
+        let device = Device {
+            id: self.next_id,
+            name: name,
+            role: request.role,
+            status: DeviceStatus::Pending,
+            api_key_hash: None,
+            registered_epoch_seconds: now_epoch_seconds,
+            approved_epoch_seconds: None,
+            last_seen_epoch_seconds: None,
+        };
+        let info = DeviceInfo::from(&device);
+        self.devices.push(device);
+        return Ok(info);
+    }
+
+    //approving an already approved device replaces its key
+    pub fn approve(
+        &mut self,
+        request: ApproveDevice,
+        now_epoch_seconds: i64,
+    ) -> Result<IssuedDeviceKey, ServerError> {
+        let api_key = auth::random_secret();
+        let api_key_hash = hash_key(&api_key)
+            .ok_or(ServerError::Internal("Could not derive a device key".to_string()))?;
+        let device = self
+            .devices
+            .iter_mut()
+            .find(|d| d.id == request.id)
+            .ok_or(ServerError::NotFound(format!("No device with id {}", request.id)))?;
+        device.role = request.role;
+        device.status = DeviceStatus::Approved;
+        device.api_key_hash = Some(api_key_hash);
+        device.approved_epoch_seconds = Some(now_epoch_seconds);
+        return Ok(IssuedDeviceKey {
+            id: device.id,
+            role: device.role,
+            api_key: api_key,
+        });
+    }
+
+    //the device stays listed, so purchases it made keep their origin
+    pub fn revoke(&mut self, id: u64) -> Result<(), ServerError> {
+        let device = self
+            .devices
+            .iter_mut()
+            .find(|d| d.id == id)
+            .ok_or(ServerError::NotFound(format!("No device with id {}", id)))?;
+        device.status = DeviceStatus::Revoked;
+        device.api_key_hash = None;
+        return Ok(());
+    }
+
+    //last_seen is only kept in memory and persisted with the next change
+    pub fn authenticate(&mut self, api_key: &str, now_epoch_seconds: i64) -> Option<Device> {
+        let api_key_hash = hash_key(api_key)?;
+        let device = self.devices.iter_mut().find(|d| {
+            d.status == DeviceStatus::Approved && d.api_key_hash.as_ref() == Some(&api_key_hash)
+        })?;
+        device.last_seen_epoch_seconds = Some(now_epoch_seconds);
+        return Some(device.clone());
+    }
+
+    pub fn device_name(&self, id: u64) -> Option<&str> {
+        return self.devices.iter().find(|d| d.id == id).map(|d| d.name.as_str());
+    }
+
+    //appended to the origin log, a purchase never rewrites a whole file
+    pub fn record_origins(&mut self, origins: Vec<PurchaseOrigin>) {
+        if origins.is_empty() {
+            return;
+        }
+        if let Some(ref path) = self.origins_path {
+            if let Err(e) = sidecar::append_json_lines(path, &origins) {
+                error!("Could not persist purchase origins to {:?}: {:?}", path, e);
+            }
+        }
+        self.origins.extend(origins);
+    }
+
+    //origins of finalized bills are only needed by exports, which read them from the log.
+    //without a log the memory is the only copy, so nothing is dropped
+    pub fn prune_origins(&mut self, finalized_periods: &[(i64, i64)]) -> usize {
+        if self.origins_path.is_none() {
+            return 0;
+        }
+        let before = self.origins.len();
+        self.origins.retain(|o| {
+            !finalized_periods
+                .iter()
+                .any(|&(from, to)| from <= o.timestamp_epoch_millis && o.timestamp_epoch_millis <= to)
+        });
+        return before - self.origins.len();
+    }
+
+    pub fn device_column(&self, timestamp_from: i64, timestamp_to: i64) -> DeviceColumn {
+        let origins: Vec<PurchaseOrigin> = match self.origins_path {
+            Some(_) => Vec::new(),
+            None => self
+                .origins
+                .iter()
+                .filter(|o| timestamp_from <= o.timestamp_epoch_millis && o.timestamp_epoch_millis <= timestamp_to)
+                .cloned()
+                .collect(),
+        };
+        return DeviceColumn {
+            names: self.devices.iter().map(|d| (d.id, d.name.to_string())).collect(),
+            origins: origins,
+            timestamp_from: timestamp_from,
+            timestamp_to: timestamp_to,
+            origins_path: self.origins_path.clone(),
+        };
+    }
+
+    //purchases of finalized bills get no name, their origins were pruned
+    pub fn device_names_for_purchases(&self, purchase_ids: &[u64]) -> BTreeMap<u64, String> {
+        let mut names: BTreeMap<u64, String> = BTreeMap::new();
+        for origin in &self.origins {
+            if !purchase_ids.contains(&origin.purchase_id) {
+                continue;
+            }
+            if let Some(name) = self.device_name(origin.device_id) {
+                names.insert(origin.purchase_id, name.to_string());
+            }
+        }
+        return names;
+    }
+}
+
+//all purchases booked by one event share its timestamp
+pub fn origins_of_event(
+    backend: &Backend,
+    device_id: u64,
+    user_id: u32,
+    timestamp_epoch_millis: i64,
+) -> Vec<PurchaseOrigin> {
+    use rustix_bl::datastore::DatastoreQueries;
+    use rustix_bl::datastore::Purchase;
+
+    let mut origins: Vec<PurchaseOrigin> = Vec::new();
+    let purchases = backend
+        .datastore
+        .global_log_filtered(timestamp_epoch_millis - 1, timestamp_epoch_millis + 1)
+        .to_vec();
+    for purchase in &purchases {
+        let (purchase_id, consumer_id, item_name) = match purchase {
+            &Purchase::SimplePurchase {
+                unique_id,
+                consumer_id,
+                item_id,
+                ..
+            } => match backend.datastore.items.get(&item_id) {
+                Some(item) => (unique_id, consumer_id, item.name.to_string()),
+                None => continue,
+            },
+            &Purchase::SpecialPurchase {
+                unique_id,
+                consumer_id,
+                ref special_name,
+                ..
+            } => (unique_id, consumer_id, special_name.to_string()),
+            //giveouts are not booked for a member at the terminal
+            &Purchase::FFAPurchase { .. } => continue,
+        };
+        if *purchase.get_timestamp() != timestamp_epoch_millis || consumer_id != user_id {
+            continue;
+        }
+        origins.push(PurchaseOrigin {
+            purchase_id: purchase_id,
+            device_id: device_id,
+            user_id: user_id,
+            item_name: item_name,
+            timestamp_epoch_millis: timestamp_epoch_millis,
+        });
+    }
+    return origins;
+}
+
+pub fn finalized_periods(backend: &Backend) -> Vec<(i64, i64)> {
+    use rustix_bl::datastore::DatastoreQueries;
+    return backend
+        .datastore
+        .bills_filtered(None, -10000000000000000i64, 10000000000000000i64)
+        .iter()
+        .filter(|b| b.bill_state != BillState::Created)
+        .map(|b| (b.timestamp_from, b.timestamp_to))
+        .collect();
+}
+
+//appended as last column, positions are matched by member, day and item name just like they were aggregated
+pub fn with_device_column(rows: Vec<Vec<String>>, bill: &Bill, column: &DeviceColumn) -> Vec<Vec<String>> {
+    let mut per_position: HashMap<(String, String, String), BTreeSet<String>> = HashMap::new();
+    for origin in &column.origins {
+        if origin.timestamp_epoch_millis < bill.timestamp_from
+            || origin.timestamp_epoch_millis > bill.timestamp_to
+        {
+            continue;
+        }
+        let external_user_id = match bill
+            .finalized_data
+            .all_users
+            .get(&origin.user_id)
+            .and_then(|u| u.external_user_id.clone())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let name = match column.names.get(&origin.device_id) {
+            Some(name) => name.replace(";", ","),
+            None => continue,
+        };
+        let day_index = (origin.timestamp_epoch_millis - bill.timestamp_from) / MILLIS_PER_DAY;
+        let day = Utc
+            .timestamp((bill.timestamp_from + day_index * MILLIS_PER_DAY) / 1000, 0)
+            .format(billformatter::DATE_FORMAT_STRING)
+            .to_string();
+        per_position
+            .entry((external_user_id, day, origin.item_name.to_string()))
+            .or_insert_with(BTreeSet::new)
+            .insert(name);
+    }
+    return rows
+        .into_iter()
+        .map(|mut row| {
+            let devices: String = if row.len() > 4 {
+                per_position
+                    .get(&(row[1].to_string(), row[3].to_string(), row[4].to_string()))
+                    .map(|names| names.iter().cloned().collect::<Vec<String>>().join(", "))
+                    .unwrap_or(String::new())
+            } else {
+                String::new()
+            };
+            row.push(devices);
+            row
+        })
+        .collect();
+}
+
+pub fn normalized_path(path: &[&str]) -> String {
+    let segments: Vec<&str> = path.iter().filter(|s| !s.is_empty()).map(|s| *s).collect();
+    return format!("/{}", segments.join("/"));
+}
+
+//roles only restrict, admin routes still need an admin token on top
+pub fn check_access(
+    device: Option<&Device>,
+    path: &str,
+    is_read: bool,
+    require_registered_device: bool,
+) -> Result<(), String> {
+    let device = match device {
+        Some(device) => device,
+        None => {
+            if !require_registered_device
+                || path.starts_with("/public/")
+                || UNREGISTERED_PATHS.contains(&path)
+            {
+                return Ok(());
+            }
+            return Err("Only registered devices are accepted, register via /api/devices/register and ask an admin for approval".to_string());
+        }
+    };
+    let is_admin_path = auth::ADMIN_ONLY_PATHS.contains(&path);
+    return match device.role {
+        DeviceRole::AdminConsole => Ok(()),
+        DeviceRole::ReadOnlyDisplay if is_read && !is_admin_path => Ok(()),
+        DeviceRole::ReadOnlyDisplay => Err(format!("Read-only display '{}' may not call {}", device.name, path)),
+        DeviceRole::PurchaseTerminal if !is_admin_path && (is_read || TERMINAL_WRITE_PATHS.contains(&path)) => Ok(()),
+        DeviceRole::PurchaseTerminal => Err(format!("Purchase terminal '{}' may not call {}", device.name, path)),
+    };
+}
+
+//the device which sent the current request, set by DeviceAuthentication
+pub struct CurrentDevice;
+impl Key for CurrentDevice { type Value = Device; }
+
+//the key is expected as 'X-Device-Key: <key>', the query parameter device_key works for plain links
+fn extract_device_key(req: &iron::request::Request) -> Option<String> {
+    let from_header = req
+        .headers
+        .get_raw(DEVICE_KEY_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .map(|value| value.trim().to_string());
+    if from_header.is_some() {
+        return from_header;
+    }
+    //not via Params, that would consume the body of json posts
+    return req.url.query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|&(ref key, _)| key == "device_key")
+            .map(|(_, value)| value.into_owned())
+    });
+}
+
+fn reject(path: &str, reason: String) -> IronError {
+    warn!("Rejected device call to {}: {}", path, reason);
+    let body = serde_json::to_string(&ServerError::Unauthorized(reason).to_write_result())
+        .unwrap_or(String::new());
+    return IronError::new(
+        NotAuthorizedError {
+            path: path.to_string(),
+        },
+        (iron::status::Unauthorized, body),
+    );
+}
+
+pub struct DeviceAuthentication;
+
+impl BeforeMiddleware for DeviceAuthentication {
+    fn before(&self, req: &mut iron::request::Request) -> IronResult<()> {
+        let path = normalized_path(&req.url.path());
+        let is_read = req.method == iron::method::Method::Get;
+        let require_registered_device = req
+            .get::<State<SharedConfig>>()
+            .map(|live| current_config(&live).devices.require_registered_device)
+            .unwrap_or(false);
+
+        let device: Option<Device> = match extract_device_key(req) {
+            None => None,
+            Some(api_key) => {
+                let bookholder = match req.get::<State<SharedDevices>>() {
+                    Ok(bookholder) => bookholder,
+                    Err(_) => return Err(reject(&path, "Devices are not available".to_string())),
+                };
+                let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+                match book.authenticate(&api_key, Utc::now().timestamp()) {
+                    Some(device) => Some(device),
+                    None => return Err(reject(&path, "Unknown or revoked device key".to_string())),
+                }
+            }
+        };
+
+        if let Err(reason) = check_access(device.as_ref(), &path, is_read, require_registered_device) {
+            return Err(reject(&path, reason));
+        }
+        if let Some(device) = device {
+            req.extensions.insert::<CurrentDevice>(device);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ServerConfig;
+    use devices::*;
+    use serde_json;
+    use std;
+    use uuid::Uuid;
+
+    fn registered(book: &mut DeviceBook, role: DeviceRole) -> IssuedDeviceKey {
+        let info = book
+            .register(
+                RegisterDevice {
+                    name: "Bar kiosk".to_string(),
+                    role: role,
+                },
+                100,
+            )
+            .unwrap();
+        assert_eq!(info.status, DeviceStatus::Pending);
+        return book.approve(ApproveDevice { id: info.id, role: role }, 200).unwrap();
+    }
+
+    #[test]
+    fn approved_keys_authenticate_until_revoked() {
+        let mut book = DeviceBook::default();
+        assert!(book
+            .register(RegisterDevice { name: " ".to_string(), role: DeviceRole::PurchaseTerminal }, 100)
+            .is_err());
+
+        let issued = registered(&mut book, DeviceRole::PurchaseTerminal);
+        assert!(book.authenticate("wrong", 300).is_none());
+        let device = book.authenticate(&issued.api_key, 300).unwrap();
+        assert_eq!(device.last_seen_epoch_seconds, Some(300));
+        //the key is never stored in plain
+        assert!(!serde_json::to_string(&book).unwrap().contains(&issued.api_key));
+
+        //approving again replaces the key
+        let reissued = book.approve(ApproveDevice { id: issued.id, role: DeviceRole::AdminConsole }, 400).unwrap();
+        assert!(book.authenticate(&issued.api_key, 500).is_none());
+        assert_eq!(book.authenticate(&reissued.api_key, 500).unwrap().role, DeviceRole::AdminConsole);
+
+        book.revoke(issued.id).unwrap();
+        assert!(book.authenticate(&reissued.api_key, 600).is_none());
+        assert_eq!(book.infos()[0].status, DeviceStatus::Revoked);
+        assert!(book.revoke(99).is_err());
+    }
+
+    #[test]
+    fn roles_restrict_what_a_device_may_call() {
+        let mut book = DeviceBook::default();
+        let terminal_key = registered(&mut book, DeviceRole::PurchaseTerminal);
+        let display_key = registered(&mut book, DeviceRole::ReadOnlyDisplay);
+        let terminal = book.authenticate(&terminal_key.api_key, 1).unwrap();
+        let display = book.authenticate(&display_key.api_key, 1).unwrap();
+
+        assert!(check_access(Some(&terminal), "/purchases/cart", false, true).is_ok());
+        assert!(check_access(Some(&terminal), "/users/all", true, true).is_ok());
+        assert!(check_access(Some(&terminal), "/users", false, true).is_err());
+        assert!(check_access(Some(&terminal), "/inventory", true, true).is_err());
+
+        assert!(check_access(Some(&display), "/purchases/global", true, true).is_ok());
+        assert!(check_access(Some(&display), "/purchases", false, true).is_err());
+
+        assert!(check_access(None, "/purchases", false, false).is_ok());
+        assert!(check_access(None, "/purchases", false, true).is_err());
+        assert!(check_access(None, "/devices/register", false, true).is_ok());
+        assert!(check_access(None, "/public/health", true, true).is_ok());
+    }
+
+    fn origin(purchase_id: u64, timestamp_epoch_millis: i64) -> PurchaseOrigin {
+        return PurchaseOrigin {
+            purchase_id: purchase_id,
+            device_id: 1,
+            user_id: 0,
+            item_name: "beer".to_string(),
+            timestamp_epoch_millis: timestamp_epoch_millis,
+        };
+    }
+
+    #[test]
+    fn finalized_origins_leave_the_memory_but_stay_in_the_log() {
+        let directory = std::env::temp_dir().join(format!("cervisia-devices-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let mut book = DeviceBook::load(&config);
+        book.register(RegisterDevice { name: "Tap room".to_string(), role: DeviceRole::PurchaseTerminal }, 1)
+            .unwrap();
+        book.save();
+        book.record_origins(vec![origin(1, 100), origin(2, 200)]);
+        book.record_origins(vec![origin(3, 300)]);
+
+        assert_eq!(book.prune_origins(&[(0, 250)]), 2);
+        assert_eq!(book.device_names_for_purchases(&[1, 3]).len(), 1);
+
+        let mut column = book.device_column(0, 250);
+        column.load_logged_origins();
+        assert_eq!(column.origins.len(), 2);
+        assert_eq!(column.names[&1], "Tap room");
+
+        //the devices file stays small, a restart reads the origins back from the log
+        let reloaded = DeviceBook::load(&config);
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(reloaded.devices.len(), 1);
+        assert_eq!(reloaded.origins.len(), 3);
+        assert!(!serde_json::to_string(&reloaded).unwrap().contains("origins"));
+    }
+
+    #[test]
+    fn without_a_log_nothing_is_pruned() {
+        let mut book = DeviceBook::default();
+        book.record_origins(vec![origin(1, 100)]);
+        assert_eq!(book.prune_origins(&[(0, 250)]), 0);
+        assert_eq!(book.device_column(0, 250).origins.len(), 1);
+    }
+}
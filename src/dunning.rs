@@ -0,0 +1,491 @@
+use accounting::AccountingProfile;
+use billformatter::cents_to_currency_string_i64;
+use chrono::prelude::*;
+use configuration::{current_config, LiveConfig, ServerConfig};
+use mailqueue::{MailDraft, MailQueue};
+use payments::{user_balances, PaymentLedger, PaymentStatus};
+use prepaid::PrepaidBook;
+use rustix_bl::datastore::{Bill, User};
+use server::Backend;
+use sidecar;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use typescriptify::TypeScriptifyTrait;
+use workers::Worker;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+//one escalation step, days are counted from the payment deadline of the bill
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReminderLevel {
+    pub days_after_deadline: u32,
+    //placeholders: {username}, {open_amount}, {period}, {deadline}, {level}
+    pub subject: String,
+    pub body: String,
+}
+
+//configured in the [dunning] table of the config file, levels as [[dunning.levels]]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DunningSettings {
+    pub enabled: bool,
+    pub check_interval_seconds: u64,
+    pub levels: Vec<ReminderLevel>,
+}
+
+impl Default for DunningSettings {
+    fn default() -> Self {
+        return DunningSettings {
+            enabled: false,
+            check_interval_seconds: 60 * 60,
+            levels: vec![
+                ReminderLevel {
+                    days_after_deadline: 0,
+                    subject: "Reminder: your bill for {period} is still open".to_string(),
+                    body: "Hello {username},\n\nyour bill for {period} was due on {deadline}, {open_amount} € are still open. Please transfer the amount soon.\n".to_string(),
+                },
+                ReminderLevel {
+                    days_after_deadline: 14,
+                    subject: "Second reminder: your bill for {period} is still open".to_string(),
+                    body: "Hello {username},\n\nwe have not received the remaining {open_amount} € for {period} yet, although the bill was due on {deadline}. Please settle it within the next days.\n".to_string(),
+                },
+                ReminderLevel {
+                    days_after_deadline: 28,
+                    subject: "Final reminder: your bill for {period} is still open".to_string(),
+                    body: "Hello {username},\n\nthis is the last reminder for the {open_amount} € of your bill for {period}, which was due on {deadline}. If the amount is not paid, the board will get in touch with you.\n".to_string(),
+                },
+            ],
+        };
+    }
+}
+
+impl DunningSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.check_interval_seconds == 0 {
+            problems.push("dunning.check_interval_seconds must be positive".to_string());
+        }
+        let mut last_days: Option<u32> = None;
+        for level in &self.levels {
+            if last_days.map(|d| d >= level.days_after_deadline).unwrap_or(false) {
+                problems.push("dunning.levels must be sorted by strictly increasing days_after_deadline".to_string());
+            }
+            last_days = Some(level.days_after_deadline);
+        }
+        if self.enabled && self.levels.is_empty() {
+            problems.push("dunning.levels must not be empty when dunning is enabled".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct ReminderRecord {
+    pub bill_timestamp_from: i64,
+    pub bill_timestamp_to: i64,
+    //1 for the first reminder
+    pub level: u32,
+    pub open_cents: i64,
+    pub sent_epoch_seconds: i64,
+    pub mail_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct UserDunning {
+    pub user_id: u32,
+    //rustix-bl has no mail addresses, users without one are skipped
+    pub email: Option<String>,
+    pub paused: bool,
+    pub history: Vec<ReminderRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetDunningContact {
+    pub user_id: u32,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct PauseDunning {
+    pub user_id: u32,
+    pub paused: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DunningBook {
+    pub users: Vec<UserDunning>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl DunningBook {
+    pub fn load(config: &ServerConfig) -> DunningBook {
+        let path = match sidecar::sidecar_path(config, "dunning") {
+            Some(path) => path,
+            None => return DunningBook::default(),
+        };
+        let mut book: DunningBook = sidecar::load_json_or_default(&path, "dunning history");
+        book.path = Some(path);
+        return book;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist dunning history to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn get(&self, user_id: u32) -> Option<&UserDunning> {
+        return self.users.iter().find(|u| u.user_id == user_id);
+    }
+
+    pub fn entry(&mut self, user_id: u32) -> &mut UserDunning {
+        if self.get(user_id).is_none() {
+            self.users.push(UserDunning {
+                user_id: user_id,
+                email: None,
+                paused: false,
+                history: Vec::new(),
+            });
+            self.users.sort_by_key(|u| u.user_id);
+        }
+        return self.users.iter_mut().find(|u| u.user_id == user_id).unwrap();
+    }
+
+    //the reminder with the highest level sent for this bill
+    fn last_reminder(&self, user_id: u32, bill_timestamp_from: i64, bill_timestamp_to: i64) -> Option<&ReminderRecord> {
+        return self.get(user_id).and_then(|u| {
+            u.history
+                .iter()
+                .filter(|r| {
+                    r.bill_timestamp_from == bill_timestamp_from
+                        && r.bill_timestamp_to == bill_timestamp_to
+                })
+                .max_by_key(|r| r.level)
+        });
+    }
+}
+
+//bills are due late_after_days after the end of the billing period, like the late date of the SEWOBE export
+pub fn deadline_epoch_seconds(bill_timestamp_to: i64, profile: &AccountingProfile) -> i64 {
+    return bill_timestamp_to / 1000 + profile.late_after_days as i64 * SECONDS_PER_DAY;
+}
+
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = template.to_string();
+    for &(key, ref value) in values {
+        result = result.replace(&format!("{{{}}}", key), value);
+    }
+    return result;
+}
+
+pub fn format_day(epoch_seconds: i64) -> String {
+    return Utc.timestamp(epoch_seconds, 0).format("%d.%m.%Y").to_string();
+}
+
+#[derive(Debug, Clone)]
+pub struct DueReminder {
+    pub user_id: u32,
+    pub bill_timestamp_from: i64,
+    pub bill_timestamp_to: i64,
+    pub level: u32,
+    pub open_cents: i64,
+    pub draft: MailDraft,
+}
+
+//at most one reminder per user and bill and run, escalating one level at a time.
+//a reminder sent late (e.g. because dunning was switched on late) pushes the next one back,
+//so the member always gets the configured days between two levels to pay
+pub fn due_reminders(
+    now_epoch_seconds: i64,
+    bills: &[Bill],
+    ledger: &PaymentLedger,
+    users: &HashMap<u32, User>,
+    config: &ServerConfig,
+    book: &DunningBook,
+) -> Vec<DueReminder> {
+    let mut result: Vec<DueReminder> = Vec::new();
+    let levels = &config.dunning.levels;
+
+    for balance in user_balances(bills, ledger, users, &config.accounting) {
+        let (email, paused) = match book.get(balance.user_id) {
+            Some(entry) => (entry.email.clone(), entry.paused),
+            None => (None, false),
+        };
+        if paused {
+            continue;
+        }
+        for bill in &balance.bills {
+            if bill.open_cents <= 0
+                || (bill.status != PaymentStatus::Open && bill.status != PaymentStatus::PartiallyPaid)
+            {
+                continue;
+            }
+            let deadline = deadline_epoch_seconds(bill.bill_timestamp_to, &config.accounting);
+            let last = book.last_reminder(balance.user_id, bill.bill_timestamp_from, bill.bill_timestamp_to);
+            let sent = last.map(|r| r.level).unwrap_or(0);
+            let next = match levels.get(sent as usize) {
+                Some(level) => level,
+                None => continue,
+            };
+            if now_epoch_seconds < deadline + next.days_after_deadline as i64 * SECONDS_PER_DAY {
+                continue;
+            }
+            if let Some(last) = last {
+                let previous_days = levels
+                    .get(sent as usize - 1)
+                    .map(|l| l.days_after_deadline)
+                    .unwrap_or(0);
+                let gap_days = next.days_after_deadline.saturating_sub(previous_days) as i64;
+                if now_epoch_seconds < last.sent_epoch_seconds + gap_days * SECONDS_PER_DAY {
+                    continue;
+                }
+            }
+            let email = match email {
+                Some(ref email) => email.to_string(),
+                None => {
+                    warn!(
+                        "Bill of {} (user {}) is overdue, but there is no mail address to remind them",
+                        balance.username, balance.user_id
+                    );
+                    continue;
+                }
+            };
+
+            let values = vec![
+                ("username", balance.username.to_string()),
+                ("open_amount", cents_to_currency_string_i64(bill.open_cents)),
+                (
+                    "period",
+                    format!(
+                        "{} - {}",
+                        format_day(bill.bill_timestamp_from / 1000),
+                        format_day(bill.bill_timestamp_to / 1000)
+                    ),
+                ),
+                ("deadline", format_day(deadline)),
+                ("level", (sent + 1).to_string()),
+            ];
+            result.push(DueReminder {
+                user_id: balance.user_id,
+                bill_timestamp_from: bill.bill_timestamp_from,
+                bill_timestamp_to: bill.bill_timestamp_to,
+                level: sent + 1,
+                open_cents: bill.open_cents,
+                draft: MailDraft {
+                    receiver_email: email,
+                    subject: render(&next.subject, &values),
+                    body: render(&next.body, &values),
+                    attachments: HashMap::new(),
+                    binary_attachments: HashMap::new(),
+                    zipfilename: format!("{}_{}", bill.bill_timestamp_from, bill.bill_timestamp_to),
+                },
+            });
+        }
+    }
+    return result;
+}
+
+//queues the due reminders and records them, returns how many were queued
+pub fn run_dunning(
+    now_epoch_seconds: i64,
+    bills: &[Bill],
+    ledger: &PaymentLedger,
+    users: &HashMap<u32, User>,
+    config: &ServerConfig,
+    book: &mut DunningBook,
+    queue: &mut MailQueue,
+) -> usize {
+    let reminders = due_reminders(now_epoch_seconds, bills, ledger, users, config, book);
+    for reminder in &reminders {
+        let mail_id = queue.enqueue(reminder.draft.clone(), now_epoch_seconds);
+        info!(
+            "Queued reminder {} for user {} as mail {}",
+            reminder.level, reminder.user_id, mail_id
+        );
+        book.entry(reminder.user_id).history.push(ReminderRecord {
+            bill_timestamp_from: reminder.bill_timestamp_from,
+            bill_timestamp_to: reminder.bill_timestamp_to,
+            level: reminder.level,
+            open_cents: reminder.open_cents,
+            sent_epoch_seconds: now_epoch_seconds,
+            mail_id: mail_id,
+        });
+    }
+    return reminders.len();
+}
+
+//settings are read from the live config on every round, so dunning can be switched on without a restart
+pub fn start_dunning_worker(
+    book: Arc<RwLock<DunningBook>>,
+    ledger: Arc<RwLock<PaymentLedger>>,
+    prepaid: Arc<RwLock<PrepaidBook>>,
+    backend: Arc<RwLock<Backend>>,
+    queue: Arc<RwLock<MailQueue>>,
+    config: LiveConfig,
+) -> Worker {
+    return Worker::spawn_paced("dunning", move || {
+        let conf = current_config(&config);
+        if conf.dunning.enabled {
+            let (bills, users) = {
+                let dat = backend.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let prepaid = prepaid.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                use rustix_bl::datastore::DatastoreQueries;
+                //prepaid users owe nothing on bills, so they never get reminders
+                let bills: Vec<Bill> = dat
+                    .datastore
+                    .bills_filtered(None, -10000000000000000i64, 10000000000000000i64)
+                    .iter()
+                    .map(|b| prepaid.without_prepaid_users(b))
+                    .collect();
+                (bills, dat.datastore.users.clone())
+            };
+            let ledger = ledger.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+            let mut book = book.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut queue = queue.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if run_dunning(Utc::now().timestamp(), &bills, &ledger, &users, &conf, &mut book, &mut queue) > 0 {
+                queue.save();
+                book.save();
+            }
+        }
+        return Duration::from_secs(conf.dunning.check_interval_seconds.max(1));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use billformatter::tests::simple_bill;
+    use configuration::ServerConfig;
+    use dunning::*;
+    use mailqueue::{process_due_mails, MailQueue};
+    use payments::{CreatePayment, PaymentLedger, PaymentMethod};
+    use std;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use uuid::Uuid;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn book_with_contact() -> DunningBook {
+        let mut book = DunningBook::default();
+        book.entry(0).email = Some("alice@hostname.org".to_string());
+        return book;
+    }
+
+    #[test]
+    fn reminders_escalate_until_the_bill_is_paid() {
+        let config = ServerConfig::default();
+        let bills = vec![simple_bill()];
+        let deadline = deadline_epoch_seconds(bills[0].timestamp_to, &config.accounting);
+        let mut ledger = PaymentLedger::default();
+        let mut book = book_with_contact();
+        let mut queue = MailQueue::default();
+        let users = HashMap::new();
+
+        assert_eq!(run_dunning(deadline - 1, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+        assert_eq!(run_dunning(deadline, &bills, &ledger, &users, &config, &mut book, &mut queue), 1);
+        //the same level is never sent twice
+        assert_eq!(run_dunning(deadline + DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+        assert!(queue.mails[0].draft.subject.starts_with("Reminder: your bill for"));
+        assert!(queue.mails[0].draft.body.contains("Hello alice"));
+        assert!(queue.mails[0].draft.body.contains("1292,75 €"));
+
+        ledger.add(
+            CreatePayment {
+                user_id: 0,
+                amount_cents: 100000,
+                method: PaymentMethod::Transfer,
+                received_epoch_seconds: None,
+                bill_timestamp_from: None,
+                bill_timestamp_to: None,
+                comment: String::new(),
+            },
+            deadline,
+        );
+        assert_eq!(run_dunning(deadline + 14 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 1);
+        assert!(queue.mails[1].draft.subject.starts_with("Second reminder"));
+        assert!(queue.mails[1].draft.body.contains("292,75 €"));
+
+        book.entry(0).paused = true;
+        assert_eq!(run_dunning(deadline + 28 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+        book.entry(0).paused = false;
+        assert_eq!(run_dunning(deadline + 28 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 1);
+        //no level after the final reminder
+        assert_eq!(run_dunning(deadline + 100 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+
+        let levels: Vec<u32> = book.get(0).unwrap().history.iter().map(|r| r.level).collect();
+        assert_eq!(levels, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn late_reminders_push_back_the_next_level() {
+        let config = ServerConfig::default();
+        let bills = vec![simple_bill()];
+        let deadline = deadline_epoch_seconds(bills[0].timestamp_to, &config.accounting);
+        let ledger = PaymentLedger::default();
+        let mut book = book_with_contact();
+        let mut queue = MailQueue::default();
+        let users = HashMap::new();
+
+        //dunning was switched on long after the deadline, only the first level is sent
+        assert_eq!(run_dunning(deadline + 30 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 1);
+        assert_eq!(run_dunning(deadline + 31 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+        //the second level only follows 14 days after the first one
+        assert_eq!(run_dunning(deadline + 44 * DAY - 1, &bills, &ledger, &users, &config, &mut book, &mut queue), 0);
+        assert_eq!(run_dunning(deadline + 44 * DAY, &bills, &ledger, &users, &config, &mut book, &mut queue), 1);
+        assert!(queue.mails[1].draft.subject.starts_with("Second reminder"));
+    }
+
+    #[test]
+    fn reminders_are_delivered_through_the_file_drop() {
+        let directory = std::env::temp_dir().join(format!("cervisia-dunning-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            mail_drop_directory: Some(directory.to_string_lossy().to_string()),
+            ..ServerConfig::default()
+        };
+        let bills = vec![simple_bill()];
+        let deadline = deadline_epoch_seconds(bills[0].timestamp_to, &config.accounting);
+        let mut book = book_with_contact();
+        let queue = RwLock::new(MailQueue::default());
+
+        let queued = run_dunning(
+            deadline,
+            &bills,
+            &PaymentLedger::default(),
+            &HashMap::new(),
+            &config,
+            &mut book,
+            &mut queue.write().unwrap(),
+        );
+        assert_eq!(queued, 1);
+        assert_eq!(process_due_mails(&queue, &config), 1);
+        let delivered = std::fs::read_dir(&directory).unwrap().count();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(delivered, 1);
+
+        //users without mail address are skipped
+        let mut book = DunningBook::default();
+        let mut queue = MailQueue::default();
+        assert_eq!(
+            run_dunning(deadline, &bills, &PaymentLedger::default(), &HashMap::new(), &config, &mut book, &mut queue),
+            0
+        );
+    }
+
+    #[test]
+    fn templates_replace_placeholders() {
+        assert_eq!(
+            render("{username} owes {open_amount} €", &[("username", "bob".to_string()), ("open_amount", "1,50".to_string())]),
+            "bob owes 1,50 €"
+        );
+        let mut settings = DunningSettings::default();
+        assert!(settings.problems().is_empty());
+        settings.levels.swap(0, 1);
+        assert_eq!(settings.problems().len(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+use iron;
+use iron::prelude::*;
+use serde_json;
+use server::ServerWriteResult;
+use std;
+
+//every failing request is answered with a ServerWriteResult carrying one of these, error_code() is meant for clients to match on
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Conflict(String),
+    //repeated failed attempts, the client has to wait before trying again
+    TooManyRequests(String),
+    //a limited resource is exhausted for now, e.g. all live event streams are taken
+    Unavailable(String),
+    Internal(String),
+}
+
+impl ServerError {
+    pub fn error_code(&self) -> &'static str {
+        return match *self {
+            ServerError::BadRequest(_) => "bad_request",
+            ServerError::Unauthorized(_) => "unauthorized",
+            ServerError::NotFound(_) => "not_found",
+            ServerError::Conflict(_) => "conflict",
+            ServerError::TooManyRequests(_) => "too_many_requests",
+            ServerError::Unavailable(_) => "unavailable",
+            ServerError::Internal(_) => "internal_error",
+        };
+    }
+
+    pub fn status(&self) -> iron::status::Status {
+        return match *self {
+            ServerError::BadRequest(_) => iron::status::BadRequest,
+            ServerError::Unauthorized(_) => iron::status::Unauthorized,
+            ServerError::NotFound(_) => iron::status::NotFound,
+            ServerError::Conflict(_) => iron::status::Conflict,
+            ServerError::TooManyRequests(_) => iron::status::TooManyRequests,
+            ServerError::Unavailable(_) => iron::status::ServiceUnavailable,
+            ServerError::Internal(_) => iron::status::InternalServerError,
+        };
+    }
+
+    pub fn message(&self) -> &str {
+        return match *self {
+            ServerError::BadRequest(ref m) => m,
+            ServerError::Unauthorized(ref m) => m,
+            ServerError::NotFound(ref m) => m,
+            ServerError::Conflict(ref m) => m,
+            ServerError::TooManyRequests(ref m) => m,
+            ServerError::Unavailable(ref m) => m,
+            ServerError::Internal(ref m) => m,
+        };
+    }
+
+    pub fn to_write_result(&self) -> ServerWriteResult {
+        return ServerWriteResult {
+            error_message: Some(self.message().to_string()),
+            error_code: Some(self.error_code().to_string()),
+            is_success: false,
+            content: None,
+            error_details: None,
+        };
+    }
+
+    pub fn to_response(&self) -> Response {
+        let body = serde_json::to_string(&self.to_write_result()).unwrap_or(String::new());
+        return Response::with((self.status(), body));
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error_code(), self.message())
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn description(&self) -> &str {
+        return self.message();
+    }
+}
+
+//serialization problems while building responses are our fault, parse errors of client input are mapped explicitly
+impl From<serde_json::Error> for ServerError {
+    fn from(e: serde_json::Error) -> Self {
+        return ServerError::Internal(format!("Could not serialize result: {}", e));
+    }
+}
+
+pub trait OrNotFound<T> {
+    fn or_not_found(self, what: &str) -> Result<T, ServerError>;
+}
+
+impl<T> OrNotFound<T> for Option<T> {
+    fn or_not_found(self, what: &str) -> Result<T, ServerError> {
+        return match self {
+            Some(v) => Ok(v),
+            None => Err(ServerError::NotFound(format!("{} not found", what))),
+        };
+    }
+}
+
+pub fn ensure_applied(applied: bool, event_name: &str) -> Result<(), ServerError> {
+    if applied {
+        return Ok(());
+    }
+    return Err(ServerError::Conflict(format!(
+        "{} was rejected by the backend",
+        event_name
+    )));
+}
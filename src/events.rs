@@ -0,0 +1,369 @@
+use errors::ServerError;
+use iron::response::WriteBody;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use typescriptify::TypeScriptifyTrait;
+use webhooks;
+
+//reconnecting clients get what they missed, as long as it is among the last updates
+const REPLAY_SIZE: usize = 256;
+
+//proxies and tablets drop idle connections, a comment line keeps them open and detects dead clients
+const KEEPALIVE_SECONDS: u64 = 15;
+
+//one tablet may reconnect before its old stream is noticed as dead, more than that would starve the others
+pub const MAX_STREAMS_PER_CLIENT: usize = 2;
+
+//compact on purpose, clients re-query what they display instead of getting whole records pushed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub enum LiveChange {
+    PurchaseCreated {
+        user_id: Option<u32>,
+        item_ids: Vec<u32>,
+    },
+    PurchaseUndone {
+        unique_id: Option<u64>,
+    },
+    UserChanged {
+        user_id: Option<u32>,
+    },
+    ItemChanged {
+        item_id: Option<u32>,
+    },
+    BillChanged {
+        timestamp_from: Option<i64>,
+        timestamp_to: Option<i64>,
+    },
+    GiveoutCreated {
+        donor: Option<u32>,
+        recipient: Option<u32>,
+    },
+    GiveoutUsed {
+        freeby_id: Option<u64>,
+    },
+    //events without a dedicated change, clients should refresh everything
+    Other {
+        event_type: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct LiveUpdate {
+    pub sequence: u64,
+    pub timestamp_epoch_millis: i64,
+    pub event_type: String,
+    pub change: LiveChange,
+}
+
+fn field_u32(fields: &serde_json::Value, key: &str) -> Option<u32> {
+    return fields.get(key).and_then(|v| v.as_u64()).map(|v| v as u32);
+}
+
+fn field_u64(fields: &serde_json::Value, key: &str) -> Option<u64> {
+    return fields.get(key).and_then(|v| v.as_u64());
+}
+
+fn field_i64(fields: &serde_json::Value, key: &str) -> Option<i64> {
+    return fields.get(key).and_then(|v| v.as_i64());
+}
+
+//works on the serialized event like the webhook payload, so new backend events show up as Other instead of breaking
+pub fn changes_for(event: &serde_json::Value) -> Vec<(String, LiveChange)> {
+    let event_type = match webhooks::event_type(event) {
+        Some(event_type) => event_type,
+        None => return Vec::new(),
+    };
+    let fields = event.get(&event_type).cloned().unwrap_or(serde_json::Value::Null);
+    let mut changes: Vec<LiveChange> = Vec::new();
+    match event_type.as_str() {
+        "MakeSimplePurchase" | "MakeShoppingCartPurchase" | "MakeSpecialPurchase" => {
+            let mut item_ids: Vec<u32> = field_u32(&fields, "item_id").into_iter().collect();
+            if let Some(ids) = fields.get("item_ids").and_then(|v| v.as_array()) {
+                item_ids.extend(ids.iter().filter_map(|v| v.as_u64()).map(|v| v as u32));
+            }
+            changes.push(LiveChange::PurchaseCreated {
+                user_id: field_u32(&fields, "user_id"),
+                item_ids: item_ids,
+            });
+        }
+        "MakeFreeForAllPurchase" => {
+            changes.push(LiveChange::PurchaseCreated {
+                user_id: None,
+                item_ids: field_u32(&fields, "item_id").into_iter().collect(),
+            });
+            changes.push(LiveChange::GiveoutUsed {
+                freeby_id: field_u64(&fields, "ffa_id"),
+            });
+        }
+        "UndoPurchase" => changes.push(LiveChange::PurchaseUndone {
+            unique_id: field_u64(&fields, "unique_id"),
+        }),
+        "CreateUser" | "UpdateUser" | "DeleteUser" => changes.push(LiveChange::UserChanged {
+            user_id: field_u32(&fields, "user_id"),
+        }),
+        "CreateItem" | "UpdateItem" | "DeleteItem" => changes.push(LiveChange::ItemChanged {
+            item_id: field_u32(&fields, "item_id"),
+        }),
+        "CreateFreeBudget" | "CreateFreeCount" | "CreateFreeForAll" => {
+            changes.push(LiveChange::GiveoutCreated {
+                donor: field_u32(&fields, "donor"),
+                recipient: field_u32(&fields, "recipient"),
+            })
+        }
+        _ if event_type.contains("Bill") || event_type.contains("Special") => {
+            changes.push(LiveChange::BillChanged {
+                timestamp_from: field_i64(&fields, "timestamp_from"),
+                timestamp_to: field_i64(&fields, "timestamp_to"),
+            })
+        }
+        _ => changes.push(LiveChange::Other {
+            event_type: event_type.to_string(),
+        }),
+    }
+    return changes
+        .into_iter()
+        .map(|c| (event_type.to_string(), c))
+        .collect();
+}
+
+#[derive(Default)]
+struct HubState {
+    next_sequence: u64,
+    recent: VecDeque<LiveUpdate>,
+    subscribers: Vec<Sender<LiveUpdate>>,
+    closed: bool,
+}
+
+//senders are not Sync, so everything lives behind one mutex
+pub struct EventHub {
+    state: Mutex<HubState>,
+    //every open stream occupies a worker thread of the server, so their number is capped
+    max_streams: usize,
+    open_streams: Arc<AtomicUsize>,
+    streams_per_client: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl EventHub {
+    pub fn new(max_streams: usize) -> EventHub {
+        return EventHub {
+            state: Mutex::new(HubState::default()),
+            max_streams: max_streams,
+            open_streams: Arc::new(AtomicUsize::new(0)),
+            streams_per_client: Arc::new(Mutex::new(HashMap::new())),
+        };
+    }
+
+    pub fn publish(&self, event_type: String, change: LiveChange, now_epoch_millis: i64) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.next_sequence += 1;
+        let update = LiveUpdate {
+            sequence: state.next_sequence,
+            timestamp_epoch_millis: now_epoch_millis,
+            event_type: event_type,
+            change: change,
+        };
+        if state.recent.len() >= REPLAY_SIZE {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(update.clone());
+        //closed streams drop their receiver, their senders are removed here
+        state.subscribers.retain(|s| s.send(update.clone()).is_ok());
+    }
+
+    //the backlog and the registration happen under the same lock, so no update is lost or sent twice
+    pub fn subscribe(&self, client: &str, last_seen_sequence: Option<u64>) -> Result<EventStream, ServerError> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if state.closed {
+            return Err(ServerError::Unavailable("The server is shutting down".to_string()));
+        }
+        if self.open_streams.load(Ordering::SeqCst) >= self.max_streams {
+            return Err(ServerError::Unavailable(format!(
+                "All {} live event streams are taken, please try again later",
+                self.max_streams
+            )));
+        }
+        {
+            let mut per_client = self.streams_per_client.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let count = per_client.entry(client.to_string()).or_insert(0);
+            if *count >= MAX_STREAMS_PER_CLIENT {
+                return Err(ServerError::TooManyRequests(format!(
+                    "At most {} live event streams per client are allowed, close one first",
+                    MAX_STREAMS_PER_CLIENT
+                )));
+            }
+            *count += 1;
+        }
+        self.open_streams.fetch_add(1, Ordering::SeqCst);
+        let backlog: Vec<LiveUpdate> = match last_seen_sequence {
+            Some(last) => state.recent.iter().filter(|u| u.sequence > last).cloned().collect(),
+            None => Vec::new(),
+        };
+        let (sender, receiver) = channel();
+        state.subscribers.push(sender);
+        return Ok(EventStream {
+            backlog: backlog,
+            receiver: receiver,
+            open_streams: self.open_streams.clone(),
+            client: client.to_string(),
+            streams_per_client: self.streams_per_client.clone(),
+        });
+    }
+
+    //dropping the senders ends every stream through Disconnected, so no handler thread outlives the server
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.closed = true;
+        state.subscribers.clear();
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return state.subscribers.len();
+    }
+
+    pub fn open_stream_count(&self) -> usize {
+        return self.open_streams.load(Ordering::SeqCst);
+    }
+}
+
+pub struct EventStream {
+    backlog: Vec<LiveUpdate>,
+    receiver: Receiver<LiveUpdate>,
+    open_streams: Arc<AtomicUsize>,
+    client: String,
+    streams_per_client: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+//iron drops the body once the client is gone, which frees the slot for the next stream
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.open_streams.fetch_sub(1, Ordering::SeqCst);
+        let mut per_client = self.streams_per_client.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let remaining = match per_client.get_mut(&self.client) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            per_client.remove(&self.client);
+        }
+    }
+}
+
+fn write_update(res: &mut dyn Write, update: &LiveUpdate) -> io::Result<()> {
+    let data = serde_json::to_string(update).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    return write!(res, "id: {}\nevent: {}\ndata: {}\n\n", update.sequence, update.event_type, data);
+}
+
+//blocks the handler thread until the client goes away, which shows up as a failed write
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        res.write_all(b": connected\n\n")?;
+        for update in &self.backlog {
+            write_update(res, update)?;
+        }
+        res.flush()?;
+        loop {
+            match self.receiver.recv_timeout(Duration::from_secs(KEEPALIVE_SECONDS)) {
+                Ok(update) => write_update(res, &update)?,
+                Err(RecvTimeoutError::Timeout) => res.write_all(b": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            res.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use events::*;
+    use serde_json;
+
+    #[test]
+    fn events_are_mapped_to_compact_changes() {
+        let purchase: serde_json::Value =
+            serde_json::from_str("{\"MakeFreeForAllPurchase\":{\"ffa_id\":3,\"item_id\":7,\"timestamp\":1}}").unwrap();
+        let changes = changes_for(&purchase);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[1].1,
+            LiveChange::GiveoutUsed { freeby_id: Some(3) }
+        );
+
+        let bill: serde_json::Value =
+            serde_json::from_str("{\"FinalizeBill\":{\"timestamp_from\":10,\"timestamp_to\":20}}").unwrap();
+        assert_eq!(
+            changes_for(&bill)[0].1,
+            LiveChange::BillChanged {
+                timestamp_from: Some(10),
+                timestamp_to: Some(20),
+            }
+        );
+
+        let unknown: serde_json::Value = serde_json::from_str("{\"SomethingNew\":{}}").unwrap();
+        assert_eq!(
+            changes_for(&unknown)[0].1,
+            LiveChange::Other {
+                event_type: "SomethingNew".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn reconnecting_clients_get_missed_updates_and_closed_streams_are_dropped() {
+        let hub = EventHub::new(4);
+        let first = hub.subscribe("10.0.0.1", None).unwrap();
+        hub.publish("CreateUser".to_string(), LiveChange::UserChanged { user_id: Some(1) }, 100);
+        hub.publish("CreateItem".to_string(), LiveChange::ItemChanged { item_id: Some(2) }, 200);
+
+        assert_eq!(first.receiver.recv().unwrap().sequence, 1);
+        assert_eq!(first.receiver.recv().unwrap().sequence, 2);
+
+        let reconnected = hub.subscribe("10.0.0.1", Some(1)).unwrap();
+        assert_eq!(reconnected.backlog.len(), 1);
+        assert_eq!(reconnected.backlog[0].sequence, 2);
+
+        drop(first);
+        hub.publish("DeleteUser".to_string(), LiveChange::UserChanged { user_id: Some(1) }, 300);
+        assert_eq!(hub.subscriber_count(), 1);
+        assert_eq!(reconnected.receiver.recv().unwrap().sequence, 3);
+    }
+
+    #[test]
+    fn streams_above_the_cap_are_refused_until_one_is_closed() {
+        let hub = EventHub::new(2);
+        let first = hub.subscribe("10.0.0.1", None).unwrap();
+        let _second = hub.subscribe("10.0.0.2", None).unwrap();
+        assert_eq!(hub.open_stream_count(), 2);
+        assert_eq!(hub.subscribe("10.0.0.3", None).err().unwrap().error_code(), "unavailable");
+
+        drop(first);
+        assert_eq!(hub.open_stream_count(), 1);
+        assert!(hub.subscribe("10.0.0.3", None).is_ok());
+    }
+
+    #[test]
+    fn one_client_cannot_take_every_stream_and_closing_the_hub_ends_all_streams() {
+        let hub = EventHub::new(8);
+        let first = hub.subscribe("10.0.0.1", None).unwrap();
+        let _second = hub.subscribe("10.0.0.1", None).unwrap();
+        assert_eq!(hub.subscribe("10.0.0.1", None).err().unwrap().error_code(), "too_many_requests");
+        let other = hub.subscribe("10.0.0.2", None).unwrap();
+
+        drop(first);
+        assert!(hub.subscribe("10.0.0.1", None).is_ok());
+
+        hub.close();
+        assert_eq!(hub.subscriber_count(), 0);
+        assert!(other.receiver.recv().is_err());
+        assert_eq!(hub.subscribe("10.0.0.3", None).err().unwrap().error_code(), "unavailable");
+    }
+}
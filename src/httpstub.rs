@@ -0,0 +1,49 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+//a minimal http server standing in for a webhook endpoint in tests.
+//it answers exactly one request with 200 and sends the raw request (head and body) to the receiver
+pub fn serve_one_request(path: &str) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        //a client that never finishes its request must not hang the test
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut request: Vec<u8> = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buffer).unwrap_or(0);
+            request.extend_from_slice(&buffer[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let complete = match text.find("\r\n\r\n") {
+                Some(header_end) => {
+                    let content_length = text[..header_end]
+                        .lines()
+                        .find(|l| l.to_ascii_lowercase().starts_with("content-length:"))
+                        .and_then(|l| l[15..].trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    request.len() >= header_end + 4 + content_length
+                }
+                None => false,
+            };
+            if n == 0 || complete {
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                let _ = sender.send(text);
+                break;
+            }
+        }
+    });
+    return (url, receiver);
+}
+
+pub fn body_of(request: &str) -> &str {
+    return match request.find("\r\n\r\n") {
+        Some(header_end) => &request[header_end + 4..],
+        None => "",
+    };
+}
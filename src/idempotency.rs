@@ -0,0 +1,444 @@
+use configuration::ServerConfig;
+use errors::ServerError;
+use iron;
+use notifier;
+use serde::ser::Serialize;
+use serde_json;
+use chrono::Utc;
+use sidecar;
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+//requests are compared by this hash, it ends up in the log next to the database
+const FINGERPRINT_CONTEXT: &str = "cervisia-idempotency";
+
+//the log is rewritten once it holds this many lines more than twice the live keys
+const COMPACTION_SLACK: usize = 100;
+
+//configured in the [idempotency] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencySettings {
+    //kiosks retry within seconds, a day also covers a tablet that was offline for a while
+    pub retention_seconds: i64,
+    pub max_keys: usize,
+    //larger responses are stored without their refreshed data, a replay then only confirms the success
+    pub max_body_bytes: usize,
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        return IdempotencySettings {
+            retention_seconds: 24 * 60 * 60,
+            max_keys: 5000,
+            max_body_bytes: 16 * 1024,
+        };
+    }
+}
+
+impl IdempotencySettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.retention_seconds <= 0 {
+            problems.push("idempotency.retention_seconds must be positive".to_string());
+        }
+        if self.max_keys < 1 {
+            problems.push("idempotency.max_keys must be at least 1".to_string());
+        }
+        if self.max_body_bytes < 1 {
+            problems.push("idempotency.max_body_bytes must be at least 1".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredResponse {
+    pub fingerprint: String,
+    //the serialized ServerWriteResult exactly as it was sent the first time
+    pub body: String,
+    pub created_epoch_seconds: i64,
+    //fixed when the response is stored, offline sync keeps its keys longer than ordinary retries
+    pub expires_epoch_seconds: i64,
+}
+
+//one line of the log, a later line for the same key replaces the earlier one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LoggedResponse {
+    key: String,
+    response: StoredResponse,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyBook {
+    pub responses: BTreeMap<String, StoredResponse>,
+    path: Option<PathBuf>,
+    //including lines of expired and dropped keys
+    logged_lines: usize,
+    //requests which are being applied right now, only requests with the same key wait for each other
+    in_flight: HashMap<String, Arc<Mutex<()>>>,
+}
+
+pub enum Outcome<T> {
+    //the stored body of the first request with this key
+    Replayed(String),
+    Applied(T),
+}
+
+//keys are opaque to the server, clients usually send a uuid per purchase attempt
+pub fn extract_idempotency_key(req: &iron::request::Request) -> Option<String> {
+    return req
+        .headers
+        .get_raw(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+}
+
+//sync entries carry the purchase with its credential one level deeper
+fn strip_credentials(value: &mut serde_json::Value) {
+    match *value {
+        serde_json::Value::Object(ref mut fields) => {
+            fields.remove("credential");
+            for field in fields.values_mut() {
+                strip_credentials(field);
+            }
+        }
+        serde_json::Value::Array(ref mut values) => {
+            for value in values.iter_mut() {
+                strip_credentials(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+//credentials are left out, otherwise a pin could be found by hashing every candidate until one matches the log
+pub fn fingerprint<T: Serialize>(request: &T) -> Result<String, ServerError> {
+    let mut value = serde_json::to_value(request)?;
+    strip_credentials(&mut value);
+    let body = serde_json::to_string(&value)?;
+    return notifier::sign(&body, FINGERPRINT_CONTEXT)
+        .map_err(|e| ServerError::Internal(format!("Could not fingerprint request: {}", e)));
+}
+
+impl IdempotencyBook {
+    pub fn load(config: &ServerConfig) -> IdempotencyBook {
+        let path = match sidecar::sidecar_log_path(config, "idempotency") {
+            Some(path) => path,
+            None => return IdempotencyBook::default(),
+        };
+        let mut book = IdempotencyBook::default();
+        match sidecar::load_json_lines::<LoggedResponse>(&path) {
+            Ok(lines) => {
+                book.logged_lines = lines.len();
+                for line in lines {
+                    book.responses.insert(line.key, line.response);
+                }
+            }
+            Err(e) => sidecar::move_aside(&path, "idempotency keys", e),
+        }
+        book.path = Some(path);
+        let settings = &config.idempotency;
+        book.drop_old_keys(settings.max_keys, Utc::now().timestamp());
+        book.compact_if_needed();
+        return book;
+    }
+
+    fn compact_if_needed(&mut self) {
+        if self.logged_lines > 2 * self.responses.len() + COMPACTION_SLACK {
+            self.compact();
+        }
+    }
+
+    //rewrites the log with only the live keys
+    fn compact(&mut self) {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let lines: Vec<LoggedResponse> = self
+            .responses
+            .iter()
+            .map(|(key, response)| LoggedResponse {
+                key: key.to_string(),
+                response: response.clone(),
+            })
+            .collect();
+        match sidecar::save_json_lines(&path, &lines) {
+            Ok(()) => self.logged_lines = lines.len(),
+            Err(e) => error!("Could not compact idempotency keys in {:?}: {:?}", path, e),
+        }
+    }
+
+    fn drop_old_keys(&mut self, max_keys: usize, now_epoch_seconds: i64) {
+        self.responses.retain(|_, r| r.expires_epoch_seconds > now_epoch_seconds);
+        //over the limit the keys which would expire first go first
+        while self.responses.len() > max_keys {
+            let oldest = self
+                .responses
+                .iter()
+                .min_by_key(|&(_, r)| (r.expires_epoch_seconds, r.created_epoch_seconds))
+                .map(|(k, _)| k.to_string());
+            match oldest {
+                Some(oldest) => {
+                    self.responses.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn key_lock(&mut self, key: &str) -> Arc<Mutex<()>> {
+        return self
+            .in_flight
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+    }
+
+    //the lock is forgotten once nobody else waits for it
+    fn release_key_lock(&mut self, key: &str, lock: Arc<Mutex<()>>) {
+        drop(lock);
+        let unused = self
+            .in_flight
+            .get(key)
+            .map(|lock| Arc::strong_count(lock) == 1)
+            .unwrap_or(false);
+        if unused {
+            self.in_flight.remove(key);
+        }
+    }
+
+    //a key reused for another request is refused, answering with the other response would hide the mistake
+    pub fn replay(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now_epoch_seconds: i64,
+    ) -> Result<Option<String>, ServerError> {
+        let stored = match self.responses.get(key) {
+            Some(stored) if stored.expires_epoch_seconds > now_epoch_seconds => stored,
+            _ => return Ok(None),
+        };
+        if stored.fingerprint != fingerprint {
+            return Err(ServerError::Conflict(
+                "This idempotency key was already used for a different request".to_string(),
+            ));
+        }
+        return Ok(Some(stored.body.to_string()));
+    }
+
+    pub fn remember(
+        &mut self,
+        key: String,
+        fingerprint: String,
+        body: String,
+        settings: &IdempotencySettings,
+        now_epoch_seconds: i64,
+    ) {
+        //room is made first, so the new key is never the one dropped
+        self.drop_old_keys(settings.max_keys.saturating_sub(1), now_epoch_seconds);
+        let response = StoredResponse {
+            fingerprint: fingerprint,
+            body: body,
+            created_epoch_seconds: now_epoch_seconds,
+            expires_epoch_seconds: now_epoch_seconds + settings.retention_seconds,
+        };
+        //appended, a purchase never rewrites the whole log
+        if let Some(ref path) = self.path {
+            let line = LoggedResponse {
+                key: key.to_string(),
+                response: response.clone(),
+            };
+            match sidecar::append_json_lines(path, &[line]) {
+                Ok(()) => self.logged_lines += 1,
+                Err(e) => error!("Could not persist idempotency key to {:?}: {:?}", path, e),
+            }
+        }
+        self.responses.insert(key, response);
+        self.compact_if_needed();
+    }
+}
+
+//applies a request once per key. the book is only locked to look up and store responses,
+//while the request runs just a retry with the same key waits for it
+pub fn apply_once<T, F>(
+    holder: &RwLock<IdempotencyBook>,
+    key: &str,
+    fingerprint: &str,
+    settings: &IdempotencySettings,
+    now_epoch_seconds: i64,
+    apply: F,
+) -> Result<Outcome<T>, ServerError>
+where
+    F: FnOnce() -> Result<(T, String), ServerError>,
+{
+    let lock = {
+        let mut book = holder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(body) = book.replay(key, fingerprint, now_epoch_seconds)? {
+            return Ok(Outcome::Replayed(body));
+        }
+        book.key_lock(key)
+    };
+    let result = {
+        let _running = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        //a retry which waited here finds the response of the request it raced with
+        let replayed = holder
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .replay(key, fingerprint, now_epoch_seconds);
+        match replayed {
+            Ok(Some(body)) => Ok(Outcome::Replayed(body)),
+            Ok(None) => apply().map(|(value, body)| {
+                let mut book = holder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+                book.remember(key.to_string(), fingerprint.to_string(), body, settings, now_epoch_seconds);
+                Outcome::Applied(value)
+            }),
+            Err(e) => Err(e),
+        }
+    };
+    holder
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .release_key_lock(key, lock);
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use configuration::ServerConfig;
+    use idempotency::*;
+    use serde_json;
+    use sidecar;
+    use std;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn known_keys_replay_the_first_response() {
+        let settings = IdempotencySettings::default();
+        let mut book = IdempotencyBook::default();
+        let first = fingerprint(&(1u32, 2u32)).unwrap();
+        let other = fingerprint(&(1u32, 3u32)).unwrap();
+        assert_ne!(first, other);
+        //retries with another pin are the same request, the log holds nothing to test pins against
+        let with_pin = |pin: &str| -> serde_json::Value {
+            serde_json::from_str(&format!("{{\"uuid\":\"u1\",\"simple\":{{\"user_id\":1,\"credential\":{{\"pin\":\"{}\"}}}}}}", pin)).unwrap()
+        };
+        assert_eq!(fingerprint(&with_pin("1234")).unwrap(), fingerprint(&with_pin("9876")).unwrap());
+
+        assert_eq!(book.replay("/purchases:abc", &first, 100).unwrap(), None);
+        book.remember("/purchases:abc".to_string(), first.to_string(), "{\"is_success\":true}".to_string(), &settings, 100);
+        assert_eq!(
+            book.replay("/purchases:abc", &first, 200).unwrap(),
+            Some("{\"is_success\":true}".to_string())
+        );
+        assert_eq!(
+            book.replay("/purchases:abc", &other, 200).unwrap_err().error_code(),
+            "conflict"
+        );
+        //expired keys are treated as new
+        assert_eq!(
+            book.replay("/purchases:abc", &first, 100 + settings.retention_seconds).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn expired_and_oldest_keys_are_dropped() {
+        let settings = IdempotencySettings {
+            retention_seconds: 1000,
+            max_keys: 2,
+            ..IdempotencySettings::default()
+        };
+        let mut book = IdempotencyBook::default();
+        book.remember("a".to_string(), "f".to_string(), "1".to_string(), &settings, 0);
+        book.remember("b".to_string(), "f".to_string(), "2".to_string(), &settings, 10);
+        book.remember("c".to_string(), "f".to_string(), "3".to_string(), &settings, 20);
+        assert_eq!(book.responses.keys().cloned().collect::<Vec<String>>(), vec!["b", "c"]);
+
+        book.remember("d".to_string(), "f".to_string(), "4".to_string(), &settings, 1015);
+        assert_eq!(book.responses.keys().cloned().collect::<Vec<String>>(), vec!["c", "d"]);
+
+        //keys kept longer, like those of offline sync, outlive ordinary ones stored after them
+        let longer = IdempotencySettings {
+            retention_seconds: 100_000,
+            ..settings.clone()
+        };
+        book.remember("e".to_string(), "f".to_string(), "5".to_string(), &longer, 1016);
+        book.remember("f".to_string(), "f".to_string(), "6".to_string(), &settings, 1017);
+        assert_eq!(book.responses.keys().cloned().collect::<Vec<String>>(), vec!["e", "f"]);
+        assert_eq!(book.replay("e", "f", 50_000).unwrap(), Some("5".to_string()));
+    }
+
+    #[test]
+    fn keys_are_appended_to_a_log_which_is_compacted() {
+        let directory = std::env::temp_dir().join(format!("cervisia-idempotency-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            idempotency: IdempotencySettings {
+                max_keys: 2,
+                ..IdempotencySettings::default()
+            },
+            ..ServerConfig::default()
+        };
+        let now = Utc::now().timestamp();
+        let mut book = IdempotencyBook::load(&config);
+        for i in 0..300 {
+            book.remember(format!("k{}", i), "f".to_string(), i.to_string(), &config.idempotency, now + i);
+        }
+
+        let log = sidecar::sidecar_log_path(&config, "idempotency").unwrap();
+        let lines = std::fs::read_to_string(&log).unwrap().lines().count();
+        let reloaded = IdempotencyBook::load(&config);
+        let _ = std::fs::remove_dir_all(&directory);
+        assert!(lines <= 2 * 2 + COMPACTION_SLACK);
+        assert_eq!(reloaded.responses.keys().cloned().collect::<Vec<String>>(), vec!["k298", "k299"]);
+    }
+
+    #[test]
+    fn only_requests_with_the_same_key_wait_for_each_other() {
+        let settings = IdempotencySettings::default();
+        let holder = Arc::new(RwLock::new(IdempotencyBook::default()));
+        let (started, running) = channel();
+        let (finish, finished) = channel::<()>();
+        let slow_holder = holder.clone();
+        let slow = thread::spawn(move || {
+            let settings = IdempotencySettings::default();
+            return apply_once(&slow_holder, "a", "f", &settings, 100, || {
+                started.send(()).unwrap();
+                finished.recv().unwrap();
+                return Ok(((), "slow".to_string()));
+            })
+            .map(|_| ());
+        });
+        running.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        //another key is applied while "a" is still running
+        let other = apply_once(&holder, "b", "f", &settings, 100, || Ok((1, "1".to_string()))).unwrap();
+        assert!(match other {
+            Outcome::Applied(1) => true,
+            _ => false,
+        });
+
+        finish.send(()).unwrap();
+        slow.join().unwrap().unwrap();
+        let retried = apply_once(&holder, "a", "f", &settings, 100, || Ok((2, "2".to_string()))).unwrap();
+        assert!(match retried {
+            Outcome::Replayed(ref body) => body == "slow",
+            _ => false,
+        });
+        assert!(holder.read().unwrap().in_flight.is_empty());
+    }
+}
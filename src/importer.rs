@@ -1,3 +1,4 @@
+use barcodes::BarcodeBook;
 use rustix_bl;
 use rustix_bl::datastore::DatastoreQueries;
 use rustix_bl::rustix_backend::WriteBackend;
@@ -17,6 +18,8 @@ pub struct ImportedItem {
     pub name: String,
     pub category: String,
     pub price: u32,
+    #[serde(default)]
+    pub barcodes: Vec<String>,
 }
 
 fn get_user_by_name(
@@ -84,6 +87,7 @@ pub fn import_users_into_store(
 
 pub fn import_items_into_store(
     backend: &mut rustix_bl::rustix_backend::RustixBackend,
+    barcodes: &mut BarcodeBook,
     items: Vec<ImportedItem>,
 ) -> () {
     println!("Importing {} items into backend...", items.len());
@@ -113,7 +117,23 @@ pub fn import_items_into_store(
             });
             println!("Updated item {}...", import_item.name);
         }
+
+        //an empty list keeps the codes assigned via the api
+        if import_item.barcodes.is_empty() {
+            continue;
+        }
+        if let Some(item) = get_item_by_name(&backend.datastore, &import_item.name) {
+            match barcodes.validate(Some(item.item_id), &import_item.barcodes, &backend.datastore.items) {
+                Ok(codes) => {
+                    if barcodes.set(item.item_id, codes) {
+                        println!("Updated barcodes of item {}...", import_item.name);
+                    }
+                }
+                Err(e) => println!("Skipped barcodes of item {}: {}", import_item.name, e),
+            }
+        }
     }
+    barcodes.save();
 }
 
 pub fn load_users_json_file() -> Vec<ImportedUser> {
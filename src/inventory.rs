@@ -0,0 +1,692 @@
+use billformatter::cents_to_currency_string_i64;
+use chrono::Utc;
+use configuration::ServerConfig;
+use errors::ServerError;
+use rustix_bl;
+use rustix_bl::datastore::Item;
+use rustix_bl::rustix_event_shop::BLEvents;
+use server::Backend;
+use sidecar;
+use std;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+//configured in the [inventory] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InventorySettings {
+    //items at or below this quantity are reported as low on stock, items can override it
+    pub default_low_stock_threshold: i64,
+}
+
+impl Default for InventorySettings {
+    fn default() -> Self {
+        return InventorySettings {
+            default_low_stock_threshold: 5,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum StockMovementKind {
+    Delivery,
+    Sale,
+    Undo,
+    Stocktaking,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct StockMovement {
+    pub id: u64,
+    pub item_id: u32,
+    pub kind: StockMovementKind,
+    pub quantity_change: i64,
+    pub timestamp_epoch_millis: i64,
+    //per unit, only known for deliveries
+    pub purchase_price_cents: Option<u32>,
+    pub comment: String,
+}
+
+//only items with a stock entry are tracked, the first delivery or stocktaking creates it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct ItemStock {
+    pub item_id: u32,
+    pub quantity: i64,
+    pub low_stock_threshold: Option<i64>,
+    //of the last delivery, the movements with it are only kept in the log
+    pub last_purchase_price_cents: Option<u32>,
+}
+
+impl ItemStock {
+    pub fn threshold(&self, settings: &InventorySettings) -> i64 {
+        return self
+            .low_stock_threshold
+            .unwrap_or(settings.default_low_stock_threshold);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct Restock {
+    pub item_id: u32,
+    pub quantity: i64,
+    pub purchase_price_cents: u32,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct StockCount {
+    pub item_id: u32,
+    pub counted: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct Stocktaking {
+    pub counts: Vec<StockCount>,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetLowStockThreshold {
+    pub item_id: u32,
+    //None falls back to the configured default
+    pub low_stock_threshold: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct StockLevel {
+    pub item_id: u32,
+    pub name: String,
+    pub quantity: i64,
+    pub low_stock_threshold: i64,
+    pub is_low: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct LowStockWarning {
+    pub item_id: u32,
+    pub name: String,
+    pub quantity: i64,
+    pub low_stock_threshold: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct ShrinkageLine {
+    pub item_id: u32,
+    pub name: String,
+    pub expected: i64,
+    pub counted: i64,
+    //negative if items went missing
+    pub difference: i64,
+    pub value_cents: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct ShrinkageReport {
+    pub lines: Vec<ShrinkageLine>,
+    pub total_value_cents: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct InventoryReportLine {
+    pub item_id: u32,
+    pub name: String,
+    pub delivered: i64,
+    //sales minus undone sales
+    pub sold: i64,
+    pub stocktaking_correction: i64,
+    pub quantity: i64,
+    pub last_purchase_price_cents: Option<u32>,
+    pub stock_value_cents: i64,
+}
+
+//the stock file is only rewritten by admin changes, every sale just appends its movement to the log.
+//movements from next_id on are not part of the stored stock yet and are replayed onto it when loading
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Inventory {
+    pub next_id: u64,
+    pub stock: Vec<ItemStock>,
+    //with a log only the recent ones, which the reorder suggestions need
+    #[serde(skip)]
+    pub movements: Vec<StockMovement>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    #[serde(skip)]
+    movements_path: Option<PathBuf>,
+    //recorded, but not yet appended to the log
+    #[serde(skip)]
+    unlogged: Vec<StockMovement>,
+    #[serde(skip)]
+    memory_window_millis: i64,
+}
+
+//a copy taken under the lock, so the log can be read without holding it
+pub struct StockMovementLog {
+    path: Option<PathBuf>,
+    movements: Vec<StockMovement>,
+}
+
+impl StockMovementLog {
+    pub fn read(self) -> Vec<StockMovement> {
+        let path = match self.path {
+            Some(path) => path,
+            None => return self.movements,
+        };
+        return match sidecar::load_json_lines::<StockMovement>(&path) {
+            Ok(mut movements) => {
+                //appended after the backend lock is released, so concurrent sales may land out of order
+                movements.sort_by_key(|m| m.id);
+                movements
+            }
+            Err(e) => {
+                error!("Could not read stock movements from {:?}: {:?}", path, e);
+                Vec::new()
+            }
+        };
+    }
+}
+
+pub fn append_movements(path: &Option<PathBuf>, movements: &[StockMovement]) {
+    if movements.is_empty() {
+        return;
+    }
+    if let Some(ref path) = *path {
+        if let Err(e) = sidecar::append_json_lines(path, movements) {
+            error!("Could not persist stock movements to {:?}: {:?}", path, e);
+        }
+    }
+}
+
+fn item_name(items: &HashMap<u32, Item>, item_id: u32) -> String {
+    return items
+        .get(&item_id)
+        .map(|i| i.name.to_string())
+        .unwrap_or(String::new());
+}
+
+impl Inventory {
+    pub fn load(config: &ServerConfig) -> Inventory {
+        let path = match sidecar::sidecar_path(config, "inventory") {
+            Some(path) => path,
+            None => return Inventory::default(),
+        };
+        let mut inventory: Inventory = sidecar::load_json_or_default(&path, "inventory");
+        inventory.path = Some(path);
+        let movements_path = match sidecar::sidecar_log_path(config, "inventory-movements") {
+            Some(path) => path,
+            None => return inventory,
+        };
+        match sidecar::load_json_lines::<StockMovement>(&movements_path) {
+            Ok(movements) => {
+                let stored_until = inventory.next_id;
+                for movement in movements {
+                    if movement.id >= stored_until {
+                        inventory.apply(&movement);
+                        inventory.next_id = std::cmp::max(inventory.next_id, movement.id + 1);
+                    }
+                    inventory.movements.push(movement);
+                }
+            }
+            Err(e) => sidecar::move_aside(&movements_path, "stock movements", e),
+        }
+        inventory.movements_path = Some(movements_path);
+        inventory.memory_window_millis = std::cmp::max(config.alerts.consumption_window_days, 1) * MILLIS_PER_DAY;
+        let now = Utc::now().timestamp() * 1000;
+        inventory.forget_old_movements(now);
+        return inventory;
+    }
+
+    //the movements recorded since the last save go to the log first, the stock file refers to them by next_id
+    pub fn save(&mut self) {
+        let unlogged = self.take_unlogged();
+        append_movements(&self.movements_path, &unlogged);
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist inventory to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    //for sales, which append them with append_movements once the backend lock is released
+    pub fn take_unlogged(&mut self) -> Vec<StockMovement> {
+        return std::mem::replace(&mut self.unlogged, Vec::new());
+    }
+
+    pub fn movements_log_path(&self) -> Option<PathBuf> {
+        return self.movements_path.clone();
+    }
+
+    pub fn movement_log(&self) -> StockMovementLog {
+        return StockMovementLog {
+            path: self.movements_path.clone(),
+            movements: match self.movements_path {
+                Some(_) => Vec::new(),
+                None => self.movements.clone(),
+            },
+        };
+    }
+
+    //without a log the memory is the only copy, so nothing is dropped
+    fn forget_old_movements(&mut self, now_epoch_millis: i64) {
+        if self.movements_path.is_none() {
+            return;
+        }
+        let since = now_epoch_millis - self.memory_window_millis;
+        self.movements.retain(|m| m.timestamp_epoch_millis >= since);
+    }
+
+    fn apply(&mut self, movement: &StockMovement) {
+        let stock = self.entry(movement.item_id);
+        stock.quantity += movement.quantity_change;
+        if movement.purchase_price_cents.is_some() {
+            stock.last_purchase_price_cents = movement.purchase_price_cents;
+        }
+    }
+
+    pub fn get(&self, item_id: u32) -> Option<&ItemStock> {
+        return self.stock.iter().find(|s| s.item_id == item_id);
+    }
+
+    pub fn entry(&mut self, item_id: u32) -> &mut ItemStock {
+        if self.get(item_id).is_none() {
+            self.stock.push(ItemStock {
+                item_id: item_id,
+                quantity: 0,
+                low_stock_threshold: None,
+                last_purchase_price_cents: None,
+            });
+            self.stock.sort_by_key(|s| s.item_id);
+        }
+        return self.stock.iter_mut().find(|s| s.item_id == item_id).unwrap();
+    }
+
+    fn record(
+        &mut self,
+        item_id: u32,
+        kind: StockMovementKind,
+        quantity_change: i64,
+        now_epoch_millis: i64,
+        purchase_price_cents: Option<u32>,
+        comment: String,
+    ) {
+        let movement = StockMovement {
+            id: self.next_id,
+            item_id: item_id,
+            kind: kind,
+            quantity_change: quantity_change,
+            timestamp_epoch_millis: now_epoch_millis,
+            purchase_price_cents: purchase_price_cents,
+            comment: comment,
+        };
+        self.next_id += 1;
+        self.apply(&movement);
+        if self.movements_path.is_some() {
+            self.unlogged.push(movement.clone());
+        }
+        self.movements.push(movement);
+        self.forget_old_movements(now_epoch_millis);
+    }
+
+    pub fn restock(&mut self, restock: Restock, now_epoch_millis: i64) -> Result<(), ServerError> {
+        if restock.quantity <= 0 {
+            return Err(ServerError::BadRequest("quantity must be positive".to_string()));
+        }
+        self.record(
+            restock.item_id,
+            StockMovementKind::Delivery,
+            restock.quantity,
+            now_epoch_millis,
+            Some(restock.purchase_price_cents),
+            restock.comment,
+        );
+        return Ok(());
+    }
+
+    //sales of untracked items are not recorded, otherwise every item would start with a negative stock
+    pub fn record_sales(&mut self, changes: &[(u32, i64)], now_epoch_millis: i64) -> bool {
+        let mut recorded = false;
+        for &(item_id, change) in changes {
+            if self.get(item_id).is_none() || change == 0 {
+                continue;
+            }
+            let kind = if change < 0 {
+                StockMovementKind::Sale
+            } else {
+                StockMovementKind::Undo
+            };
+            self.record(item_id, kind, change, now_epoch_millis, None, String::new());
+            recorded = true;
+        }
+        return recorded;
+    }
+
+    pub fn last_purchase_price_cents(&self, item_id: u32) -> Option<u32> {
+        return self.get(item_id).and_then(|s| s.last_purchase_price_cents);
+    }
+
+    //shrinkage is valued at the last purchase price, or at the sales price for items never delivered
+    pub fn stocktaking(
+        &mut self,
+        stocktaking: Stocktaking,
+        items: &HashMap<u32, Item>,
+        now_epoch_millis: i64,
+    ) -> Result<ShrinkageReport, ServerError> {
+        if let Some(count) = stocktaking.counts.iter().find(|c| c.counted < 0) {
+            return Err(ServerError::BadRequest(format!(
+                "Counted quantity of item {} must not be negative",
+                count.item_id
+            )));
+        }
+        let mut lines: Vec<ShrinkageLine> = Vec::new();
+        for count in stocktaking.counts {
+            let expected = self.get(count.item_id).map(|s| s.quantity).unwrap_or(0);
+            let difference = count.counted - expected;
+            let unit_value = self
+                .last_purchase_price_cents(count.item_id)
+                .or(items.get(&count.item_id).map(|i| i.cost_cents))
+                .unwrap_or(0) as i64;
+            //a matching count still starts tracking the item
+            self.entry(count.item_id);
+            if difference != 0 {
+                self.record(
+                    count.item_id,
+                    StockMovementKind::Stocktaking,
+                    difference,
+                    now_epoch_millis,
+                    None,
+                    stocktaking.comment.to_string(),
+                );
+            }
+            lines.push(ShrinkageLine {
+                item_id: count.item_id,
+                name: item_name(items, count.item_id),
+                expected: expected,
+                counted: count.counted,
+                difference: difference,
+                value_cents: difference * unit_value,
+            });
+        }
+        let total_value_cents = lines.iter().map(|l| l.value_cents).sum();
+        return Ok(ShrinkageReport {
+            lines: lines,
+            total_value_cents: total_value_cents,
+        });
+    }
+
+    pub fn stock_levels(&self, items: &HashMap<u32, Item>, settings: &InventorySettings) -> Vec<StockLevel> {
+        return self
+            .stock
+            .iter()
+            .map(|s| StockLevel {
+                item_id: s.item_id,
+                name: item_name(items, s.item_id),
+                quantity: s.quantity,
+                low_stock_threshold: s.threshold(settings),
+                is_low: s.quantity <= s.threshold(settings),
+            })
+            .collect();
+    }
+
+    //deleted items are no longer sold, so they never warn
+    pub fn low_stock_warnings(
+        &self,
+        items: &HashMap<u32, Item>,
+        settings: &InventorySettings,
+    ) -> Vec<LowStockWarning> {
+        return self
+            .stock_levels(items, settings)
+            .into_iter()
+            .filter(|l| l.is_low)
+            .filter(|l| items.get(&l.item_id).map(|i| !i.deleted).unwrap_or(false))
+            .map(|l| LowStockWarning {
+                item_id: l.item_id,
+                name: l.name,
+                quantity: l.quantity,
+                low_stock_threshold: l.low_stock_threshold,
+            })
+            .collect();
+    }
+
+    //movements within [from, to) per tracked item, the quantity is always the current one
+    pub fn report(
+        &self,
+        items: &HashMap<u32, Item>,
+        movements: &[StockMovement],
+        from: i64,
+        to: i64,
+    ) -> Vec<InventoryReportLine> {
+        let mut result: Vec<InventoryReportLine> = Vec::new();
+        for stock in &self.stock {
+            let mut line = InventoryReportLine {
+                item_id: stock.item_id,
+                name: item_name(items, stock.item_id),
+                delivered: 0,
+                sold: 0,
+                stocktaking_correction: 0,
+                quantity: stock.quantity,
+                last_purchase_price_cents: self.last_purchase_price_cents(stock.item_id),
+                stock_value_cents: 0,
+            };
+            for movement in movements.iter().filter(|m| {
+                m.item_id == stock.item_id && m.timestamp_epoch_millis >= from && m.timestamp_epoch_millis < to
+            }) {
+                match movement.kind {
+                    StockMovementKind::Delivery => line.delivered += movement.quantity_change,
+                    StockMovementKind::Sale | StockMovementKind::Undo => line.sold -= movement.quantity_change,
+                    StockMovementKind::Stocktaking => line.stocktaking_correction += movement.quantity_change,
+                }
+            }
+            line.stock_value_cents =
+                std::cmp::max(line.quantity, 0) * line.last_purchase_price_cents.unwrap_or(0) as i64;
+            result.push(line);
+        }
+        return result;
+    }
+}
+
+pub fn format_report_as_csv(lines: &[InventoryReportLine]) -> String {
+    let mut rows: Vec<String> = vec![
+        "item_id;name;delivered;sold;stocktaking_correction;quantity;last_purchase_price;stock_value".to_string(),
+    ];
+    for line in lines {
+        rows.push(format!(
+            "{};{};{};{};{};{};{};{}",
+            line.item_id,
+            line.name,
+            line.delivered,
+            line.sold,
+            line.stocktaking_correction,
+            line.quantity,
+            line.last_purchase_price_cents
+                .map(|c| cents_to_currency_string_i64(c as i64))
+                .unwrap_or(String::new()),
+            cents_to_currency_string_i64(line.stock_value_cents)
+        ));
+    }
+    return rows.join("\n");
+}
+
+//has to run before the event is applied, an undone purchase is gone afterwards
+pub fn stock_changes(backend: &Backend, event: &BLEvents) -> Vec<(u32, i64)> {
+    return match event {
+        &BLEvents::MakeSimplePurchase { item_id, .. } => vec![(item_id, -1)],
+        &BLEvents::MakeFreeForAllPurchase { item_id, .. } => vec![(item_id, -1)],
+        &BLEvents::MakeShoppingCartPurchase { ref item_ids, .. } => {
+            item_ids.iter().map(|id| (*id, -1)).collect()
+        }
+        &BLEvents::UndoPurchase { unique_id } => match undone_item_id(backend, unique_id) {
+            Some(item_id) => vec![(item_id, 1)],
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+}
+
+fn undone_item_id(backend: &Backend, unique_id: u64) -> Option<u32> {
+    use rustix_bl::datastore::DatastoreQueries;
+    use rustix_bl::datastore::Purchase;
+    let timestamp = backend.datastore.get_purchase_timestamp(unique_id)?;
+    let purchases = backend
+        .datastore
+        .global_log_filtered(timestamp - 1, timestamp + 1)
+        .to_vec();
+    for purchase in &purchases {
+        let id = match purchase {
+            &Purchase::SimplePurchase { unique_id: id, .. } => id,
+            &Purchase::FFAPurchase { unique_id: id, .. } => id,
+            //specials have no item and therefore no stock
+            &Purchase::SpecialPurchase { .. } => continue,
+        };
+        if id == unique_id && purchase.has_item_id() {
+            return Some(*purchase.get_item_id());
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use configuration::ServerConfig;
+    use inventory::*;
+    use rustix_bl::datastore::Item;
+    use std;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn items() -> HashMap<u32, Item> {
+        let mut items = HashMap::new();
+        items.insert(
+            0,
+            Item {
+                name: "beer".to_string(),
+                item_id: 0,
+                category: None,
+                cost_cents: 95,
+                deleted: false,
+            },
+        );
+        items.insert(
+            1,
+            Item {
+                name: "soda".to_string(),
+                item_id: 1,
+                category: None,
+                cost_cents: 85,
+                deleted: false,
+            },
+        );
+        return items;
+    }
+
+    fn restock(inventory: &mut Inventory, item_id: u32, quantity: i64, price: u32, now: i64) {
+        inventory
+            .restock(
+                Restock {
+                    item_id: item_id,
+                    quantity: quantity,
+                    purchase_price_cents: price,
+                    comment: String::new(),
+                },
+                now,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn sales_and_undos_only_change_tracked_items() {
+        let settings = InventorySettings::default();
+        let mut inventory = Inventory::default();
+        restock(&mut inventory, 0, 10, 60, 100);
+
+        assert!(inventory.record_sales(&[(0, -1), (0, -1), (1, -1)], 200));
+        assert!(!inventory.record_sales(&[(1, -1)], 300));
+        assert!(inventory.record_sales(&[(0, 1)], 400));
+        assert_eq!(inventory.get(0).unwrap().quantity, 9);
+        assert!(inventory.get(1).is_none());
+        assert!(inventory.low_stock_warnings(&items(), &settings).is_empty());
+
+        inventory.record_sales(&[(0, -1), (0, -1), (0, -1), (0, -1)], 500);
+        let warnings = inventory.low_stock_warnings(&items(), &settings);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].name, "beer");
+        assert_eq!(warnings[0].quantity, 5);
+
+        inventory.entry(0).low_stock_threshold = Some(2);
+        assert!(inventory.low_stock_warnings(&items(), &settings).is_empty());
+    }
+
+    #[test]
+    fn stocktaking_reports_shrinkage_at_purchase_price() {
+        let mut inventory = Inventory::default();
+        restock(&mut inventory, 0, 24, 60, 100);
+        inventory.record_sales(&[(0, -1), (0, -1)], 200);
+
+        let report = inventory
+            .stocktaking(
+                Stocktaking {
+                    counts: vec![
+                        StockCount { item_id: 0, counted: 19 },
+                        StockCount { item_id: 1, counted: 6 },
+                    ],
+                    comment: "monthly count".to_string(),
+                },
+                &items(),
+                300,
+            )
+            .unwrap();
+        assert_eq!(report.lines[0].expected, 22);
+        assert_eq!(report.lines[0].difference, -3);
+        assert_eq!(report.lines[0].value_cents, -180);
+        //never delivered, valued at the sales price
+        assert_eq!(report.lines[1].value_cents, 6 * 85);
+        assert_eq!(report.total_value_cents, -180 + 510);
+        assert_eq!(inventory.get(0).unwrap().quantity, 19);
+        assert_eq!(inventory.get(1).unwrap().quantity, 6);
+
+        let report = inventory.report(&items(), &inventory.movements, 0, 250);
+        assert_eq!(report[0].delivered, 24);
+        assert_eq!(report[0].sold, 2);
+        assert_eq!(report[0].stocktaking_correction, 0);
+        assert_eq!(report[0].stock_value_cents, 19 * 60);
+        assert_eq!(
+            format_report_as_csv(&report).lines().nth(1),
+            Some("0;beer;24;2;0;19;0,60;11,40")
+        );
+    }
+
+    #[test]
+    fn sales_are_appended_to_the_log_and_replayed_when_loading() {
+        let directory = std::env::temp_dir().join(format!("cervisia-inventory-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let now = Utc::now().timestamp() * 1000;
+        let mut inventory = Inventory::load(&config);
+        restock(&mut inventory, 0, 10, 60, now - 30 * MILLIS_PER_DAY);
+        inventory.save();
+        let stored = std::fs::read_to_string(inventory.path.clone().unwrap()).unwrap();
+
+        //a sale does not touch the stock file
+        inventory.record_sales(&[(0, -1), (0, -1)], now);
+        append_movements(&inventory.movements_log_path(), &inventory.take_unlogged());
+        assert_eq!(std::fs::read_to_string(inventory.path.clone().unwrap()).unwrap(), stored);
+        //the delivery is older than the consumption window, only the log keeps it
+        assert_eq!(inventory.movements.len(), 2);
+
+        let reloaded = Inventory::load(&config);
+        let logged = reloaded.movement_log().read();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(reloaded.get(0).unwrap().quantity, 8);
+        assert_eq!(reloaded.last_purchase_price_cents(0), Some(60));
+        assert_eq!(reloaded.next_id, 3);
+        assert_eq!(logged.len(), 3);
+        assert_eq!(reloaded.report(&items(), &logged, 0, now + 1)[0].sold, 2);
+    }
+}
@@ -0,0 +1,295 @@
+use accounting::{format_vat_rate, AccountingProfile, VatSplit};
+use billformatter::{bill_external_id, cents_to_currency_string_i64, documented_positions,
+                    DocumentedPosition, PositionKind};
+use chrono::prelude::*;
+use pdf::{Font, PdfDocument, A4_HEIGHT};
+use rustix_bl::datastore::Bill;
+use std;
+use std::collections::BTreeMap;
+
+const LEFT: f32 = 50.0;
+const RIGHT: f32 = 545.0;
+const TOP: f32 = A4_HEIGHT - 60.0;
+const BOTTOM: f32 = 60.0;
+const LINE_HEIGHT: f32 = 14.0;
+
+//table columns, amounts are right aligned at these positions
+const COLUMN_POSITION: f32 = 70.0;
+const COLUMN_COUNT: f32 = 360.0;
+const COLUMN_UNIT_PRICE: f32 = 425.0;
+const COLUMN_VAT: f32 = 470.0;
+
+//club details printed on the invoices, configured in the [invoice] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvoiceSettings {
+    pub club_name: String,
+    pub club_address_lines: Vec<String>,
+    pub account_holder: String,
+    pub iban: String,
+    pub bic: String,
+}
+
+impl Default for InvoiceSettings {
+    fn default() -> Self {
+        return InvoiceSettings {
+            club_name: "Cervisia".to_string(),
+            club_address_lines: Vec::new(),
+            account_holder: String::new(),
+            iban: String::new(),
+            bic: String::new(),
+        };
+    }
+}
+
+pub trait InvoiceFormatting {
+    //None if the user has no positions on this bill
+    fn format_as_invoice_pdf(
+        &self,
+        user_id: &u32,
+        profile: &AccountingProfile,
+        settings: &InvoiceSettings,
+        date_today: i64,
+    ) -> Option<Vec<u8>>;
+}
+
+//keeps track of the vertical position and starts a new page when the current one is full
+struct InvoiceWriter {
+    document: PdfDocument,
+    y: f32,
+}
+
+impl InvoiceWriter {
+    fn new() -> InvoiceWriter {
+        let mut document = PdfDocument::new();
+        document.add_page();
+        return InvoiceWriter {
+            document: document,
+            y: TOP,
+        };
+    }
+
+    fn next_line(&mut self, lines: f32) {
+        self.y -= LINE_HEIGHT * lines;
+        if self.y < BOTTOM {
+            self.document.add_page();
+            self.y = TOP;
+        }
+    }
+
+    fn text(&mut self, x: f32, size: f32, font: Font, text: &str) {
+        let y = self.y;
+        self.document.current_page().text(x, y, size, font, text);
+    }
+
+    fn text_right(&mut self, right_x: f32, size: f32, font: Font, text: &str) {
+        let y = self.y;
+        self.document.current_page().text_right(right_x, y, size, font, text);
+    }
+
+    fn rule(&mut self) {
+        let y = self.y + LINE_HEIGHT - 4.0;
+        self.document.current_page().line(LEFT, y, RIGHT, y);
+    }
+
+    fn amount_row(&mut self, label: &str, cents: i64, font: Font) {
+        self.text(COLUMN_UNIT_PRICE - 120.0, 10.0, font, label);
+        self.text_right(RIGHT, 10.0, font, &format!("{} €", cents_to_currency_string_i64(cents)));
+        self.next_line(1.0);
+    }
+}
+
+fn describe(position: &DocumentedPosition) -> String {
+    let line = &position.line;
+    return match position.kind {
+        PositionKind::Purchase => line.item_name.to_string(),
+        PositionKind::Special => format!("{} (special)", line.item_name),
+        PositionKind::FfaGiveout => format!("{} (given out to everyone)", line.item_name),
+        PositionKind::CountGiveout => format!("{} (given out to {})", line.item_name, line.recipient),
+        PositionKind::BudgetGiven => format!("Budget given to {}", line.recipient),
+        PositionKind::BudgetReceived => format!("Budget received from {}", line.donor),
+    };
+}
+
+fn format_day(millis: i64) -> String {
+    return Utc.timestamp(millis / 1000, 0).format("%d.%m.%Y").to_string();
+}
+
+impl InvoiceFormatting for Bill {
+    fn format_as_invoice_pdf(
+        &self,
+        user_id: &u32,
+        profile: &AccountingProfile,
+        settings: &InvoiceSettings,
+        date_today: i64,
+    ) -> Option<Vec<u8>> {
+        let mut positions = documented_positions(self, user_id, profile);
+        if positions.is_empty() {
+            return None;
+        }
+        //stable, so positions of one day keep the bill order
+        positions.sort_by_key(|p| p.line.day);
+
+        let user = self.finalized_data.all_users.get(user_id)?;
+        let member_id = user.external_user_id.clone().unwrap_or(String::new());
+        let invoice_number = bill_external_id(date_today, &member_id);
+
+        let mut writer = InvoiceWriter::new();
+
+        //club letterhead on the left, invoice details on the right
+        writer.text(LEFT, 16.0, Font::Bold, &settings.club_name);
+        writer.text_right(RIGHT, 16.0, Font::Bold, "Invoice");
+        writer.next_line(1.5);
+        let details = vec![
+            format!("Invoice number: {}", invoice_number),
+            format!("Date: {}", format_day(date_today)),
+            format!(
+                "Period: {} - {}",
+                format_day(self.timestamp_from),
+                format_day(self.timestamp_to)
+            ),
+        ];
+        for i in 0..std::cmp::max(settings.club_address_lines.len(), details.len()) {
+            if let Some(address_line) = settings.club_address_lines.get(i) {
+                writer.text(LEFT, 10.0, Font::Regular, address_line);
+            }
+            if let Some(detail) = details.get(i) {
+                writer.text_right(RIGHT, 10.0, Font::Regular, detail);
+            }
+            writer.next_line(1.0);
+        }
+        writer.next_line(1.0);
+        writer.text(LEFT, 11.0, Font::Bold, &user.username);
+        writer.next_line(1.0);
+        writer.text(LEFT, 10.0, Font::Regular, &format!("Member number: {}", member_id));
+        writer.next_line(2.0);
+
+        writer.text(LEFT, 10.0, Font::Bold, "Day");
+        writer.text(COLUMN_POSITION + 30.0, 10.0, Font::Bold, "Position");
+        writer.text_right(COLUMN_COUNT, 10.0, Font::Bold, "Count");
+        writer.text_right(COLUMN_UNIT_PRICE, 10.0, Font::Bold, "Unit price");
+        writer.text_right(COLUMN_VAT, 10.0, Font::Bold, "VAT");
+        writer.text_right(RIGHT, 10.0, Font::Bold, "Amount");
+        writer.next_line(1.0);
+        writer.rule();
+
+        let mut per_rate: BTreeMap<u32, VatSplit> = BTreeMap::new();
+        let mut transfers = VatSplit::default();
+        let mut total = VatSplit::default();
+        let mut last_day: Option<DateTime<Utc>> = None;
+
+        for position in &positions {
+            let split = position.vat_split();
+            total.add(&split);
+            match position.vat_basis_points {
+                Some(basis_points) => per_rate
+                    .entry(basis_points)
+                    .or_insert(VatSplit::default())
+                    .add(&split),
+                None => transfers.add(&split),
+            }
+
+            //the day is only printed once for all of its positions
+            if last_day != Some(position.line.day) {
+                writer.text(LEFT, 10.0, Font::Regular, &position.line.day.format("%d.%m.").to_string());
+                last_day = Some(position.line.day);
+            }
+            writer.text(COLUMN_POSITION + 30.0, 10.0, Font::Regular, &describe(position));
+            writer.text_right(COLUMN_COUNT, 10.0, Font::Regular, &position.line.item_count.to_string());
+            writer.text_right(
+                COLUMN_UNIT_PRICE,
+                10.0,
+                Font::Regular,
+                &cents_to_currency_string_i64(position.line.item_cost_cents as i64),
+            );
+            let vat = position
+                .vat_basis_points
+                .map(|bp| format!("{}%", format_vat_rate(bp)))
+                .unwrap_or("-".to_string());
+            writer.text_right(COLUMN_VAT, 10.0, Font::Regular, &vat);
+            writer.text_right(RIGHT, 10.0, Font::Regular, &cents_to_currency_string_i64(split.gross_cents));
+            writer.next_line(1.0);
+        }
+
+        writer.rule();
+        let net_cents: i64 = per_rate.values().map(|s| s.net_cents).sum();
+        writer.amount_row("Net amount", net_cents, Font::Regular);
+        for (basis_points, split) in &per_rate {
+            writer.amount_row(
+                &format!("VAT {}%", format_vat_rate(*basis_points)),
+                split.tax_cents,
+                Font::Regular,
+            );
+        }
+        if transfers.gross_cents != 0 {
+            writer.amount_row("Budget transfers", transfers.gross_cents, Font::Regular);
+        }
+        writer.amount_row("Total", total.gross_cents, Font::Bold);
+        writer.next_line(1.0);
+
+        let instructions = if total.gross_cents <= 0 {
+            "Nothing to pay for this period.".to_string()
+        } else if user.is_sepa {
+            "The total will be collected from your account by SEPA direct debit.".to_string()
+        } else {
+            format!(
+                "Please transfer the total within {} days to {}, IBAN {}, BIC {}, reference {}.",
+                profile.payment_target_days,
+                settings.account_holder,
+                settings.iban,
+                settings.bic,
+                invoice_number
+            )
+        };
+        writer.text(LEFT, 10.0, Font::Regular, &instructions);
+        writer.next_line(1.0);
+
+        return Some(writer.document.to_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use accounting::AccountingProfile;
+    use billformatter::tests::simple_bill;
+    use invoice::*;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        return haystack.windows(needle.len()).any(|w| w == needle);
+    }
+
+    #[test]
+    fn invoice_lists_positions_totals_and_payment_instructions() {
+        let settings = InvoiceSettings {
+            club_name: "Huettenverein".to_string(),
+            club_address_lines: vec!["Am Berg 1".to_string(), "12345 Tal".to_string()],
+            account_holder: "Huettenverein e.V.".to_string(),
+            iban: "DE02120300000000202051".to_string(),
+            bic: "BYLADEM1001".to_string(),
+        };
+        let pdf = simple_bill()
+            .format_as_invoice_pdf(&0, &AccountingProfile::default(), &settings, 1532886727279i64)
+            .unwrap();
+
+        assert!(pdf.starts_with(b"%PDF-1.4"));
+        assert!(contains(&pdf, b"(Huettenverein) Tj"));
+        assert!(contains(&pdf, b"(Am Berg 1) Tj"));
+        assert!(contains(&pdf, b"(Invoice number: 18072907ExternalUserId0) Tj"));
+        assert!(contains(&pdf, b"(Banana \\(special\\)) Tj"));
+        assert!(contains(&pdf, b"(Budget received from charlie) Tj"));
+        assert!(contains(&pdf, b"(1292,75 \x80) Tj"));
+        assert!(contains(&pdf, b"SEPA direct debit"));
+    }
+
+    #[test]
+    fn users_without_positions_get_no_invoice() {
+        let bill = simple_bill();
+        let settings = InvoiceSettings::default();
+        assert!(bill
+            .format_as_invoice_pdf(&1, &AccountingProfile::default(), &settings, 1532886727279i64)
+            .is_none());
+        assert!(bill
+            .format_as_invoice_pdf(&42, &AccountingProfile::default(), &settings, 1532886727279i64)
+            .is_none());
+    }
+}
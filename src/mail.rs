@@ -4,20 +4,46 @@ use lettre::smtp::authentication::{Credentials, Mechanism};
 use lettre::smtp::client::net::*;
 use lettre::smtp::ClientSecurity;
 use lettre::smtp::ConnectionReuseParameters;
-use lettre::{SendmailTransport, SendableEmail, SmtpTransport, Envelope, EmailAddress, SmtpClient, Transport};
+use lettre::{FileTransport, SendableEmail, SendmailTransport, SmtpClient, SmtpTransport, Transport};
 use lettre_email::*;
 use mime;
 use native_tls::TlsConnector;
 use std;
 use std::collections::hash_map::DefaultHasher;
-use std::fs::File;
 use std::hash::Hasher;
-use std::io::{Seek, Write};
-use std::path::Path;
-use uuid::Uuid;
+use std::io::{Cursor, Seek, Write};
 use zip::result::ZipResult;
 use zip::write::{FileOptions, ZipWriter};
 
+#[derive(Debug)]
+pub enum MailError {
+    NotConfigured,
+    Build(String),
+    Tls(String),
+    Smtp(lettre::smtp::error::Error),
+    Sendmail(lettre::sendmail::error::Error),
+    File(lettre::file::error::Error),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            MailError::NotConfigured => write!(f, "no mail transport configured"),
+            MailError::Build(ref e) => write!(f, "could not build mail: {}", e),
+            MailError::Tls(ref e) => write!(f, "could not set up TLS: {}", e),
+            MailError::Smtp(ref e) => write!(f, "smtp transport failed: {:?}", e),
+            MailError::Sendmail(ref e) => write!(f, "sendmail transport failed: {:?}", e),
+            MailError::File(ref e) => write!(f, "file transport failed: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for MailError {
+    fn description(&self) -> &str {
+        return "sending mail failed";
+    }
+}
+
 pub fn is_too_large_for_inline(attachments: &std::collections::HashMap<String, String>) -> bool {
     let x = string_size(attachments);
     return x > 900usize;
@@ -45,28 +71,21 @@ pub fn two_numbers_to_string(a: i64, b: i64) -> String {
     return format!("{:x}_{:x}", a, b);
 }
 
-pub fn save_attachments_in_zip_file(
+//sorted by filename, so the same bill always produces the same archive layout
+fn sorted_attachments(
     attachments: &std::collections::HashMap<String, String>,
-    zipfilename: &str,
-) -> String {
-    //let timespec = time::get_time();
-    //let mills: u64 =  timespec.sec as u64 + (timespec.nsec as f64 / 1000.0 / 1000.0 / 1000.0) as u64;
-    //let hc = format!("{:x}", hash_code(attachments));
-
-    let filename = format!("bill_{}.zip", zipfilename.to_string());
-    //should no execute on (Windows + )Debug
-    if !cfg!(debug_assertions) {
-        let mut file = File::create(filename.to_string()).expect("Couldn't create file");
-        create_zip_archive(&mut file, attachments).expect("Couldn't create archive");
-
-        return filename;
-    } else {
-        let mut file = File::create(filename.to_string())
-            .expect(&("could not create file ".to_string() + &filename));
-        file.write_all(b"CERVISIA WAS COMPILED AS DEBUG! NO BILL CONTAINED HERE!")
-            .expect("Could not write file");
-        return filename.to_string();
-    }
+) -> Vec<(&String, &String)> {
+    let mut xs: Vec<(&String, &String)> = attachments.iter().collect();
+    xs.sort_by(|a, b| a.0.cmp(b.0));
+    return xs;
+}
+
+pub fn build_zip_archive(
+    attachments: &std::collections::HashMap<String, String>,
+) -> ZipResult<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    create_zip_archive(&mut buf, attachments)?;
+    return Ok(buf.into_inner());
 }
 
 fn create_zip_archive<T: Seek + Write>(
@@ -74,232 +93,275 @@ fn create_zip_archive<T: Seek + Write>(
     attachments: &std::collections::HashMap<String, String>,
 ) -> ZipResult<()> {
     let mut writer = ZipWriter::new(buf);
-    for (filename, filecontent) in attachments {
+    for (filename, filecontent) in sorted_attachments(attachments) {
         writer.start_file(filename.to_string(), FileOptions::default())?;
-        writer.write(filecontent.as_bytes())?;
+        writer.write_all(filecontent.as_bytes())?;
     }
     writer.finish()?;
     Ok(())
 }
 
-pub fn send_mail(
+fn binary_mimetype(filename: &str) -> &'static str {
+    if filename.ends_with(".pdf") {
+        return "application/pdf";
+    }
+    return "application/octet-stream";
+}
+
+//small attachments are attached as csv files directly, larger ones are bundled into a single zip
+//binary attachments (pdf invoices) are always attached as they are
+pub fn build_email(
     receiver_email: &str,
     subject: &str,
     body: &str,
     attachments: &std::collections::HashMap<String, String>,
+    binary_attachments: &std::collections::HashMap<String, Vec<u8>>,
     config: &ServerConfig,
     zipfilename: &str,
-) -> Result<lettre::smtp::response::Response, lettre::smtp::error::Error> {
-    if true {
-        //implement attachments as multipart/mixed as in: https://github.com/lettre/lettre/issues/201
-
-        //write out info in any case
-        //let receivers: String = receiver_emails.clone().into_iter().map(|email| email.to_string()).fold("".to_string(), |acc,b| acc + &b);
-        //warn!("Trying to send mail with to = {} :", receivers);
-        //warn!("Subject: {}", subject.to_string());
-        //warn!("Body: {}", body.to_string());
-
-        match config.use_sendmail_instead_of_smtp {
-            Some(true) => {
-                //experimental sendmail support!
-                //TODO: implement sendmail alternative
-                unimplemented!()
-            }
-            Some(false) => {
-                let my_uuid = Uuid::new_v4();
-                let uuid_str = format!("{}", my_uuid);
-
-                info!("Building email begin");
-
-                let boundary = "XXXXboundary";
-                let receiver_string = receiver_email.to_string();
-                let sender_string = config.sender_email_address.to_string();
-                let body_block: String = body.to_string();
-                let message_id: String =
-                    format!("<{}.{}>", uuid_str.to_string(), sender_string.to_string());
-                let date: String = "Thu, 18 Jan 2018 21:29:38 +0100".to_string();
-
-                let mut attachment_blocks: String = "".to_string();
-
-                for (filename, filecontent) in attachments {
-                    //TODO: require that filename is legit
-
-                    attachment_blocks = attachment_blocks
-                        + &format!(
-                            "--{}
-Content-Type: text/plain; charset=utf-8
-Content-Disposition: attachment;
-        filename={}
-
-{}
-
-
-",
-                            boundary, filename, filecontent
-                        );
-                }
-
-                let attachments_size = string_size(attachments);
-                let _email: SendableEmail = if false
-                /*&& !is_too_large_for_inline(attachments)*/
-                {
-                    let email_string: String = format!(
-                        "Subject: {}
-To: {}
-From: {}
-Reply-To: {}
-Date: {}
-MIME-Version: 1.0
-Message-ID: {}
-Content-Type: multipart/mixed;
-        boundary={}
-
-This is a multipart message in MIME format.
-
---{}
-Content-Type: text/plain; charset=utf-8
-
-{}
-
-{}
---{}--",
-                        subject,
-                        receiver_string,
-                        sender_string,
-                        sender_string,
-                        date,
-                        message_id,
-                        boundary,
-                        boundary,
-                        body_block,
-                        attachment_blocks,
-                        boundary
-                    );
-
-                    let email = SendableEmail::new(Envelope::new(
-                        EmailAddress::new(config.sender_email_address.to_owned()).ok(),
-                        vec![EmailAddress::new(receiver_email.to_owned()).unwrap()]).unwrap(),
-                        message_id,
-                        email_string.to_string().into_bytes(),
-                    );
-
-                    info!(
-                        "Trying to send email"
-                    );
-
-                    let tls: ClientTlsParameters = {
-                        let mut tls_builder = TlsConnector::builder().build().unwrap();
-
-                        let tls_parameters = ClientTlsParameters::new(
-                            config.smtp_host_address.to_string(),
-                            tls_builder,
-                        );
-
-                        tls_parameters
-                    };
-
-                    // Connect to a remote server on a custom port
-                    let mut mailer = SmtpTransport::new(SmtpClient::new(format!("{}:{}", config.smtp_host_address, config.smtp_port), ClientSecurity::Required(tls))?
-                        // Add credentials for authentication
-                        .credentials(Credentials::new(config.smpt_credentials_loginname.to_string(), config.smpt_credentials_password.to_string()))
-                        // Enable SMTPUTF8 if the server supports it
-                        .smtp_utf8(true)
-                        // Configure expected authentication mechanism
-                        .authentication_mechanism(Mechanism::Plain)
-                        // Enable connection reuse
-                        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited));
-
-                    let result_1 = mailer.send(email);
-                    info!("Sending email result: {:?}", result_1);
-                    if !result_1.is_ok() {
-                        error!(
-                            "Error sending mail. Whole mail size was {}",
-                            attachments_size
-                        );
-                    }
-
-                    // Explicitly close the SMTP transaction as we enabled connection reuse
-                    mailer.close();
-                    return result_1;
-                } else {
-                    let zipfile = save_attachments_in_zip_file(attachments, zipfilename);
-
-                    let mimetype: mime::Mime = "application/zip".parse().unwrap();
-
-                    let email =  SendableEmail::new(Envelope::new(
-                        EmailAddress::new(config.sender_email_address.to_owned()).ok(),
-                        vec![EmailAddress::new(receiver_email.to_owned()).unwrap()]).unwrap(),
-                                       message_id,
-                                                    body_block.to_string().into_bytes(),
-                    );
-                    /*
-                    let email = EmailBuilder::new()
-                        // Addresses can be specified by the tuple (email, alias)
-                        .to(receiver_email.to_string())
-                        // ... or by an address only
-                        .from(config.sender_email_address.to_string())
-                        .subject(subject)
-                        .text(body_block)
-                        //TODO: reimplement.attachment(Path::new(&zipfile), "my_attachment.zip", &mimetype).unwrap()
-                        .build()
-                        .unwrap();*/
-
-                    info!(
-                        "Trying to send email"
-                    );
-
-                    let tls: ClientTlsParameters = {
-                        let mut tls_builder = TlsConnector::builder().build().unwrap();
-
-                        let tls_parameters = ClientTlsParameters::new(
-                            config.smtp_host_address.to_string(),
-                            tls_builder,
-                        );
-
-                        tls_parameters
-                    };
-
-                    // Connect to a remote server on a custom port
-                    let mut mailer = SmtpTransport::new(SmtpClient::new(format!("{}:{}", config.smtp_host_address, config.smtp_port), ClientSecurity::Required(tls))?
-                        // Add credentials for authentication
-                        .credentials(Credentials::new(config.smpt_credentials_loginname.to_string(), config.smpt_credentials_password.to_string()))
-                        // Enable SMTPUTF8 if the server supports it
-                        .smtp_utf8(true)
-                        // Configure expected authentication mechanism
-                        .authentication_mechanism(Mechanism::Plain)
-                        // Enable connection reuse
-                        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited));
-
-                    info!("trying to send email");
-                    let result_1 = mailer.send(email);
-                    info!("Sending email result: {:?}", result_1);
-                    if !result_1.is_ok() {
-                        error!("Error sending mail with zip attachment. Whole mail size would have been {} bytes", attachments_size);
-                    }
-                    // Explicitly close the SMTP transaction as we enabled connection reuse
-                    mailer.close();
-                    return result_1;
-                };
-            }
-            None => {
-                return Ok(lettre::smtp::response::Response::new(
-                    lettre::smtp::response::Code::new(
-                        lettre::smtp::response::Severity::TransientNegativeCompletion,
-                        lettre::smtp::response::Category::Unspecified4,
-                        lettre::smtp::response::Detail::Four,
-                    ),
-                    vec![],
-                ))
+) -> Result<Email, MailError> {
+    let mut builder = EmailBuilder::new()
+        .to(receiver_email.to_string())
+        .from(config.sender_email_address.to_string())
+        .reply_to(config.sender_email_address.to_string())
+        .subject(subject.to_string())
+        .text(body.to_string());
+
+    if !attachments.is_empty() {
+        if is_too_large_for_inline(attachments) {
+            let zipped = build_zip_archive(attachments)
+                .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+            let mimetype: mime::Mime = "application/zip"
+                .parse()
+                .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+            builder = builder
+                .attachment(&zipped, &format!("bill_{}.zip", zipfilename), &mimetype)
+                .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+        } else {
+            let mimetype: mime::Mime = "text/csv; charset=utf-8"
+                .parse()
+                .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+            for (filename, filecontent) in sorted_attachments(attachments) {
+                builder = builder
+                    .attachment(filecontent.as_bytes(), filename, &mimetype)
+                    .map_err(|e| MailError::Build(format!("{:?}", e)))?;
             }
         }
-    } else {
-        return Ok(lettre::smtp::response::Response::new(
-            lettre::smtp::response::Code::new(
-                lettre::smtp::response::Severity::TransientNegativeCompletion,
-                lettre::smtp::response::Category::Unspecified4,
-                lettre::smtp::response::Detail::Four,
-            ),
-            vec![],
-        ));
+    }
+
+    let mut binaries: Vec<(&String, &Vec<u8>)> = binary_attachments.iter().collect();
+    binaries.sort_by(|a, b| a.0.cmp(b.0));
+    for (filename, filecontent) in binaries {
+        let mimetype: mime::Mime = binary_mimetype(filename)
+            .parse()
+            .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+        builder = builder
+            .attachment(filecontent, filename, &mimetype)
+            .map_err(|e| MailError::Build(format!("{:?}", e)))?;
+    }
+
+    //the builder sets Date and Message-ID itself
+    return builder
+        .build()
+        .map_err(|e| MailError::Build(format!("{:?}", e)));
+}
+
+fn send_via_smtp(email: SendableEmail, config: &ServerConfig) -> Result<String, MailError> {
+    let tls: ClientTlsParameters = {
+        let tls_builder = TlsConnector::builder()
+            .build()
+            .map_err(|e| MailError::Tls(format!("{:?}", e)))?;
+
+        ClientTlsParameters::new(config.smtp_host_address.to_string(), tls_builder)
+    };
+
+    let client = SmtpClient::new(
+        format!("{}:{}", config.smtp_host_address, config.smtp_port),
+        ClientSecurity::Required(tls),
+    ).map_err(MailError::Smtp)?;
+
+    // Connect to a remote server on a custom port
+    let mut mailer = SmtpTransport::new(client
+        // Add credentials for authentication
+        .credentials(Credentials::new(config.smpt_credentials_loginname.to_string(), config.smpt_credentials_password.to_string()))
+        // Enable SMTPUTF8 if the server supports it
+        .smtp_utf8(true)
+        // Configure expected authentication mechanism
+        .authentication_mechanism(Mechanism::Plain)
+        // Enable connection reuse
+        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited));
+
+    let result = mailer.send(email);
+    info!("Sending email result: {:?}", result);
+
+    // Explicitly close the SMTP transaction as we enabled connection reuse
+    mailer.close();
+    return result
+        .map(|response| format!("{:?} {}", response.code, response.message.join(" ")))
+        .map_err(MailError::Smtp);
+}
+
+fn send_via_sendmail(email: SendableEmail, config: &ServerConfig) -> Result<String, MailError> {
+    let mut mailer = match config.sendmail_command {
+        Some(ref command) => SendmailTransport::new_with_command(command.to_string()),
+        None => SendmailTransport::new(),
+    };
+    return mailer
+        .send(email)
+        .map(|_| "accepted by sendmail".to_string())
+        .map_err(MailError::Sendmail);
+}
+
+fn send_via_file_drop(email: SendableEmail, directory: &str) -> Result<String, MailError> {
+    std::fs::create_dir_all(directory)
+        .map_err(|e| MailError::Build(format!("could not create {}: {:?}", directory, e)))?;
+    let mut mailer = FileTransport::new(directory);
+    return mailer
+        .send(email)
+        .map(|_| format!("written to {}", directory))
+        .map_err(MailError::File);
+}
+
+//a configured mail_drop_directory wins over the real transports, so test setups never send real mails
+pub fn send_mail(
+    receiver_email: &str,
+    subject: &str,
+    body: &str,
+    attachments: &std::collections::HashMap<String, String>,
+    binary_attachments: &std::collections::HashMap<String, Vec<u8>>,
+    config: &ServerConfig,
+    zipfilename: &str,
+) -> Result<String, MailError> {
+    if config.mail_drop_directory.is_none() && config.use_sendmail_instead_of_smtp.is_none() {
+        warn!("Not sending mail '{}' to {}, no mail transport configured", subject, receiver_email);
+        return Err(MailError::NotConfigured);
+    }
+
+    info!("Building email begin");
+    let email: SendableEmail =
+        build_email(
+        receiver_email,
+        subject,
+        body,
+        attachments,
+        binary_attachments,
+        config,
+        zipfilename,
+    )?.into();
+    info!("Trying to send email");
+
+    let result = match (&config.mail_drop_directory, config.use_sendmail_instead_of_smtp) {
+        (&Some(ref directory), _) => send_via_file_drop(email, directory),
+        (&None, Some(true)) => send_via_sendmail(email, config),
+        (&None, _) => send_via_smtp(email, config),
+    };
+
+    if let Err(ref e) = result {
+        error!(
+            "Error sending mail: {}. Whole attachment size was {} bytes",
+            e,
+            string_size(attachments)
+        );
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ServerConfig;
+    use mail::*;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+    use uuid::Uuid;
+    use zip::ZipArchive;
+
+    fn csv_attachments(size: usize) -> HashMap<String, String> {
+        let mut hm = HashMap::new();
+        hm.insert("sewobe_import.csv".to_string(), "a;b;c\n".repeat(size));
+        hm.insert("internal_oversight.csv".to_string(), "x;y\n".repeat(size));
+        return hm;
+    }
+
+    #[test]
+    fn zip_archive_contains_all_attachments() {
+        let attachments = csv_attachments(10);
+        let zipped = build_zip_archive(&attachments).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(zipped)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut content = String::new();
+        archive
+            .by_name("sewobe_import.csv")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "a;b;c\n".repeat(10));
+    }
+
+    #[test]
+    fn file_transport_receives_multipart_mail_with_attachments() {
+        let directory = std::env::temp_dir().join(format!("cervisia-mails-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            sender_email_address: "treasurer@hostname.org".to_string(),
+            mail_drop_directory: Some(directory.to_string_lossy().to_string()),
+            ..ServerConfig::default()
+        };
+
+        send_mail(
+            "member@hostname.org",
+            "Your bill",
+            "The bill is attached",
+            &csv_attachments(1),
+            &HashMap::new(),
+            &config,
+            "1_2",
+        ).unwrap();
+
+        let entries: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(entries.len(), 1);
+
+        let mut raw = String::new();
+        File::open(&entries[0])
+            .unwrap()
+            .read_to_string(&mut raw)
+            .unwrap();
+        let stored: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        let message: Vec<u8> = stored["message"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b.as_u64().unwrap() as u8)
+            .collect();
+        let message = String::from_utf8_lossy(&message);
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert!(message.contains("multipart/mixed"));
+        assert!(message.contains("Date: "));
+        assert!(!message.contains("18 Jan 2018"));
+        assert!(message.contains("sewobe_import.csv"));
+        assert!(message.contains("internal_oversight.csv"));
+    }
+
+    #[test]
+    fn unconfigured_transport_is_an_error() {
+        let config = ServerConfig::default();
+        let result = send_mail(
+            "member@hostname.org",
+            "Your bill",
+            "The bill is attached",
+            &csv_attachments(1),
+            &HashMap::new(),
+            &config,
+            "1_2",
+        );
+        match result {
+            Err(MailError::NotConfigured) => {}
+            other => panic!("expected NotConfigured, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,380 @@
+use chrono::prelude::*;
+use configuration::{current_config, LiveConfig, ServerConfig};
+use mail;
+use sidecar;
+use std;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use typescriptify::TypeScriptifyTrait;
+use workers::Worker;
+
+//how often the worker looks for due mails
+const POLL_INTERVAL_SECONDS: u64 = 5;
+
+//retries are capped, a mail server that is down for a weekend should not lead to weekly retries
+const MAX_BACKOFF_SECONDS: i64 = 6 * 60 * 60;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum MailStatus {
+    Queued,
+    Sent,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailDraft {
+    pub receiver_email: String,
+    pub subject: String,
+    pub body: String,
+    pub attachments: HashMap<String, String>,
+    //queues written before pdf invoices existed have no binary attachments
+    #[serde(default, with = "base64_attachments")]
+    pub binary_attachments: HashMap<String, Vec<u8>>,
+    pub zipfilename: String,
+}
+
+//as a json array every byte of a pdf would take up a line of its own in the queue file
+mod base64_attachments {
+    use base64;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(attachments: &HashMap<String, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoded: HashMap<&String, String> = attachments
+            .iter()
+            .map(|(name, content)| (name, base64::encode(content)))
+            .collect();
+        return encoded.serialize(serializer);
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<u8>>, D::Error> {
+        let encoded: HashMap<String, String> = HashMap::deserialize(deserializer)?;
+        let mut attachments: HashMap<String, Vec<u8>> = HashMap::new();
+        for (name, content) in encoded {
+            let decoded = base64::decode(&content).map_err(|e| D::Error::custom(format!("{}: {}", name, e)))?;
+            attachments.insert(name, decoded);
+        }
+        return Ok(attachments);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedMail {
+    pub id: u64,
+    pub draft: MailDraft,
+    pub status: MailStatus,
+    pub attempts: u32,
+    pub created_epoch_seconds: i64,
+    pub next_attempt_epoch_seconds: i64,
+    pub last_response: Option<String>,
+    //queues written before sent mails were pruned do not know when they were sent
+    #[serde(default)]
+    pub sent_epoch_seconds: Option<i64>,
+}
+
+//what the admin ui gets to see, attachments are only listed by name
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct QueuedMailInfo {
+    pub id: u64,
+    pub receiver_email: String,
+    pub subject: String,
+    pub attachment_names: Vec<String>,
+    pub status: MailStatus,
+    pub attempts: u32,
+    pub created_epoch_seconds: i64,
+    pub next_attempt_epoch_seconds: i64,
+    pub last_response: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct ResendMail {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MailQueue {
+    pub next_id: u64,
+    pub mails: Vec<QueuedMail>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+pub fn backoff_seconds(config: &ServerConfig, attempts: u32) -> i64 {
+    let exponent = std::cmp::min(attempts.saturating_sub(1), 20);
+    let seconds = config.mail_retry_base_seconds.saturating_mul(1i64 << exponent);
+    return std::cmp::min(seconds, MAX_BACKOFF_SECONDS);
+}
+
+impl MailQueue {
+    pub fn load(config: &ServerConfig) -> MailQueue {
+        let path = match sidecar::sidecar_path(config, "mailqueue") {
+            Some(path) => path,
+            None => return MailQueue::default(),
+        };
+        let mut queue: MailQueue = sidecar::load_json_or_default(&path, "mail queue");
+        queue.path = Some(path);
+        return queue;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist mail queue to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn enqueue(&mut self, draft: MailDraft, now_epoch_seconds: i64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.mails.push(QueuedMail {
+            id: id,
+            draft: draft,
+            status: MailStatus::Queued,
+            attempts: 0,
+            created_epoch_seconds: now_epoch_seconds,
+            next_attempt_epoch_seconds: now_epoch_seconds,
+            last_response: None,
+            sent_epoch_seconds: None,
+        });
+        return id;
+    }
+
+    pub fn due(&self, now_epoch_seconds: i64) -> Vec<(u64, MailDraft)> {
+        return self
+            .mails
+            .iter()
+            .filter(|m| {
+                m.status == MailStatus::Queued && m.next_attempt_epoch_seconds <= now_epoch_seconds
+            })
+            .map(|m| (m.id, m.draft.clone()))
+            .collect();
+    }
+
+    pub fn record_result(
+        &mut self,
+        id: u64,
+        result: Result<String, String>,
+        now_epoch_seconds: i64,
+        config: &ServerConfig,
+    ) {
+        if let Some(mail) = self.mails.iter_mut().find(|m| m.id == id) {
+            mail.attempts += 1;
+            match result {
+                Ok(response) => {
+                    mail.status = MailStatus::Sent;
+                    mail.last_response = Some(response);
+                    mail.sent_epoch_seconds = Some(now_epoch_seconds);
+                }
+                Err(response) => {
+                    mail.last_response = Some(response);
+                    if mail.attempts >= config.mail_max_attempts {
+                        mail.status = MailStatus::Failed;
+                    } else {
+                        mail.next_attempt_epoch_seconds =
+                            now_epoch_seconds + backoff_seconds(config, mail.attempts);
+                    }
+                }
+            }
+        }
+    }
+
+    //sent mails carry whole bill exports as attachments, they are only kept for resending for a while
+    pub fn prune_sent(&mut self, now_epoch_seconds: i64, retention_days: i64) -> usize {
+        let before = self.mails.len();
+        let oldest_kept = now_epoch_seconds - retention_days * SECONDS_PER_DAY;
+        self.mails.retain(|m| {
+            m.status != MailStatus::Sent || m.sent_epoch_seconds.unwrap_or(m.created_epoch_seconds) > oldest_kept
+        });
+        return before - self.mails.len();
+    }
+
+    //also works for mails which were already sent, e.g. when the treasurer lost the mail
+    pub fn resend(&mut self, id: u64, now_epoch_seconds: i64) -> bool {
+        return match self.mails.iter_mut().find(|m| m.id == id) {
+            Some(mail) => {
+                mail.status = MailStatus::Queued;
+                mail.attempts = 0;
+                mail.next_attempt_epoch_seconds = now_epoch_seconds;
+                true
+            }
+            None => false,
+        };
+    }
+
+    pub fn infos(&self) -> Vec<QueuedMailInfo> {
+        return self
+            .mails
+            .iter()
+            .rev()
+            .map(|m| {
+                let mut attachment_names: Vec<String> = m
+                    .draft
+                    .attachments
+                    .keys()
+                    .chain(m.draft.binary_attachments.keys())
+                    .map(|k| k.to_string())
+                    .collect();
+                attachment_names.sort();
+                QueuedMailInfo {
+                    id: m.id,
+                    receiver_email: m.draft.receiver_email.to_string(),
+                    subject: m.draft.subject.to_string(),
+                    attachment_names: attachment_names,
+                    status: m.status,
+                    attempts: m.attempts,
+                    created_epoch_seconds: m.created_epoch_seconds,
+                    next_attempt_epoch_seconds: m.next_attempt_epoch_seconds,
+                    last_response: m.last_response.clone(),
+                }
+            })
+            .collect();
+    }
+}
+
+//the lock is only held for bookkeeping, never while talking to the mail server
+pub fn process_due_mails(queue: &RwLock<MailQueue>, config: &ServerConfig) -> usize {
+    let due = {
+        let queue = queue.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.due(Utc::now().timestamp())
+    };
+
+    for &(id, ref draft) in &due {
+        let result = mail::send_mail(
+            &draft.receiver_email,
+            &draft.subject,
+            &draft.body,
+            &draft.attachments,
+            &draft.binary_attachments,
+            config,
+            &draft.zipfilename,
+        ).map_err(|e| e.to_string());
+
+        match result {
+            Ok(_) => info!("Delivered queued mail {} to {}", id, draft.receiver_email),
+            Err(ref e) => warn!("Delivery of queued mail {} failed: {}", id, e),
+        }
+
+        let mut queue = queue.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.record_result(id, result, Utc::now().timestamp(), config);
+        queue.save();
+    }
+
+    return due.len();
+}
+
+//smtp settings are picked up from the live config on every round, so reloads apply to pending retries
+pub fn start_mail_worker(queue: Arc<RwLock<MailQueue>>, config: LiveConfig) -> Worker {
+    return Worker::spawn("mail-queue", Duration::from_secs(POLL_INTERVAL_SECONDS), move || {
+        let conf = current_config(&config);
+        process_due_mails(&queue, &conf);
+        let mut queue = queue.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let pruned = queue.prune_sent(Utc::now().timestamp(), conf.mail_sent_retention_days);
+        if pruned > 0 {
+            info!("Removed {} sent mails from the queue", pruned);
+            queue.save();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ServerConfig;
+    use mailqueue::*;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use uuid::Uuid;
+
+    fn draft() -> MailDraft {
+        let mut attachments = HashMap::new();
+        attachments.insert("exported_bill.csv".to_string(), "a;b".to_string());
+        return MailDraft {
+            receiver_email: "member@hostname.org".to_string(),
+            subject: "Your bill".to_string(),
+            body: "The bill is attached".to_string(),
+            attachments: attachments,
+            binary_attachments: HashMap::new(),
+            zipfilename: "1_2".to_string(),
+        };
+    }
+
+    #[test]
+    fn failed_mails_are_retried_with_backoff_until_max_attempts() {
+        let config = ServerConfig {
+            mail_max_attempts: 3,
+            mail_retry_base_seconds: 60,
+            ..ServerConfig::default()
+        };
+        let mut queue = MailQueue::default();
+        let id = queue.enqueue(draft(), 1000);
+        assert_eq!(queue.due(1000).len(), 1);
+
+        queue.record_result(id, Err("timeout".to_string()), 1000, &config);
+        assert_eq!(queue.mails[0].status, MailStatus::Queued);
+        assert_eq!(queue.mails[0].next_attempt_epoch_seconds, 1060);
+        assert_eq!(queue.due(1059).len(), 0);
+
+        queue.record_result(id, Err("timeout".to_string()), 1060, &config);
+        assert_eq!(queue.mails[0].next_attempt_epoch_seconds, 1180);
+
+        queue.record_result(id, Err("timeout".to_string()), 1180, &config);
+        assert_eq!(queue.mails[0].status, MailStatus::Failed);
+        assert_eq!(queue.due(100000).len(), 0);
+
+        assert!(queue.resend(id, 2000));
+        assert_eq!(queue.due(2000).len(), 1);
+        assert!(!queue.resend(id + 1, 2000));
+    }
+
+    #[test]
+    fn due_mails_are_delivered_and_marked_as_sent() {
+        let directory = std::env::temp_dir().join(format!("cervisia-queue-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            mail_drop_directory: Some(directory.to_string_lossy().to_string()),
+            ..ServerConfig::default()
+        };
+        let queue = RwLock::new(MailQueue::default());
+        queue.write().unwrap().enqueue(draft(), 0);
+
+        assert_eq!(process_due_mails(&queue, &config), 1);
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let infos = queue.read().unwrap().infos();
+        assert_eq!(infos[0].status, MailStatus::Sent);
+        assert_eq!(infos[0].attempts, 1);
+        assert_eq!(infos[0].attachment_names, vec!["exported_bill.csv".to_string()]);
+        assert_eq!(process_due_mails(&queue, &config), 0);
+    }
+
+    #[test]
+    fn only_sent_mails_are_pruned_after_the_retention() {
+        let config = ServerConfig::default();
+        let mut queue = MailQueue::default();
+        let sent = queue.enqueue(draft(), 0);
+        let failing = queue.enqueue(draft(), 0);
+        queue.record_result(sent, Ok("250 OK".to_string()), 100, &config);
+        queue.record_result(failing, Err("timeout".to_string()), 100, &config);
+
+        assert_eq!(queue.prune_sent(100 + 29 * 24 * 60 * 60, 30), 0);
+        assert_eq!(queue.prune_sent(101 + 30 * 24 * 60 * 60, 30), 1);
+        assert_eq!(queue.mails.len(), 1);
+        assert_eq!(queue.mails[0].id, failing);
+    }
+
+    #[test]
+    fn binary_attachments_are_stored_base64_encoded() {
+        let mut mail = draft();
+        mail.binary_attachments.insert("invoice.pdf".to_string(), b"%PDF-1.4\n\x00\xff".to_vec());
+        let stored = serde_json::to_string(&mail).unwrap();
+        assert!(stored.contains("\"invoice.pdf\":\"JVBERi0xLjQKAP8=\""));
+
+        let read: MailDraft = serde_json::from_str(&stored).unwrap();
+        assert_eq!(read.binary_attachments, mail.binary_attachments);
+    }
+}
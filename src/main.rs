@@ -1,3 +1,4 @@
+extern crate base64;
 extern crate chrono;
 extern crate config;
 extern crate iron;
@@ -14,6 +15,7 @@ extern crate notify;
 extern crate params;
 extern crate persistent;
 extern crate rand;
+extern crate ring;
 extern crate reqwest;
 extern crate url;
 extern crate router;
@@ -50,6 +52,55 @@ pub mod billformatter;
 
 pub mod importer;
 
+pub mod auth;
+
+pub mod sidecar;
+
+pub mod errors;
+
+pub mod mailqueue;
+
+pub mod accounting;
+
+pub mod pdf;
+
+pub mod invoice;
+
+pub mod sepa;
+
+pub mod payments;
+
+pub mod dunning;
+
+pub mod prepaid;
+
+pub mod inventory;
+
+pub mod alerts;
+
+pub mod notifier;
+
+pub mod webhooks;
+
+pub mod events;
+
+pub mod devices;
+
+pub mod credentials;
+
+pub mod barcodes;
+
+pub mod idempotency;
+
+pub mod sync;
+
+pub mod undo;
+
+pub mod workers;
+
+#[cfg(test)]
+pub mod httpstub;
+
 lazy_static! {
     static ref HELLO_SYSTEM: Mutex<String> = Mutex::new("Hello System".to_string());
 }
@@ -60,9 +111,25 @@ fn main() {
     openssl_probe::init_ssl_cert_env_vars();
     env_logger::init();
 
-    let config = ServerConfig::from_env();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config_file = config_file_path(&args);
+
+    let config = match load_layered(&config_file, &args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     info!("Found following config: {:?}", &config);
 
-    let _listener = execute_cervisia_server(&config, None, None);
+    let live = live_config(config);
+
+    if let Err(e) = watch_config_file(live.clone(), config_file, args) {
+        warn!("Configuration file will not be reloaded: {:?}", e);
+    }
+
+    let _listener = execute_cervisia_server(&live, None, None);
 }
@@ -1,3 +1,4 @@
+use errors::{ensure_applied, OrNotFound, ServerError};
 use rustix_bl;
 use rustix_bl::datastore::*;
 use rustix_bl::rustix_backend::*;
@@ -103,7 +104,7 @@ pub struct ParametersPurchaseLogGlobal {
     pub pagination: ParametersPagination,
 }
 
-#[derive(Serialize, Deserialize, TypeScriptify)]
+#[derive(Serialize, Deserialize, TypeScriptify, Clone)]
 pub struct ParametersBillsCount {
     pub start_inclusive: i64,
     pub end_exclusive: i64,
@@ -174,6 +175,9 @@ pub struct UserDetailInfo {
     pub last_bill_date: i64,
     pub last_bill_cost: u32,
     pub currently_cost: u32,
+    //only set for users with a prepaid account
+    #[serde(default)]
+    pub prepaid_balance_cents: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, TypeScriptify)]
@@ -200,39 +204,15 @@ pub enum Purchase {
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MyNoneError {}
-
-impl std::fmt::Display for MyNoneError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "MyNoneError()")
-    }
-}
-
-impl std::error::Error for MyNoneError {
-    fn description(&self) -> &str {
-        return "element not found";
-    }
-}
-
-pub trait ErrorUnwrap<T> {
-    fn unwrap_or_error(self) -> Result<T, Box<MyNoneError>>;
+//count queries answer with a plain json number
+fn count_result(count: usize) -> serde_json::Value {
+    return serde_json::Value::from(count as u64);
 }
 
-impl<T> ErrorUnwrap<T> for Option<T> {
-    fn unwrap_or_error(self) -> Result<T, Box<MyNoneError>> {
-        if self.is_some() {
-            return Ok(self.unwrap());
-        } else {
-            return Err(Box::new(MyNoneError {}));
-        }
-    }
-}
-
-fn enrich_purchase(
+pub fn enrich_purchase(
     incoming: &rustix_bl::datastore::Purchase,
     datastore: &rustix_bl::datastore::Datastore,
-) -> std::result::Result<Purchase, Box<std::error::Error>> {
+) -> std::result::Result<Purchase, ServerError> {
     return match *incoming {
         rustix_bl::datastore::Purchase::SimplePurchase {
             ref unique_id,
@@ -242,8 +222,8 @@ fn enrich_purchase(
         } => Ok(Purchase::SimplePurchase {
             unique_id: *unique_id,
             timestamp_epoch_millis: *timestamp_epoch_millis,
-            item: datastore.items.get(item_id).unwrap_or_error()?.clone(),
-            consumer: datastore.users.get(consumer_id).unwrap_or_error()?.clone(),
+            item: datastore.items.get(item_id).or_not_found("item")?.clone(),
+            consumer: datastore.users.get(consumer_id).or_not_found("user")?.clone(),
         }),
         rustix_bl::datastore::Purchase::SpecialPurchase {
             ref unique_id,
@@ -256,7 +236,7 @@ fn enrich_purchase(
             timestamp_epoch_millis: *timestamp_epoch_millis,
             special_name: special_name.to_string(),
             specialcost: *specialcost,
-            consumer: datastore.users.get(consumer_id).unwrap_or_error()?.clone(),
+            consumer: datastore.users.get(consumer_id).or_not_found("user")?.clone(),
         }),
         rustix_bl::datastore::Purchase::FFAPurchase {
             ref unique_id,
@@ -267,9 +247,9 @@ fn enrich_purchase(
         } => Ok(Purchase::FFAPurchase {
             unique_id: *unique_id,
             timestamp_epoch_millis: *timestamp_epoch_millis,
-            item: datastore.items.get(item_id).unwrap_or_error()?.clone(),
-            freeby: datastore.get_ffa_freeby(*freeby_id).unwrap().clone(),
-            donor: datastore.users.get(donor).unwrap_or_error()?.clone(),
+            item: datastore.items.get(item_id).or_not_found("item")?.clone(),
+            freeby: datastore.get_ffa_freeby(*freeby_id).or_not_found("giveout")?.clone(),
+            donor: datastore.users.get(donor).or_not_found("user")?.clone(),
         }),
     };
 }
@@ -302,7 +282,7 @@ pub struct EnrichedCountOrBudgetGiveout {
 fn enrich_freeby(
     incoming: &rustix_bl::datastore::Freeby,
     datastore: &rustix_bl::datastore::Datastore,
-) -> std::result::Result<EnrichedCountOrBudgetGiveout, Box<std::error::Error>> {
+) -> std::result::Result<EnrichedCountOrBudgetGiveout, ServerError> {
     return match *incoming {
         rustix_bl::datastore::Freeby::Transfer {
             ref id,
@@ -323,8 +303,8 @@ fn enrich_freeby(
                 left: 0,
                 text_message: text_message.to_string(),
                 created_timestamp: *created_timestamp,
-                donor: datastore.users.get(donor).unwrap().clone(),
-                recipient: datastore.users.get(recipient).unwrap().clone(),
+                donor: datastore.users.get(donor).or_not_found("donor")?.clone(),
+                recipient: datastore.users.get(recipient).or_not_found("recipient")?.clone(),
             })
         }
         rustix_bl::datastore::Freeby::Classic {
@@ -359,18 +339,20 @@ fn enrich_freeby(
                 cents_worth_total: 0,
                 cents_worth_left: 0,
                 created_timestamp: *created_timestamp,
-                donor: datastore.users.get(donor).unwrap().clone(),
-                recipient: datastore.users.get(recipient).unwrap().clone(),
+                donor: datastore.users.get(donor).or_not_found("donor")?.clone(),
+                recipient: datastore.users.get(recipient).or_not_found("recipient")?.clone(),
             })
         }
-        rustix_bl::datastore::Freeby::FFA { .. } => panic!("enrich_freeby on FFA called"),
+        rustix_bl::datastore::Freeby::FFA { .. } => Err(ServerError::Internal(
+            "enrich_freeby on FFA called".to_string(),
+        )),
     };
 }
 
 fn enrich_ffa(
     incoming: &rustix_bl::datastore::Freeby,
     datastore: &rustix_bl::datastore::Datastore,
-) -> std::result::Result<EnrichedFFA, Box<std::error::Error>> {
+) -> std::result::Result<EnrichedFFA, ServerError> {
     return match *incoming {
         rustix_bl::datastore::Freeby::FFA {
             ref id,
@@ -400,10 +382,12 @@ fn enrich_ffa(
                 left: (allowed_number_total - allowed_number_used),
                 text_message: text_message.to_string(),
                 created_timestamp: *created_timestamp,
-                donor: datastore.users.get(donor).unwrap().clone(),
+                donor: datastore.users.get(donor).or_not_found("donor")?.clone(),
             })
         }
-        _ => panic!("enrich_ffa on non-FFA called"),
+        _ => Err(ServerError::Internal(
+            "enrich_ffa on non-FFA called".to_string(),
+        )),
     };
 }
 
@@ -438,7 +422,7 @@ pub trait ServableRustix {
     fn query_read(
         backend: &Backend,
         query: ReadQueryParams,
-    ) -> Result<serde_json::Value, Box<::std::error::Error>>;
+    ) -> Result<serde_json::Value, ServerError>;
 
     /**
     returns json array or number, exactly what is to be updated (using query_read() to compute new values)
@@ -447,7 +431,7 @@ pub trait ServableRustix {
         backend: &mut Backend,
         app_state: ParametersAll,
         write_event: rustix_bl::rustix_event_shop::BLEvents,
-    ) -> Result<RefreshedData, Box<::std::error::Error>>;
+    ) -> Result<RefreshedData, ServerError>;
 }
 
 pub struct ServableRustixImpl {}
@@ -459,7 +443,7 @@ impl ServableRustix for ServableRustixImpl {
     fn query_read(
         backend: &Backend,
         query: ReadQueryParams,
-    ) -> Result<serde_json::Value, Box<::std::error::Error>> {
+    ) -> Result<serde_json::Value, ServerError> {
         use manager::ReadQueryParams::*;
         use rustix_bl::datastore::DatastoreQueries;
         use server::*;
@@ -542,7 +526,7 @@ impl ServableRustix for ServableRustixImpl {
 
                 for uid in xs_full {
                     if !highlight_users.contains(&uid)
-                        && (param.n - highlighted - (xs.len() as u16)) > 0
+                        && (highlighted as u32 + xs.len() as u32) < param.n as u32
                     {
                         xs.push(uid);
                     }
@@ -553,7 +537,11 @@ impl ServableRustix for ServableRustixImpl {
                     total += 1;
                     match backend.datastore.users.get(&id) {
                         Some(user) => v.push(user.clone()),
-                        None => panic!("Userkey for topuser not found in user hashmap"),
+                        None => {
+                            return Err(ServerError::Internal(
+                                "Userkey for topuser not found in user hashmap".to_string(),
+                            ))
+                        }
                     }
                 }
 
@@ -561,7 +549,12 @@ impl ServableRustix for ServableRustixImpl {
                     total += 1;
                     match backend.datastore.users.get(&id) {
                         Some(user) => v.push(user.clone()),
-                        None => panic!("Userkey for highlighted user not found in user hashmap"),
+                        None => {
+                            return Err(ServerError::Internal(
+                                "Userkey for highlighted user not found in user hashmap"
+                                    .to_string(),
+                            ))
+                        }
                     }
                 }
 
@@ -625,7 +618,7 @@ impl ServableRustix for ServableRustixImpl {
                                 .datastore
                                 .items
                                 .get(x.get_item_id())
-                                .unwrap()
+                                .or_not_found("item")?
                                 .cost_cents;
                         }
                         &rustix_bl::datastore::Purchase::FFAPurchase {
@@ -640,7 +633,7 @@ impl ServableRustix for ServableRustixImpl {
                                 .datastore
                                 .items
                                 .get(x.get_item_id())
-                                .unwrap()
+                                .or_not_found("item")?
                                 .cost_cents;
                         }
                     }
@@ -681,7 +674,7 @@ impl ServableRustix for ServableRustixImpl {
                                         .finalized_data
                                         .all_items
                                         .get(&item_id)
-                                        .unwrap()
+                                        .or_not_found("item")?
                                         .cost_cents;
                                     previouscost += cost_once * count;
                                 }
@@ -700,6 +693,7 @@ impl ServableRustix for ServableRustixImpl {
                         last_bill_date: bill.map(|b| b.timestamp_to).unwrap_or(0i64),
                         last_bill_cost: previouscost,
                         currently_cost: cost,
+                        prepaid_balance_cents: None,
                     }],
                 };
 
@@ -708,9 +702,10 @@ impl ServableRustix for ServableRustixImpl {
             TopPersonalDrinks(param) => {
                 let xs = backend.datastore.top_item_ids(param.user_id, param.n);
 
-                let v: Vec<rustix_bl::datastore::Item> = xs.iter()
-                    .map(|id| backend.datastore.items.get(id).unwrap().clone())
-                    .collect();
+                let mut v: Vec<rustix_bl::datastore::Item> = Vec::new();
+                for id in xs.iter() {
+                    v.push(backend.datastore.items.get(id).or_not_found("item")?.clone());
+                }
 
                 let result: PaginatedResult<rustix_bl::datastore::Item> = PaginatedResult {
                     total_count: v.len() as u32,
@@ -814,7 +809,7 @@ impl ServableRustix for ServableRustixImpl {
                         backend
                             .datastore
                             .get_bill(ts_from, ts_to)
-                            .unwrap_or_error()?
+                            .or_not_found("bill")?
                             .clone()
                     };
 
@@ -835,7 +830,7 @@ impl ServableRustix for ServableRustixImpl {
                             if !touched_users_set.contains(&uid) {
                                 //user matches criteria & isn't in list => add user to list
                                 touched_users_set.insert(uid);
-                                let usr = backend.datastore.users.get(&uid).unwrap_or_error()?;
+                                let usr = backend.datastore.users.get(&uid).or_not_found("user")?;
                                 touched_users.push(usr.clone());
 
                                 let user_idx: usize = touched_users.len() - 1;
@@ -913,10 +908,28 @@ impl ServableRustix for ServableRustixImpl {
 
                 return Ok(b);
             }
-            AllUsersCount(_param) => panic!("Not supported"),
-            AllItemsCount(_param) => panic!("Not supported"),
-            PurchaseLogGlobalCount(_param) => panic!("Not supported"),
-            BillsCount(_param) => panic!("Not supported"),
+            AllUsersCount(param) => {
+                let xs = backend.datastore.users_searchhit_ids(&param.searchterm);
+                return Ok(count_result(xs.len()));
+            }
+            AllItemsCount(param) => {
+                let xs = backend.datastore.items_searchhit_ids(&param.searchterm);
+                return Ok(count_result(xs.len()));
+            }
+            PurchaseLogGlobalCount(param) => {
+                let xs = backend
+                    .datastore
+                    .global_log_filtered(param.millis_start, param.millis_end);
+                return Ok(count_result(xs.len()));
+            }
+            BillsCount(param) => {
+                let xs = backend.datastore.bills_filtered(
+                    param.scope_user_id,
+                    param.start_inclusive,
+                    param.end_exclusive,
+                );
+                return Ok(count_result(xs.len()));
+            }
             OpenFFAFreebies(param) => {
                 let xs: Vec<Freeby> = backend
                     .datastore
@@ -939,8 +952,21 @@ impl ServableRustix for ServableRustixImpl {
                 };
                 return Ok(serde_json::from_str(&serde_json::to_string(&result)?)?);
             }
-            PurchaseLogPersonalCount(_param) => panic!("Not supported"),
-            IncomingFreebiesCount(_param) => panic!("Not supported"),
+            PurchaseLogPersonalCount(param) => {
+                let xs = backend.datastore.personal_log_filtered(
+                    param.user_id,
+                    param.millis_start,
+                    param.millis_end,
+                );
+                return Ok(count_result(xs.len()));
+            }
+            IncomingFreebiesCount(param) => {
+                let count = match backend.datastore.open_freebies.get(&param.recipient_id) {
+                    Some(xs) => xs.len(),
+                    None => 0,
+                };
+                return Ok(count_result(count));
+            }
             IncomingFreebies(param) => {
                 let xsopt = backend
                     .datastore
@@ -969,7 +995,16 @@ impl ServableRustix for ServableRustixImpl {
                 };
                 return Ok(serde_json::from_str(&serde_json::to_string(&result)?)?);
             }
-            OutgoingFreebiesCount(_param) => panic!("Not supported"),
+            OutgoingFreebiesCount(param) => {
+                let mut count: usize = 0;
+                for (_, value) in &backend.datastore.open_freebies {
+                    count += value
+                        .iter()
+                        .filter(|fb| fb.get_donor() == param.donor_id)
+                        .count();
+                }
+                return Ok(count_result(count));
+            }
             OutgoingFreebies(param) => {
                 let mut xs: Vec<Freeby> = Vec::new();
 
@@ -1008,13 +1043,13 @@ impl ServableRustix for ServableRustixImpl {
         backend: &mut Backend,
         app_state: ParametersAll,
         write_event: rustix_bl::rustix_event_shop::BLEvents,
-    ) -> Result<RefreshedData, Box<::std::error::Error>> {
+    ) -> Result<RefreshedData, ServerError> {
         use manager::ReadQueryParams::*;
         use rustix_bl::rustix_backend::WriteBackend;
         match write_event {
             rustix_event_shop::BLEvents::CreateUser { username } => {
                 let username: String = username;
-                let _ = &mut backend.create_user(username);
+                ensure_applied(backend.create_user(username), "CreateUser")?;
                 //refresh only 2 values:
                 //refresh all users
                 let all_list = Self::query_read(&*backend, AllUsers(app_state.all_users))?;
@@ -1042,7 +1077,10 @@ impl ServableRustix for ServableRustixImpl {
                 price_cents,
                 category,
             } => {
-                let _ = &mut backend.create_item(itemname, price_cents, category);
+                ensure_applied(
+                    backend.create_item(itemname, price_cents, category),
+                    "CreateItem",
+                )?;
 
                 let all_list = Self::query_read(&*backend, AllItems(app_state.all_items))?;
 
@@ -1072,14 +1110,17 @@ impl ServableRustix for ServableRustixImpl {
                 is_sepa,
             } => {
                 let username: String = username;
-                let _ = &mut backend.update_user(
-                    user_id,
-                    username,
-                    is_billed,
-                    is_highlighted,
-                    external_user_id,
-                    is_sepa,
-                );
+                ensure_applied(
+                    backend.update_user(
+                        user_id,
+                        username,
+                        is_billed,
+                        is_highlighted,
+                        external_user_id,
+                        is_sepa,
+                    ),
+                    "UpdateUser",
+                )?;
                 //refresh only 2 values:
                 //refresh all users
                 let all_list = Self::query_read(&*backend, AllUsers(app_state.all_users))?;
@@ -1112,7 +1153,10 @@ impl ServableRustix for ServableRustixImpl {
                 price_cents,
                 category,
             } => {
-                let _ = &mut backend.update_item(item_id, itemname, price_cents, category);
+                ensure_applied(
+                    backend.update_item(item_id, itemname, price_cents, category),
+                    "UpdateItem",
+                )?;
 
                 let all_list = Self::query_read(&*backend, AllItems(app_state.all_items))?;
 
@@ -1134,7 +1178,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             rustix_event_shop::BLEvents::DeleteUser { user_id } => {
-                let _ = &mut backend.delete_user(user_id);
+                ensure_applied(backend.delete_user(user_id), "DeleteUser")?;
                 //refresh only 2 values:
                 //refresh all users
                 let all_list = Self::query_read(&*backend, AllUsers(app_state.all_users))?;
@@ -1162,7 +1206,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             rustix_event_shop::BLEvents::DeleteItem { item_id } => {
-                let _ = &mut backend.delete_item(item_id);
+                ensure_applied(backend.delete_item(item_id), "DeleteItem")?;
 
                 let all_list = Self::query_read(&*backend, AllItems(app_state.all_items))?;
 
@@ -1190,7 +1234,10 @@ impl ServableRustix for ServableRustixImpl {
             } => {
                 //make simple (non-ffa, non-special) purchase
 
-                let _ = &mut backend.purchase(user_id, item_id, timestamp);
+                ensure_applied(
+                    backend.purchase(user_id, item_id, timestamp),
+                    "MakeSimplePurchase",
+                )?;
 
                 //refresh 5 values:
                 //refresh top users
@@ -1227,11 +1274,14 @@ impl ServableRustix for ServableRustixImpl {
 
                 //TODO: fix freebies
 
+                //stock changed, the item list carries the low stock warnings
+                let all_items = Self::query_read(&*backend, AllItems(app_state.all_items))?;
+
                 Ok(RefreshedData {
                     DetailInfoForUser: detail_info,
                     TopUsers: top_list,
                     AllUsers: serde_json::Value::Null,
-                    AllItems: serde_json::Value::Null,
+                    AllItems: all_items,
                     PurchaseLogGlobal: global_log,
                     LastPurchases: last_log,
                     BillsCount: serde_json::Value::Null,
@@ -1247,7 +1297,7 @@ impl ServableRustix for ServableRustixImpl {
             rustix_event_shop::BLEvents::UndoPurchase { unique_id } => {
                 //make simple (non-ffa, non-special) purchase
 
-                let _ = &mut backend.undo_purchase(unique_id);
+                ensure_applied(backend.undo_purchase(unique_id), "UndoPurchase")?;
 
                 //refresh 5 values:
                 //refresh top users
@@ -1284,11 +1334,14 @@ impl ServableRustix for ServableRustixImpl {
 
                 //TODO: fix freebies
 
+                //stock changed, the item list carries the low stock warnings
+                let all_items = Self::query_read(&*backend, AllItems(app_state.all_items))?;
+
                 Ok(RefreshedData {
                     DetailInfoForUser: detail_info,
                     TopUsers: top_list,
                     AllUsers: serde_json::Value::Null,
-                    AllItems: serde_json::Value::Null,
+                    AllItems: all_items,
                     PurchaseLogGlobal: global_log,
                     LastPurchases: last_log,
                     BillsCount: serde_json::Value::Null,
@@ -1302,7 +1355,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::MakeFreeForAllPurchase { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "MakeFreeForAllPurchase")?;
                 //refresh global log
                 //refresh lastpurchase
                 //open ffa freebies
@@ -1326,11 +1379,14 @@ impl ServableRustix for ServableRustixImpl {
                 let open_ffa =
                     Self::query_read(&*backend, OpenFFAFreebies(app_state.open_ffa_freebies))?;
 
+                //stock changed, the item list carries the low stock warnings
+                let all_items = Self::query_read(&*backend, AllItems(app_state.all_items))?;
+
                 Ok(RefreshedData {
                     DetailInfoForUser: serde_json::Value::Null,
                     TopUsers: serde_json::Value::Null,
                     AllUsers: serde_json::Value::Null,
-                    AllItems: serde_json::Value::Null,
+                    AllItems: all_items,
                     PurchaseLogGlobal: global_log,
                     LastPurchases: last_log,
                     BillsCount: serde_json::Value::Null,
@@ -1344,7 +1400,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::CreateFreeForAll { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "CreateFreeForAll")?;
                 //refresh ffa
                 let open_ffa =
                     Self::query_read(&*backend, OpenFFAFreebies(app_state.open_ffa_freebies))?;
@@ -1366,7 +1422,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::CreateFreeCount { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "CreateFreeCount")?;
                 //refresh incoming freebies
                 //refresh outgoing freebies
                 let incoming =
@@ -1391,7 +1447,7 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::CreateFreeBudget { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "CreateFreeBudget")?;
                 //refresh incoming freebies
                 //refresh outgoing freebies
                 let incoming =
@@ -1416,11 +1472,18 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             _a @ rustix_event_shop::BLEvents::MakeSpecialPurchase { .. } => {
-                panic!("MakeSpecialPurchase supported at the moment (use CartPurchase instead!)")
+                Err(ServerError::BadRequest(
+                    "MakeSpecialPurchase is not supported at the moment (use CartPurchase instead!)"
+                        .to_string(),
+                ))
             }
             a @ rustix_event_shop::BLEvents::CreateBill { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "CreateBill")?;
                 //refresh bills
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
                 Ok(RefreshedData {
                     DetailInfoForUser: serde_json::Value::Null,
@@ -1429,7 +1492,7 @@ impl ServableRustix for ServableRustixImpl {
                     AllItems: serde_json::Value::Null,
                     PurchaseLogGlobal: serde_json::Value::Null,
                     LastPurchases: serde_json::Value::Null,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: serde_json::Value::Null,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1441,9 +1504,7 @@ impl ServableRustix for ServableRustixImpl {
             }
             a @ rustix_event_shop::BLEvents::FinalizeBill { .. } => {
                 info!("Trying to finalize bill");
-                let _b = &mut backend.apply(&a);
-
-                info!("_b = {}", _b);
+                ensure_applied(backend.apply(&a), "FinalizeBill")?;
 
                 //refresh bills
                 //refresh incoming
@@ -1465,6 +1526,10 @@ impl ServableRustix for ServableRustixImpl {
                         },
                     }),
                 )?;
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
 
                 let changed_bill_details =
@@ -1487,7 +1552,7 @@ impl ServableRustix for ServableRustixImpl {
                         PurchaseLogGlobal(app_state.global_log),
                     )?,
                     LastPurchases: last_log,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: changed_bill_details,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1501,8 +1566,12 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::ExportBill { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "ExportBill")?;
                 //refresh bills
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
 
                 let changed_bill_details =
@@ -1515,7 +1584,7 @@ impl ServableRustix for ServableRustixImpl {
                     AllItems: serde_json::Value::Null,
                     PurchaseLogGlobal: serde_json::Value::Null,
                     LastPurchases: serde_json::Value::Null,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: changed_bill_details,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1526,8 +1595,12 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::DeleteUnfinishedBill { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "DeleteUnfinishedBill")?;
                 //refresh bills
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
                 Ok(RefreshedData {
                     DetailInfoForUser: serde_json::Value::Null,
@@ -1536,7 +1609,7 @@ impl ServableRustix for ServableRustixImpl {
                     AllItems: serde_json::Value::Null,
                     PurchaseLogGlobal: serde_json::Value::Null,
                     LastPurchases: serde_json::Value::Null,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: serde_json::Value::Null,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1547,8 +1620,12 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::UpdateBill { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "UpdateBill")?;
                 //refresh bills
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
 
                 let changed_bill_details =
@@ -1561,7 +1638,7 @@ impl ServableRustix for ServableRustixImpl {
                     AllItems: serde_json::Value::Null,
                     PurchaseLogGlobal: serde_json::Value::Null,
                     LastPurchases: serde_json::Value::Null,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: changed_bill_details,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1572,8 +1649,12 @@ impl ServableRustix for ServableRustixImpl {
                 })
             }
             a @ rustix_event_shop::BLEvents::SetPriceForSpecial { .. } => {
-                let _b = &mut backend.apply(&a);
+                ensure_applied(backend.apply(&a), "SetPriceForSpecial")?;
                 //refresh bills
+                let bills_count = Self::query_read(
+                    &*backend,
+                    BillsCount(app_state.bills.count_pars.clone()),
+                )?;
                 let bills = Self::query_read(&*backend, Bills(app_state.bills))?;
 
                 let changed_bill_details =
@@ -1586,7 +1667,7 @@ impl ServableRustix for ServableRustixImpl {
                     AllItems: serde_json::Value::Null,
                     PurchaseLogGlobal: serde_json::Value::Null,
                     LastPurchases: serde_json::Value::Null,
-                    BillsCount: serde_json::Value::Null,
+                    BillsCount: bills_count,
                     Bills: bills,
                     BillDetails: changed_bill_details,
                     OpenFFAFreebies: serde_json::Value::Null,
@@ -1602,7 +1683,10 @@ impl ServableRustix for ServableRustixImpl {
                 item_ids,
                 timestamp,
             } => {
-                let _b = &mut backend.cart_purchase(user_id, specials, item_ids, timestamp);
+                ensure_applied(
+                    backend.cart_purchase(user_id, specials, item_ids, timestamp),
+                    "MakeShoppingCartPurchase",
+                )?;
 
                 //refresh 5 values:
                 //refresh top users
@@ -1639,11 +1723,14 @@ impl ServableRustix for ServableRustixImpl {
 
                 //TODO: fix freebies
 
+                //stock changed, the item list carries the low stock warnings
+                let all_items = Self::query_read(&*backend, AllItems(app_state.all_items))?;
+
                 Ok(RefreshedData {
                     DetailInfoForUser: detail_info,
                     TopUsers: top_list,
                     AllUsers: serde_json::Value::Null,
-                    AllItems: serde_json::Value::Null,
+                    AllItems: all_items,
                     PurchaseLogGlobal: global_log,
                     LastPurchases: last_log,
                     BillsCount: serde_json::Value::Null,
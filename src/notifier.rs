@@ -0,0 +1,501 @@
+use configuration::ServerConfig;
+use dunning::DunningBook;
+use jwt;
+use mailqueue::{MailDraft, MailQueue};
+use reqwest;
+use serde_json;
+use server::{blocking_http_post_call_without_response, Backend};
+use sidecar;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use typescriptify::TypeScriptifyTrait;
+use workers::Worker;
+
+pub const SIGNATURE_HEADER: &str = "X-Cervisia-Signature";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, TypeScriptify)]
+pub enum NotificationKind {
+    Purchase,
+    GiveoutReceived,
+    BillReady,
+    LowBalance,
+}
+
+pub const ALL_NOTIFICATION_KINDS: [NotificationKind; 4] = [
+    NotificationKind::Purchase,
+    NotificationKind::GiveoutReceived,
+    NotificationKind::BillReady,
+    NotificationKind::LowBalance,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationChannel {
+    Webhook,
+    Mail,
+    //the push format of the AVH app, configured by the notification_* settings at the top level
+    Avh,
+}
+
+//configured in the [notifications] table of the config file, every kind lists the channels it is sent to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationSettings {
+    pub purchase: Vec<NotificationChannel>,
+    pub giveout_received: Vec<NotificationChannel>,
+    pub bill_ready: Vec<NotificationChannel>,
+    pub low_balance: Vec<NotificationChannel>,
+    //purchases are only reported if the previous one is longer ago, so regulars are not spammed
+    pub purchase_quiet_days: i64,
+    //prepaid balances falling below this amount trigger a LowBalance notification
+    pub low_balance_threshold_cents: i64,
+    pub webhook_url: Option<String>,
+    //signs the webhook body with HMAC-SHA256, the signature is sent base64url encoded in the X-Cervisia-Signature header
+    pub webhook_secret: Option<String>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        return NotificationSettings {
+            purchase: vec![NotificationChannel::Avh],
+            giveout_received: Vec::new(),
+            bill_ready: Vec::new(),
+            low_balance: Vec::new(),
+            purchase_quiet_days: 7,
+            low_balance_threshold_cents: 500,
+            webhook_url: None,
+            webhook_secret: None,
+        };
+    }
+}
+
+impl NotificationSettings {
+    pub fn channels(&self, kind: NotificationKind) -> &[NotificationChannel] {
+        return match kind {
+            NotificationKind::Purchase => &self.purchase,
+            NotificationKind::GiveoutReceived => &self.giveout_received,
+            NotificationKind::BillReady => &self.bill_ready,
+            NotificationKind::LowBalance => &self.low_balance,
+        };
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        let uses_webhook = ALL_NOTIFICATION_KINDS
+            .iter()
+            .any(|k| self.channels(*k).contains(&NotificationChannel::Webhook));
+        if uses_webhook && self.webhook_url.is_none() {
+            problems.push("notifications.webhook_url must be set when notifications are sent to the webhook".to_string());
+        }
+        if self.purchase_quiet_days < 0 {
+            problems.push("notifications.purchase_quiet_days must not be negative".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub user_id: u32,
+    pub username: String,
+    pub external_user_id: Option<String>,
+    //only needed by the mail channel, webhooks never see the address
+    #[serde(skip)]
+    pub email: Option<String>,
+    pub title: String,
+    pub body: String,
+    pub timestamp_epoch_millis: i64,
+}
+
+pub trait Notifier: Send {
+    fn channel(&self) -> NotificationChannel;
+    fn notify(&self, notification: &Notification) -> Result<(), String>;
+}
+
+pub struct WebhookNotifier {
+    pub url: String,
+    pub secret: Option<String>,
+}
+
+pub fn sign(body: &str, secret: &str) -> Result<String, String> {
+    return jwt::sign(body, secret.as_bytes(), jwt::Algorithm::HS256).map_err(|e| e.to_string());
+}
+
+impl Notifier for WebhookNotifier {
+    fn channel(&self) -> NotificationChannel {
+        return NotificationChannel::Webhook;
+    }
+
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let body = serde_json::to_string(notification).map_err(|e| e.to_string())?;
+        let client = reqwest::Client::new();
+        let mut request = client.post(&self.url);
+        if let Some(ref secret) = self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(&body, secret)?);
+        }
+        let res = request.body(body).send().map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("webhook answered with status {}", res.status()));
+        }
+        return Ok(());
+    }
+}
+
+pub struct MailNotifier {
+    pub queue: Arc<RwLock<MailQueue>>,
+}
+
+impl Notifier for MailNotifier {
+    fn channel(&self) -> NotificationChannel {
+        return NotificationChannel::Mail;
+    }
+
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let receiver = match notification.email {
+            Some(ref email) => email.to_string(),
+            None => return Err(format!("user {} has no contact address", notification.user_id)),
+        };
+        let mut queue = self.queue.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.enqueue(
+            MailDraft {
+                receiver_email: receiver,
+                subject: notification.title.to_string(),
+                body: format!("Hello {},\n\n{}\n", notification.username, notification.body),
+                attachments: HashMap::new(),
+                binary_attachments: HashMap::new(),
+                zipfilename: format!("notification_{}", notification.timestamp_epoch_millis),
+            },
+            notification.timestamp_epoch_millis / 1000,
+        );
+        queue.save();
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+struct AvhMessage {
+    targetType: String,
+    targetIdentifier: String,
+    senderId: String,
+    title: String,
+    body: String,
+}
+
+pub struct AvhNotifier {
+    pub url: String,
+    pub api_key: String,
+    pub api_id: String,
+}
+
+impl Notifier for AvhNotifier {
+    fn channel(&self) -> NotificationChannel {
+        return NotificationChannel::Avh;
+    }
+
+    //the app addresses members by their SEWOBE number
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let target = match notification.external_user_id {
+            Some(ref nr) => nr.to_string(),
+            None => return Err(format!("user {} has no external user id", notification.user_id)),
+        };
+        return blocking_http_post_call_without_response(
+            &(self.url.to_string() + "?senderSecret=" + &self.api_key),
+            &AvhMessage {
+                targetType: "NR".to_string(),
+                targetIdentifier: target,
+                senderId: self.api_id.to_string(),
+                title: notification.title.to_string(),
+                body: notification.body.to_string(),
+            },
+        )
+        .map_err(|e| e.to_string());
+    }
+}
+
+//channels without the settings they need are left out
+pub fn notifiers_for(
+    kind: NotificationKind,
+    config: &ServerConfig,
+    mail_queue: &Arc<RwLock<MailQueue>>,
+) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    for channel in config.notifications.channels(kind) {
+        match *channel {
+            NotificationChannel::Webhook => {
+                if let Some(ref url) = config.notifications.webhook_url {
+                    notifiers.push(Box::new(WebhookNotifier {
+                        url: url.to_string(),
+                        secret: config.notifications.webhook_secret.clone(),
+                    }));
+                }
+            }
+            NotificationChannel::Mail => {
+                notifiers.push(Box::new(MailNotifier {
+                    queue: mail_queue.clone(),
+                }));
+            }
+            NotificationChannel::Avh => {
+                if config.notification_enable {
+                    notifiers.push(Box::new(AvhNotifier {
+                        url: config.notification_url.to_string(),
+                        api_key: config.notification_api_key.to_string(),
+                        api_id: config.notification_api_id.to_string(),
+                    }));
+                }
+            }
+        }
+    }
+    return notifiers;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct UserNotificationPreferences {
+    pub user_id: u32,
+    //everything is opted in until the user switches it off
+    pub disabled: Vec<NotificationKind>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetNotificationPreference {
+    pub user_id: u32,
+    pub kind: NotificationKind,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NotificationPreferences {
+    pub users: Vec<UserNotificationPreferences>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl NotificationPreferences {
+    pub fn load(config: &ServerConfig) -> NotificationPreferences {
+        let path = match sidecar::sidecar_path(config, "notification_preferences") {
+            Some(path) => path,
+            None => return NotificationPreferences::default(),
+        };
+        let mut preferences: NotificationPreferences = sidecar::load_json_or_default(&path, "notification preferences");
+        preferences.path = Some(path);
+        return preferences;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist notification preferences to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn get(&self, user_id: u32) -> UserNotificationPreferences {
+        return self
+            .users
+            .iter()
+            .find(|u| u.user_id == user_id)
+            .cloned()
+            .unwrap_or(UserNotificationPreferences {
+                user_id: user_id,
+                disabled: Vec::new(),
+            });
+    }
+
+    pub fn wants(&self, user_id: u32, kind: NotificationKind) -> bool {
+        return !self.get(user_id).disabled.contains(&kind);
+    }
+
+    pub fn set(&mut self, preference: &SetNotificationPreference) -> UserNotificationPreferences {
+        let mut entry = self.get(preference.user_id);
+        entry.disabled.retain(|k| *k != preference.kind);
+        if !preference.enabled {
+            entry.disabled.push(preference.kind);
+        }
+        self.users.retain(|u| u.user_id != preference.user_id);
+        if !entry.disabled.is_empty() {
+            self.users.push(entry.clone());
+            self.users.sort_by_key(|u| u.user_id);
+        }
+        return entry;
+    }
+}
+
+struct NotificationJob {
+    notifiers: Vec<Box<dyn Notifier>>,
+    notification: Notification,
+}
+
+//a single worker delivers all notifications, so slow endpoints never hold up a purchase
+pub struct NotificationDispatcher {
+    sender: Mutex<Sender<NotificationJob>>,
+}
+
+impl NotificationDispatcher {
+    //the worker belongs to the server, notifications still queued when it is closed are dropped
+    pub fn start() -> (NotificationDispatcher, Worker) {
+        let (sender, receiver) = channel::<NotificationJob>();
+        //waiting only briefly for the next job, so the worker notices when it should stop
+        let worker = Worker::spawn_paced("notifications", move || {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(job) => {
+                    for notifier in &job.notifiers {
+                        if let Err(e) = notifier.notify(&job.notification) {
+                            warn!(
+                                "Could not send {:?} notification for user {} via {:?}: {}",
+                                job.notification.kind,
+                                job.notification.user_id,
+                                notifier.channel(),
+                                e
+                            );
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Duration::from_secs(1),
+            }
+            return Duration::from_millis(0);
+        });
+        let dispatcher = NotificationDispatcher {
+            sender: Mutex::new(sender),
+        };
+        return (dispatcher, worker);
+    }
+
+    pub fn dispatch(&self, notifiers: Vec<Box<dyn Notifier>>, notification: Notification) {
+        if notifiers.is_empty() {
+            return;
+        }
+        let sender = self.sender.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if sender
+            .send(NotificationJob {
+                notifiers: notifiers,
+                notification: notification,
+            })
+            .is_err()
+        {
+            error!("Notification worker is gone, dropping notification");
+        }
+    }
+}
+
+//everything a handler needs to notify users, gathered before the backend is locked
+pub struct NotificationContext {
+    pub dispatcher: Arc<RwLock<NotificationDispatcher>>,
+    pub preferences: Arc<RwLock<NotificationPreferences>>,
+    //mail notifications go to the contact address that is also used for reminders
+    pub contacts: Arc<RwLock<DunningBook>>,
+    pub mail_queue: Arc<RwLock<MailQueue>>,
+}
+
+impl NotificationContext {
+    pub fn notify(
+        &self,
+        dat: &Backend,
+        config: &ServerConfig,
+        user_id: u32,
+        kind: NotificationKind,
+        title: String,
+        body: String,
+        now_epoch_millis: i64,
+    ) {
+        let wanted = self
+            .preferences
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .wants(user_id, kind);
+        if !wanted {
+            return;
+        }
+        let user = match dat.datastore.users.get(&user_id) {
+            Some(user) => user,
+            None => {
+                error!("Cannot find user with user_id = {}", user_id);
+                return;
+            }
+        };
+        let email = self
+            .contacts
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(user_id)
+            .and_then(|u| u.email.clone());
+        let notification = Notification {
+            kind: kind,
+            user_id: user_id,
+            username: user.username.to_string(),
+            external_user_id: user.external_user_id.clone(),
+            email: email,
+            title: title,
+            body: body,
+            timestamp_epoch_millis: now_epoch_millis,
+        };
+        let notifiers = notifiers_for(kind, config, &self.mail_queue);
+        self.dispatcher
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .dispatch(notifiers, notification);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpstub;
+    use notifier::*;
+
+    fn notification() -> Notification {
+        return Notification {
+            kind: NotificationKind::GiveoutReceived,
+            user_id: 3,
+            username: "alice".to_string(),
+            external_user_id: Some("1234".to_string()),
+            email: Some("alice@hostname.org".to_string()),
+            title: "You received a giveout".to_string(),
+            body: "bob gave you 2 drinks".to_string(),
+            timestamp_epoch_millis: 1_500_000_000_000,
+        };
+    }
+
+    #[test]
+    fn preferences_opt_out_and_back_in() {
+        let mut preferences = NotificationPreferences::default();
+        assert!(preferences.wants(3, NotificationKind::Purchase));
+
+        preferences.set(&SetNotificationPreference {
+            user_id: 3,
+            kind: NotificationKind::Purchase,
+            enabled: false,
+        });
+        assert!(!preferences.wants(3, NotificationKind::Purchase));
+        assert!(preferences.wants(3, NotificationKind::BillReady));
+        assert!(preferences.wants(4, NotificationKind::Purchase));
+
+        preferences.set(&SetNotificationPreference {
+            user_id: 3,
+            kind: NotificationKind::Purchase,
+            enabled: true,
+        });
+        assert!(preferences.wants(3, NotificationKind::Purchase));
+        assert!(preferences.users.is_empty());
+    }
+
+    #[test]
+    fn webhook_bodies_are_signed_and_carry_no_mail_address() {
+        let (url, rx) = httpstub::serve_one_request("/hook");
+
+        let notifier = WebhookNotifier {
+            url: url,
+            secret: Some("hook secret".to_string()),
+        };
+        notifier.notify(&notification()).unwrap();
+
+        let request = rx.recv().unwrap();
+        let body = httpstub::body_of(&request).to_string();
+        let expected = format!("{}: {}", SIGNATURE_HEADER.to_lowercase(), sign(&body, "hook secret").unwrap());
+        assert!(request.to_lowercase().contains(&expected.to_lowercase()));
+        assert!(body.contains("\"GiveoutReceived\""));
+        assert!(!body.contains("alice@hostname.org"));
+    }
+}
@@ -0,0 +1,386 @@
+use accounting::AccountingProfile;
+use billformatter::billed_total_cents;
+use configuration::ServerConfig;
+use rustix_bl::datastore::{Bill, BillState, User};
+use sidecar;
+use std;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum PaymentMethod {
+    Cash,
+    Transfer,
+    SepaDirectDebit,
+    //a direct debit the bank of the member sent back, takes back money already booked as paid
+    SepaReturn,
+}
+
+impl PaymentMethod {
+    pub fn sign(&self) -> i64 {
+        return match *self {
+            PaymentMethod::SepaReturn => -1,
+            _ => 1,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct Payment {
+    pub id: u64,
+    pub user_id: u32,
+    //always positive, the method decides whether it is paid or taken back
+    pub amount_cents: i64,
+    pub method: PaymentMethod,
+    pub received_epoch_seconds: i64,
+    //payments without bill settle the oldest open bill of the user first
+    pub bill_timestamp_from: Option<i64>,
+    pub bill_timestamp_to: Option<i64>,
+    pub comment: String,
+}
+
+impl Payment {
+    pub fn signed_cents(&self) -> i64 {
+        return self.method.sign() * self.amount_cents;
+    }
+
+    fn is_for(&self, bill: &Bill) -> bool {
+        return self.bill_timestamp_from == Some(bill.timestamp_from)
+            && self.bill_timestamp_to == Some(bill.timestamp_to);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct CreatePayment {
+    pub user_id: u32,
+    pub amount_cents: i64,
+    pub method: PaymentMethod,
+    //defaults to now
+    pub received_epoch_seconds: Option<i64>,
+    pub bill_timestamp_from: Option<i64>,
+    pub bill_timestamp_to: Option<i64>,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct DeletePayment {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum PaymentStatus {
+    Open,
+    PartiallyPaid,
+    Paid,
+    Overpaid,
+}
+
+impl PaymentStatus {
+    pub fn of(billed_cents: i64, paid_cents: i64) -> PaymentStatus {
+        if paid_cents > billed_cents {
+            return PaymentStatus::Overpaid;
+        }
+        if paid_cents == billed_cents {
+            return PaymentStatus::Paid;
+        }
+        if paid_cents > 0 {
+            return PaymentStatus::PartiallyPaid;
+        }
+        return PaymentStatus::Open;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct BillBalance {
+    pub bill_timestamp_from: i64,
+    pub bill_timestamp_to: i64,
+    pub billed_cents: i64,
+    pub paid_cents: i64,
+    pub open_cents: i64,
+    pub status: PaymentStatus,
+}
+
+//a negative open amount is a credit of the user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct UserBalance {
+    pub user_id: u32,
+    pub username: String,
+    pub billed_cents: i64,
+    pub paid_cents: i64,
+    pub open_cents: i64,
+    pub bills: Vec<BillBalance>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PaymentLedger {
+    pub next_id: u64,
+    pub payments: Vec<Payment>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl PaymentLedger {
+    pub fn load(config: &ServerConfig) -> PaymentLedger {
+        let path = match sidecar::sidecar_path(config, "payments") {
+            Some(path) => path,
+            None => return PaymentLedger::default(),
+        };
+        let mut ledger: PaymentLedger = sidecar::load_json_or_default(&path, "payments");
+        ledger.path = Some(path);
+        return ledger;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist payments to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn add(&mut self, create: CreatePayment, now_epoch_seconds: i64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.payments.push(Payment {
+            id: id,
+            user_id: create.user_id,
+            amount_cents: create.amount_cents,
+            method: create.method,
+            received_epoch_seconds: create.received_epoch_seconds.unwrap_or(now_epoch_seconds),
+            bill_timestamp_from: create.bill_timestamp_from,
+            bill_timestamp_to: create.bill_timestamp_to,
+            comment: create.comment,
+        });
+        return id;
+    }
+
+    //the id stays, everything else is replaced
+    pub fn update(&mut self, payment: Payment) -> bool {
+        return match self.payments.iter_mut().find(|p| p.id == payment.id) {
+            Some(existing) => {
+                *existing = payment;
+                true
+            }
+            None => false,
+        };
+    }
+
+    pub fn remove(&mut self, id: u64) -> bool {
+        let before = self.payments.len();
+        self.payments.retain(|p| p.id != id);
+        return self.payments.len() != before;
+    }
+
+    pub fn filtered(&self, user_id: Option<u32>) -> Vec<Payment> {
+        return self
+            .payments
+            .iter()
+            .filter(|p| user_id.map(|u| u == p.user_id).unwrap_or(true))
+            .cloned()
+            .collect();
+    }
+}
+
+//amounts must be positive and a bill is referenced by both of its timestamps or not at all
+pub fn payment_problems(amount_cents: i64, bill_timestamp_from: Option<i64>, bill_timestamp_to: Option<i64>) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    if amount_cents <= 0 {
+        problems.push("amount_cents must be positive".to_string());
+    }
+    if bill_timestamp_from.is_some() != bill_timestamp_to.is_some() {
+        problems.push("bill_timestamp_from and bill_timestamp_to must be given together".to_string());
+    }
+    return problems;
+}
+
+//payments assigned to a bill count for that bill, all others are spread over the open bills from oldest to newest
+pub fn user_balances(
+    bills: &[Bill],
+    ledger: &PaymentLedger,
+    users: &HashMap<u32, User>,
+    profile: &AccountingProfile,
+) -> Vec<UserBalance> {
+    let mut finalized: Vec<&Bill> = bills
+        .iter()
+        .filter(|b| b.bill_state != BillState::Created)
+        .collect();
+    finalized.sort_by_key(|b| (b.timestamp_to, b.timestamp_from));
+
+    let mut user_ids: BTreeSet<u32> = ledger.payments.iter().map(|p| p.user_id).collect();
+    for bill in &finalized {
+        user_ids.extend(bill.finalized_data.all_users.keys());
+    }
+
+    let mut result: Vec<UserBalance> = Vec::new();
+    for user_id in user_ids {
+        let payments: Vec<&Payment> = ledger.payments.iter().filter(|p| p.user_id == user_id).collect();
+
+        let mut bill_balances: Vec<BillBalance> = Vec::new();
+        for bill in &finalized {
+            let billed_cents = billed_total_cents(bill, &user_id, profile);
+            let paid_cents: i64 = payments
+                .iter()
+                .filter(|p| p.is_for(bill))
+                .map(|p| p.signed_cents())
+                .sum();
+            if billed_cents == 0 && paid_cents == 0 {
+                continue;
+            }
+            bill_balances.push(BillBalance {
+                bill_timestamp_from: bill.timestamp_from,
+                bill_timestamp_to: bill.timestamp_to,
+                billed_cents: billed_cents,
+                paid_cents: paid_cents,
+                open_cents: billed_cents - paid_cents,
+                status: PaymentStatus::of(billed_cents, paid_cents),
+            });
+        }
+
+        //payments for bills that do not exist (anymore) are treated like unassigned ones
+        let mut unassigned: i64 = payments
+            .iter()
+            .filter(|p| !finalized.iter().any(|b| p.is_for(b)))
+            .map(|p| p.signed_cents())
+            .sum();
+        for balance in bill_balances.iter_mut() {
+            if unassigned <= 0 {
+                break;
+            }
+            let settled = std::cmp::min(unassigned, std::cmp::max(balance.open_cents, 0));
+            balance.paid_cents += settled;
+            balance.open_cents -= settled;
+            balance.status = PaymentStatus::of(balance.billed_cents, balance.paid_cents);
+            unassigned -= settled;
+        }
+
+        let billed_cents: i64 = bill_balances.iter().map(|b| b.billed_cents).sum();
+        let paid_cents: i64 = payments.iter().map(|p| p.signed_cents()).sum();
+        let username = users
+            .get(&user_id)
+            .map(|u| u.username.to_string())
+            .or(finalized
+                .iter()
+                .filter_map(|b| b.finalized_data.all_users.get(&user_id))
+                .map(|u| u.username.to_string())
+                .last())
+            .unwrap_or(String::new());
+        result.push(UserBalance {
+            user_id: user_id,
+            username: username,
+            billed_cents: billed_cents,
+            paid_cents: paid_cents,
+            open_cents: billed_cents - paid_cents,
+            bills: bill_balances,
+        });
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use accounting::AccountingProfile;
+    use billformatter::tests::simple_bill;
+    use configuration::ServerConfig;
+    use payments::*;
+    use sidecar;
+    use std;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn payment(amount_cents: i64, method: PaymentMethod, for_bill: bool) -> CreatePayment {
+        let bill = simple_bill();
+        return CreatePayment {
+            user_id: 0,
+            amount_cents: amount_cents,
+            method: method,
+            received_epoch_seconds: None,
+            bill_timestamp_from: if for_bill { Some(bill.timestamp_from) } else { None },
+            bill_timestamp_to: if for_bill { Some(bill.timestamp_to) } else { None },
+            comment: String::new(),
+        };
+    }
+
+    fn alice(ledger: &PaymentLedger) -> UserBalance {
+        let balances = user_balances(&[simple_bill()], ledger, &HashMap::new(), &AccountingProfile::default());
+        return balances.into_iter().find(|b| b.user_id == 0).unwrap();
+    }
+
+    #[test]
+    fn payments_settle_bills_and_returns_reopen_them() {
+        let mut ledger = PaymentLedger::default();
+        assert_eq!(alice(&ledger).open_cents, 129275);
+        assert_eq!(alice(&ledger).bills[0].status, PaymentStatus::Open);
+        assert_eq!(alice(&ledger).username, "alice");
+
+        ledger.add(payment(100000, PaymentMethod::Transfer, true), 1000);
+        assert_eq!(alice(&ledger).bills[0].status, PaymentStatus::PartiallyPaid);
+        assert_eq!(alice(&ledger).open_cents, 29275);
+
+        //cash without bill goes to the oldest open bill
+        let cash = ledger.add(payment(29275, PaymentMethod::Cash, false), 1000);
+        assert_eq!(alice(&ledger).bills[0].status, PaymentStatus::Paid);
+        assert_eq!(alice(&ledger).open_cents, 0);
+
+        ledger.add(payment(29275, PaymentMethod::SepaReturn, true), 2000);
+        assert_eq!(alice(&ledger).bills[0].status, PaymentStatus::PartiallyPaid);
+        assert_eq!(alice(&ledger).bills[0].open_cents, 29275);
+
+        let mut updated = ledger.payments.iter().find(|p| p.id == cash).unwrap().clone();
+        updated.amount_cents = 100000;
+        assert!(ledger.update(updated));
+        assert_eq!(alice(&ledger).open_cents, -41450);
+        assert_eq!(alice(&ledger).bills[0].status, PaymentStatus::Paid);
+
+        assert!(ledger.remove(cash));
+        assert!(!ledger.remove(cash));
+        assert_eq!(ledger.filtered(Some(0)).len(), 2);
+        assert_eq!(ledger.filtered(Some(1)).len(), 0);
+    }
+
+    #[test]
+    fn users_without_charges_or_payments_have_no_bill_balances() {
+        let balances = user_balances(
+            &[simple_bill()],
+            &PaymentLedger::default(),
+            &HashMap::new(),
+            &AccountingProfile::default(),
+        );
+        let bob = balances.iter().find(|b| b.user_id == 1).unwrap();
+        assert!(bob.bills.is_empty());
+        assert_eq!(bob.open_cents, 0);
+
+        assert_eq!(payment_problems(0, Some(1), None).len(), 2);
+        assert!(payment_problems(5, None, None).is_empty());
+    }
+
+    #[test]
+    fn unreadable_ledgers_are_moved_aside_instead_of_overwritten() {
+        let directory = std::env::temp_dir().join(format!("cervisia-payments-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let path = sidecar::sidecar_path(&config, "payments").unwrap();
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(&path, "{\"next_id\": 3, \"payments\": [").unwrap();
+
+        let mut ledger = PaymentLedger::load(&config);
+        ledger.add(payment(500, PaymentMethod::Cash, false), 100);
+        ledger.save();
+
+        let kept: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.contains(".broken-"))
+            .collect();
+        let broken = std::fs::read_to_string(directory.join(&kept[0])).unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(broken, "{\"next_id\": 3, \"payments\": [");
+        assert_eq!(ledger.payments.len(), 1);
+    }
+}
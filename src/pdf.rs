@@ -0,0 +1,209 @@
+//minimal pdf writer for text documents, only uses the standard fonts every viewer ships with
+
+pub const A4_WIDTH: f32 = 595.0;
+pub const A4_HEIGHT: f32 = 842.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Font {
+    Regular,
+    Bold,
+}
+
+impl Font {
+    fn resource_name(&self) -> &'static str {
+        return match *self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PdfPage {
+    content: Vec<u8>,
+}
+
+impl PdfPage {
+    pub fn text(&mut self, x: f32, y: f32, size: f32, font: Font, text: &str) {
+        self.content.extend(
+            format!("BT /{} {:.1} Tf {:.2} {:.2} Td (", font.resource_name(), size, x, y).as_bytes(),
+        );
+        self.content.extend(escape(&encode_win_ansi(text)));
+        self.content.extend(b") Tj ET\n");
+    }
+
+    pub fn text_right(&mut self, right_x: f32, y: f32, size: f32, font: Font, text: &str) {
+        self.text(right_x - text_width(text, size), y, size, font, text);
+    }
+
+    pub fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32) {
+        self.content
+            .extend(format!("{:.2} {:.2} m {:.2} {:.2} l S\n", x1, y1, x2, y2).as_bytes());
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PdfDocument {
+    pages: Vec<PdfPage>,
+}
+
+impl PdfDocument {
+    pub fn new() -> PdfDocument {
+        return PdfDocument::default();
+    }
+
+    pub fn add_page(&mut self) -> &mut PdfPage {
+        self.pages.push(PdfPage::default());
+        return self.pages.last_mut().unwrap();
+    }
+
+    pub fn current_page(&mut self) -> &mut PdfPage {
+        if self.pages.is_empty() {
+            return self.add_page();
+        }
+        return self.pages.last_mut().unwrap();
+    }
+
+    //objects: 1 catalog, 2 page tree, 3 and 4 fonts, then page and content stream per page
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut offsets: Vec<usize> = Vec::new();
+        out.extend(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n");
+
+        let page_ids: Vec<usize> = (0..self.pages.len()).map(|i| 5 + 2 * i).collect();
+        let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                self.pages.len()
+            ).into_bytes(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_vec(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>"
+                .to_vec(),
+        ];
+        for (page, page_id) in self.pages.iter().zip(page_ids.iter()) {
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                    A4_WIDTH,
+                    A4_HEIGHT,
+                    page_id + 1
+                ).into_bytes(),
+            );
+            let mut stream = format!("<< /Length {} >>\nstream\n", page.content.len()).into_bytes();
+            stream.extend(&page.content);
+            stream.extend(b"\nendstream");
+            objects.push(stream);
+        }
+
+        for (index, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n", index + 1).as_bytes());
+            out.extend(object);
+            out.extend(b"\nendobj\n");
+        }
+
+        let xref_offset = out.len();
+        out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in &offsets {
+            out.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        out.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref_offset
+            ).as_bytes(),
+        );
+        return out;
+    }
+}
+
+//latin-1 matches WinAnsi for umlauts and the like, the euro sign has its own slot
+fn encode_win_ansi(text: &str) -> Vec<u8> {
+    return text
+        .chars()
+        .map(|c| match c {
+            '€' => 0x80,
+            c if (c as u32) < 0x80 || ((c as u32) >= 0xA0 && (c as u32) <= 0xFF) => c as u32 as u8,
+            _ => b'?',
+        })
+        .collect();
+}
+
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        if b == b'(' || b == b')' || b == b'\\' {
+            out.push(b'\\');
+        }
+        out.push(b);
+    }
+    return out;
+}
+
+//Helvetica widths per 1000 units, exact for digits and punctuation which is what gets right aligned
+fn glyph_width(c: char) -> f32 {
+    return match c {
+        '0'..='9' | '€' => 556.0,
+        ' ' | ',' | '.' | ':' => 278.0,
+        '-' => 333.0,
+        '%' => 889.0,
+        'A'..='Z' => 667.0,
+        _ => 500.0,
+    };
+}
+
+pub fn text_width(text: &str, size: f32) -> f32 {
+    return text.chars().map(glyph_width).sum::<f32>() * size / 1000.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use pdf::*;
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        return haystack.windows(needle.len()).position(|w| w == needle);
+    }
+
+    #[test]
+    fn xref_table_points_at_every_object() {
+        let mut document = PdfDocument::new();
+        document.add_page().text(50.0, 800.0, 12.0, Font::Bold, "Rechnung (Müller)");
+        document.add_page().text_right(545.0, 800.0, 10.0, Font::Regular, "12,50 €");
+        let bytes = document.to_bytes();
+
+        assert!(bytes.starts_with(b"%PDF-1.4"));
+        assert!(bytes.ends_with(b"%%EOF\n"));
+        assert!(find(&bytes, b"/Count 2").is_some());
+        assert!(find(&bytes, b"(Rechnung \\(M\xFCller\\)) Tj").is_some());
+        assert!(find(&bytes, b"(12,50 \x80) Tj").is_some());
+
+        //everything after the xref keyword is plain ascii
+        let trailer_start = find(&bytes, b"startxref\n").unwrap();
+        let startxref: usize = String::from_utf8(bytes[trailer_start..].to_vec())
+            .unwrap()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(bytes[startxref..].starts_with(b"xref"));
+        let raw = String::from_utf8(bytes[startxref..].to_vec()).unwrap();
+
+        let entries: Vec<usize> = raw
+            .lines()
+            .skip(3)
+            .take_while(|l| l.ends_with(" n "))
+            .map(|l| l[..10].parse().unwrap())
+            .collect();
+        assert_eq!(entries.len(), 8);
+        for (index, offset) in entries.iter().enumerate() {
+            assert!(bytes[*offset..].starts_with(format!("{} 0 obj", index + 1).as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use configuration::ServerConfig;
+use errors::{OrNotFound, ServerError};
+use rustix_bl;
+use rustix_bl::datastore::Bill;
+use server::Backend;
+use sidecar;
+use std;
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+//configured in the [prepaid] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrepaidSettings {
+    //how far a prepaid balance may go below zero, accounts can override it
+    pub default_overdraft_limit_cents: i64,
+}
+
+impl Default for PrepaidSettings {
+    fn default() -> Self {
+        return PrepaidSettings {
+            default_overdraft_limit_cents: 0,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct TopUp {
+    pub amount_cents: i64,
+    pub timestamp_epoch_millis: i64,
+    pub comment: String,
+}
+
+//prepaid users pay in advance, they are left out of every bill export
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct PrepaidAccount {
+    pub user_id: u32,
+    //purchases before this point in time are billed as usual
+    pub opened_epoch_millis: i64,
+    pub overdraft_limit_cents: Option<i64>,
+    pub topups: Vec<TopUp>,
+}
+
+impl PrepaidAccount {
+    pub fn topped_up_cents(&self) -> i64 {
+        return self.topups.iter().map(|t| t.amount_cents).sum();
+    }
+
+    pub fn overdraft_limit(&self, settings: &PrepaidSettings) -> i64 {
+        return self
+            .overdraft_limit_cents
+            .unwrap_or(settings.default_overdraft_limit_cents);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct RecordTopUp {
+    pub user_id: u32,
+    pub amount_cents: i64,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SetOverdraftLimit {
+    pub user_id: u32,
+    //None falls back to the configured default
+    pub overdraft_limit_cents: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct PrepaidBalance {
+    pub user_id: u32,
+    pub topped_up_cents: i64,
+    pub spent_cents: i64,
+    pub balance_cents: i64,
+    pub overdraft_limit_cents: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct PrepaidAccountInfo {
+    pub account: PrepaidAccount,
+    pub balance: PrepaidBalance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PrepaidBook {
+    pub accounts: Vec<PrepaidAccount>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl PrepaidBook {
+    pub fn load(config: &ServerConfig) -> PrepaidBook {
+        let path = match sidecar::sidecar_path(config, "prepaid") {
+            Some(path) => path,
+            None => return PrepaidBook::default(),
+        };
+        let mut book: PrepaidBook = sidecar::load_json_or_default(&path, "prepaid accounts");
+        book.path = Some(path);
+        return book;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist prepaid accounts to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn get(&self, user_id: u32) -> Option<&PrepaidAccount> {
+        return self.accounts.iter().find(|a| a.user_id == user_id);
+    }
+
+    //the first top up opens the account
+    pub fn entry(&mut self, user_id: u32, now_epoch_millis: i64) -> &mut PrepaidAccount {
+        if self.get(user_id).is_none() {
+            self.accounts.push(PrepaidAccount {
+                user_id: user_id,
+                opened_epoch_millis: now_epoch_millis,
+                overdraft_limit_cents: None,
+                topups: Vec::new(),
+            });
+            self.accounts.sort_by_key(|a| a.user_id);
+        }
+        return self.accounts.iter_mut().find(|a| a.user_id == user_id).unwrap();
+    }
+
+    pub fn top_up(&mut self, top_up: RecordTopUp, now_epoch_millis: i64) -> Result<(), ServerError> {
+        if top_up.amount_cents <= 0 {
+            return Err(ServerError::BadRequest("amount_cents must be positive".to_string()));
+        }
+        self.entry(top_up.user_id, now_epoch_millis).topups.push(TopUp {
+            amount_cents: top_up.amount_cents,
+            timestamp_epoch_millis: now_epoch_millis,
+            comment: top_up.comment,
+        });
+        return Ok(());
+    }
+
+    //bills keep the users, but nobody gets charged twice for what was paid in advance
+    pub fn without_prepaid_users(&self, bill: &Bill) -> Bill {
+        let mut bill = bill.clone();
+        for account in &self.accounts {
+            if account.opened_epoch_millis <= bill.timestamp_from {
+                bill.users_that_will_not_be_billed.insert(account.user_id);
+            }
+        }
+        return bill;
+    }
+}
+
+//what the user consumed on a finalized bill, independent of whether the user would be billed for it
+pub fn bill_spent_cents(bill: &Bill, user_id: u32) -> i64 {
+    let mut spent: i64 = 0;
+    if let Some(consumption) = bill.finalized_data.user_consumption.get(&user_id) {
+        for (_, day) in &consumption.per_day {
+            for (item_id, count) in &day.personally_consumed {
+                if let Some(item) = bill.finalized_data.all_items.get(item_id) {
+                    spent += item.cost_cents as i64 * *count as i64;
+                }
+            }
+            for special in &day.specials_consumed {
+                spent += special.price as i64;
+            }
+        }
+    }
+    return spent;
+}
+
+//finalized purchases leave the purchase log, so they are taken from the bills the account covers
+pub fn spent_cents(backend: &Backend, account: &PrepaidAccount) -> Result<i64, ServerError> {
+    use rustix_bl::datastore::DatastoreQueries;
+    let mut spent: i64 = backend
+        .datastore
+        .bills
+        .iter()
+        .filter(|b| b.bill_state.is_finalized() && account.opened_epoch_millis <= b.timestamp_from)
+        .map(|b| bill_spent_cents(b, account.user_id))
+        .sum();
+    //free for all purchases are paid by the donor
+    for purchase in &backend
+        .datastore
+        .personal_log_filtered(account.user_id, account.opened_epoch_millis, std::i64::MAX)
+    {
+        match purchase {
+            &rustix_bl::datastore::Purchase::SpecialPurchase { ref specialcost, .. } => {
+                spent += specialcost.unwrap_or(0) as i64;
+            }
+            &rustix_bl::datastore::Purchase::SimplePurchase { ref item_id, .. } => {
+                spent += backend.datastore.items.get(item_id).or_not_found("item")?.cost_cents as i64;
+            }
+            &rustix_bl::datastore::Purchase::FFAPurchase { .. } => {}
+        }
+    }
+    return Ok(spent);
+}
+
+pub fn balance(account: &PrepaidAccount, spent_cents: i64, settings: &PrepaidSettings) -> PrepaidBalance {
+    let topped_up_cents = account.topped_up_cents();
+    return PrepaidBalance {
+        user_id: account.user_id,
+        topped_up_cents: topped_up_cents,
+        spent_cents: spent_cents,
+        balance_cents: topped_up_cents - spent_cents,
+        overdraft_limit_cents: account.overdraft_limit(settings),
+    };
+}
+
+//purchases of users without prepaid account are never rejected
+pub fn check_purchase(
+    balance: Option<&PrepaidBalance>,
+    purchase_cents: i64,
+) -> Result<(), ServerError> {
+    if let Some(balance) = balance {
+        if balance.balance_cents - purchase_cents < -balance.overdraft_limit_cents {
+            return Err(ServerError::Conflict(format!(
+                "Prepaid balance of {} cents does not cover the purchase of {} cents",
+                balance.balance_cents, purchase_cents
+            )));
+        }
+    }
+    return Ok(());
+}
+
+pub fn current_balance(
+    backend: &Backend,
+    book: &PrepaidBook,
+    settings: &PrepaidSettings,
+    user_id: u32,
+) -> Result<Option<PrepaidBalance>, ServerError> {
+    return match book.get(user_id) {
+        Some(account) => Ok(Some(balance(account, spent_cents(backend, account)?, settings))),
+        None => Ok(None),
+    };
+}
+
+//rejects the purchase with a conflict if it would take a prepaid user below the overdraft limit
+pub fn check_user_purchase(
+    backend: &Backend,
+    book: &PrepaidBook,
+    settings: &PrepaidSettings,
+    user_id: u32,
+    purchase_cents: i64,
+) -> Result<(), ServerError> {
+    let current = current_balance(backend, book, settings, user_id)?;
+    return check_purchase(current.as_ref(), purchase_cents);
+}
+
+pub fn account_infos(
+    backend: &Backend,
+    book: &PrepaidBook,
+    settings: &PrepaidSettings,
+) -> Result<Vec<PrepaidAccountInfo>, ServerError> {
+    let mut result: Vec<PrepaidAccountInfo> = Vec::new();
+    for account in &book.accounts {
+        result.push(PrepaidAccountInfo {
+            account: account.clone(),
+            balance: balance(account, spent_cents(backend, account)?, settings),
+        });
+    }
+    return Ok(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use billformatter::tests::simple_bill;
+    use errors::ServerError;
+    use prepaid::*;
+
+    fn top_up(book: &mut PrepaidBook, user_id: u32, amount_cents: i64) {
+        book.top_up(
+            RecordTopUp {
+                user_id: user_id,
+                amount_cents: amount_cents,
+                comment: String::new(),
+            },
+            1000,
+        ).unwrap();
+    }
+
+    #[test]
+    fn purchases_beyond_the_overdraft_limit_are_rejected() {
+        let settings = PrepaidSettings {
+            default_overdraft_limit_cents: 200,
+        };
+        let mut book = PrepaidBook::default();
+        top_up(&mut book, 7, 500);
+        top_up(&mut book, 7, 300);
+        assert_eq!(book.accounts.len(), 1);
+
+        let current = balance(book.get(7).unwrap(), 900, &settings);
+        assert_eq!(current.balance_cents, -100);
+        assert!(check_purchase(Some(&current), 100).is_ok());
+        match check_purchase(Some(&current), 101) {
+            Err(ServerError::Conflict(_)) => {}
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert!(check_purchase(None, 100000).is_ok());
+
+        book.entry(7, 2000).overdraft_limit_cents = Some(0);
+        let current = balance(book.get(7).unwrap(), 800, &settings);
+        assert!(check_purchase(Some(&current), 0).is_ok());
+        assert!(check_purchase(Some(&current), 1).is_err());
+        assert!(book
+            .top_up(
+                RecordTopUp {
+                    user_id: 7,
+                    amount_cents: 0,
+                    comment: String::new(),
+                },
+                3000
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn prepaid_users_are_left_out_of_bills() {
+        let mut book = PrepaidBook::default();
+        book.entry(0, 0);
+        let bill = book.without_prepaid_users(&simple_bill());
+        assert!(bill.users_that_will_not_be_billed.contains(&0));
+        assert!(bill.users_that_will_not_be_billed.contains(&1));
+
+        //accounts opened after the bill started do not change it
+        let mut book = PrepaidBook::default();
+        book.entry(0, simple_bill().timestamp_from + 1);
+        assert!(!book
+            .without_prepaid_users(&simple_bill())
+            .users_that_will_not_be_billed
+            .contains(&0));
+    }
+
+    #[test]
+    fn consumption_on_bills_counts_for_every_user() {
+        let mut bill = simple_bill();
+        //3 + 99 beers, 19 sodas and the banana; giveouts are paid by the donor
+        assert_eq!(bill_spent_cents(&bill, 0), 102 * 95 + 19 * 85 + 12345);
+        //a guest without member number is never billed, but still pays for the same consumption
+        bill.finalized_data.all_users.get_mut(&0).unwrap().external_user_id = None;
+        assert_eq!(bill_spent_cents(&bill, 0), 102 * 95 + 19 * 85 + 12345);
+        assert_eq!(bill_spent_cents(&bill, 1), 0);
+        assert_eq!(bill_spent_cents(&bill, 42), 0);
+    }
+}
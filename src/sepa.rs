@@ -0,0 +1,648 @@
+use accounting::AccountingProfile;
+use billformatter::{bill_external_id, billed_total_cents, InOrderableu32};
+use chrono::prelude::*;
+use configuration::ServerConfig;
+use rustix_bl::datastore::Bill;
+use sidecar;
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+const PAIN_008_NAMESPACE: &str = "urn:iso:std:iso:20022:tech:xsd:pain.008.001.02";
+
+//identifiers like MsgId, EndToEndId and MndtId are Max35Text, the remittance info Max140Text
+const MAX_ID_LENGTH: usize = 35;
+const MAX_NAME_LENGTH: usize = 70;
+const MAX_REMITTANCE_LENGTH: usize = 140;
+
+//creditor side of the direct debits, configured in the [sepa] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SepaSettings {
+    pub creditor_name: String,
+    pub creditor_id: String,
+    pub iban: String,
+    pub bic: String,
+    //CORE for consumers, B2B for companies
+    pub local_instrument: String,
+    //FRST, RCUR, OOFF or FNAL
+    pub sequence_type: String,
+    pub days_until_collection: u32,
+}
+
+impl Default for SepaSettings {
+    fn default() -> Self {
+        return SepaSettings {
+            creditor_name: String::new(),
+            creditor_id: String::new(),
+            iban: String::new(),
+            bic: String::new(),
+            local_instrument: "CORE".to_string(),
+            sequence_type: "RCUR".to_string(),
+            days_until_collection: 5,
+        };
+    }
+}
+
+impl SepaSettings {
+    //only checked when exporting, clubs without direct debits never fill in the [sepa] table
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if sepa_text(&self.creditor_name, MAX_NAME_LENGTH).trim().is_empty() {
+            problems.push("sepa.creditor_name must not be empty".to_string());
+        }
+        if self.creditor_id.len() < 8
+            || self.creditor_id.len() > MAX_ID_LENGTH
+            || !self.creditor_id.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            problems.push(format!("sepa.creditor_id '{}' is not a creditor identifier", self.creditor_id));
+        }
+        if !is_valid_iban(&self.iban) {
+            problems.push(format!("sepa.iban '{}' is not a valid IBAN", self.iban));
+        }
+        if !self.bic.is_empty() && !is_valid_bic(&self.bic) {
+            problems.push(format!("sepa.bic '{}' is not a valid BIC", self.bic));
+        }
+        if !["CORE", "B2B"].contains(&self.local_instrument.as_str()) {
+            problems.push("sepa.local_instrument must be CORE or B2B".to_string());
+        }
+        if !["FRST", "RCUR", "OOFF", "FNAL"].contains(&self.sequence_type.as_str()) {
+            problems.push("sepa.sequence_type must be FRST, RCUR, OOFF or FNAL".to_string());
+        }
+        return problems;
+    }
+}
+
+//the debtor side, one mandate per user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct SepaMandate {
+    pub user_id: u32,
+    pub iban: String,
+    //may stay empty for german accounts, exported as NOTPROVIDED then
+    pub bic: String,
+    pub mandate_reference: String,
+    //as YYYY-MM-DD
+    pub mandate_signature_date: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct DeleteSepaMandate {
+    pub user_id: u32,
+}
+
+impl SepaMandate {
+    //banks print IBANs in blocks of four, the export wants them without spaces
+    pub fn normalized(&self) -> SepaMandate {
+        return SepaMandate {
+            user_id: self.user_id,
+            iban: compact(&self.iban),
+            bic: compact(&self.bic),
+            mandate_reference: self.mandate_reference.trim().to_string(),
+            mandate_signature_date: self.mandate_signature_date.trim().to_string(),
+        };
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if !is_valid_iban(&self.iban) {
+            problems.push(format!("'{}' is not a valid IBAN", self.iban));
+        }
+        if !self.bic.is_empty() && !is_valid_bic(&self.bic) {
+            problems.push(format!("'{}' is not a valid BIC", self.bic));
+        }
+        if self.mandate_reference.is_empty()
+            || self.mandate_reference.len() > MAX_ID_LENGTH
+            || sepa_text(&self.mandate_reference, MAX_ID_LENGTH) != self.mandate_reference
+        {
+            problems.push(format!(
+                "mandate reference '{}' must be 1 to 35 characters of the SEPA character set",
+                self.mandate_reference
+            ));
+        }
+        if NaiveDate::parse_from_str(&self.mandate_signature_date, "%Y-%m-%d").is_err() {
+            problems.push(format!(
+                "mandate signature date '{}' is not a date like 2018-07-29",
+                self.mandate_signature_date
+            ));
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SepaMandates {
+    pub mandates: Vec<SepaMandate>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl SepaMandates {
+    pub fn load(config: &ServerConfig) -> SepaMandates {
+        let path = match sidecar::sidecar_path(config, "sepamandates") {
+            Some(path) => path,
+            None => return SepaMandates::default(),
+        };
+        let mut mandates: SepaMandates = sidecar::load_json_or_default(&path, "SEPA mandates");
+        mandates.path = Some(path);
+        return mandates;
+    }
+
+    pub fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist SEPA mandates to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn get(&self, user_id: u32) -> Option<&SepaMandate> {
+        return self.mandates.iter().find(|m| m.user_id == user_id);
+    }
+
+    //replaces an older mandate of the same user
+    pub fn set(&mut self, mandate: SepaMandate) {
+        self.mandates.retain(|m| m.user_id != mandate.user_id);
+        self.mandates.push(mandate);
+        self.mandates.sort_by_key(|m| m.user_id);
+    }
+
+    pub fn remove(&mut self, user_id: u32) -> bool {
+        let before = self.mandates.len();
+        self.mandates.retain(|m| m.user_id != user_id);
+        return self.mandates.len() != before;
+    }
+}
+
+fn compact(text: &str) -> String {
+    return text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+}
+
+//ISO 13616 check digits: move the first four characters to the end, letters count as 10..35, mod 97 must be 1
+pub fn is_valid_iban(iban: &str) -> bool {
+    let iban = compact(iban);
+    if iban.len() < 15 || iban.len() > 34 || !iban.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return false;
+    }
+    let (head, tail) = iban.split_at(4);
+    if !head[..2].chars().all(|c| c.is_ascii_alphabetic())
+        || !head[2..].chars().all(|c| c.is_ascii_digit())
+    {
+        return false;
+    }
+    let mut remainder: u32 = 0;
+    for c in tail.chars().chain(head.chars()) {
+        let value = c.to_digit(36).unwrap();
+        remainder = if value < 10 {
+            (remainder * 10 + value) % 97
+        } else {
+            (remainder * 100 + value) % 97
+        };
+    }
+    return remainder == 1;
+}
+
+pub fn is_valid_bic(bic: &str) -> bool {
+    let bic = compact(bic);
+    return (bic.len() == 8 || bic.len() == 11)
+        && bic.chars().take(6).all(|c| c.is_ascii_alphabetic())
+        && bic.chars().all(|c| c.is_ascii_alphanumeric());
+}
+
+//banks only accept the latin subset of the SEPA character set, umlauts are transliterated
+pub fn sepa_text(text: &str, max_length: usize) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            'ä' => out.push_str("ae"),
+            'ö' => out.push_str("oe"),
+            'ü' => out.push_str("ue"),
+            'Ä' => out.push_str("Ae"),
+            'Ö' => out.push_str("Oe"),
+            'Ü' => out.push_str("Ue"),
+            'ß' => out.push_str("ss"),
+            c if c.is_ascii_alphanumeric() || "/-?:().,'+ ".contains(c) => out.push(c),
+            _ => out.push(' '),
+        }
+    }
+    return out.chars().take(max_length).collect();
+}
+
+fn format_amount(cents: i64) -> String {
+    return format!("{}.{:02}", cents / 100, cents % 100);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectDebit {
+    pub user_id: u32,
+    pub debtor_name: String,
+    pub end_to_end_id: String,
+    pub amount_cents: i64,
+    pub mandate: SepaMandate,
+}
+
+//one debit per SEPA user with a positive total, users without a usable mandate are reported instead
+pub fn direct_debits(
+    bill: &Bill,
+    date_today: i64,
+    profile: &AccountingProfile,
+    mandates: &SepaMandates,
+) -> Result<Vec<DirectDebit>, Vec<String>> {
+    let mut debits: Vec<DirectDebit> = Vec::new();
+    let mut problems: Vec<String> = Vec::new();
+    let users = &bill.finalized_data.all_users;
+
+    for user_id in &users.in_order_keys() {
+        let user = users.get(user_id).unwrap();
+        if !user.is_sepa {
+            continue;
+        }
+        let amount_cents = billed_total_cents(bill, user_id, profile);
+        if amount_cents <= 0 {
+            continue;
+        }
+        let mandate = match mandates.get(*user_id) {
+            Some(mandate) => mandate,
+            None => {
+                problems.push(format!("{} (user {}) has no SEPA mandate", user.username, user_id));
+                continue;
+            }
+        };
+        for problem in mandate.problems() {
+            problems.push(format!("{} (user {}): {}", user.username, user_id, problem));
+        }
+        let external_user_id = user.external_user_id.clone().unwrap_or(String::new());
+        debits.push(DirectDebit {
+            user_id: *user_id,
+            debtor_name: sepa_text(&user.username, MAX_NAME_LENGTH),
+            end_to_end_id: sepa_text(&bill_external_id(date_today, &external_user_id), MAX_ID_LENGTH),
+            amount_cents: amount_cents,
+            mandate: mandate.clone(),
+        });
+    }
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    return Ok(debits);
+}
+
+//indents like a human would, banks do not care but treasurers sometimes open the file
+struct XmlWriter {
+    out: String,
+    depth: usize,
+}
+
+impl XmlWriter {
+    fn open(&mut self, name: &str) {
+        self.out.push_str(&format!("{}<{}>\n", "  ".repeat(self.depth), name));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, name: &str) {
+        self.depth -= 1;
+        self.out.push_str(&format!("{}</{}>\n", "  ".repeat(self.depth), name));
+    }
+
+    //values are either generated or passed through sepa_text, neither contains markup characters
+    fn leaf(&mut self, name: &str, value: &str) {
+        self.out.push_str(&format!("{}<{}>{}</{}>\n", "  ".repeat(self.depth), name, value, name));
+    }
+
+    fn amount(&mut self, name: &str, cents: i64) {
+        self.out.push_str(&format!(
+            "{}<{} Ccy=\"EUR\">{}</{}>\n",
+            "  ".repeat(self.depth),
+            name,
+            format_amount(cents),
+            name
+        ));
+    }
+
+    fn nested(&mut self, names: &[&str], value: &str) {
+        let (last, outer) = names.split_last().unwrap();
+        for name in outer {
+            self.open(name);
+        }
+        self.leaf(last, value);
+        for name in outer.iter().rev() {
+            self.close(name);
+        }
+    }
+}
+
+//customer direct debit initiation in the pain.008.001.02 layout, one payment information block for the whole bill
+pub fn pain008_document(
+    bill: &Bill,
+    date_today: i64,
+    profile: &AccountingProfile,
+    settings: &SepaSettings,
+    mandates: &SepaMandates,
+) -> Result<String, Vec<String>> {
+    let mut problems = settings.problems();
+    let debits = match direct_debits(bill, date_today, profile, mandates) {
+        Ok(debits) => debits,
+        Err(mandate_problems) => {
+            problems.extend(mandate_problems);
+            Vec::new()
+        }
+    };
+    if problems.is_empty() && debits.is_empty() {
+        problems.push("No SEPA user has anything to pay on this bill".to_string());
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let today = Utc.timestamp(date_today / 1000, 0);
+    let collection_day = today + ::chrono::Duration::days(settings.days_until_collection as i64);
+    let message_id = format!("CERVISIA-{}-{}", bill.timestamp_to / 1000, date_today / 1000);
+    let message_id = sepa_text(&message_id, MAX_ID_LENGTH);
+    let number_of_transactions = debits.len().to_string();
+    let control_sum = format_amount(debits.iter().map(|d| d.amount_cents).sum());
+    let creditor_name = sepa_text(&settings.creditor_name, MAX_NAME_LENGTH);
+    let remittance = sepa_text(
+        &format!(
+            "{} {} - {}",
+            profile.bill_name,
+            Utc.timestamp(bill.timestamp_from / 1000, 0).format("%d.%m.%Y"),
+            Utc.timestamp(bill.timestamp_to / 1000, 0).format("%d.%m.%Y")
+        ),
+        MAX_REMITTANCE_LENGTH,
+    );
+
+    let mut xml = XmlWriter {
+        out: "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string(),
+        depth: 0,
+    };
+    xml.out.push_str(&format!(
+        "<Document xmlns=\"{}\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n",
+        PAIN_008_NAMESPACE
+    ));
+    xml.depth = 1;
+    xml.open("CstmrDrctDbtInitn");
+
+    xml.open("GrpHdr");
+    xml.leaf("MsgId", &message_id);
+    xml.leaf("CreDtTm", &today.format("%Y-%m-%dT%H:%M:%S").to_string());
+    xml.leaf("NbOfTxs", &number_of_transactions);
+    xml.leaf("CtrlSum", &control_sum);
+    xml.nested(&["InitgPty", "Nm"], &creditor_name);
+    xml.close("GrpHdr");
+
+    xml.open("PmtInf");
+    xml.leaf("PmtInfId", &sepa_text(&format!("{}-1", message_id), MAX_ID_LENGTH));
+    xml.leaf("PmtMtd", "DD");
+    xml.leaf("NbOfTxs", &number_of_transactions);
+    xml.leaf("CtrlSum", &control_sum);
+    xml.open("PmtTpInf");
+    xml.nested(&["SvcLvl", "Cd"], "SEPA");
+    xml.nested(&["LclInstrm", "Cd"], &settings.local_instrument);
+    xml.leaf("SeqTp", &settings.sequence_type);
+    xml.close("PmtTpInf");
+    xml.leaf("ReqdColltnDt", &collection_day.format("%Y-%m-%d").to_string());
+    xml.nested(&["Cdtr", "Nm"], &creditor_name);
+    xml.nested(&["CdtrAcct", "Id", "IBAN"], &compact(&settings.iban));
+    write_agent(&mut xml, "CdtrAgt", &settings.bic);
+    xml.leaf("ChrgBr", "SLEV");
+    xml.open("CdtrSchmeId");
+    xml.open("Id");
+    xml.open("PrvtId");
+    xml.open("Othr");
+    xml.leaf("Id", &compact(&settings.creditor_id));
+    xml.nested(&["SchmeNm", "Prtry"], "SEPA");
+    xml.close("Othr");
+    xml.close("PrvtId");
+    xml.close("Id");
+    xml.close("CdtrSchmeId");
+
+    for debit in &debits {
+        let mandate = debit.mandate.normalized();
+        xml.open("DrctDbtTxInf");
+        xml.nested(&["PmtId", "EndToEndId"], &debit.end_to_end_id);
+        xml.amount("InstdAmt", debit.amount_cents);
+        xml.open("DrctDbtTx");
+        xml.open("MndtRltdInf");
+        xml.leaf("MndtId", &mandate.mandate_reference);
+        xml.leaf("DtOfSgntr", &mandate.mandate_signature_date);
+        xml.close("MndtRltdInf");
+        xml.close("DrctDbtTx");
+        write_agent(&mut xml, "DbtrAgt", &mandate.bic);
+        xml.nested(&["Dbtr", "Nm"], &debit.debtor_name);
+        xml.nested(&["DbtrAcct", "Id", "IBAN"], &mandate.iban);
+        xml.nested(&["RmtInf", "Ustrd"], &remittance);
+        xml.close("DrctDbtTxInf");
+    }
+
+    xml.close("PmtInf");
+    xml.close("CstmrDrctDbtInitn");
+    xml.out.push_str("</Document>\n");
+    return Ok(xml.out);
+}
+
+//IBAN only payments name the agent as NOTPROVIDED
+fn write_agent(xml: &mut XmlWriter, name: &str, bic: &str) {
+    xml.open(name);
+    xml.open("FinInstnId");
+    if bic.is_empty() {
+        xml.nested(&["Othr", "Id"], "NOTPROVIDED");
+    } else {
+        xml.leaf("BIC", &compact(bic));
+    }
+    xml.close("FinInstnId");
+    xml.close(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use accounting::AccountingProfile;
+    use billformatter::tests::simple_bill;
+    use billformatter::BillFormatting;
+    use sepa::*;
+
+    fn settings() -> SepaSettings {
+        return SepaSettings {
+            creditor_name: "Hüttenverein e.V.".to_string(),
+            creditor_id: "DE98ZZZ09999999999".to_string(),
+            iban: "DE02 1203 0000 0000 2020 51".to_string(),
+            bic: "BYLADEM1001".to_string(),
+            ..SepaSettings::default()
+        };
+    }
+
+    fn alice_mandate() -> SepaMandate {
+        return SepaMandate {
+            user_id: 0,
+            iban: "DE02100500000054540402".to_string(),
+            bic: String::new(),
+            mandate_reference: "CERVISIA-0".to_string(),
+            mandate_signature_date: "2018-01-15".to_string(),
+        };
+    }
+
+    //element name and its children in document order, checked against the sequences of the pain.008.001.02 xsd
+    fn element_children(xml: &str) -> Vec<(String, Vec<String>)> {
+        let mut elements: Vec<(String, Vec<String>)> = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        let mut rest = xml;
+        while let Some(start) = rest.find('<') {
+            let end = rest[start..].find('>').unwrap() + start;
+            let tag = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') {
+                continue;
+            }
+            if tag.starts_with('/') {
+                let open = stack.pop().expect("closing tag without opening tag");
+                assert_eq!(elements[open].0, &tag[1..]);
+                continue;
+            }
+            let name = tag.split_whitespace().next().unwrap().to_string();
+            if let Some(&parent) = stack.last() {
+                elements[parent].1.push(name.to_string());
+            }
+            elements.push((name, Vec::new()));
+            stack.push(elements.len() - 1);
+        }
+        assert!(stack.is_empty(), "unclosed elements");
+        return elements;
+    }
+
+    fn children_of<'a>(elements: &'a [(String, Vec<String>)], name: &str) -> Vec<&'a Vec<String>> {
+        return elements.iter().filter(|e| e.0 == name).map(|e| &e.1).collect();
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        return names.iter().map(|s| s.to_string()).collect();
+    }
+
+    fn text_of(xml: &str, name: &str) -> Vec<String> {
+        let open = format!("<{}", name);
+        return xml
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| l.starts_with(&open) && l.ends_with(&format!("</{}>", name)))
+            .map(|l| l[l.find('>').unwrap() + 1..l.rfind('<').unwrap()].to_string())
+            .collect();
+    }
+
+    #[test]
+    fn pain008_follows_the_schema_sequences() {
+        let mut mandates = SepaMandates::default();
+        mandates.set(alice_mandate());
+        let xml = simple_bill()
+            .format_as_sepa_direct_debit(1532886727279i64, &AccountingProfile::default(), &settings(), &mandates)
+            .unwrap();
+
+        assert!(xml.contains("<Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:pain.008.001.02\""));
+        let elements = element_children(&xml);
+
+        assert_eq!(children_of(&elements, "Document"), vec![&strings(&["CstmrDrctDbtInitn"])]);
+        assert_eq!(
+            children_of(&elements, "CstmrDrctDbtInitn"),
+            vec![&strings(&["GrpHdr", "PmtInf"])]
+        );
+        assert_eq!(
+            children_of(&elements, "GrpHdr"),
+            vec![&strings(&["MsgId", "CreDtTm", "NbOfTxs", "CtrlSum", "InitgPty"])]
+        );
+        assert_eq!(
+            children_of(&elements, "PmtInf"),
+            vec![&strings(&[
+                "PmtInfId",
+                "PmtMtd",
+                "NbOfTxs",
+                "CtrlSum",
+                "PmtTpInf",
+                "ReqdColltnDt",
+                "Cdtr",
+                "CdtrAcct",
+                "CdtrAgt",
+                "ChrgBr",
+                "CdtrSchmeId",
+                "DrctDbtTxInf",
+            ])]
+        );
+        assert_eq!(
+            children_of(&elements, "PmtTpInf"),
+            vec![&strings(&["SvcLvl", "LclInstrm", "SeqTp"])]
+        );
+        assert_eq!(
+            children_of(&elements, "DrctDbtTxInf"),
+            vec![&strings(&[
+                "PmtId",
+                "InstdAmt",
+                "DrctDbtTx",
+                "DbtrAgt",
+                "Dbtr",
+                "DbtrAcct",
+                "RmtInf",
+            ])]
+        );
+        assert_eq!(
+            children_of(&elements, "MndtRltdInf"),
+            vec![&strings(&["MndtId", "DtOfSgntr"])]
+        );
+
+        //only alice is billed, bob and charlie are excluded from the bill
+        assert_eq!(text_of(&xml, "NbOfTxs"), vec!["1", "1"]);
+        assert_eq!(text_of(&xml, "CtrlSum"), vec!["1292.75", "1292.75"]);
+        assert_eq!(text_of(&xml, "InstdAmt"), vec!["1292.75"]);
+        assert_eq!(text_of(&xml, "ReqdColltnDt"), vec!["2018-08-03"]);
+        assert_eq!(text_of(&xml, "Nm"), vec!["Huettenverein e.V.", "Huettenverein e.V.", "alice"]);
+        assert_eq!(text_of(&xml, "IBAN"), vec!["DE02120300000000202051", "DE02100500000054540402"]);
+        assert_eq!(text_of(&xml, "Id").last().unwrap(), "NOTPROVIDED");
+        for id in text_of(&xml, "MsgId")
+            .iter()
+            .chain(text_of(&xml, "EndToEndId").iter())
+            .chain(text_of(&xml, "PmtInfId").iter())
+        {
+            assert!(id.len() <= 35, "{} is too long", id);
+        }
+    }
+
+    #[test]
+    fn missing_mandates_and_creditor_data_are_reported() {
+        let problems = simple_bill()
+            .format_as_sepa_direct_debit(
+                1532886727279i64,
+                &AccountingProfile::default(),
+                &SepaSettings::default(),
+                &SepaMandates::default(),
+            )
+            .unwrap_err();
+        assert!(problems.iter().any(|p| p.starts_with("sepa.creditor_id")));
+        assert!(problems.iter().any(|p| p == "alice (user 0) has no SEPA mandate"));
+        assert_eq!(problems.len(), 4);
+    }
+
+    #[test]
+    fn mandates_are_validated() {
+        assert!(is_valid_iban("DE89 3704 0044 0532 0130 00"));
+        assert!(!is_valid_iban("DE89 3704 0044 0532 0130 01"));
+        assert!(is_valid_bic("COBADEFFXXX"));
+        assert!(!is_valid_bic("COBADE"));
+        assert!(alice_mandate().problems().is_empty());
+
+        let broken = SepaMandate {
+            iban: "DE00123".to_string(),
+            mandate_reference: "Müller & Söhne".to_string(),
+            mandate_signature_date: "15.01.2018".to_string(),
+            ..alice_mandate()
+        };
+        assert_eq!(broken.problems().len(), 3);
+
+        let mut mandates = SepaMandates::default();
+        mandates.set(alice_mandate());
+        mandates.set(SepaMandate {
+            mandate_reference: "CERVISIA-0-B".to_string(),
+            ..alice_mandate()
+        });
+        assert_eq!(mandates.mandates.len(), 1);
+        assert_eq!(mandates.get(0).unwrap().mandate_reference, "CERVISIA-0-B");
+        assert!(mandates.remove(0));
+        assert!(!mandates.remove(0));
+    }
+}
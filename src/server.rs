@@ -5,7 +5,11 @@ use iron::prelude::*;
 use std::collections::*;
 use std::error::Error;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 
+use auth;
+use auth::AdminAuthentication;
+use devices::DeviceAuthentication;
 use configuration;
 use configuration::*;
 use importer::*;
@@ -24,13 +28,29 @@ use std::io::Read;
 
 use billformatter::get_date_today;
 use iron::typemap::Key;
-use jwt::{decode, encode, Algorithm, Header, Validation};
+use jwt::Validation;
 use mail;
+use mailqueue;
+use alerts;
+use barcodes;
+use credentials;
+use devices;
+use dunning;
+use events;
+use idempotency;
+use sync;
+use undo;
+use inventory;
+use notifier;
+use webhooks;
+use workers;
 use manager;
+use payments;
+use prepaid;
+use sepa;
 use manager::fill_backend_with_large_test_data;
 use manager::*;
 use persistent::State;
-use rand::Rng;
 use responsehandlers::*;
 use router::Router;
 use rustix_bl::rustix_backend::WriteBackend;
@@ -56,11 +76,80 @@ struct BillJwtClaims {
     to: i64,
     sewobe: bool,
     user: Option<u32>,
+    //tickets issued before invoices existed are csv tickets
+    #[serde(default)]
+    pdf: bool,
 }
 
 #[derive(Copy, Clone)]
 pub struct SecretKey;
-impl Key for SecretKey { type Value = String; }
+impl Key for SecretKey { type Value = auth::Keyring; }
+
+#[derive(Copy, Clone)]
+pub struct SharedMailQueue;
+impl Key for SharedMailQueue { type Value = mailqueue::MailQueue; }
+
+#[derive(Copy, Clone)]
+pub struct SharedSepaMandates;
+impl Key for SharedSepaMandates { type Value = sepa::SepaMandates; }
+
+#[derive(Copy, Clone)]
+pub struct SharedPaymentLedger;
+impl Key for SharedPaymentLedger { type Value = payments::PaymentLedger; }
+
+#[derive(Copy, Clone)]
+pub struct SharedDunningBook;
+impl Key for SharedDunningBook { type Value = dunning::DunningBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedPrepaidBook;
+impl Key for SharedPrepaidBook { type Value = prepaid::PrepaidBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedInventory;
+impl Key for SharedInventory { type Value = inventory::Inventory; }
+
+#[derive(Copy, Clone)]
+pub struct SharedEventHub;
+impl Key for SharedEventHub { type Value = events::EventHub; }
+
+#[derive(Copy, Clone)]
+pub struct SharedWebhookBook;
+impl Key for SharedWebhookBook { type Value = webhooks::WebhookBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedNotificationDispatcher;
+impl Key for SharedNotificationDispatcher { type Value = notifier::NotificationDispatcher; }
+
+#[derive(Copy, Clone)]
+pub struct SharedNotificationPreferences;
+impl Key for SharedNotificationPreferences { type Value = notifier::NotificationPreferences; }
+
+#[derive(Copy, Clone)]
+pub struct SharedDevices;
+impl Key for SharedDevices { type Value = devices::DeviceBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedCredentials;
+impl Key for SharedCredentials { type Value = credentials::CredentialBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedBarcodes;
+impl Key for SharedBarcodes { type Value = barcodes::BarcodeBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedIdempotency;
+impl Key for SharedIdempotency { type Value = idempotency::IdempotencyBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedUndos;
+impl Key for SharedUndos { type Value = undo::UndoBook; }
+
+#[derive(Copy, Clone)]
+pub struct SharedConfig;
+impl Key for SharedConfig { type Value = ServerConfig; }
+
+const BILL_TICKET_SUBJECT: &str = "bill-download";
 
 fn typescript_definitions() -> Vec<String> {
     return vec![
@@ -117,6 +206,78 @@ fn typescript_definitions() -> Vec<String> {
         responsehandlers::CreateCountGiveout::type_script_ify(),
         responsehandlers::SetPriceForSpecial::type_script_ify(),
         responsehandlers::KeyValue::type_script_ify(),
+        auth::AdminToken::type_script_ify(),
+        mailqueue::MailStatus::type_script_ify(),
+        mailqueue::QueuedMailInfo::type_script_ify(),
+        mailqueue::ResendMail::type_script_ify(),
+        sepa::SepaMandate::type_script_ify(),
+        sepa::DeleteSepaMandate::type_script_ify(),
+        payments::PaymentMethod::type_script_ify(),
+        payments::Payment::type_script_ify(),
+        payments::CreatePayment::type_script_ify(),
+        payments::DeletePayment::type_script_ify(),
+        payments::PaymentStatus::type_script_ify(),
+        payments::BillBalance::type_script_ify(),
+        payments::UserBalance::type_script_ify(),
+        dunning::ReminderRecord::type_script_ify(),
+        dunning::UserDunning::type_script_ify(),
+        dunning::SetDunningContact::type_script_ify(),
+        dunning::PauseDunning::type_script_ify(),
+        prepaid::TopUp::type_script_ify(),
+        prepaid::PrepaidAccount::type_script_ify(),
+        prepaid::RecordTopUp::type_script_ify(),
+        prepaid::SetOverdraftLimit::type_script_ify(),
+        prepaid::PrepaidBalance::type_script_ify(),
+        prepaid::PrepaidAccountInfo::type_script_ify(),
+        inventory::StockMovementKind::type_script_ify(),
+        inventory::StockMovement::type_script_ify(),
+        inventory::ItemStock::type_script_ify(),
+        inventory::Restock::type_script_ify(),
+        inventory::StockCount::type_script_ify(),
+        inventory::Stocktaking::type_script_ify(),
+        inventory::SetLowStockThreshold::type_script_ify(),
+        inventory::StockLevel::type_script_ify(),
+        inventory::LowStockWarning::type_script_ify(),
+        inventory::ShrinkageLine::type_script_ify(),
+        inventory::ShrinkageReport::type_script_ify(),
+        inventory::InventoryReportLine::type_script_ify(),
+        alerts::AlertReason::type_script_ify(),
+        alerts::ReorderLine::type_script_ify(),
+        alerts::StockAlert::type_script_ify(),
+        notifier::NotificationKind::type_script_ify(),
+        notifier::UserNotificationPreferences::type_script_ify(),
+        notifier::SetNotificationPreference::type_script_ify(),
+        webhooks::WebhookSubscriptionInfo::type_script_ify(),
+        webhooks::CreateWebhookSubscription::type_script_ify(),
+        webhooks::WebhookSubscriptionId::type_script_ify(),
+        webhooks::DeliveryStatus::type_script_ify(),
+        webhooks::WebhookDelivery::type_script_ify(),
+        events::LiveChange::type_script_ify(),
+        events::LiveUpdate::type_script_ify(),
+        devices::DeviceRole::type_script_ify(),
+        devices::DeviceStatus::type_script_ify(),
+        devices::DeviceInfo::type_script_ify(),
+        devices::RegisterDevice::type_script_ify(),
+        devices::ApproveDevice::type_script_ify(),
+        devices::DeviceId::type_script_ify(),
+        devices::IssuedDeviceKey::type_script_ify(),
+        credentials::CredentialInfo::type_script_ify(),
+        credentials::PurchaseCredential::type_script_ify(),
+        credentials::SetPin::type_script_ify(),
+        credentials::CardAssignment::type_script_ify(),
+        credentials::SetCredentialRequired::type_script_ify(),
+        credentials::ResolveCard::type_script_ify(),
+        credentials::ResolvedCard::type_script_ify(),
+        sync::SyncEntry::type_script_ify(),
+        sync::SyncBatch::type_script_ify(),
+        sync::SyncStatus::type_script_ify(),
+        sync::SyncEntryResult::type_script_ify(),
+        sync::SyncBatchResult::type_script_ify(),
+        undo::UndoKind::type_script_ify(),
+        undo::UndoActor::type_script_ify(),
+        undo::UndoRecord::type_script_ify(),
+        undo::UndoRefusalReason::type_script_ify(),
+        undo::UndoRefusal::type_script_ify(),
     ];
 }
 
@@ -128,7 +289,27 @@ fn typescript_definition_string() -> String {
     return s;
 }
 
-pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Listening {
+//the listener together with the background workers of this server, closing it stops both
+pub struct CervisiaServer {
+    pub listening: iron::Listening,
+    workers: Vec<workers::Worker>,
+    event_hub: Arc<RwLock<events::EventHub>>,
+}
+
+impl CervisiaServer {
+    pub fn close(&mut self) -> iron::error::HttpResult<()> {
+        //open live streams would otherwise keep their handler threads forever
+        self.event_hub.read().unwrap_or_else(|poisoned| poisoned.into_inner()).close();
+        workers::stop_all(std::mem::replace(&mut self.workers, Vec::new()));
+        return self.listening.close();
+    }
+}
+
+pub fn build_server(live_config: &LiveConfig, backend: Option<Backend>) -> CervisiaServer {
+    //listener, database and keyring are set up once, handlers read the live config per request
+    let config = &current_config(live_config);
+    let mut workers: Vec<workers::Worker> = Vec::new();
+    let event_hub = Arc::new(RwLock::new(events::EventHub::new(config.live_events_max_streams)));
     let mut router = Router::new();
 
     //let endpoints = typescript_definition_string();
@@ -150,8 +331,19 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
     router.get("/users/all", all_users, "allusers");
     router.get("/users/top", top_users, "topusers");
     router.get("/users/detail", user_detail_info, "userdetails");
-    router.get("/items/top", top_items, "topitems");
+    {
+        let config = live_config.clone();
+        router.get(
+            "/items/top",
+            move |req: &mut iron::request::Request| {
+                let conf = current_config(&config);
+                top_items(req, &conf)
+            },
+            "topitems",
+        );
+    }
     router.get("/items/all", all_items, "allitems");
+    router.get("/items/bybarcode", item_by_barcode, "itembybarcode");
     router.get("/purchases/global", global_log, "globallog");
     router.get("/purchases/personal", personal_log, "personallog");
     router.get("/bills", get_bills, "getbills");
@@ -167,6 +359,21 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
         get_outgoing_giveouts,
         "outgoinggiveouts",
     );
+    router.get("/users/count", count_users, "countusers");
+    router.get("/items/count", count_items, "countitems");
+    router.get("/purchases/global/count", count_global_log, "countgloballog");
+    router.get("/purchases/personal/count", count_personal_log, "countpersonallog");
+    router.get("/bills/count", count_bills, "countbills");
+    router.get(
+        "/giveout/incoming/count",
+        count_incoming_giveouts,
+        "countincominggiveouts",
+    );
+    router.get(
+        "/giveout/outgoing/count",
+        count_outgoing_giveouts,
+        "countoutgoinggiveouts",
+    );
     router.post("/users", add_user, "adduser");
     router.post("/items", add_item, "additem");
     router.post("/users/update", update_user, "updateuser");
@@ -175,44 +382,51 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
     router.post("/items/delete", delete_item, "deleteitem");
 
     {
-        let config = config.clone();
+        let config = live_config.clone();
         router.post(
             "/purchases",
             move |req: &mut iron::request::Request| {
-                let conf = config.clone();
+                let conf = current_config(&config);
                 simple_purchase(req, &conf)
             },
             "addsimplepurchase",
         );
     }
     {
-        let config = config.clone();
+        let config = live_config.clone();
         router.post(
             "/purchases/cart",
             move |req: &mut iron::request::Request| {
-                let conf = config.clone();
+                let conf = current_config(&config);
                 cart_purchase(req, &conf)
             },
             "addcartpurchase",
         );
     }
+    router.post("/purchases/ffa", ffa_purchase, "addffapurchase");
     {
-        let config = config.clone();
+        let config = live_config.clone();
         router.post(
-            "/purchases/ffa",
+            "/purchases/sync",
             move |req: &mut iron::request::Request| {
-                let conf = config.clone();
-                ffa_purchase(req, &conf)
+                let conf = current_config(&config);
+                sync_purchases(req, &conf)
             },
-            "addffapurchase",
+            "syncpurchases",
         );
     }
 
-    router.post(
-        "/purchases/undo/user",
-        undo_purchase_by_user,
-        "undopurchaseuser",
-    );
+    {
+        let config = live_config.clone();
+        router.post(
+            "/purchases/undo/user",
+            move |req: &mut iron::request::Request| {
+                let conf = current_config(&config);
+                undo_purchase_by_user(req, &conf)
+            },
+            "undopurchaseuser",
+        );
+    }
     router.post(
         "/purchases/undo/admin",
         undo_purchase_by_admin,
@@ -232,32 +446,114 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
 
     router.get(PATH_PUBLIC_TICKET, public_ticket_receiver, "publicticketreceiver");
     router.get("/public/health", public_health_check, "publichealthcheck");
-    router.get("/bill/download/requestjwt", request_bill_jwt_link, "requestbilljwtlink");
-
+    router.get("/bill/download/sepa", download_sepa_direct_debit, "downloadsepadirectdebit");
     {
-        let config = config.clone();
-        router.post(
-            "/bill/export",
+        let config = live_config.clone();
+        router.get(
+            "/bill/download/requestjwt",
             move |req: &mut iron::request::Request| {
-                let conf = config.clone();
-                export_bill(req, &conf)
+                let conf = current_config(&config);
+                request_bill_jwt_link(req, &conf)
             },
-            "exportbill",
+            "requestbilljwtlink",
         );
     }
 
+    router.post("/bill/export", export_bill, "exportbill");
+
+    //both endpoints answer whether a password is right, so they share one lockout
+    let login_throttle = Arc::new(Mutex::new(auth::LoginThrottle::default()));
     {
-        let config = config.clone();
+        let config = live_config.clone();
+        let throttle = login_throttle.clone();
         router.post(
             "/admin/checkpassword",
             move |req: &mut iron::request::Request| {
-                let conf = config.clone();
-                check_password(req, &conf)
+                let conf = current_config(&config);
+                check_password(req, &conf, &throttle)
             },
             "checkpassword",
         );
     }
 
+    {
+        let config = live_config.clone();
+        let throttle = login_throttle.clone();
+        router.post(
+            "/admin/login",
+            move |req: &mut iron::request::Request| {
+                let conf = current_config(&config);
+                admin_login(req, &conf, &throttle)
+            },
+            "adminlogin",
+        );
+    }
+
+    {
+        let config = live_config.clone();
+        router.post(
+            "/admin/jwt/rotate",
+            move |req: &mut iron::request::Request| {
+                let conf = current_config(&config);
+                rotate_jwt_secret(req, &conf)
+            },
+            "rotatejwtsecret",
+        );
+    }
+
+    router.get("/admin/mails", list_queued_mails, "listqueuedmails");
+    router.post("/admin/mails/resend", resend_queued_mail, "resendqueuedmail");
+    router.get("/admin/sepa/mandates", list_sepa_mandates, "listsepamandates");
+    router.post("/admin/sepa/mandates", set_sepa_mandate, "setsepamandate");
+    router.post("/admin/sepa/mandates/delete", delete_sepa_mandate, "deletesepamandate");
+
+    router.get("/payments", list_payments, "listpayments");
+    router.post("/payments", add_payment, "addpayment");
+    router.post("/payments/update", update_payment, "updatepayment");
+    router.post("/payments/delete", delete_payment, "deletepayment");
+    router.get("/payments/balances", open_balances, "openbalances");
+
+    router.get("/admin/dunning", list_dunning, "listdunning");
+    router.post("/admin/dunning/contact", set_dunning_contact, "setdunningcontact");
+    router.post("/admin/dunning/pause", pause_dunning, "pausedunning");
+
+    router.get("/prepaid", list_prepaid_accounts, "listprepaidaccounts");
+    router.post("/prepaid/topup", top_up_prepaid_account, "topupprepaidaccount");
+    router.post("/prepaid/limit", set_prepaid_overdraft_limit, "setprepaidoverdraftlimit");
+
+    router.get("/inventory", list_stock_levels, "liststocklevels");
+    router.post("/inventory/restock", restock_item, "restockitem");
+    router.post("/inventory/stocktaking", take_stock, "takestock");
+    router.post("/inventory/threshold", set_low_stock_threshold, "setlowstockthreshold");
+    router.get("/inventory/movements", list_stock_movements, "liststockmovements");
+    router.get("/inventory/report", inventory_report, "inventoryreport");
+    router.get("/inventory/reorder", reorder_list, "reorderlist");
+
+    router.get("/notifications/preferences", get_notification_preferences, "getnotificationpreferences");
+    router.post("/notifications/preferences", set_notification_preference, "setnotificationpreference");
+
+    router.get("/events", live_events, "liveevents");
+
+    router.get("/admin/webhooks", list_webhooks, "listwebhooks");
+    router.post("/admin/webhooks", add_webhook, "addwebhook");
+    router.post("/admin/webhooks/delete", delete_webhook, "deletewebhook");
+    router.post("/admin/webhooks/test", test_webhook, "testwebhook");
+    router.get("/admin/webhooks/deliveries", list_webhook_deliveries, "listwebhookdeliveries");
+
+    router.post("/devices/register", register_device, "registerdevice");
+    router.get("/devices/current", current_device, "currentdevice");
+    router.get("/admin/devices", list_devices, "listdevices");
+    router.post("/admin/devices/approve", approve_device, "approvedevice");
+    router.post("/admin/devices/revoke", revoke_device, "revokedevice");
+
+    router.post("/credentials/resolve", resolve_card, "resolvecard");
+    router.post("/credentials/required", set_credential_required, "setcredentialrequired");
+    router.get("/admin/credentials", list_credentials, "listcredentials");
+    router.post("/admin/credentials/pin", set_pin, "setpin");
+    router.post("/admin/credentials/cards", add_card, "addcard");
+    router.post("/admin/credentials/cards/delete", remove_card, "removecard");
+
+    router.get("/admin/undos", list_undos, "listundos");
     router.post(
         "/purchases/special/setprice",
         set_special_price,
@@ -299,26 +595,93 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
             b
         });
 
+        let mut barcode_book = barcodes::BarcodeBook::load(config);
         if !config.use_mock_data {
             import_users_into_store(&mut backend, load_users_json_file());
-            import_items_into_store(&mut backend, load_items_json_file())
+            import_items_into_store(&mut backend, &mut barcode_book, load_items_json_file())
         }
 
-        let state = State::<SharedBackend>::both(backend);
+        //shared with the background workers, which need the bills but are no iron handlers
+        let backend = Arc::new(RwLock::new(backend));
+        let state = State::<SharedBackend>::both(backend.clone());
 
         chain.link(state);
 
 
-        let jwt_secret: String = {
-            let mut rng = rand::thread_rng();
-            let random_nr: u64 = rng.gen();
-            format!("{}", random_nr)
-        };
+        let keyring = auth::load_keyring(config);
 
-        let jwt_state = persistent::Read::<SecretKey>::both(jwt_secret);
+        let jwt_state = State::<SecretKey>::both(keyring);
 
         chain.link(jwt_state);
 
+        //the worker shares the queue with the handlers, so exports never wait for the mail server
+        let mail_queue = Arc::new(RwLock::new(mailqueue::MailQueue::load(config)));
+        workers.push(mailqueue::start_mail_worker(mail_queue.clone(), live_config.clone()));
+
+        chain.link_before(State::<SharedMailQueue>::one(mail_queue.clone()));
+
+        let sepa_mandates = Arc::new(RwLock::new(sepa::SepaMandates::load(config)));
+        chain.link_before(State::<SharedSepaMandates>::one(sepa_mandates));
+
+        let payment_ledger = Arc::new(RwLock::new(payments::PaymentLedger::load(config)));
+        chain.link_before(State::<SharedPaymentLedger>::one(payment_ledger.clone()));
+
+        chain.link_before(State::<SharedEventHub>::one(event_hub.clone()));
+
+        let webhook_book = Arc::new(RwLock::new(webhooks::WebhookBook::load(config)));
+        workers.push(webhooks::start_webhook_worker(webhook_book.clone(), live_config.clone()));
+        chain.link_before(State::<SharedWebhookBook>::one(webhook_book));
+
+        let inventory = Arc::new(RwLock::new(inventory::Inventory::load(config)));
+        chain.link_before(State::<SharedInventory>::one(inventory));
+
+        chain.link_before(State::<SharedBarcodes>::one(Arc::new(RwLock::new(barcode_book))));
+
+        let prepaid_book = Arc::new(RwLock::new(prepaid::PrepaidBook::load(config)));
+        chain.link_before(State::<SharedPrepaidBook>::one(prepaid_book.clone()));
+
+        let dunning_book = Arc::new(RwLock::new(dunning::DunningBook::load(config)));
+        workers.push(dunning::start_dunning_worker(
+            dunning_book.clone(),
+            payment_ledger,
+            prepaid_book,
+            backend.clone(),
+            mail_queue,
+            live_config.clone(),
+        ));
+        chain.link_before(State::<SharedDunningBook>::one(dunning_book));
+
+        let (notification_dispatcher, notification_worker) = notifier::NotificationDispatcher::start();
+        workers.push(notification_worker);
+        let notification_dispatcher = Arc::new(RwLock::new(notification_dispatcher));
+        chain.link_before(State::<SharedNotificationDispatcher>::one(notification_dispatcher));
+
+        let notification_preferences = Arc::new(RwLock::new(notifier::NotificationPreferences::load(config)));
+        chain.link_before(State::<SharedNotificationPreferences>::one(notification_preferences));
+
+        let mut device_book = devices::DeviceBook::load(config);
+        //origins of bills finalized before the restart are only kept in the log
+        device_book.prune_origins(&devices::finalized_periods(
+            &backend.read().unwrap_or_else(|poisoned| poisoned.into_inner()),
+        ));
+        let device_book = Arc::new(RwLock::new(device_book));
+        chain.link_before(State::<SharedDevices>::one(device_book));
+
+        let credential_book = Arc::new(RwLock::new(credentials::CredentialBook::load(config)));
+        chain.link_before(State::<SharedCredentials>::one(credential_book));
+
+        let idempotency_book = Arc::new(RwLock::new(idempotency::IdempotencyBook::load(config)));
+        chain.link_before(State::<SharedIdempotency>::one(idempotency_book));
+
+        let undo_book = Arc::new(RwLock::new(undo::UndoBook::load(config)));
+        chain.link_before(State::<SharedUndos>::one(undo_book));
+
+        chain.link_before(State::<SharedConfig>::one(live_config.clone()));
+
+        //device roles are checked first, admin routes additionally need the admin token
+        chain.link_before(DeviceAuthentication);
+        chain.link_before(AdminAuthentication);
+
         let _ = mount
             .mount("/api/", chain)
             .mount("/", Static::new(Path::new(&config.web_path)));
@@ -326,12 +689,22 @@ pub fn build_server(config: &ServerConfig, backend: Option<Backend>) -> iron::Li
 
     let url = format!("{}:{}", config.host, config.server_port);
     debug!("Starting server under host and port = {}", &url);
-    let serv = Iron::new(mount).http(url).unwrap();
-    return serv;
+    let mut iron = Iron::new(mount);
+    //live event streams never take the threads needed for ordinary requests
+    iron.threads = std::cmp::max(iron.threads, config.live_events_max_streams + MIN_REQUEST_THREADS);
+    let serv = iron.http(url).unwrap();
+    return CervisiaServer {
+        listening: serv,
+        workers: workers,
+        event_hub: event_hub,
+    };
 }
 
 const PATH_PUBLIC_TICKET: &str = "/public/ticket";
 
+//worker threads of the listener which are always left for requests besides the live event streams
+const MIN_REQUEST_THREADS: usize = 8;
+
 pub fn get_ticket_url(jwt: &str) -> String {
     let base_url = std::env::var("CERVISIA_BASE_URL").unwrap_or("http://localhost:8080".to_owned());
     let api_path = std::env::var("CERVISIA_API_PATH").unwrap_or("/api".to_owned());
@@ -341,21 +714,38 @@ pub fn get_ticket_url(jwt: &str) -> String {
 
 pub mod responsehandlers {
     use super::*;
-    use billformatter::BillFormatting;
+    use accounting::AccountingProfile;
+    use billformatter::{billed_total_cents, cents_to_currency_string_i64, BillFormatting};
+    use invoice::{InvoiceFormatting, InvoiceSettings};
     use manager::*;
 
+    use errors::{OrNotFound, ServerError};
     use iron::headers::Charset;
     use iron::headers::ContentDisposition;
     use iron::headers::DispositionParam;
     use iron::headers::DispositionType;
     use iron::mime;
     use rustix_bl::persistencer::Persistencer;
+    use serde::de::DeserializeOwned;
+
+    //returns the matching error response early instead of unwrapping
+    macro_rules! try_or_respond {
+        ($e:expr) => {
+            match $e {
+                Ok(v) => v,
+                Err(err) => return Ok(error_response(err)),
+            }
+        };
+    }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct CreateItem {
         pub name: String,
         pub price_cents: u32,
         pub category: Option<String>,
+        //EAN-8, UPC-A or EAN-13
+        #[serde(default)]
+        pub barcodes: Vec<String>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -369,6 +759,9 @@ pub mod responsehandlers {
         pub item_id: u32,
         pub category: Option<String>,
         pub price_cents: u32,
+        //None keeps the current barcodes, an empty list removes them
+        #[serde(default)]
+        pub barcodes: Option<Vec<String>>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -395,6 +788,9 @@ pub mod responsehandlers {
     pub struct MakeSimplePurchase {
         pub user_id: u32,
         pub item_id: u32,
+        //only needed if the credential policy asks for it
+        #[serde(default)]
+        pub credential: Option<credentials::PurchaseCredential>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
@@ -408,6 +804,8 @@ pub mod responsehandlers {
         pub user_id: u32,
         pub items: Vec<KeyValue>,
         pub specials: Vec<String>,
+        #[serde(default)]
+        pub credential: Option<credentials::PurchaseCredential>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
@@ -442,6 +840,9 @@ pub mod responsehandlers {
         pub timestamp_to: i64,
         pub limit_to_user: Option<u32>,
         pub email_address: String,
+        //only used together with limit_to_user
+        #[serde(default)]
+        pub attach_pdf: bool,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
@@ -490,7 +891,10 @@ pub mod responsehandlers {
     }
 
     fn extract_query(req: &mut iron::request::Request) -> Option<String> {
-        let map = req.get_ref::<Params>().unwrap();
+        let map = match req.get_ref::<Params>() {
+            Ok(map) => map,
+            Err(_) => return None,
+        };
         return match map.find(&["query"]) {
             Some(&Value::String(ref json)) => {
                 return Some(json.to_string());
@@ -501,7 +905,10 @@ pub mod responsehandlers {
 
 
     fn extract_query_param(req: &mut iron::request::Request, key: &str) -> Option<String> {
-        let map = req.get_ref::<Params>().unwrap();
+        let map = match req.get_ref::<Params>() {
+            Ok(map) => map,
+            Err(_) => return None,
+        };
         return match map.find(&[key]) {
             Some(&Value::String(ref json)) => {
                 return Some(json.to_string());
@@ -518,11 +925,24 @@ pub mod responsehandlers {
         return format!("{}_abrechnung.csv", newdate);
     }
 
+    fn build_invoice_filename(timestamp_millis: i64, user_id: u32) -> String {
+        let naive = NaiveDateTime::from_timestamp(timestamp_millis / 1000i64, 0);
+        let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+        let newdate = datetime.format("%Y_%m_%d_%H_%M_%S");
+        return format!("{}_rechnung_{}.pdf", newdate, user_id);
+    }
+
     pub fn list_bills_api(req: &mut iron::request::Request) -> IronResult<Response> {
         use rustix_bl::datastore::DatastoreQueries;
 
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
+        //links in the list have to carry the admin token, otherwise the browser cannot follow them
+        let token_suffix: String = match auth::extract_admin_token(req) {
+            Some(token) => format!("&admin_token={}", token),
+            None => String::new(),
+        };
+
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
         let mut lines: Vec<String> = Vec::new();
 
         lines.push("<ol>".to_string());
@@ -542,7 +962,7 @@ pub mod responsehandlers {
 
 
             for b in result {
-                lines.push(format!("<li> <a href=\"/api/bill/download?from={:?}&to={:?}&sewobeform=true&limitedtouser=none\">/api/bill/download?from={:?}&to={:?}&sewobeform=true&limitedtouser=none</a> </li>", b.timestamp_from, b.timestamp_to, b.timestamp_from, b.timestamp_to));
+                lines.push(format!("<li> <a href=\"/api/bill/download?from={:?}&to={:?}&sewobeform=true&limitedtouser=none{}\">/api/bill/download?from={:?}&to={:?}&sewobeform=true&limitedtouser=none</a> </li>", b.timestamp_from, b.timestamp_to, token_suffix, b.timestamp_from, b.timestamp_to));
             }
         }
         lines.push("</ol>".to_string());
@@ -557,24 +977,28 @@ pub mod responsehandlers {
         return Ok(resp);
     }
 
-    fn get_jwt_for_bill(jwt_secret: &str, from: i64, to: i64, sewobe_form: bool, limit_to_user: Option<u32>) -> String {
-        let expiration_epoch_seconds =  Utc::now().timestamp() + 180i64;
+    pub fn get_jwt_for_bill(keyring: &auth::Keyring, lifetime_seconds: i64, from: i64, to: i64, sewobe_form: bool, limit_to_user: Option<u32>, pdf: bool) -> String {
+        let expiration_epoch_seconds =  Utc::now().timestamp() + lifetime_seconds;
         let my_claims = BillJwtClaims {
-            sub: "bill-download".to_string(),
+            sub: BILL_TICKET_SUBJECT.to_string(),
             exp: expiration_epoch_seconds,
             from,
             to,
             sewobe: sewobe_form,
-            user: limit_to_user
+            user: limit_to_user,
+            pdf: pdf,
         };
-        let token = encode(&Header::default(), &my_claims, jwt_secret.as_bytes()).unwrap_or("invalid-token-generation".to_owned());
-        return token;
+        return keyring.sign(&my_claims);
     }
 
 
-    pub fn request_bill_jwt_link(req: &mut iron::request::Request) -> IronResult<Response> {
-        let arc = req.get::<persistent::Read<SecretKey>>().unwrap();
-        let jwt_secret = arc.as_ref();
+    //purpose=mail produces a long living link meant to be sent to members, everything else a short lived download link
+    //format=pdf links to the pdf invoice of limitedtouser instead of the csv export
+    pub fn request_bill_jwt_link(req: &mut iron::request::Request, conf: &ServerConfig) -> IronResult<Response> {
+        let purpose = match extract_query_param(req, "purpose") {
+            Some(ref p) if p == "mail" => auth::TicketPurpose::BillMail,
+            _ => auth::TicketPurpose::BillDownload,
+        };
 
         let fromstr = extract_query_param(req, "from");
         let tostr = extract_query_param(req, "to");
@@ -584,10 +1008,11 @@ pub mod responsehandlers {
         let use_sewobe_form: bool = sewobeform.unwrap_or("true".to_string()) != "false";
         let from: i64 = fromstr.unwrap_or("no-date-declared".to_string()).parse::<i64>().ok().unwrap_or(0);
         let to: i64 = tostr.unwrap_or("no-date-declared".to_string()).parse::<i64>().ok().unwrap_or(0);
+        let as_pdf: bool = extract_query_param(req, "format") == Some("pdf".to_string());
 
-
-
-        let jwt = get_jwt_for_bill(jwt_secret, from, to, use_sewobe_form, limit_to_user);
+        let keyholder = try_or_respond!(secret_keyring(req));
+        let keyring = keyholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let jwt = get_jwt_for_bill(&keyring, purpose.lifetime_seconds(conf), from, to, use_sewobe_form, limit_to_user, as_pdf);
 
         let mresponsetext : String = get_ticket_url(&jwt);
         let content_type = "text/html".parse::<mime::Mime>().unwrap();
@@ -610,20 +1035,25 @@ pub mod responsehandlers {
     pub fn public_ticket_receiver(req: &mut iron::request::Request) -> IronResult<Response> {
         let jwtstr = extract_query_param(req, "jwt").unwrap_or("no-token-given".to_owned());
 
-        let arc = req.get::<persistent::Read<SecretKey>>().unwrap();
-        let jwt_secret = arc.as_ref();
-
         // treat error case humanely by throwing 404 in that case
-        let token_result = decode::<BillJwtClaims>(&jwtstr, jwt_secret.as_bytes(), &Validation::default());
+        let token_result = {
+            let keyholder = try_or_respond!(secret_keyring(req));
+            let keyring = keyholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let validation = Validation {
+                sub: Some(BILL_TICKET_SUBJECT.to_string()),
+                ..Validation::default()
+            };
+            keyring.verify::<BillJwtClaims>(&jwtstr, &validation)
+        };
         match token_result {
             Err(e) => {
                 let content_type = "text/html".parse::<mime::Mime>().unwrap();
-                return Ok(Response::with((content_type, iron::status::BadRequest, format!("JWT did not parse correctly: {}   <br> with reason: <br>{:?}<br>", jwtstr, e))));
+                return Ok(Response::with((content_type, iron::status::BadRequest, format!("JWT did not parse correctly: {}   <br> with reason: <br>{}<br>", jwtstr, e))));
             }
             Ok(token) => {
                 let claims = token.claims;
                 //retrieve bill for given claims, and return as csv download
-                return build_bill_download(req, claims.user, claims.sewobe, claims.from, claims.to);
+                return build_bill_download(req, claims.user, claims.sewobe, claims.pdf, claims.from, claims.to);
             }
         }
 
@@ -638,34 +1068,59 @@ pub mod responsehandlers {
         let use_sewobe_form: bool = sewobeform.unwrap_or("true".to_string()) != "false";
         let from: i64 = fromstr.unwrap_or("no-date-declared".to_string()).parse::<i64>().ok().unwrap_or(0);
         let to: i64 = tostr.unwrap_or("no-date-declared".to_string()).parse::<i64>().ok().unwrap_or(0);
+        let as_pdf: bool = extract_query_param(req, "format") == Some("pdf".to_string());
+
+        return build_bill_download( req, limit_to_user, use_sewobe_form, as_pdf, from, to);
+    }
+
+    fn build_invoice_download(req: &mut iron::request::Request, limit_to_user: Option<u32>, from: i64, to: i64) -> IronResult<Response> {
+        let user_id = try_or_respond!(limit_to_user.ok_or(ServerError::BadRequest(
+            "Invoices are only available for a single user".to_string()
+        )));
+        let conf = try_or_respond!(shared_config(req));
+        let filecontent: Vec<u8> = {
+            let datholder = try_or_respond!(shared_backend(req));
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            use rustix_bl::datastore::DatastoreQueries;
+            let bill: &rustix_bl::datastore::Bill = try_or_respond!(dat.datastore
+                .get_bill(from, to)
+                .or_not_found("bill with given params"));
+            try_or_respond!(bill
+                .format_as_invoice_pdf(&user_id, &conf.accounting, &conf.invoice, get_date_today())
+                .or_not_found("positions of this user on the bill"))
+        };
 
-        return build_bill_download( req, limit_to_user, use_sewobe_form, from, to);
+        let content_type = "application/pdf".parse::<mime::Mime>().unwrap();
+        let mut resp = Response::with((content_type, iron::status::Ok, filecontent));
+        resp.headers.set(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Iso_8859_1,
+                None,
+                build_invoice_filename(to, user_id).into_bytes(),
+            )],
+        });
+        return Ok(resp);
     }
 
-    fn build_bill_download(req: &mut iron::request::Request, limit_to_user: Option<u32>, use_sewobe_form: bool, from: i64, to: i64 ) -> IronResult<Response> {
+    fn build_bill_download(req: &mut iron::request::Request, limit_to_user: Option<u32>, use_sewobe_form: bool, as_pdf: bool, from: i64, to: i64 ) -> IronResult<Response> {
+        if as_pdf {
+            return build_invoice_download(req, limit_to_user, from, to);
+        }
+        let conf = try_or_respond!(shared_config(req));
+        let prepaid_book = try_or_respond!(prepaid_book_snapshot(req));
+        let device_column = try_or_respond!(device_column(req, from, to));
         let filetitle: String = build_filename(to);
         let filecontent: String;
         {
-            let datholder = req.get::<State<SharedBackend>>().unwrap();
-            let dat = datholder.write().unwrap();
+            let datholder = try_or_respond!(shared_backend(req));
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
 
             use rustix_bl::datastore::DatastoreQueries;
-            let bill_opt = dat.datastore
-                .get_bill(from, to);
-
-            if bill_opt.is_none() {
-                return Ok(Response::with((
-                    iron::status::Conflict,
-                    serde_json::to_string(&ServerWriteResult {
-                        error_message: Some("Could not find a bill with given params".to_string()),
-                        is_success: false,
-                        content: None,
-                    }).unwrap(),
-                )));
-            }
-
-            let bill: rustix_bl::datastore::Bill = bill_opt.unwrap()
-                .clone();
+            let bill: rustix_bl::datastore::Bill = prepaid_book.without_prepaid_users(try_or_respond!(dat.datastore
+                .get_bill(from, to)
+                .or_not_found("bill with given params")));
             match limit_to_user {
                 Some(user_id) => {
                     let _subject = format!(
@@ -673,7 +1128,7 @@ pub mod responsehandlers {
                         Utc::now().format("%d.%m.%Y")
                     );
                     let body_cells =
-                        bill.format_as_personalized_documentation(&user_id);
+                        bill.format_as_personalized_documentation(&user_id, &conf.accounting);
 
                     let mut lines: Vec<String> = Vec::new();
 
@@ -691,7 +1146,7 @@ pub mod responsehandlers {
                     let mut lines_a: Vec<String> = Vec::new();
                     let mut lines_b: Vec<String> = Vec::new();
                     if use_sewobe_form {
-                        let body_a_cells = bill.format_as_sewobe_csv(date_today);
+                        let body_a_cells = bill.format_as_sewobe_csv(date_today, &conf.accounting);
                         info!("Finished SEWOBE bill for admin");
                         for line_vec in body_a_cells {
                             lines_a.push(line_vec.join(";"));
@@ -699,7 +1154,11 @@ pub mod responsehandlers {
                         let body_a: String = lines_a.join("\n");
                         filecontent = body_a;
                     } else {
-                        let body_b_cells = bill.format_as_documentation();
+                        let body_b_cells = devices::with_device_column(
+                            bill.format_as_documentation(&conf.accounting),
+                            &bill,
+                            &device_column,
+                        );
                         info!("Finished internal bill for admin");
                         for line_vec in body_b_cells {
                             lines_b.push(line_vec.join(";"));
@@ -738,244 +1197,508 @@ pub mod responsehandlers {
         return s;
     }
 
-    pub fn undo_purchase_by_user(req: &mut iron::request::Request) -> IronResult<Response> {
+    fn secret_keyring(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<auth::Keyring>>, ServerError> {
+        return req
+            .get::<State<SecretKey>>()
+            .map_err(|_| ServerError::Internal("Signing keys are not available".to_string()));
+    }
+
+    fn parse_body<T: DeserializeOwned>(req: &mut iron::request::Request) -> Result<T, ServerError> {
         let posted_body = extract_body(req);
         debug!("posted_body = {:?}", posted_body);
-        let parsed_body: UndoPurchase = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
+        return serde_json::from_str(&posted_body)
+            .map_err(|e| ServerError::BadRequest(format!("Could not parse request body: {}", e)));
+    }
 
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
+    fn parse_query<T: DeserializeOwned>(req: &mut iron::request::Request) -> Result<T, ServerError> {
+        let json_query = extract_query(req).ok_or(ServerError::BadRequest(
+            "Missing parameter 'query'".to_string(),
+        ))?;
+        return serde_json::from_str(&json_query)
+            .map_err(|e| ServerError::BadRequest(format!("Could not parse query: {}", e)));
+    }
 
-                let cur = current_time_millis();
+    fn shared_backend(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<Backend>>, ServerError> {
+        return req
+            .get::<State<SharedBackend>>()
+            .map_err(|_| ServerError::Internal("Backend is not available".to_string()));
+    }
 
-                use rustix_bl::datastore::DatastoreQueries;
+    fn error_response(err: ServerError) -> Response {
+        warn!("Request failed with {}", err);
+        return err.to_response();
+    }
 
-                let t_if = match dat.datastore.get_purchase_timestamp(parsed_body.unique_id) {
-                    Some(ref t) => *t,
-                    None => -1i64,
-                };
+    fn write_response(result: Result<RefreshedData, ServerError>) -> IronResult<Response> {
+        return Ok(match result {
+            Ok(refreshed_data) => Response::with((
+                iron::status::Ok,
+                serde_json::to_string(&ServerWriteResult::success(refreshed_data))
+                    .unwrap_or(String::new()),
+            )),
+            Err(err) => error_response(err),
+        });
+    }
 
-                if match dat.datastore.get_purchase_timestamp(parsed_body.unique_id) {
-                    Some(ref t) => cur - (60i64 * 1000i64) > *t,
-                    None => false,
-                } {
-                    let seconds_too_late = (cur - (60i64 * 1000i64) - t_if) / 1000i64;
-                    return Ok(Response::with((
-                        iron::status::Conflict,
-                        serde_json::to_string(&ServerWriteResult {
-                            error_message: Some(
-                                format!("A user may only undo a purchase before 60s have passed. With current time = {}  and  purchase's timestamp = {}  you were {} seconds too late."
-                                , cur, t_if, seconds_too_late),
-                            ),
-                            is_success: false,
-                            content: None,
-                        }).unwrap(),
-                    )));
-                } else {
-                    let result = ServableRustixImpl::check_apply_write(
-                        &mut dat,
-                        param,
-                        rustix_bl::rustix_event_shop::BLEvents::UndoPurchase {
-                            unique_id: parsed_body.unique_id,
-                        },
-                    );
+    //a retried request with a known key gets the stored result instead of being applied again
+    fn with_idempotency<F>(
+        req: &mut iron::request::Request,
+        settings: &idempotency::IdempotencySettings,
+        fingerprint: String,
+        handler: F,
+    ) -> IronResult<Response>
+    where
+        F: FnOnce(&mut iron::request::Request) -> Result<RefreshedData, ServerError>,
+    {
+        let key = match idempotency::extract_idempotency_key(req) {
+            Some(key) => format!("/{}:{}", req.url.path().join("/"), key),
+            None => return write_response(handler(req)),
+        };
+        let holder = try_or_respond!(shared_idempotency(req));
+        let now = Utc::now().timestamp();
+        //failed requests are not remembered, the client may fix them and retry with the same key
+        let outcome = idempotency::apply_once(&holder, &key, &fingerprint, settings, now, || {
+            let body = serde_json::to_string(&ServerWriteResult::success(handler(req)?))?;
+            //a replay of a large response only confirms the success, the client reloads what it shows
+            let stored = if body.len() > settings.max_body_bytes {
+                serde_json::to_string(&ServerWriteResult::success_without_content())?
+            } else {
+                body.to_string()
+            };
+            return Ok((body, stored));
+        });
+        let body = match try_or_respond!(outcome) {
+            idempotency::Outcome::Replayed(body) => body,
+            idempotency::Outcome::Applied(body) => body,
+        };
+        return Ok(Response::with((iron::status::Ok, body)));
+    }
 
-                    match result {
-                        Ok(sux) => {
-                            return Ok(Response::with((
-                                iron::status::Ok,
-                                serde_json::to_string(&ServerWriteResult {
-                                    error_message: None,
-                                    is_success: true,
-                                    content: Some(SuccessContent {
-                                        timestamp_epoch_millis: current_time_millis(),
-                                        refreshed_data: sux,
-                                    }),
-                                })
-                                .unwrap(),
-                            )));
-                        }
-                        Err(err) => {
-                            return Ok(Response::with((
-                                iron::status::Conflict,
-                                serde_json::to_string(&ServerWriteResult {
-                                    error_message: Some(err.description().to_string()),
-                                    is_success: false,
-                                    content: None,
-                                })
-                                .unwrap(),
-                            )));
-                        }
-                    }
+    fn shared_idempotency(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<idempotency::IdempotencyBook>>, ServerError> {
+        return req
+            .get::<State<SharedIdempotency>>()
+            .map_err(|_| ServerError::Internal("Idempotency keys are not available".to_string()));
+    }
+
+    fn read_response(result: Result<serde_json::Value, ServerError>) -> IronResult<Response> {
+        return Ok(match result {
+            Ok(sux) => Response::with((
+                iron::status::Ok,
+                serde_json::to_string(&sux).unwrap_or(String::new()),
+            )),
+            Err(err) => error_response(err),
+        });
+    }
+
+    fn apply_event(
+        req: &mut iron::request::Request,
+        event: rustix_bl::rustix_event_shop::BLEvents,
+    ) -> Result<RefreshedData, ServerError> {
+        return apply_event_with(req, event, |_| Ok(()), |_| Ok(()));
+    }
+
+    //check_before runs under the same write lock as the event itself, after_success sees the already changed backend
+    fn apply_event_with<B, A>(
+        req: &mut iron::request::Request,
+        event: rustix_bl::rustix_event_shop::BLEvents,
+        check_before: B,
+        after_success: A,
+    ) -> Result<RefreshedData, ServerError>
+    where
+        B: FnOnce(&Backend) -> Result<(), ServerError>,
+        A: FnOnce(&Backend) -> Result<(), ServerError>,
+    {
+        let param: ParametersAll = parse_query(req)?;
+        let conf = shared_config(req)?;
+        let inventoryholder = shared_inventory(req)?;
+        let barcodeholder = shared_barcodes(req)?;
+        let queueholder = shared_mail_queue(req)?;
+        let webhookholder = shared_webhook_book(req)?;
+        let hubholder = shared_event_hub(req)?;
+        let datholder = shared_backend(req)?;
+        //a panic in another request must not take the whole bar offline
+        let mut dat = datholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        check_before(&*dat)?;
+        let stock_changes = inventory::stock_changes(&*dat, &event);
+        let exhausted_ffa = alerts::exhausted_ffa(&*dat, &event);
+        let event_json = serde_json::to_value(&event).ok();
+        let mut refreshed_data = ServableRustixImpl::check_apply_write(&mut dat, param, event)?;
+        if let Some(ref event_json) = event_json {
+            let hub = hubholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for (event_type, change) in events::changes_for(event_json) {
+                hub.publish(event_type, change, current_time_millis());
+            }
+        }
+        let queued_webhooks = publish_to_webhooks(&dat, &webhookholder, event_json);
+        let (sales, sales_log) = {
+            let mut inventory = inventoryholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let low_before = inventory.low_stock_warnings(&dat.datastore.items, &conf.inventory);
+            inventory.record_sales(&stock_changes, current_time_millis());
+            attach_low_stock_warnings(&mut refreshed_data, &dat, &inventory, &conf.inventory);
+            let barcode_book = barcodeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            attach_item_barcodes(&mut refreshed_data.AllItems, &barcode_book);
+
+            if conf.alerts.enabled {
+                let low_after = inventory.low_stock_warnings(&dat.datastore.items, &conf.inventory);
+                let mut reasons = alerts::newly_low(&low_before, &low_after);
+                reasons.extend(exhausted_ffa);
+                if !reasons.is_empty() {
+                    let now = current_time_millis();
+                    let alert = alerts::StockAlert {
+                        timestamp_epoch_millis: now,
+                        reasons: reasons,
+                        reorder_list: alerts::reorder_list(&inventory, &dat.datastore.items, &conf.inventory, &conf.alerts, now),
+                    };
+                    alerts::dispatch(alert, &conf.alerts, &queueholder, Utc::now().timestamp());
                 }
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+            (inventory.take_unlogged(), inventory.movements_log_path())
+        };
+        let after = after_success(&*dat);
+        //files are written once other requests can use the backend again
+        drop(dat);
+        inventory::append_movements(&sales_log, &sales);
+        if queued_webhooks {
+            webhookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner()).save_deliveries();
+        }
+        after?;
+        return Ok(refreshed_data);
+    }
+
+    //only queued here, the webhook worker delivers and retries. true if deliveries wait to be saved
+    fn publish_to_webhooks(
+        dat: &Backend,
+        webhookholder: &Arc<RwLock<webhooks::WebhookBook>>,
+        event_json: Option<serde_json::Value>,
+    ) -> bool {
+        let mut book = webhookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if book.subscriptions.is_empty() {
+            return false;
+        }
+        let payload = match event_json.and_then(|e| webhooks::build_payload(dat, e, current_time_millis())) {
+            Some(payload) => payload,
+            None => return false,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Could not serialize webhook payload: {}", e);
+                return false;
+            }
+        };
+        return book.enqueue(&payload.event_type, &body, Utc::now().timestamp()) > 0;
+    }
+
+    //the warnings travel next to the paginated items, so clients without stock tracking can ignore them
+    fn attach_low_stock_warnings(
+        refreshed_data: &mut RefreshedData,
+        dat: &Backend,
+        inventory: &inventory::Inventory,
+        settings: &inventory::InventorySettings,
+    ) {
+        if !refreshed_data.AllItems.is_object() {
+            return;
+        }
+        let warnings = inventory.low_stock_warnings(&dat.datastore.items, settings);
+        refreshed_data.AllItems["low_stock_warnings"] =
+            serde_json::to_value(&warnings).unwrap_or(serde_json::Value::Null);
+    }
+
+    //next to the paginated items as well, rustix-bl items have no room for them
+    fn attach_item_barcodes(items: &mut serde_json::Value, book: &barcodes::BarcodeBook) {
+        let item_ids: Vec<u32> = match items.get("results").and_then(|r| r.as_array()) {
+            Some(results) => results
+                .iter()
+                .filter_map(|i| i.get("item_id").and_then(|id| id.as_u64()))
+                .map(|id| id as u32)
+                .collect(),
+            None => return,
+        };
+        items["item_barcodes"] =
+            serde_json::to_value(&book.barcodes_of_items(&item_ids)).unwrap_or(serde_json::Value::Null);
+    }
+
+    fn query_backend<P, F>(req: &mut iron::request::Request, to_query: F) -> IronResult<Response>
+    where
+        P: DeserializeOwned,
+        F: FnOnce(P) -> ReadQueryParams,
+    {
+        let param: P = try_or_respond!(parse_query(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return read_response(ServableRustixImpl::query_read(&dat, to_query(param)));
+    }
+
+    pub fn undo_purchase_by_user(
+        req: &mut iron::request::Request,
+        config: &ServerConfig,
+    ) -> IronResult<Response> {
+        let parsed_body: UndoPurchase = try_or_respond!(parse_body(req));
+        let unique_id = parsed_body.unique_id;
+        let undoholder = try_or_respond!(shared_undos(req));
+        let device_id = calling_device(req).map(|d| d.id);
+        let target: std::cell::RefCell<Option<undo::UndoTarget>> = std::cell::RefCell::new(None);
+        let refusal: std::cell::RefCell<Option<undo::UndoRefusal>> = std::cell::RefCell::new(None);
+
+        let result = apply_event_with(
+            req,
+            rustix_bl::rustix_event_shop::BLEvents::UndoPurchase { unique_id: unique_id },
+            |dat| {
+                let found = match undo::describe_purchase(dat, unique_id) {
+                    Some(found) => found,
+                    None => return Ok(()),
+                };
+                let book = undoholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                if let Err(refused) = book.check(&found, &config.undo, current_time_millis()) {
+                    let err = refused.to_error();
+                    *refusal.borrow_mut() = Some(refused);
+                    return Err(err);
+                }
+                *target.borrow_mut() = Some(found);
+                return Ok(());
+            },
+            |_| {
+                record_undo(&undoholder, target.borrow_mut().take(), undo::UndoActor::Member, device_id);
+                return Ok(());
+            },
+        );
+        if let Some(ref refused) = *refusal.borrow() {
+            warn!("Refused to undo purchase {}: {:?}", unique_id, refused.reason);
+            return Ok(refused.to_response());
+        }
+        return write_response(result);
+    }
+
+    fn record_undo(
+        undoholder: &Arc<RwLock<undo::UndoBook>>,
+        target: Option<undo::UndoTarget>,
+        undone_by: undo::UndoActor,
+        device_id: Option<u64>,
+    ) {
+        if let Some(target) = target {
+            info!("Purchase {} ({}) was undone by {:?}", target.purchase_id, target.description, undone_by);
+            let mut book = undoholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            book.record(target, undone_by, device_id, current_time_millis());
+        }
+    }
+
+    fn shared_undos(req: &mut iron::request::Request) -> Result<Arc<RwLock<undo::UndoBook>>, ServerError> {
+        return req
+            .get::<State<SharedUndos>>()
+            .map_err(|_| ServerError::Internal("Undo records are not available".to_string()));
+    }
+
+    pub fn list_undos(req: &mut iron::request::Request) -> IronResult<Response> {
+        let undoholder = try_or_respond!(shared_undos(req));
+        let (path, recent) = {
+            let book = undoholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            (book.log_path(), book.records.clone())
         };
+        let records = try_or_respond!(undo::read_log(&path, recent));
+        let newest_first: Vec<&undo::UndoRecord> = records.iter().rev().collect();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&newest_first).unwrap_or(String::new()),
+        )));
     }
 
     pub fn snapshot_by_admin(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
+        let datholder = try_or_respond!(shared_backend(req));
+        let mut dat = datholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
         match dat.snapshot() {
             Some(count) => Ok(Response::with((iron::status::Ok, count.to_string()))),
-            None => Ok(Response::with((
-                iron::status::BadRequest,
-                "Could not snapshot state, is this not a persistent implementation?",
+            None => Ok(error_response(ServerError::BadRequest(
+                "Could not snapshot state, is this not a persistent implementation?".to_string(),
             ))),
         }
     }
 
     pub fn undo_purchase_by_admin(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: UndoPurchase = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                debug!("json queried");
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                use rustix_bl::datastore::DatastoreQueries;
-                if dat
-                    .datastore
-                    .get_purchase_timestamp(parsed_body.unique_id)
-                    .is_none()
-                {
-                    debug!("purchase not found");
-                    return Ok(Response::with((iron::status::Conflict, serde_json::to_string(&ServerWriteResult {
-                        error_message: Some("Cannot find purchase to delete (the purchase may have already been finalized into a bill, undoing such a purchase is not possible)".to_string()),
-                        is_success: false,
-                        content: None,
-                    }).unwrap())));
-                } else {
-                    let result = ServableRustixImpl::check_apply_write(
-                        &mut dat,
-                        param,
-                        rustix_bl::rustix_event_shop::BLEvents::UndoPurchase {
-                            unique_id: parsed_body.unique_id,
-                        },
-                    );
-
-                    match result {
-                        Ok(sux) => {
-                            return Ok(Response::with((
-                                iron::status::Ok,
-                                serde_json::to_string(&ServerWriteResult {
-                                    error_message: None,
-                                    is_success: true,
-                                    content: Some(SuccessContent {
-                                        timestamp_epoch_millis: current_time_millis(),
-                                        refreshed_data: sux,
-                                    }),
-                                })
-                                .unwrap(),
-                            )));
-                        }
-                        Err(err) => {
-                            return Ok(Response::with((
-                                iron::status::Conflict,
-                                serde_json::to_string(&ServerWriteResult {
-                                    error_message: Some(err.description().to_string()),
-                                    is_success: false,
-                                    content: None,
-                                })
-                                .unwrap(),
-                            )));
-                        }
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        let parsed_body: UndoPurchase = try_or_respond!(parse_body(req));
+        let unique_id = parsed_body.unique_id;
+        let undoholder = try_or_respond!(shared_undos(req));
+        let device_id = calling_device(req).map(|d| d.id);
+        let target: std::cell::RefCell<Option<undo::UndoTarget>> = std::cell::RefCell::new(None);
+
+        let result = apply_event_with(
+            req,
+            rustix_bl::rustix_event_shop::BLEvents::UndoPurchase { unique_id: unique_id },
+            |dat| {
+                let found = undo::describe_purchase(dat, unique_id)
+                    .ok_or(ServerError::NotFound("Cannot find purchase to delete (the purchase may have already been finalized into a bill, undoing such a purchase is not possible)".to_string()))?;
+                *target.borrow_mut() = Some(found);
+                return Ok(());
+            },
+            |_| {
+                record_undo(&undoholder, target.borrow_mut().take(), undo::UndoActor::Admin, device_id);
+                return Ok(());
+            },
+        );
+        return write_response(result);
     }
 
     pub fn check_password(
         req: &mut iron::request::Request,
         conf: &configuration::ServerConfig,
+        throttle: &Mutex<auth::LoginThrottle>,
     ) -> IronResult<Response> {
         let posted_body: String = extract_body(req);
+        let client = req.remote_addr.ip().to_string();
+        let matches = try_or_respond!(throttle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .check_password(conf, &client, &posted_body, Utc::now().timestamp()));
         return Ok(Response::with((
             iron::status::Ok,
-            serde_json::to_string(&(posted_body.trim() == conf.admin_password.trim())).unwrap(),
+            serde_json::to_string(&matches).unwrap(),
         )));
     }
 
-    pub fn simple_purchase(
+    pub fn admin_login(
         req: &mut iron::request::Request,
-        config: &ServerConfig,
+        conf: &configuration::ServerConfig,
+        throttle: &Mutex<auth::LoginThrottle>,
     ) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: MakeSimplePurchase = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::MakeSimplePurchase {
-                        user_id: parsed_body.user_id,
-                        item_id: parsed_body.item_id,
-                        timestamp: current_time_millis(),
-                    },
-                );
+        let posted_body: String = extract_body(req);
+        if conf.admin_password.trim().is_empty() {
+            warn!("Admin login attempted, but no admin_password is configured");
+            return Ok(error_response(ServerError::Unauthorized(
+                "Admin login is disabled until an admin_password is configured".to_string(),
+            )));
+        }
+        let client = req.remote_addr.ip().to_string();
+        let matches = try_or_respond!(throttle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .check_password(conf, &client, &posted_body, Utc::now().timestamp()));
+        if !matches {
+            warn!("Admin login with wrong password");
+            return Ok(error_response(ServerError::Unauthorized(
+                "Wrong admin password".to_string(),
+            )));
+        }
 
-                match result {
-                    Ok(sux) => {
-                        log_purchase(&dat, parsed_body.item_id, Some(parsed_body.user_id), config, dat.datastore.last_millis_of_purchase_by_user.get(&parsed_body.user_id));
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        let keyholder = try_or_respond!(secret_keyring(req));
+        let keyring = keyholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let token = auth::issue_admin_token(
+            &keyring,
+            auth::TicketPurpose::AdminSession.lifetime_seconds(conf),
+        );
+
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&token).unwrap(),
+        )));
     }
 
-fn log_purchase(
-        dat: &Backend,
-        item_id: u32,
-        user_id: Option<u32>,
-        config: &ServerConfig,
-        last_timestamp: Option<&i64>,
-    ) {
-        let item_opt = dat.datastore.items.get(&item_id);
-        if item_opt.is_some() {
-            let item = item_opt.unwrap();
-            match user_id {
+    //replaces the signing key, tokens signed with the old key stay valid for the configured grace period
+    pub fn rotate_jwt_secret(
+        req: &mut iron::request::Request,
+        conf: &configuration::ServerConfig,
+    ) -> IronResult<Response> {
+        let keyholder = try_or_respond!(secret_keyring(req));
+        let mut keyring = keyholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        //a key only held in memory would silently be replaced by the configured one on the next start
+        if keyring.path.is_none() {
+            return Ok(error_response(ServerError::Conflict(
+                "The signing key is not stored in a file (jwt_secret is set or persistence is off), change jwt_secret instead".to_string(),
+            )));
+        }
+        keyring.rotate(Utc::now().timestamp(), conf.jwt_rotation_grace_seconds);
+        if let Err(e) = keyring.save() {
+            error!("Could not persist rotated JWT keyring: {:?}", e);
+            return Ok(error_response(ServerError::Internal(
+                "Key was rotated but could not be persisted".to_string(),
+            )));
+        }
+        info!("Rotated JWT signing key, {} keys still accepted", keyring.keys.len());
+        let token = auth::issue_admin_token(
+            &keyring,
+            auth::TicketPurpose::AdminSession.lifetime_seconds(conf),
+        );
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&token).unwrap(),
+        )));
+    }
+
+    pub fn simple_purchase(
+        req: &mut iron::request::Request,
+        config: &ServerConfig,
+    ) -> IronResult<Response> {
+        let parsed_body: MakeSimplePurchase = try_or_respond!(parse_body(req));
+        let fingerprint = try_or_respond!(idempotency::fingerprint(&parsed_body));
+        return with_idempotency(req, &config.idempotency, fingerprint, |req| {
+            book_simple_purchase(req, config, parsed_body, current_time_millis(), |_| Ok(()))
+        });
+    }
+
+    //timestamp is only in the past for purchases synchronized by offline kiosks
+    fn book_simple_purchase<C>(
+        req: &mut iron::request::Request,
+        config: &ServerConfig,
+        parsed_body: MakeSimplePurchase,
+        timestamp: i64,
+        check_before: C,
+    ) -> Result<RefreshedData, ServerError>
+    where
+        C: FnOnce(&Backend) -> Result<(), ServerError>,
+    {
+        let prepaidholder = shared_prepaid_book(req)?;
+        let notifications = notification_context(req)?;
+        let devicesholder = shared_devices(req)?;
+        let credentialsholder = shared_credentials(req)?;
+        let credential_match = credentials::match_credential(
+            &credentialsholder,
+            parsed_body.user_id,
+            parsed_body.credential.as_ref(),
+            Utc::now().timestamp(),
+        )?;
+        let device = calling_device(req);
+        let previous_purchase: std::cell::Cell<Option<i64>> = std::cell::Cell::new(None);
+
+        return apply_event_with(
+            req,
+            rustix_bl::rustix_event_shop::BLEvents::MakeSimplePurchase {
+                user_id: parsed_body.user_id,
+                item_id: parsed_body.item_id,
+                timestamp: timestamp,
+            },
+            |dat| {
+                check_before(dat)?;
+                //unknown items are rejected by the backend itself
+                let cost: i64 = dat.datastore.items.get(&parsed_body.item_id).map(|i| i.cost_cents as i64).unwrap_or(0);
+                credentialsholder
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .check_purchase(parsed_body.user_id, cost, false, credential_match, &config.credentials, Utc::now().timestamp())?;
+                previous_purchase.set(dat.datastore.last_millis_of_purchase_by_user.get(&parsed_body.user_id).cloned());
+                let book = prepaidholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                return prepaid::check_user_purchase(dat, &book, &config.prepaid, parsed_body.user_id, cost);
+            },
+            |dat| {
+                log_purchase(dat, parsed_body.item_id, Some(parsed_body.user_id));
+                record_purchase_origins(dat, &devicesholder, &device, parsed_body.user_id, timestamp);
+                let item_ids = vec![parsed_body.item_id];
+                notify_purchase(dat, &notifications, config, parsed_body.user_id, &item_ids, previous_purchase.get());
+                let book = prepaidholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                notify_low_balance(dat, &notifications, config, &book, parsed_body.user_id, &item_ids);
+                return Ok(());
+            },
+        );
+    }
+
+    fn log_purchase(dat: &Backend, item_id: u32, user_id: Option<u32>) {
+        let item_opt = dat.datastore.items.get(&item_id);
+        if item_opt.is_some() {
+            let item = item_opt.unwrap();
+            match user_id {
                 Some(uid) => {
                     let user_opt = dat.datastore.users.get(&uid);
                     if user_opt.is_some() {
@@ -984,14 +1707,6 @@ fn log_purchase(
                             "Purchase by {} (id = {}): item {} with cost of {} cents",
                             user.username, user.user_id, item.name, item.cost_cents
                         );
-                        let now = current_time_millis();
-                        inform_user(
-                            now,
-                            *last_timestamp.unwrap_or(&now),
-                            item.name.clone(),
-                            user.clone().external_user_id,
-                            config,
-                        )
                     } else {
                         error!("Cannot find user with user_id = {}", uid);
                     }
@@ -1008,1206 +1723,1735 @@ fn log_purchase(
         }
     }
 
+    fn notification_context(
+        req: &mut iron::request::Request,
+    ) -> Result<notifier::NotificationContext, ServerError> {
+        return Ok(notifier::NotificationContext {
+            dispatcher: req
+                .get::<State<SharedNotificationDispatcher>>()
+                .map_err(|_| ServerError::Internal("Notifications are not available".to_string()))?,
+            preferences: shared_notification_preferences(req)?,
+            contacts: shared_dunning_book(req)?,
+            mail_queue: shared_mail_queue(req)?,
+        });
+    }
+
+    //previous_purchase is read before the purchase is applied, afterwards it is always the current one
+    fn notify_purchase(
+        dat: &Backend,
+        notifications: &notifier::NotificationContext,
+        config: &ServerConfig,
+        user_id: u32,
+        item_ids: &[u32],
+        previous_purchase: Option<i64>,
+    ) {
+        let now = current_time_millis();
+        let quiet_millis = config.notifications.purchase_quiet_days * 24 * 60 * 60 * 1000;
+        if previous_purchase.map(|p| now - p < quiet_millis).unwrap_or(false) {
+            return;
+        }
+        let names: Vec<String> = item_ids
+            .iter()
+            .filter_map(|id| dat.datastore.items.get(id))
+            .map(|i| i.name.to_string())
+            .collect();
+        if names.is_empty() {
+            return;
+        }
+        notifications.notify(
+            dat,
+            config,
+            user_id,
+            notifier::NotificationKind::Purchase,
+            "Booked on the beer list".to_string(),
+            format!(
+                "{} was booked in your name. You get this message because your previous purchase was more than {} days ago.",
+                names.join(", "),
+                config.notifications.purchase_quiet_days
+            ),
+            now,
+        );
+    }
+
+    //only sent when the purchase crosses the threshold, not for every purchase below it
+    fn notify_low_balance(
+        dat: &Backend,
+        notifications: &notifier::NotificationContext,
+        config: &ServerConfig,
+        book: &prepaid::PrepaidBook,
+        user_id: u32,
+        item_ids: &[u32],
+    ) {
+        let balance = match prepaid::current_balance(dat, book, &config.prepaid, user_id) {
+            Ok(Some(balance)) => balance,
+            _ => return,
+        };
+        let cost: i64 = item_ids
+            .iter()
+            .filter_map(|id| dat.datastore.items.get(id))
+            .map(|i| i.cost_cents as i64)
+            .sum();
+        let threshold = config.notifications.low_balance_threshold_cents;
+        if balance.balance_cents >= threshold || balance.balance_cents + cost < threshold {
+            return;
+        }
+        notifications.notify(
+            dat,
+            config,
+            user_id,
+            notifier::NotificationKind::LowBalance,
+            "Your prepaid balance is running low".to_string(),
+            format!(
+                "Your prepaid balance is down to {} €, please top it up soon.",
+                cents_to_currency_string_i64(balance.balance_cents)
+            ),
+            current_time_millis(),
+        );
+    }
+
+    fn notify_giveout(
+        dat: &Backend,
+        notifications: &notifier::NotificationContext,
+        config: &ServerConfig,
+        donor: u32,
+        recipient: u32,
+        gift: &str,
+        text_message: &str,
+    ) {
+        let donor_name = match dat.datastore.users.get(&donor) {
+            Some(user) => user.username.to_string(),
+            None => return,
+        };
+        let mut body = format!("{} gave you {}.", donor_name, gift);
+        if !text_message.trim().is_empty() {
+            body = format!("{} Message: {}", body, text_message.trim());
+        }
+        notifications.notify(
+            dat,
+            config,
+            recipient,
+            notifier::NotificationKind::GiveoutReceived,
+            "You received a giveout".to_string(),
+            body,
+            current_time_millis(),
+        );
+    }
+
+    //prepaid users and users that are not billed get nothing to pay, so they are not told about the bill
+    fn notify_bill_ready(
+        dat: &Backend,
+        notifications: &notifier::NotificationContext,
+        config: &ServerConfig,
+        prepaid_book: &prepaid::PrepaidBook,
+        timestamp_from: i64,
+        timestamp_to: i64,
+    ) {
+        let bill = match dat
+            .datastore
+            .bills
+            .iter()
+            .find(|b| b.timestamp_from == timestamp_from && b.timestamp_to == timestamp_to)
+        {
+            Some(bill) => prepaid_book.without_prepaid_users(bill),
+            None => return,
+        };
+        let period = format!(
+            "{} - {}",
+            dunning::format_day(timestamp_from / 1000),
+            dunning::format_day(timestamp_to / 1000)
+        );
+        let now = current_time_millis();
+        for user_id in bill.finalized_data.user_consumption.keys() {
+            if bill.users_that_will_not_be_billed.contains(user_id) {
+                continue;
+            }
+            let total = billed_total_cents(&bill, user_id, &config.accounting);
+            notifications.notify(
+                dat,
+                config,
+                *user_id,
+                notifier::NotificationKind::BillReady,
+                format!("Your bill for {} is ready", period),
+                format!(
+                    "Your bill for {} has been finalized, it amounts to {} €.",
+                    period,
+                    cents_to_currency_string_i64(total)
+                ),
+                now,
+            );
+        }
+    }
+
     pub fn cart_purchase(
         req: &mut iron::request::Request,
         config: &ServerConfig,
     ) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: MakeCartPurchase = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let mut item_ids: Vec<u32> = Vec::new();
-                for kv in parsed_body.items {
-                    for _i in 0..kv.value {
-                        item_ids.push(kv.key);
-                    }
-                }
+        let parsed_body: MakeCartPurchase = try_or_respond!(parse_body(req));
+        let fingerprint = try_or_respond!(idempotency::fingerprint(&parsed_body));
+        return with_idempotency(req, &config.idempotency, fingerprint, |req| {
+            book_cart_purchase(req, config, parsed_body, current_time_millis(), |_| Ok(()))
+        });
+    }
 
-                let event = rustix_bl::rustix_event_shop::BLEvents::MakeShoppingCartPurchase {
-                    user_id: parsed_body.user_id,
-                    specials: parsed_body.specials,
-                    item_ids: item_ids.clone(),
-                    timestamp: current_time_millis(),
-                };
+    fn book_cart_purchase<C>(
+        req: &mut iron::request::Request,
+        config: &ServerConfig,
+        parsed_body: MakeCartPurchase,
+        timestamp: i64,
+        check_before: C,
+    ) -> Result<RefreshedData, ServerError>
+    where
+        C: FnOnce(&Backend) -> Result<(), ServerError>,
+    {
+        let mut item_ids: Vec<u32> = Vec::new();
+        for kv in parsed_body.items {
+            for _i in 0..kv.value {
+                item_ids.push(kv.key);
+            }
+        }
 
-                let result = ServableRustixImpl::check_apply_write(&mut dat, param, event);
-
-                match result {
-                    Ok(sux) => {
-                        for item_id in item_ids {
-                            log_purchase(
-                                &dat,
-                                item_id,
-                                Some(parsed_body.user_id),
-                                config,
-                                dat.datastore
-                                    .last_millis_of_purchase_by_user
-                                    .get(&parsed_body.user_id),
-                            );
-                        }
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+        let user_id = parsed_body.user_id;
+        let has_specials = !parsed_body.specials.is_empty();
+        let event = rustix_bl::rustix_event_shop::BLEvents::MakeShoppingCartPurchase {
+            user_id: user_id,
+            specials: parsed_body.specials,
+            item_ids: item_ids.clone(),
+            timestamp: timestamp,
+        };
+
+        let prepaidholder = shared_prepaid_book(req)?;
+        let notifications = notification_context(req)?;
+        let devicesholder = shared_devices(req)?;
+        let device = calling_device(req);
+        let previous_purchase: std::cell::Cell<Option<i64>> = std::cell::Cell::new(None);
+        let previous = &previous_purchase;
+        let checked_prepaidholder = prepaidholder.clone();
+        let checked_item_ids = item_ids.clone();
+        let credentialsholder = shared_credentials(req)?;
+        let credential_match = credentials::match_credential(
+            &credentialsholder,
+            user_id,
+            parsed_body.credential.as_ref(),
+            Utc::now().timestamp(),
+        )?;
+        let check_prepaid = move |dat: &Backend| {
+            check_before(dat)?;
+            //specials are priced later, so only the items count against a prepaid balance
+            let cost: i64 = checked_item_ids
+                .iter()
+                .filter_map(|id| dat.datastore.items.get(id))
+                .map(|i| i.cost_cents as i64)
+                .sum();
+            credentialsholder
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .check_purchase(user_id, cost, has_specials, credential_match, &config.credentials, Utc::now().timestamp())?;
+            previous.set(dat.datastore.last_millis_of_purchase_by_user.get(&user_id).cloned());
+            let book = checked_prepaidholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            return prepaid::check_user_purchase(dat, &book, &config.prepaid, user_id, cost);
+        };
+
+        return apply_event_with(req, event, check_prepaid, |dat| {
+            for item_id in &item_ids {
+                log_purchase(dat, *item_id, Some(user_id));
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+            record_purchase_origins(dat, &devicesholder, &device, user_id, timestamp);
+            //one notification for the whole cart
+            notify_purchase(dat, &notifications, config, user_id, &item_ids, previous_purchase.get());
+            let book = prepaidholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            notify_low_balance(dat, &notifications, config, &book, user_id, &item_ids);
+            return Ok(());
+        });
+    }
+
+    pub fn ffa_purchase(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: MakeFFAPurchase = try_or_respond!(parse_body(req));
+        return write_response(book_ffa_purchase(req, parsed_body, current_time_millis(), |_| Ok(())));
+    }
+
+    fn book_ffa_purchase<C>(
+        req: &mut iron::request::Request,
+        parsed_body: MakeFFAPurchase,
+        timestamp: i64,
+        check_before: C,
+    ) -> Result<RefreshedData, ServerError>
+    where
+        C: FnOnce(&Backend) -> Result<(), ServerError>,
+    {
+        let event = rustix_bl::rustix_event_shop::BLEvents::MakeFreeForAllPurchase {
+            ffa_id: parsed_body.ffa_id,
+            item_id: parsed_body.item_id,
+            timestamp: timestamp,
         };
+
+        return apply_event_with(req, event, check_before, |dat| {
+            log_purchase(dat, parsed_body.item_id, None);
+            return Ok(());
+        });
     }
 
-    pub fn ffa_purchase(
+    //entries of an offline kiosk, each uuid is booked at most once
+    pub fn sync_purchases(
         req: &mut iron::request::Request,
         config: &ServerConfig,
     ) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: MakeFFAPurchase = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let event = rustix_bl::rustix_event_shop::BLEvents::MakeFreeForAllPurchase {
-                    ffa_id: parsed_body.ffa_id,
-                    item_id: parsed_body.item_id,
-                    timestamp: current_time_millis(),
-                };
+        let parsed_body: sync::SyncBatch = try_or_respond!(parse_body(req));
+        if parsed_body.entries.len() > config.sync.max_entries {
+            return Ok(error_response(ServerError::BadRequest(format!(
+                "At most {} purchases can be synchronized at once",
+                config.sync.max_entries
+            ))));
+        }
+        let holder = try_or_respond!(shared_idempotency(req));
+        let now = current_time_millis();
+        let mut results: Vec<Option<sync::SyncEntryResult>> = vec![None; parsed_body.entries.len()];
+        for index in sync::application_order(&parsed_body.entries) {
+            let entry = parsed_body.entries[index].clone();
+            let uuid = entry.uuid.trim().to_string();
+            results[index] = Some(match sync_entry(req, config, &holder, entry, now) {
+                Ok(status) => sync::SyncEntryResult::succeeded(uuid, status),
+                Err(err) => sync::SyncEntryResult::failed(uuid, &err),
+            });
+        }
+        let result = sync::SyncBatchResult {
+            results: results.into_iter().filter_map(|r| r).collect(),
+        };
+        return read_response(serde_json::to_value(&result).map_err(ServerError::from));
+    }
 
-                let result = ServableRustixImpl::check_apply_write(&mut dat, param, event);
-
-                match result {
-                    Ok(sux) => {
-                        log_purchase(&dat, parsed_body.item_id, None, config, None);
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
+    fn sync_entry(
+        req: &mut iron::request::Request,
+        config: &ServerConfig,
+        holder: &RwLock<idempotency::IdempotencyBook>,
+        entry: sync::SyncEntry,
+        now: i64,
+    ) -> Result<sync::SyncStatus, ServerError> {
+        sync::validate_entry(&entry, &config.sync, now)?;
+        let key = format!("/purchases/sync:{}", entry.uuid.trim());
+        let fingerprint = idempotency::fingerprint(&entry)?;
+        //offline kiosks resend much later than ordinary retries, their uuids are kept as long as entries are accepted
+        let settings = idempotency::IdempotencySettings {
+            retention_seconds: config.sync.uuid_retention_seconds,
+            ..config.idempotency.clone()
+        };
+
+        let timestamp = entry.timestamp_epoch_millis;
+        let period_open = move |dat: &Backend| sync::check_period_open(&dat.datastore.bills, timestamp);
+        //a resent batch waits for each entry of the first one which is still being booked
+        let outcome = idempotency::apply_once(holder, &key, &fingerprint, &settings, now / 1000, || {
+            match (entry.simple, entry.cart, entry.ffa) {
+                (Some(purchase), None, None) => {
+                    book_simple_purchase(req, config, purchase, timestamp, period_open)?;
+                }
+                (None, Some(purchase), None) => {
+                    book_cart_purchase(req, config, purchase, timestamp, period_open)?;
+                }
+                (None, None, Some(purchase)) => {
+                    book_ffa_purchase(req, purchase, timestamp, period_open)?;
+                }
+                _ => {
+                    return Err(ServerError::BadRequest(
+                        "Every entry needs exactly one of simple, cart or ffa".to_string(),
+                    ))
                 }
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+            let status = sync::SyncStatus::Applied;
+            return Ok((status, serde_json::to_string(&status)?));
+        })?;
+        return Ok(match outcome {
+            idempotency::Outcome::Replayed(_) => sync::SyncStatus::AlreadyApplied,
+            idempotency::Outcome::Applied(status) => status,
+        });
     }
 
     pub fn create_budget_freeby(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateBudgetGiveout = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeBudget {
-                    cents_worth_total: parsed_body.cents_worth_total,
-                    text_message: parsed_body.text_message,
-                    created_timestamp: current_time_millis(),
-                    donor: parsed_body.donor,
-                    recipient: parsed_body.recipient,
+        let parsed_body: CreateBudgetGiveout = try_or_respond!(parse_body(req));
+        let conf = try_or_respond!(shared_config(req));
+        let notifications = try_or_respond!(notification_context(req));
+        let gift = format!("{} €", cents_to_currency_string_i64(parsed_body.cents_worth_total as i64));
+        let text_message = parsed_body.text_message.to_string();
+        let (donor, recipient) = (parsed_body.donor, parsed_body.recipient);
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeBudget {
+            cents_worth_total: parsed_body.cents_worth_total,
+            text_message: parsed_body.text_message,
+            created_timestamp: current_time_millis(),
+            donor: parsed_body.donor,
+            recipient: parsed_body.recipient,
+        };
+
+        let result = apply_event_with(req, event, |_| Ok(()), |dat| {
+            notify_giveout(dat, &notifications, &conf, donor, recipient, &gift, &text_message);
+            return Ok(());
+        });
+        return write_response(result);
+    }
+
+    pub fn create_count_freeby(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: CreateCountGiveout = try_or_respond!(parse_body(req));
+        let conf = try_or_respond!(shared_config(req));
+        let notifications = try_or_respond!(notification_context(req));
+        let gift = format!("{} drinks", parsed_body.allowed_number_total);
+        let text_message = parsed_body.text_message.to_string();
+        let (donor, recipient) = (parsed_body.donor, parsed_body.recipient);
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeCount {
+            allowed_categories: parsed_body.allowed_categories,
+            allowed_drinks: parsed_body.allowed_drinks,
+            allowed_number_total: parsed_body.allowed_number_total,
+            text_message: parsed_body.text_message,
+            created_timestamp: current_time_millis(),
+            donor: parsed_body.donor,
+            recipient: parsed_body.recipient,
+        };
+
+        let result = apply_event_with(req, event, |_| Ok(()), |dat| {
+            notify_giveout(dat, &notifications, &conf, donor, recipient, &gift, &text_message);
+            return Ok(());
+        });
+        return write_response(result);
+    }
+
+    pub fn create_ffa_freeby(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: CreateFreeForAll = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeForAll {
+            allowed_categories: parsed_body.allowed_categories,
+            allowed_drinks: parsed_body.allowed_drinks,
+            allowed_number_total: parsed_body.allowed_number_total,
+            text_message: parsed_body.text_message,
+            created_timestamp: current_time_millis(),
+            donor: parsed_body.donor,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn add_user(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: CreateUser = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateUser {
+            username: parsed_body.username,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn delete_user(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: DeleteUser = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::DeleteUser {
+            user_id: parsed_body.user_id,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn delete_item(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: DeleteItem = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::DeleteItem {
+            item_id: parsed_body.item_id,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn update_user(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: UpdateUser = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::UpdateUser {
+            user_id: parsed_body.user_id,
+            username: parsed_body.username,
+            is_billed: parsed_body.is_billed,
+            is_highlighted: parsed_body.highlight_in_ui,
+            external_user_id: parsed_body.external_user_id,
+            is_sepa: parsed_body.is_sepa,
+        };
+
+        let result = apply_event(req, event);
+        debug!("Going to change user to new state: result = {:?}", result);
+        return write_response(result);
+    }
+
+    pub fn add_item(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: CreateItem = try_or_respond!(parse_body(req));
+        let barcodeholder = try_or_respond!(shared_barcodes(req));
+        let validated: std::cell::RefCell<Vec<String>> = std::cell::RefCell::new(Vec::new());
+        let name = parsed_body.name.to_string();
+        let raw_barcodes = parsed_body.barcodes;
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateItem {
+            itemname: parsed_body.name,
+            price_cents: parsed_body.price_cents,
+            category: parsed_body.category,
+        };
+
+        let result = apply_event_with(
+            req,
+            event,
+            |dat| {
+                let book = barcodeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                *validated.borrow_mut() = book.validate(None, &raw_barcodes, &dat.datastore.items)?;
+                return Ok(());
+            },
+            |dat| {
+                let codes = validated.replace(Vec::new());
+                if codes.is_empty() {
+                    return Ok(());
+                }
+                //the backend does not tell the new id, it is the newest item with this name
+                let item_id = dat
+                    .datastore
+                    .items
+                    .values()
+                    .filter(|i| i.name == name && !i.deleted)
+                    .map(|i| i.item_id)
+                    .max()
+                    .or_not_found("created item")?;
+                let mut book = barcodeholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+                book.set(item_id, codes);
+                book.save();
+                return Ok(());
+            },
+        );
+        return write_response(result);
+    }
+
+    pub fn create_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: CreateBill = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::CreateBill {
+            timestamp_from: parsed_body.timestamp_from,
+            timestamp_to: parsed_body.timestamp_to,
+            user_ids: rustix_bl::datastore::UserGroup::AllUsers {},
+            comment: parsed_body.comment,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn update_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: EditBill = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::UpdateBill {
+            timestamp_from: parsed_body.timestamp_from,
+            timestamp_to: parsed_body.timestamp_to,
+            comment: parsed_body.comment,
+            users: rustix_bl::datastore::UserGroup::AllUsers {},
+            users_that_will_not_be_billed: parsed_body.exclude_user_ids,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn delete_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: DeleteUnfinishedBill = try_or_respond!(parse_body(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::DeleteUnfinishedBill {
+            timestamp_from: parsed_body.timestamp_from,
+            timestamp_to: parsed_body.timestamp_to,
+        };
+
+        return write_response(apply_event(req, event));
+    }
+
+    pub fn finalize_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: FinalizeBill = try_or_respond!(parse_body(req));
+        let conf = try_or_respond!(shared_config(req));
+        let prepaid_book = try_or_respond!(prepaid_book_snapshot(req));
+        let notifications = try_or_respond!(notification_context(req));
+        let devicesholder = try_or_respond!(shared_devices(req));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::FinalizeBill {
+            timestamp_from: parsed_body.timestamp_from,
+            timestamp_to: parsed_body.timestamp_to,
+        };
+
+        let result = apply_event_with(req, event, |_| Ok(()), |dat| {
+            notify_bill_ready(dat, &notifications, &conf, &prepaid_book, parsed_body.timestamp_from, parsed_body.timestamp_to);
+            let mut book = devicesholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            book.prune_origins(&[(parsed_body.timestamp_from, parsed_body.timestamp_to)]);
+            return Ok(());
+        });
+        return write_response(result);
+    }
+
+    pub fn export_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: ExportBill = try_or_respond!(parse_body(req));
+        let conf = try_or_respond!(shared_config(req));
+        let prepaid_book = try_or_respond!(prepaid_book_snapshot(req));
+        let device_column = try_or_respond!(device_column(req, parsed_body.timestamp_from, parsed_body.timestamp_to));
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::ExportBill {
+            timestamp_from: parsed_body.timestamp_from,
+            timestamp_to: parsed_body.timestamp_to,
+        };
+
+        let mut draft: Option<mailqueue::MailDraft> = None;
+        let result = apply_event_with(req, event, |_| Ok(()), |dat| {
+            draft = Some(build_bill_export_mail(dat, &parsed_body, &conf, &prepaid_book, &device_column)?);
+            return Ok(());
+        });
+
+        //the mail is only queued here, delivery happens in the mail worker
+        if let Some(draft) = draft {
+            let queueholder = try_or_respond!(shared_mail_queue(req));
+            let mut queue = queueholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let id = queue.enqueue(draft, Utc::now().timestamp());
+            queue.save();
+            info!("Queued bill export mail {} to {}", id, parsed_body.email_address);
+        }
+        return write_response(result);
+    }
+
+    fn build_bill_export_mail(
+        dat: &Backend,
+        parsed_body: &ExportBill,
+        conf: &ServerConfig,
+        prepaid_book: &prepaid::PrepaidBook,
+        device_column: &devices::DeviceColumn,
+    ) -> Result<mailqueue::MailDraft, ServerError> {
+        use rustix_bl::datastore::DatastoreQueries;
+
+        let profile: &AccountingProfile = &conf.accounting;
+        let invoice_settings: &InvoiceSettings = &conf.invoice;
+        let bill: rustix_bl::datastore::Bill = prepaid_book.without_prepaid_users(dat
+            .datastore
+            .get_bill(parsed_body.timestamp_from, parsed_body.timestamp_to)
+            .or_not_found("bill")?);
+
+        let zipfilename =
+            mail::two_numbers_to_string(parsed_body.timestamp_from, parsed_body.timestamp_to);
+
+        match parsed_body.limit_to_user {
+            Some(user_id) => {
+                let subject = format!(
+                    "Your Cervisia bill export on {}",
+                    Utc::now().format("%d.%m.%Y")
+                );
+                let body_cells = bill.format_as_personalized_documentation(&user_id, profile);
+
+                //TODO: replace delimiter by making it configurable
+                let mut lines: Vec<String> = Vec::new();
+                for line_vec in body_cells {
+                    lines.push(line_vec.join(";"));
+                }
+                let body: String = lines.join("\n");
+
+                let attachments: HashMap<String, String> = {
+                    let mut hm = HashMap::new();
+                    hm.insert("exported_bill.csv".to_string(), body);
+                    hm
                 };
 
-                let result = ServableRustixImpl::check_apply_write(&mut dat, param, event);
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
+                let mut binary_attachments: HashMap<String, Vec<u8>> = HashMap::new();
+                if parsed_body.attach_pdf {
+                    let pdf = bill.format_as_invoice_pdf(
+                        &user_id,
+                        profile,
+                        invoice_settings,
+                        get_date_today(),
+                    );
+                    if let Some(pdf) = pdf {
+                        binary_attachments.insert("invoice.pdf".to_string(), pdf);
                     }
                 }
+
+                let body = if binary_attachments.is_empty() {
+                    "Your bill is attached to this mail as a CSV file"
+                } else {
+                    "Your bill is attached to this mail as a CSV file and as a PDF invoice"
+                };
+
+                return Ok(mailqueue::MailDraft {
+                    receiver_email: parsed_body.email_address.to_string(),
+                    subject: subject,
+                    body: body.to_string(),
+                    attachments: attachments,
+                    binary_attachments: binary_attachments,
+                    zipfilename: zipfilename,
+                });
+            }
+            None => {
+                let subject = format!(
+                    "Cervisia bill export on {}",
+                    Utc::now().format("%d.%m.%Y")
+                );
+                let date_today = get_date_today();
+                info!("Building bill for admin at datestamp {}", &date_today);
+
+                // construct csv to attach to mail
+                let body_a_cells = bill.format_as_sewobe_csv(date_today, profile);
+                info!("Finished SEWOBE bill for admin");
+
+                // construct total list for all users
+                let body_b_cells = devices::with_device_column(
+                    bill.format_as_documentation(profile),
+                    &bill,
+                    device_column,
+                );
+                info!("Finished internal bill for admin");
+
+                // send both to receiver
+                let mut lines_a: Vec<String> = Vec::new();
+                let mut lines_b: Vec<String> = Vec::new();
+                for line_vec in body_a_cells {
+                    lines_a.push(line_vec.join(";"));
+                }
+                let body_a: String = lines_a.join("\n");
+                for line_vec in body_b_cells {
+                    lines_b.push(line_vec.join(";"));
+                }
+                let body_b: String = lines_b.join("\n");
+
+                let mut attachments: HashMap<String, String> = {
+                    let mut hm = HashMap::new();
+                    hm.insert("internal_oversight.csv".to_string(), body_b);
+                    hm.insert("sewobe_import.csv".to_string(), body_a);
+                    hm
+                };
+
+                //prepaid users are not part of the SEWOBE import, they are listed on their own
+                let mut body = "The bill is attached as two CSV files. One is to import into SEWOBE, the other is for internal tracking and contains additional information.".to_string();
+                let prepaid_lines = prepaid_export_lines(dat, &bill, prepaid_book, &conf.prepaid)?;
+                if prepaid_lines.len() > 1 {
+                    attachments.insert("prepaid_accounts.csv".to_string(), prepaid_lines.join("\n"));
+                    body = body + " Prepaid users are not billed, their consumption and balances are listed in a third file.";
+                }
+
+                return Ok(mailqueue::MailDraft {
+                    receiver_email: parsed_body.email_address.to_string(),
+                    subject: subject,
+                    body: body,
+                    attachments: attachments,
+                    binary_attachments: HashMap::new(),
+                    zipfilename: zipfilename,
+                });
+            }
+        }
+    }
+
+    //one line per prepaid user that consumed on the bill or has an account covering it, with header
+    fn prepaid_export_lines(
+        dat: &Backend,
+        bill: &rustix_bl::datastore::Bill,
+        prepaid_book: &prepaid::PrepaidBook,
+        settings: &prepaid::PrepaidSettings,
+    ) -> Result<Vec<String>, ServerError> {
+        let mut lines: Vec<String> = vec!["user_id;username;consumed_in_period;balance".to_string()];
+        for info in prepaid::account_infos(dat, prepaid_book, settings)? {
+            if !bill.users_that_will_not_be_billed.contains(&info.account.user_id) {
+                continue;
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+            let username = bill
+                .finalized_data
+                .all_users
+                .get(&info.account.user_id)
+                .or(dat.datastore.users.get(&info.account.user_id))
+                .map(|u| u.username.to_string())
+                .unwrap_or(String::new());
+            lines.push(format!(
+                "{};{};{};{}",
+                info.account.user_id,
+                username,
+                cents_to_currency_string_i64(prepaid::bill_spent_cents(bill, info.account.user_id)),
+                cents_to_currency_string_i64(info.balance.balance_cents)
+            ));
+        }
+        return Ok(lines);
+    }
+
+    //the live config, so bill exports pick up a reloaded accounting profile
+    fn shared_config(req: &mut iron::request::Request) -> Result<ServerConfig, ServerError> {
+        return req
+            .get::<State<SharedConfig>>()
+            .map(|live| current_config(&live))
+            .map_err(|_| ServerError::Internal("Configuration is not available".to_string()));
+    }
+
+    fn shared_mail_queue(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<mailqueue::MailQueue>>, ServerError> {
+        return req
+            .get::<State<SharedMailQueue>>()
+            .map_err(|_| ServerError::Internal("Mail queue is not available".to_string()));
+    }
+
+    pub fn list_queued_mails(req: &mut iron::request::Request) -> IronResult<Response> {
+        let queueholder = try_or_respond!(shared_mail_queue(req));
+        let queue = queueholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&queue.infos()).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn resend_queued_mail(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: mailqueue::ResendMail = try_or_respond!(parse_body(req));
+        let queueholder = try_or_respond!(shared_mail_queue(req));
+        let mut queue = queueholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !queue.resend(parsed_body.id, Utc::now().timestamp()) {
+            return Ok(error_response(ServerError::NotFound(format!(
+                "No queued mail with id {}",
+                parsed_body.id
+            ))));
+        }
+        queue.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&queue.infos()).unwrap_or(String::new()),
+        )));
+    }
+
+    fn shared_sepa_mandates(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<sepa::SepaMandates>>, ServerError> {
+        return req
+            .get::<State<SharedSepaMandates>>()
+            .map_err(|_| ServerError::Internal("SEPA mandates are not available".to_string()));
+    }
+
+    pub fn list_sepa_mandates(req: &mut iron::request::Request) -> IronResult<Response> {
+        let mandateholder = try_or_respond!(shared_sepa_mandates(req));
+        let mandates = mandateholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&mandates.mandates).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn set_sepa_mandate(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: sepa::SepaMandate = try_or_respond!(parse_body(req));
+        let mandate = parsed_body.normalized();
+        let problems = mandate.problems();
+        if !problems.is_empty() {
+            return Ok(error_response(ServerError::BadRequest(problems.join("; "))));
+        }
+        try_or_respond!(check_user_exists(req, mandate.user_id));
+
+        let mandateholder = try_or_respond!(shared_sepa_mandates(req));
+        let mut mandates = mandateholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        mandates.set(mandate);
+        mandates.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&mandates.mandates).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn delete_sepa_mandate(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: sepa::DeleteSepaMandate = try_or_respond!(parse_body(req));
+        let mandateholder = try_or_respond!(shared_sepa_mandates(req));
+        let mut mandates = mandateholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !mandates.remove(parsed_body.user_id) {
+            return Ok(error_response(ServerError::NotFound(format!(
+                "No SEPA mandate for user {}",
+                parsed_body.user_id
+            ))));
+        }
+        mandates.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&mandates.mandates).unwrap_or(String::new()),
+        )));
+    }
+
+    //the pain.008 file is handed to the bank directly, so it is only built for complete data
+    pub fn download_sepa_direct_debit(req: &mut iron::request::Request) -> IronResult<Response> {
+        let from: i64 = extract_query_param(req, "from").and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+        let to: i64 = extract_query_param(req, "to").and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+        let conf = try_or_respond!(shared_config(req));
+        let mandates: sepa::SepaMandates = {
+            let mandateholder = try_or_respond!(shared_sepa_mandates(req));
+            let mandates = mandateholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            mandates.clone()
+        };
+
+        let prepaid_book = try_or_respond!(prepaid_book_snapshot(req));
+
+        let document = {
+            let datholder = try_or_respond!(shared_backend(req));
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            use rustix_bl::datastore::DatastoreQueries;
+            let bill: rustix_bl::datastore::Bill = prepaid_book.without_prepaid_users(try_or_respond!(dat.datastore
+                .get_bill(from, to)
+                .or_not_found("bill with given params")));
+            bill.format_as_sepa_direct_debit(get_date_today(), &conf.accounting, &conf.sepa, &mandates)
+        };
+        let document = try_or_respond!(document.map_err(|problems| ServerError::Conflict(problems.join("; "))));
+
+        let content_type = "application/xml".parse::<mime::Mime>().unwrap();
+        let mut resp = Response::with((content_type, iron::status::Ok, document));
+        resp.headers.set(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Iso_8859_1,
+                None,
+                build_filename(to).replace(".csv", "_sepa.xml").into_bytes(),
+            )],
+        });
+        return Ok(resp);
+    }
+
+    fn shared_payment_ledger(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<payments::PaymentLedger>>, ServerError> {
+        return req
+            .get::<State<SharedPaymentLedger>>()
+            .map_err(|_| ServerError::Internal("Payments are not available".to_string()));
+    }
+
+    //payments may only reference existing users and bills that are no longer editable
+    fn check_payment(
+        req: &mut iron::request::Request,
+        user_id: u32,
+        amount_cents: i64,
+        bill_timestamp_from: Option<i64>,
+        bill_timestamp_to: Option<i64>,
+    ) -> Result<(), ServerError> {
+        let problems = payments::payment_problems(amount_cents, bill_timestamp_from, bill_timestamp_to);
+        if !problems.is_empty() {
+            return Err(ServerError::BadRequest(problems.join("; ")));
+        }
+        let datholder = shared_backend(req)?;
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        dat.datastore.users.get(&user_id).or_not_found("user")?;
+        if let (Some(from), Some(to)) = (bill_timestamp_from, bill_timestamp_to) {
+            use rustix_bl::datastore::DatastoreQueries;
+            let bill = dat.datastore.get_bill(from, to).or_not_found("bill")?;
+            if bill.bill_state == rustix_bl::datastore::BillState::Created {
+                return Err(ServerError::Conflict(
+                    "Payments can only be booked on finalized bills".to_string(),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn list_payments(req: &mut iron::request::Request) -> IronResult<Response> {
+        let user_id: Option<u32> = extract_query_param(req, "user_id").and_then(|s| s.parse::<u32>().ok());
+        let ledgerholder = try_or_respond!(shared_payment_ledger(req));
+        let ledger = ledgerholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&ledger.filtered(user_id)).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn add_payment(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: payments::CreatePayment = try_or_respond!(parse_body(req));
+        try_or_respond!(check_payment(
+            req,
+            parsed_body.user_id,
+            parsed_body.amount_cents,
+            parsed_body.bill_timestamp_from,
+            parsed_body.bill_timestamp_to
+        ));
+        let ledgerholder = try_or_respond!(shared_payment_ledger(req));
+        let mut ledger = ledgerholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let id = ledger.add(parsed_body, Utc::now().timestamp());
+        ledger.save();
+        let created = ledger.payments.iter().find(|p| p.id == id).cloned();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&created).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn update_payment(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: payments::Payment = try_or_respond!(parse_body(req));
+        try_or_respond!(check_payment(
+            req,
+            parsed_body.user_id,
+            parsed_body.amount_cents,
+            parsed_body.bill_timestamp_from,
+            parsed_body.bill_timestamp_to
+        ));
+        let ledgerholder = try_or_respond!(shared_payment_ledger(req));
+        let mut ledger = ledgerholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let id = parsed_body.id;
+        if !ledger.update(parsed_body.clone()) {
+            return Ok(error_response(ServerError::NotFound(format!("No payment with id {}", id))));
+        }
+        ledger.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&parsed_body).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn delete_payment(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: payments::DeletePayment = try_or_respond!(parse_body(req));
+        let ledgerholder = try_or_respond!(shared_payment_ledger(req));
+        let mut ledger = ledgerholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !ledger.remove(parsed_body.id) {
+            return Ok(error_response(ServerError::NotFound(format!(
+                "No payment with id {}",
+                parsed_body.id
+            ))));
+        }
+        ledger.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&ledger.filtered(None)).unwrap_or(String::new()),
+        )));
+    }
+
+    //only_open=true hides settled users, format=csv gives the list the treasurer used to keep in a spreadsheet
+    pub fn open_balances(req: &mut iron::request::Request) -> IronResult<Response> {
+        let user_id: Option<u32> = extract_query_param(req, "user_id").and_then(|s| s.parse::<u32>().ok());
+        let only_open: bool = extract_query_param(req, "only_open") == Some("true".to_string());
+        let as_csv: bool = extract_query_param(req, "format") == Some("csv".to_string());
+        let conf = try_or_respond!(shared_config(req));
+        let ledger: payments::PaymentLedger = {
+            let ledgerholder = try_or_respond!(shared_payment_ledger(req));
+            let ledger = ledgerholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            ledger.clone()
+        };
+        let prepaid_book = try_or_respond!(prepaid_book_snapshot(req));
+
+        let mut balances: Vec<payments::UserBalance> = {
+            let datholder = try_or_respond!(shared_backend(req));
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            use rustix_bl::datastore::DatastoreQueries;
+            let bills: Vec<rustix_bl::datastore::Bill> = dat
+                .datastore
+                .bills_filtered(None, -10000000000000000i64, 10000000000000000i64)
+                .iter()
+                .map(|b| prepaid_book.without_prepaid_users(b))
+                .collect();
+            payments::user_balances(&bills, &ledger, &dat.datastore.users, &conf.accounting)
+        };
+        balances.retain(|b| user_id.map(|u| u == b.user_id).unwrap_or(true));
+        if only_open {
+            balances.retain(|b| b.open_cents != 0);
+        }
+
+        if !as_csv {
+            return Ok(Response::with((
+                iron::status::Ok,
+                serde_json::to_string(&balances).unwrap_or(String::new()),
+            )));
+        }
+
+        let mut lines: Vec<String> = vec!["user_id;username;billed;paid;open".to_string()];
+        for balance in &balances {
+            lines.push(format!(
+                "{};{};{};{};{}",
+                balance.user_id,
+                balance.username,
+                cents_to_currency_string_i64(balance.billed_cents),
+                cents_to_currency_string_i64(balance.paid_cents),
+                cents_to_currency_string_i64(balance.open_cents)
+            ));
+        }
+        let content_type = "text/csv".parse::<mime::Mime>().unwrap();
+        let mut resp = Response::with((content_type, iron::status::Ok, lines.join("\n")));
+        resp.headers.set(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Iso_8859_1,
+                None,
+                b"open_balances.csv".to_vec(),
+            )],
+        });
+        return Ok(resp);
+    }
+
+    fn shared_dunning_book(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<dunning::DunningBook>>, ServerError> {
+        return req
+            .get::<State<SharedDunningBook>>()
+            .map_err(|_| ServerError::Internal("Dunning history is not available".to_string()));
+    }
+
+    pub fn list_dunning(req: &mut iron::request::Request) -> IronResult<Response> {
+        let bookholder = try_or_respond!(shared_dunning_book(req));
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.users).unwrap_or(String::new()),
+        )));
+    }
+
+    fn check_user_exists(req: &mut iron::request::Request, user_id: u32) -> Result<(), ServerError> {
+        let datholder = shared_backend(req)?;
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        dat.datastore.users.get(&user_id).or_not_found("user")?;
+        return Ok(());
+    }
+
+    pub fn set_dunning_contact(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: dunning::SetDunningContact = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let email = parsed_body.email.map(|e| e.trim().to_string()).filter(|e| !e.is_empty());
+        if email.as_ref().map(|e| !e.contains('@')).unwrap_or(false) {
+            return Ok(error_response(ServerError::BadRequest(
+                "email is not a mail address".to_string(),
+            )));
+        }
+        let bookholder = try_or_respond!(shared_dunning_book(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        book.entry(parsed_body.user_id).email = email;
+        book.save();
+        let entry = book.get(parsed_body.user_id).cloned();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&entry).unwrap_or(String::new()),
+        )));
+    }
+
+    //paused users keep their history, reminders continue at the next level once unpaused
+    pub fn pause_dunning(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: dunning::PauseDunning = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let bookholder = try_or_respond!(shared_dunning_book(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        book.entry(parsed_body.user_id).paused = parsed_body.paused;
+        book.save();
+        let entry = book.get(parsed_body.user_id).cloned();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&entry).unwrap_or(String::new()),
+        )));
+    }
+
+    fn shared_inventory(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<inventory::Inventory>>, ServerError> {
+        return req
+            .get::<State<SharedInventory>>()
+            .map_err(|_| ServerError::Internal("Inventory is not available".to_string()));
+    }
+
+    fn check_item_exists(req: &mut iron::request::Request, item_id: u32) -> Result<(), ServerError> {
+        let datholder = shared_backend(req)?;
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        dat.datastore.items.get(&item_id).or_not_found("item")?;
+        return Ok(());
+    }
+
+    fn stock_levels(req: &mut iron::request::Request) -> Result<Vec<inventory::StockLevel>, ServerError> {
+        let conf = shared_config(req)?;
+        let inventoryholder = shared_inventory(req)?;
+        let datholder = shared_backend(req)?;
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let inventory = inventoryholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(inventory.stock_levels(&dat.datastore.items, &conf.inventory));
+    }
+
+    pub fn list_stock_levels(req: &mut iron::request::Request) -> IronResult<Response> {
+        let levels = try_or_respond!(stock_levels(req));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&levels).unwrap_or(String::new()),
+        )));
+    }
+
+    //deliveries are the only way to raise the stock besides undone purchases and stocktaking
+    pub fn restock_item(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: inventory::Restock = try_or_respond!(parse_body(req));
+        try_or_respond!(check_item_exists(req, parsed_body.item_id));
+        {
+            let inventoryholder = try_or_respond!(shared_inventory(req));
+            let mut inventory = inventoryholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            try_or_respond!(inventory.restock(parsed_body, current_time_millis()));
+            inventory.save();
+        }
+        return list_stock_levels(req);
+    }
+
+    pub fn take_stock(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: inventory::Stocktaking = try_or_respond!(parse_body(req));
+        for count in &parsed_body.counts {
+            try_or_respond!(check_item_exists(req, count.item_id));
+        }
+        let items: HashMap<u32, rustix_bl::datastore::Item> = {
+            let datholder = try_or_respond!(shared_backend(req));
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            dat.datastore.items.clone()
         };
+        let inventoryholder = try_or_respond!(shared_inventory(req));
+        let mut inventory = inventoryholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let report = try_or_respond!(inventory.stocktaking(parsed_body, &items, current_time_millis()));
+        inventory.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&report).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn set_low_stock_threshold(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: inventory::SetLowStockThreshold = try_or_respond!(parse_body(req));
+        try_or_respond!(check_item_exists(req, parsed_body.item_id));
+        {
+            let inventoryholder = try_or_respond!(shared_inventory(req));
+            let mut inventory = inventoryholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            inventory.entry(parsed_body.item_id).low_stock_threshold = parsed_body.low_stock_threshold;
+            inventory.save();
+        }
+        return list_stock_levels(req);
+    }
+
+    pub fn list_stock_movements(req: &mut iron::request::Request) -> IronResult<Response> {
+        let item_id: Option<u32> = extract_query_param(req, "item_id").and_then(|s| s.parse::<u32>().ok());
+        let inventoryholder = try_or_respond!(shared_inventory(req));
+        let log = inventoryholder.read().unwrap_or_else(|poisoned| poisoned.into_inner()).movement_log();
+        let movements: Vec<inventory::StockMovement> = log
+            .read()
+            .into_iter()
+            .filter(|m| item_id.map(|i| i == m.item_id).unwrap_or(true))
+            .collect();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&movements).unwrap_or(String::new()),
+        )));
+    }
+
+    //from and to limit the counted movements, format=csv downloads the report like a bill
+    pub fn inventory_report(req: &mut iron::request::Request) -> IronResult<Response> {
+        let from: i64 = extract_query_param(req, "from").and_then(|s| s.parse::<i64>().ok()).unwrap_or(std::i64::MIN);
+        let to: i64 = extract_query_param(req, "to").and_then(|s| s.parse::<i64>().ok()).unwrap_or(std::i64::MAX);
+        let as_csv: bool = extract_query_param(req, "format") == Some("csv".to_string());
+        let report: Vec<inventory::InventoryReportLine> = {
+            let inventoryholder = try_or_respond!(shared_inventory(req));
+            let datholder = try_or_respond!(shared_backend(req));
+            let log = inventoryholder.read().unwrap_or_else(|poisoned| poisoned.into_inner()).movement_log();
+            let movements = log.read();
+            let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let inventory = inventoryholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            inventory.report(&dat.datastore.items, &movements, from, to)
+        };
+
+        if !as_csv {
+            return Ok(Response::with((
+                iron::status::Ok,
+                serde_json::to_string(&report).unwrap_or(String::new()),
+            )));
+        }
+
+        let content_type = "text/csv".parse::<mime::Mime>().unwrap();
+        let mut resp = Response::with((
+            content_type,
+            iron::status::Ok,
+            inventory::format_report_as_csv(&report),
+        ));
+        resp.headers.set(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Iso_8859_1,
+                None,
+                build_filename(current_time_millis()).replace("_abrechnung.csv", "_inventur.csv").into_bytes(),
+            )],
+        });
+        return Ok(resp);
+    }
+
+    pub fn reorder_list(req: &mut iron::request::Request) -> IronResult<Response> {
+        let conf = try_or_respond!(shared_config(req));
+        let inventoryholder = try_or_respond!(shared_inventory(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let inventory = inventoryholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let list = alerts::reorder_list(&inventory, &dat.datastore.items, &conf.inventory, &conf.alerts, current_time_millis());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&list).unwrap_or(String::new()),
+        )));
+    }
+
+    fn shared_event_hub(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<events::EventHub>>, ServerError> {
+        return req
+            .get::<State<SharedEventHub>>()
+            .map_err(|_| ServerError::Internal("Live updates are not available".to_string()));
+    }
+
+    //server-sent events, EventSource sends Last-Event-ID on reconnects, last_event_id works for other clients
+    pub fn live_events(req: &mut iron::request::Request) -> IronResult<Response> {
+        let from_header: Option<u64> = req
+            .headers
+            .get_raw("Last-Event-ID")
+            .and_then(|values| values.first())
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        let last_seen = from_header.or(extract_query_param(req, "last_event_id").and_then(|s| s.parse::<u64>().ok()));
+        let client = req.remote_addr.ip().to_string();
+        let hubholder = try_or_respond!(shared_event_hub(req));
+        let stream: Box<dyn iron::response::WriteBody> = {
+            let hub = hubholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            Box::new(try_or_respond!(hub.subscribe(&client, last_seen)))
+        };
+        let content_type = "text/event-stream".parse::<mime::Mime>().unwrap();
+        let mut resp = Response::with((content_type, iron::status::Ok, stream));
+        resp.headers.set(iron::headers::CacheControl(vec![iron::headers::CacheDirective::NoCache]));
+        return Ok(resp);
+    }
+
+    fn shared_webhook_book(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<webhooks::WebhookBook>>, ServerError> {
+        return req
+            .get::<State<SharedWebhookBook>>()
+            .map_err(|_| ServerError::Internal("Webhook subscriptions are not available".to_string()));
+    }
+
+    pub fn list_webhooks(req: &mut iron::request::Request) -> IronResult<Response> {
+        let bookholder = try_or_respond!(shared_webhook_book(req));
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn add_webhook(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: webhooks::CreateWebhookSubscription = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_webhook_book(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.subscribe(parsed_body, Utc::now().timestamp()));
+        book.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn delete_webhook(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: webhooks::WebhookSubscriptionId = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_webhook_book(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !book.unsubscribe(parsed_body.id) {
+            return Ok(error_response(ServerError::NotFound(format!(
+                "No webhook subscription with id {}",
+                parsed_body.id
+            ))));
+        }
+        book.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn test_webhook(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: webhooks::WebhookSubscriptionId = try_or_respond!(parse_body(req));
+        let conf = try_or_respond!(shared_config(req));
+        let bookholder = try_or_respond!(shared_webhook_book(req));
+        let delivery = try_or_respond!(webhooks::fire_test(&bookholder, parsed_body.id, &conf.webhooks));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&delivery).unwrap_or(String::new()),
+        )));
+    }
+
+    pub fn list_webhook_deliveries(req: &mut iron::request::Request) -> IronResult<Response> {
+        let subscription_id: Option<u64> =
+            extract_query_param(req, "subscription_id").and_then(|s| s.parse::<u64>().ok());
+        let bookholder = try_or_respond!(shared_webhook_book(req));
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.log(subscription_id)).unwrap_or(String::new()),
+        )));
+    }
+
+    fn shared_devices(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<devices::DeviceBook>>, ServerError> {
+        return req
+            .get::<State<SharedDevices>>()
+            .map_err(|_| ServerError::Internal("Devices are not available".to_string()));
+    }
+
+    fn calling_device(req: &iron::request::Request) -> Option<devices::Device> {
+        return req.extensions.get::<devices::CurrentDevice>().cloned();
+    }
+
+    //called by the kiosk itself, it stays pending until an admin approves it
+    pub fn register_device(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: devices::RegisterDevice = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_devices(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let info = try_or_respond!(book.register(parsed_body, Utc::now().timestamp()));
+        book.save();
+        info!("Device '{}' registered with id {}, waiting for approval", info.name, info.id);
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&info).unwrap_or(String::new()),
+        )));
+    }
+
+    //null for requests without a device key
+    pub fn current_device(req: &mut iron::request::Request) -> IronResult<Response> {
+        let info: Option<devices::DeviceInfo> = calling_device(req).map(|d| devices::DeviceInfo::from(&d));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&info).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn create_count_freeby(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateCountGiveout = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeCount {
-                    allowed_categories: parsed_body.allowed_categories,
-                    allowed_drinks: parsed_body.allowed_drinks,
-                    allowed_number_total: parsed_body.allowed_number_total,
-                    text_message: parsed_body.text_message,
-                    created_timestamp: current_time_millis(),
-                    donor: parsed_body.donor,
-                    recipient: parsed_body.recipient,
-                };
+    pub fn list_devices(req: &mut iron::request::Request) -> IronResult<Response> {
+        let bookholder = try_or_respond!(shared_devices(req));
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
+    }
 
-                let result = ServableRustixImpl::check_apply_write(&mut dat, param, event);
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn approve_device(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: devices::ApproveDevice = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_devices(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let issued = try_or_respond!(book.approve(parsed_body, Utc::now().timestamp()));
+        book.save();
+        info!("Approved device {} as {:?}", issued.id, issued.role);
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&issued).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn create_ffa_freeby(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateFreeForAll = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let event = rustix_bl::rustix_event_shop::BLEvents::CreateFreeForAll {
-                    allowed_categories: parsed_body.allowed_categories,
-                    allowed_drinks: parsed_body.allowed_drinks,
-                    allowed_number_total: parsed_body.allowed_number_total,
-                    text_message: parsed_body.text_message,
-                    created_timestamp: current_time_millis(),
-                    donor: parsed_body.donor,
-                };
+    pub fn revoke_device(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: devices::DeviceId = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_devices(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.revoke(parsed_body.id));
+        book.save();
+        info!("Revoked device {}", parsed_body.id);
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
+    }
 
-                let result = ServableRustixImpl::check_apply_write(&mut dat, param, event);
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+    //purchases without a device key have no recorded origin
+    fn record_purchase_origins(
+        dat: &Backend,
+        devicesholder: &Arc<RwLock<devices::DeviceBook>>,
+        device: &Option<devices::Device>,
+        user_id: u32,
+        timestamp: i64,
+    ) {
+        let device_id = match *device {
+            Some(ref device) => device.id,
+            None => return,
         };
+        let origins = devices::origins_of_event(dat, device_id, user_id, timestamp);
+        let mut book = devicesholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        book.record_origins(origins);
     }
 
-    pub fn add_user(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateUser = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::CreateUser {
-                        username: parsed_body.username,
-                    },
-                );
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+    //exports of big bills should not copy every origin while the device lock is held
+    fn device_column(
+        req: &mut iron::request::Request,
+        timestamp_from: i64,
+        timestamp_to: i64,
+    ) -> Result<devices::DeviceColumn, ServerError> {
+        let bookholder = shared_devices(req)?;
+        let mut column = {
+            let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            book.device_column(timestamp_from, timestamp_to)
         };
+        column.load_logged_origins();
+        return Ok(column);
     }
 
-    pub fn delete_user(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: DeleteUser = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::DeleteUser {
-                        user_id: parsed_body.user_id,
-                    },
-                );
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+    //like the low stock warnings, the device names travel next to the paginated purchases
+    fn attach_purchase_devices(log: &mut serde_json::Value, book: &devices::DeviceBook) {
+        let purchase_ids: Vec<u64> = match log.get("results").and_then(|r| r.as_array()) {
+            Some(results) => results
+                .iter()
+                .filter_map(|p| p.as_object().and_then(|o| o.values().next()))
+                .filter_map(|p| p.get("unique_id").and_then(|id| id.as_u64()))
+                .collect(),
+            None => return,
         };
+        log["purchase_devices"] = serde_json::to_value(&book.device_names_for_purchases(&purchase_ids))
+            .unwrap_or(serde_json::Value::Null);
     }
 
-    pub fn delete_item(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: DeleteItem = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::DeleteItem {
-                        item_id: parsed_body.item_id,
-                    },
-                );
+    fn shared_credentials(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<credentials::CredentialBook>>, ServerError> {
+        return req
+            .get::<State<SharedCredentials>>()
+            .map_err(|_| ServerError::Internal("User credentials are not available".to_string()));
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    fn credential_infos_response(book: &credentials::CredentialBook) -> IronResult<Response> {
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&book.infos()).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn update_user(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: UpdateUser = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::UpdateUser {
-                        user_id: parsed_body.user_id,
-                        username: parsed_body.username,
-                        is_billed: parsed_body.is_billed,
-                        is_highlighted: parsed_body.highlight_in_ui,
-                        external_user_id: parsed_body.external_user_id,
-                        is_sepa: parsed_body.is_sepa,
-                    },
-                );
+    pub fn list_credentials(req: &mut iron::request::Request) -> IronResult<Response> {
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return credential_infos_response(&book);
+    }
 
-                println!("Going to change user to new state: result = {:?}", result);
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn set_pin(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: credentials::SetPin = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let conf = try_or_respond!(shared_config(req));
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.set_pin(parsed_body.user_id, parsed_body.pin, &conf.credentials));
+        book.save();
+        return credential_infos_response(&book);
     }
 
-    pub fn add_item(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateItem = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::CreateItem {
-                        itemname: parsed_body.name,
-                        price_cents: parsed_body.price_cents,
-                        category: parsed_body.category,
-                    },
-                );
+    pub fn add_card(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: credentials::CardAssignment = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.add_card(parsed_body.user_id, &parsed_body.card_uid));
+        book.save();
+        return credential_infos_response(&book);
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn remove_card(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: credentials::CardAssignment = try_or_respond!(parse_body(req));
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.remove_card(parsed_body.user_id, &parsed_body.card_uid));
+        book.save();
+        return credential_infos_response(&book);
     }
 
-    pub fn create_bill(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: CreateBill = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::CreateBill {
-                        timestamp_from: parsed_body.timestamp_from,
-                        timestamp_to: parsed_body.timestamp_to,
-                        user_ids: rustix_bl::datastore::UserGroup::AllUsers {},
-                        comment: parsed_body.comment,
-                    },
-                );
+    //members opt in (or out) themselves, proving it is them with the credential they want to require
+    pub fn set_credential_required(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: credentials::SetCredentialRequired = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let conf = try_or_respond!(shared_config(req));
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let matched = try_or_respond!(credentials::match_credential(
+            &bookholder,
+            parsed_body.user_id,
+            Some(&parsed_body.credential),
+            Utc::now().timestamp(),
+        ));
+        let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        try_or_respond!(book.verify(parsed_body.user_id, matched, &conf.credentials, Utc::now().timestamp()));
+        try_or_respond!(book.set_required(parsed_body.user_id, parsed_body.required));
+        book.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&parsed_body.required).unwrap_or(String::new()),
+        )));
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+    //failed lookups are rate limited per client address
+    pub fn resolve_card(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: credentials::ResolveCard = try_or_respond!(parse_body(req));
+        let client = req.remote_addr.ip().to_string();
+        let conf = try_or_respond!(shared_config(req));
+        let bookholder = try_or_respond!(shared_credentials(req));
+        let user_id = {
+            let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            try_or_respond!(book.resolve_card(&parsed_body.card_uid, &client, &conf.credentials, Utc::now().timestamp()))
         };
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let user = try_or_respond!(dat.datastore.users.get(&user_id).or_not_found("user"));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&credentials::ResolvedCard {
+                user_id: user_id,
+                username: user.username.to_string(),
+            }).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn update_bill(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: EditBill = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::UpdateBill {
-                        timestamp_from: parsed_body.timestamp_from,
-                        timestamp_to: parsed_body.timestamp_to,
-                        comment: parsed_body.comment,
-                        users: rustix_bl::datastore::UserGroup::AllUsers {},
-                        users_that_will_not_be_billed: parsed_body.exclude_user_ids,
-                    },
-                );
+    fn shared_notification_preferences(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<notifier::NotificationPreferences>>, ServerError> {
+        return req
+            .get::<State<SharedNotificationPreferences>>()
+            .map_err(|_| ServerError::Internal("Notification preferences are not available".to_string()));
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+    pub fn get_notification_preferences(req: &mut iron::request::Request) -> IronResult<Response> {
+        let user_id: u32 = match extract_query_param(req, "user_id").and_then(|s| s.parse::<u32>().ok()) {
+            Some(user_id) => user_id,
+            None => {
+                return Ok(error_response(ServerError::BadRequest(
+                    "user_id is missing".to_string(),
+                )))
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
         };
+        try_or_respond!(check_user_exists(req, user_id));
+        let preferencesholder = try_or_respond!(shared_notification_preferences(req));
+        let preferences = preferencesholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&preferences.get(user_id)).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn delete_bill(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: DeleteUnfinishedBill = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::DeleteUnfinishedBill {
-                        timestamp_from: parsed_body.timestamp_from,
-                        timestamp_to: parsed_body.timestamp_to,
-                    },
-                );
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn set_notification_preference(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: notifier::SetNotificationPreference = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        let preferencesholder = try_or_respond!(shared_notification_preferences(req));
+        let mut preferences = preferencesholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entry = preferences.set(&parsed_body);
+        preferences.save();
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&entry).unwrap_or(String::new()),
+        )));
     }
 
-    pub fn finalize_bill(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: FinalizeBill = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::FinalizeBill {
-                        timestamp_from: parsed_body.timestamp_from,
-                        timestamp_to: parsed_body.timestamp_to,
-                    },
-                );
+    fn shared_prepaid_book(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<prepaid::PrepaidBook>>, ServerError> {
+        return req
+            .get::<State<SharedPrepaidBook>>()
+            .map_err(|_| ServerError::Internal("Prepaid accounts are not available".to_string()));
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    //a copy, so exports never hold the prepaid lock while formatting bills
+    fn prepaid_book_snapshot(req: &mut iron::request::Request) -> Result<prepaid::PrepaidBook, ServerError> {
+        let bookholder = shared_prepaid_book(req)?;
+        let book = bookholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(book.clone());
     }
 
-    pub fn export_bill(
+    fn prepaid_account_infos(
         req: &mut iron::request::Request,
-        conf: &configuration::ServerConfig,
-    ) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: ExportBill = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::ExportBill {
-                        timestamp_from: parsed_body.timestamp_from,
-                        timestamp_to: parsed_body.timestamp_to,
-                    },
-                );
+    ) -> Result<Vec<prepaid::PrepaidAccountInfo>, ServerError> {
+        let conf = shared_config(req)?;
+        let book = prepaid_book_snapshot(req)?;
+        let datholder = shared_backend(req)?;
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return prepaid::account_infos(&dat, &book, &conf.prepaid);
+    }
 
-                match result {
-                    Ok(sux) => {
-                        use rustix_bl::datastore::DatastoreQueries;
-                        let bill: rustix_bl::datastore::Bill = dat
-                            .datastore
-                            .get_bill(parsed_body.timestamp_from, parsed_body.timestamp_to)
-                            .unwrap()
-                            .clone();
-
-                        match parsed_body.limit_to_user {
-                            Some(user_id) => {
-                                let subject = format!(
-                                    "Your Cervisia bill export on {}",
-                                    Utc::now().format("%d.%m.%Y")
-                                );
-                                let body_cells =
-                                    bill.format_as_personalized_documentation(&user_id);
-                                //TODO: replace delimiter by making it configurable
-
-                                let mut lines: Vec<String> = Vec::new();
-
-                                for line_vec in body_cells {
-                                    lines.push(line_vec.join(";"));
-                                }
-                                let body: String = lines.join("\n");
-
-                                let attachments: HashMap<
-                                    String,
-                                    String,
-                                > = {
-                                    let mut hm = HashMap::new();
-                                    hm.insert("exported_bill.csv".to_string(), body);
-                                    hm
-                                };
-
-                                let emailresponse = mail::send_mail(
-                                    &parsed_body.email_address,
-                                    &subject,
-                                    "Your bill is attached to this mail as a CSV file",
-                                    &attachments,
-                                    conf,
-                                    &mail::two_numbers_to_string(
-                                        parsed_body.timestamp_from,
-                                        parsed_body.timestamp_to,
-                                    ),
-                                );
-                                if emailresponse.is_ok() {
-                                    info!("email successfully send: {:?}", emailresponse.unwrap());
-                                } else {
-                                    error!(
-                                        "email sending failed: {:?}",
-                                        emailresponse.err().unwrap()
-                                    );
-                                }
-                            }
-                            None => {
-                                let subject = format!(
-                                    "Cervisia bill export on {}",
-                                    Utc::now().format("%d.%m.%Y")
-                                );
-                                let date_today = get_date_today();
-                                info!("Building bill for admin at datestamp {}", &date_today);
-                                // construct csv to attach to mail
-                                let body_a_cells = bill.format_as_sewobe_csv(date_today);
-                                info!("Finished SEWOBE bill for admin");
-                                // construct total list for all users
-                                let body_b_cells = bill.format_as_documentation();
-                                info!("Finished internal bill for admin");
-
-                                // send both to receiver
-                                let mut lines_a: Vec<String> = Vec::new();
-                                let mut lines_b: Vec<String> = Vec::new();
-
-                                for line_vec in body_a_cells {
-                                    lines_a.push(line_vec.join(";"));
-                                }
-                                let body_a: String = lines_a.join("\n");
-
-                                for line_vec in body_b_cells {
-                                    lines_b.push(line_vec.join(";"));
-                                }
-                                let body_b: String = lines_b.join("\n");
-
-                                let attachments: HashMap<String, String> = {
-                                    let mut hm = HashMap::new();
-                                    hm.insert("internal_oversight.csv".to_string(), body_b);
-                                    hm.insert("sewobe_import.csv".to_string(), body_a);
-                                    hm
-                                };
-
-                                info!("now trying to send attachments");
-
-                                let emailresponse = mail::send_mail(&parsed_body.email_address, &subject, "The bill is attached as two CSV files. One is to import into SEWOBE, the other is for internal tracking and contains additional information.", &attachments, conf, &mail::two_numbers_to_string(parsed_body.timestamp_from, parsed_body.timestamp_to));
-                                if emailresponse.is_ok() {
-                                    info!("email successfully send: {:?}", emailresponse.unwrap());
-                                } else {
-                                    error!(
-                                        "email sending failed: {:?}",
-                                        emailresponse.err().unwrap()
-                                    );
-                                }
-                            }
-                        }
+    pub fn list_prepaid_accounts(req: &mut iron::request::Request) -> IronResult<Response> {
+        let infos = try_or_respond!(prepaid_account_infos(req));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(&infos).unwrap_or(String::new()),
+        )));
+    }
 
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+    //the first top up turns a post-paid user into a prepaid user
+    pub fn top_up_prepaid_account(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: prepaid::RecordTopUp = try_or_respond!(parse_body(req));
+        try_or_respond!(check_user_exists(req, parsed_body.user_id));
+        {
+            let bookholder = try_or_respond!(shared_prepaid_book(req));
+            let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            try_or_respond!(book.top_up(parsed_body, current_time_millis()));
+            book.save();
+        }
+        return list_prepaid_accounts(req);
+    }
+
+    pub fn set_prepaid_overdraft_limit(req: &mut iron::request::Request) -> IronResult<Response> {
+        let parsed_body: prepaid::SetOverdraftLimit = try_or_respond!(parse_body(req));
+        if parsed_body.overdraft_limit_cents.map(|l| l < 0).unwrap_or(false) {
+            return Ok(error_response(ServerError::BadRequest(
+                "overdraft_limit_cents must not be negative".to_string(),
+            )));
+        }
+        {
+            let bookholder = try_or_respond!(shared_prepaid_book(req));
+            let mut book = bookholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if book.get(parsed_body.user_id).is_none() {
+                return Ok(error_response(ServerError::NotFound(format!(
+                    "No prepaid account for user {}",
+                    parsed_body.user_id
+                ))));
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+            book.entry(parsed_body.user_id, current_time_millis()).overdraft_limit_cents =
+                parsed_body.overdraft_limit_cents;
+            book.save();
+        }
+        return list_prepaid_accounts(req);
     }
 
     pub fn set_special_price(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: SetPriceForSpecial = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::SetPriceForSpecial {
-                        unique_id: parsed_body.unique_id,
-                        price: parsed_body.price,
-                    },
-                );
+        let parsed_body: SetPriceForSpecial = try_or_respond!(parse_body(req));
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+        let event = rustix_bl::rustix_event_shop::BLEvents::SetPriceForSpecial {
+            unique_id: parsed_body.unique_id,
+            price: parsed_body.price,
         };
+
+        return write_response(apply_event(req, event));
     }
 
     pub fn update_item(req: &mut iron::request::Request) -> IronResult<Response> {
-        let posted_body = extract_body(req);
-        debug!("posted_body = {:?}", posted_body);
-        let parsed_body: UpdateItem = serde_json::from_str(&posted_body).unwrap();
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let mut dat = datholder.write().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAll = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::check_apply_write(
-                    &mut dat,
-                    param,
-                    rustix_bl::rustix_event_shop::BLEvents::UpdateItem {
-                        item_id: parsed_body.item_id,
-                        itemname: parsed_body.name,
-                        price_cents: parsed_body.price_cents,
-                        category: parsed_body.category,
-                    },
-                );
+        let parsed_body: UpdateItem = try_or_respond!(parse_body(req));
+        let barcodeholder = try_or_respond!(shared_barcodes(req));
+        let validated: std::cell::RefCell<Option<Vec<String>>> = std::cell::RefCell::new(None);
+        let item_id = parsed_body.item_id;
+        let barcodes = parsed_body.barcodes;
+
+        let event = rustix_bl::rustix_event_shop::BLEvents::UpdateItem {
+            item_id: item_id,
+            itemname: parsed_body.name,
+            price_cents: parsed_body.price_cents,
+            category: parsed_body.category,
+        };
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: None,
-                                is_success: true,
-                                content: Some(SuccessContent {
-                                    timestamp_epoch_millis: current_time_millis(),
-                                    refreshed_data: sux,
-                                }),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                    Err(err) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&ServerWriteResult {
-                                error_message: Some(err.description().to_string()),
-                                is_success: false,
-                                content: None,
-                            })
-                            .unwrap(),
-                        )));
+        let result = apply_event_with(
+            req,
+            event,
+            |dat| {
+                if let Some(ref codes) = barcodes {
+                    let book = barcodeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    *validated.borrow_mut() = Some(book.validate(Some(item_id), codes, &dat.datastore.items)?);
+                }
+                return Ok(());
+            },
+            |_| {
+                if let Some(codes) = validated.replace(None) {
+                    let mut book = barcodeholder.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if book.set(item_id, codes) {
+                        book.save();
                     }
                 }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+                return Ok(());
+            },
+        );
+        return write_response(result);
     }
 
-    pub fn top_items(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersTopPersonalDrinks = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::TopPersonalDrinks(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    //the configured top items count caps what clients may ask for
+    pub fn top_items(req: &mut iron::request::Request, conf: &ServerConfig) -> IronResult<Response> {
+        let limit = std::cmp::min(conf.top_items_per_user, u8::max_value() as u16) as u8;
+        return query_backend(req, |mut param: ParametersTopPersonalDrinks| {
+            param.n = std::cmp::min(param.n, limit);
+            ReadQueryParams::TopPersonalDrinks(param)
+        });
     }
 
     pub fn get_ffa_giveouts(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersOpenFFAFreebies = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::OpenFFAFreebies(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        return query_backend(req, ReadQueryParams::OpenFFAFreebies);
     }
 
     pub fn get_incoming_giveouts(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersIncomingFreebies = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::IncomingFreebies(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        return query_backend(req, ReadQueryParams::IncomingFreebies);
     }
 
     pub fn get_outgoing_giveouts(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersOutgoingFreebies = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::OutgoingFreebies(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        return query_backend(req, ReadQueryParams::OutgoingFreebies);
     }
 
     pub fn database_export_to_string(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-
-        println!("beginning processing of request");
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
 
         match &dat.persistencer.load_into_string() {
             Ok(sux) => {
@@ -2217,328 +3461,173 @@ fn log_purchase(
                 )));
             }
             Err(_) => {
-                return Ok(Response::with((
-                    iron::status::InternalServerError,
-                                             "Internal error happened"),
-                ));
+                return Ok(error_response(ServerError::Internal(
+                    "Could not export database".to_string(),
+                )));
             }
         }
     }
 
     pub fn all_users(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAllUsers = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::query_read(&dat, ReadQueryParams::AllUsers(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+        return query_backend(req, ReadQueryParams::AllUsers);
+    }
+
+    //a scanned barcode typed into the search finds its item, everything else is the name search of the backend
+    pub fn all_items(req: &mut iron::request::Request) -> IronResult<Response> {
+        let param: ParametersAllItems = try_or_respond!(parse_query(req));
+        let barcodeholder = try_or_respond!(shared_barcodes(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let book = barcodeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut items = match book.item_for(&param.count_pars.searchterm, &dat.datastore.items) {
+            Some(item_id) => {
+                let result: PaginatedResult<rustix_bl::datastore::Item> = PaginatedResult {
+                    total_count: 1,
+                    from: param.pagination.start_inclusive,
+                    to: param.pagination.end_exclusive,
+                    results: dat
+                        .datastore
+                        .items
+                        .get(&item_id)
+                        .into_iter()
+                        .cloned()
+                        .take(param.pagination.end_exclusive as usize)
+                        .skip(param.pagination.start_inclusive as usize)
+                        .collect(),
+                };
+                try_or_respond!(serde_json::to_value(&result).map_err(ServerError::from))
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
+            None => try_or_respond!(ServableRustixImpl::query_read(&dat, ReadQueryParams::AllItems(param))),
         };
+        attach_item_barcodes(&mut items, &book);
+        return read_response(Ok(items));
     }
 
-    pub fn all_items(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersAllItems = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::query_read(&dat, ReadQueryParams::AllItems(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+    fn shared_barcodes(
+        req: &mut iron::request::Request,
+    ) -> Result<Arc<RwLock<barcodes::BarcodeBook>>, ServerError> {
+        return req
+            .get::<State<SharedBarcodes>>()
+            .map_err(|_| ServerError::Internal("Barcodes are not available".to_string()));
+    }
+
+    //for scanners at the kiosk, deleted items are not found
+    pub fn item_by_barcode(req: &mut iron::request::Request) -> IronResult<Response> {
+        let barcode: String = match extract_query_param(req, "barcode") {
+            Some(barcode) => barcode,
+            None => {
+                return Ok(error_response(ServerError::BadRequest(
+                    "barcode is missing".to_string(),
+                )))
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
         };
+        if let Err(e) = barcodes::normalize_barcode(&barcode) {
+            return Ok(error_response(ServerError::BadRequest(e)));
+        }
+        let barcodeholder = try_or_respond!(shared_barcodes(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let book = barcodeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let item = try_or_respond!(book
+            .item_for(&barcode, &dat.datastore.items)
+            .and_then(|item_id| dat.datastore.items.get(&item_id))
+            .or_not_found("item with this barcode"));
+        return Ok(Response::with((
+            iron::status::Ok,
+            serde_json::to_string(item).unwrap_or(String::new()),
+        )));
     }
 
+    //the prepaid balance lives next to the backend, so it is added to the backend's answer
     pub fn user_detail_info(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersDetailInfoForUser = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::DetailInfoForUser(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
+        let param: ParametersDetailInfoForUser = try_or_respond!(parse_query(req));
+        let user_id = param.user_id;
+        let conf = try_or_respond!(shared_config(req));
+        let book = try_or_respond!(prepaid_book_snapshot(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut value = try_or_respond!(ServableRustixImpl::query_read(
+            &dat,
+            ReadQueryParams::DetailInfoForUser(param)
+        ));
+        if let Some(balance) = try_or_respond!(prepaid::current_balance(&dat, &book, &conf.prepaid, user_id)) {
+            if let Some(info) = value.pointer_mut("/results/0") {
+                info["prepaid_balance_cents"] = serde_json::Value::from(balance.balance_cents);
             }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        }
+        return read_response(Ok(value));
     }
 
     pub fn personal_log(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersPurchaseLogPersonal =
-                    serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::query_read(
-                    &dat,
-                    ReadQueryParams::PurchaseLogPersonal(param),
-                );
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+        return query_backend(req, ReadQueryParams::PurchaseLogPersonal);
     }
 
     pub fn get_bills(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersBills = serde_json::from_str(&json_query).unwrap();
+        return query_backend(req, ReadQueryParams::Bills);
+    }
 
-                let result = ServableRustixImpl::query_read(&dat, ReadQueryParams::Bills(param));
+    pub fn get_detailed_bill(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::BillDetails);
+    }
 
-                debug!("Bills are queried with result = {:?}", result);
+    pub fn global_log(req: &mut iron::request::Request) -> IronResult<Response> {
+        let param: ParametersPurchaseLogGlobal = try_or_respond!(parse_query(req));
+        let devicesholder = try_or_respond!(shared_devices(req));
+        let datholder = try_or_respond!(shared_backend(req));
+        let dat = datholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut log = try_or_respond!(ServableRustixImpl::query_read(&dat, ReadQueryParams::PurchaseLogGlobal(param)));
+        let book = devicesholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        attach_purchase_devices(&mut log, &book);
+        return read_response(Ok(log));
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::Bill> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn top_users(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::TopUsers);
     }
 
-    pub fn get_detailed_bill(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
+    pub fn count_users(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::AllUsersCount);
+    }
 
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersBillDetails = serde_json::from_str(&json_query).unwrap();
+    pub fn count_items(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::AllItemsCount);
+    }
 
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::BillDetails(param));
+    pub fn count_global_log(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::PurchaseLogGlobalCount);
+    }
 
-                debug!("Bill details are queried with result = {:?}", result);
+    pub fn count_personal_log(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::PurchaseLogPersonalCount);
+    }
 
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<DetailedBill> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn count_bills(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::BillsCount);
     }
 
-    pub fn global_log(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersPurchaseLogGlobal = serde_json::from_str(&json_query).unwrap();
-
-                let result =
-                    ServableRustixImpl::query_read(&dat, ReadQueryParams::PurchaseLogGlobal(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn count_incoming_giveouts(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::IncomingFreebiesCount);
     }
 
-    pub fn top_users(req: &mut iron::request::Request) -> IronResult<Response> {
-        let datholder = req.get::<State<SharedBackend>>().unwrap();
-        let dat = datholder.read().unwrap();
-        let query_str = extract_query(req);
-
-        match query_str {
-            Some(json_query) => {
-                let param: ParametersTopUsers = serde_json::from_str(&json_query).unwrap();
-
-                let result = ServableRustixImpl::query_read(&dat, ReadQueryParams::TopUsers(param));
-
-                match result {
-                    Ok(sux) => {
-                        return Ok(Response::with((
-                            iron::status::Ok,
-                            serde_json::to_string(&sux).unwrap(),
-                        )));
-                    }
-                    Err(_) => {
-                        return Ok(Response::with((
-                            iron::status::Conflict,
-                            serde_json::to_string(&PaginatedResult::<rustix_bl::datastore::User> {
-                                total_count: 0,
-                                from: 0,
-                                to: 0,
-                                results: Vec::new(),
-                            })
-                            .unwrap(),
-                        )));
-                    }
-                }
-            }
-            _ => return Ok(Response::with(iron::status::BadRequest)),
-        };
+    pub fn count_outgoing_giveouts(req: &mut iron::request::Request) -> IronResult<Response> {
+        return query_backend(req, ReadQueryParams::OutgoingFreebiesCount);
     }
 }
 
 pub fn execute_cervisia_server(
-    with_config: &ServerConfig,
-    old_server: Option<iron::Listening>,
+    with_config: &LiveConfig,
+    old_server: Option<CervisiaServer>,
     backend: Option<Backend>,
-) -> (iron::Listening) {
+) -> CervisiaServer {
     info!(
         "execute_cervisia_server begins for config = {:?}",
-        with_config
+        current_config(with_config)
     );
 
-    if old_server.is_some() {
+    if let Some(mut old_server) = old_server {
         info!("Closing old server");
         //TODO: does not work, see https://github.com/hyperium/hyper/issues/338
-        old_server.unwrap().close().unwrap();
+        old_server.close().unwrap();
     };
 
     info!("Building server");
@@ -2553,8 +3642,39 @@ pub fn execute_cervisia_server(
 #[derive(Serialize, Deserialize, Clone, Debug, TypeScriptify)]
 pub struct ServerWriteResult {
     pub error_message: Option<String>,
+    #[serde(default)]
+    pub error_code: Option<String>,
     pub is_success: bool,
     pub content: Option<SuccessContent>,
+    //machine readable reasons for some errors, e.g. the remaining undo window
+    #[serde(default)]
+    pub error_details: Option<serde_json::Value>,
+}
+
+impl ServerWriteResult {
+    pub fn success(refreshed_data: RefreshedData) -> ServerWriteResult {
+        return ServerWriteResult {
+            error_message: None,
+            error_code: None,
+            is_success: true,
+            content: Some(SuccessContent {
+                timestamp_epoch_millis: current_time_millis(),
+                refreshed_data: refreshed_data,
+            }),
+            error_details: None,
+        };
+    }
+
+    //without the refreshed data, e.g. for responses too large to keep
+    pub fn success_without_content() -> ServerWriteResult {
+        return ServerWriteResult {
+            error_message: None,
+            error_code: None,
+            is_success: true,
+            content: None,
+            error_details: None,
+        };
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TypeScriptify)]
@@ -2614,13 +3734,17 @@ pub trait WriteApplicator {
         return match r {
             Ok(res) => ServerWriteResult {
                 error_message: None,
+                error_code: None,
                 is_success: true,
                 content: Some(res),
+                error_details: None,
             },
             Err(e) => ServerWriteResult {
                 error_message: Some(e.description().to_string()),
+                error_code: None,
                 is_success: false,
                 content: None,
+                error_details: None,
             },
         };
     }
@@ -2641,6 +3765,27 @@ pub fn blocking_http_get_call(url: &str) -> Result<String, reqwest::Error> {
     return Ok(s);
 }
 
+//number of purchases in the global log, api is the base url ending in /api
+#[cfg(test)]
+pub fn global_purchase_count(api: &str) -> u64 {
+    let log_query = ParametersPurchaseLogGlobal {
+        count_pars: ParametersPurchaseLogGlobalCount {
+            millis_start: 0,
+            millis_end: i64::max_value(),
+        },
+        pagination: ParametersPagination {
+            start_inclusive: 0,
+            end_exclusive: 1,
+        },
+    };
+    let encoded: String = ::url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("query", &serde_json::to_string(&log_query).unwrap())
+        .finish();
+    let log = blocking_http_get_call(&format!("{}/purchases/global?{}", api, encoded)).unwrap();
+    let log: serde_json::Value = serde_json::from_str(&log).unwrap();
+    return log["total_count"].as_u64().unwrap();
+}
+
 pub fn blocking_http_post_call<T: serde::ser::Serialize>(
     url: &str,
     content: &T,
@@ -2680,51 +3825,6 @@ pub fn blocking_http_post_call_without_response<T: serde::ser::Serialize>(
     return Ok(());
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AvhMessage {
-    targetType: String,
-    targetIdentifier: String,
-    senderId: String,
-    title: String,
-    body: String,
-}
-
-pub fn inform_user(
-    cur_date: i64,
-    last_date: i64,
-    item_name: String,
-    user_sewobe_nr: Option<String>,
-    config: &ServerConfig,
-) {
-    if !config.notification_enable {
-        return;
-    }
-    if user_sewobe_nr.is_none() {
-        return;
-    }
-    if cur_date - last_date < 1000 * 60 * 60 * 24 * 7 {
-        return;
-    }
-    info!(
-        "Nachricht an {} vorbereitet",
-        user_sewobe_nr.clone().unwrap()
-    );
-    let _ = blocking_http_post_call_without_response(
-        &(config.notification_url.to_string() + "?senderSecret=" + &config.notification_api_key),
-        &AvhMessage {
-            targetType: "NR".to_string(),
-            targetIdentifier: user_sewobe_nr.clone().unwrap(),
-            senderId: config.notification_api_id.to_string(),
-            title: "Auf der Bierliste abgestrichen".to_string(),
-            body: format!("Auf der Bierliste wurde {} in deinem Namen abgesprichen. Du erhälst diese Nachricht, weil das letzte mal über eine Woche zurückliegt.", item_name),
-        },
-    );
-    info!(
-        "Nachricht an {} versendet",
-        user_sewobe_nr.unwrap()
-    );
-}
-
 #[cfg(test)]
 mod tests {
     use iron;
@@ -2740,9 +3840,12 @@ mod tests {
 
     use std::sync::Mutex;
 
-    use configuration::ServerConfig;
+    use configuration::{live_config, ServerConfig};
     use server::responsehandlers::CreateUser;
+    use server::responsehandlers::DeleteUser;
     use server::responsehandlers::MakeSimplePurchase;
+    use auth::AdminToken;
+    use reqwest;
     use url::form_urlencoded;
 
     const HOST_WITHOUTPORT: &'static str = "http://localhost:";
@@ -2773,15 +3876,16 @@ mod tests {
             smpt_credentials_password: String::new(),
             smtp_port: 0,
             use_mock_data: true,
-            admin_password: "".to_string(),
+            admin_password: "test-admin-password".to_string(),
             notification_enable: false,
             notification_url: "".to_string(),
             notification_api_key: "".to_string(),
             notification_api_id: "".to_string(),
+            ..ServerConfig::default()
         };
     }
 
-    fn build_default_server<T>(function_to_fill_backend: T) -> (iron::Listening, ServerConfig)
+    fn build_default_server<T>(function_to_fill_backend: T) -> (CervisiaServer, ServerConfig)
     where
         T: Fn(&mut Backend) -> (),
     {
@@ -2791,7 +3895,7 @@ mod tests {
 
         function_to_fill_backend(&mut backend);
 
-        let a = execute_cervisia_server(&default_server_conf, None, Some(backend));
+        let a = execute_cervisia_server(&live_config(default_server_conf.clone()), None, Some(backend));
 
         return (a, default_server_conf);
     }
@@ -3125,6 +4229,7 @@ mod tests {
         let postjson = MakeSimplePurchase {
             user_id: 1,
             item_id: 1,
+            credential: None,
         };
 
         let query = serde_json::to_string(&state).unwrap();
@@ -3158,4 +4263,770 @@ mod tests {
         assert!(unpacked.refreshed_data.OutgoingFreebies.is_null());
         assert!(unpacked.refreshed_data.OpenFFAFreebies.is_null());
     }
+
+    fn empty_app_state() -> ParametersAll {
+        let empty_pagination = || ParametersPagination {
+            start_inclusive: 0,
+            end_exclusive: 0,
+        };
+        return ParametersAll {
+            top_users: ParametersTopUsers { n: 0 },
+            all_users: ParametersAllUsers {
+                count_pars: ParametersAllUsersCount {
+                    searchterm: String::new(),
+                },
+                pagination: empty_pagination(),
+            },
+            all_items: ParametersAllItems {
+                count_pars: ParametersAllItemsCount {
+                    searchterm: String::new(),
+                },
+                pagination: empty_pagination(),
+            },
+            global_log: ParametersPurchaseLogGlobal {
+                count_pars: ParametersPurchaseLogGlobalCount {
+                    millis_start: 0,
+                    millis_end: 0,
+                },
+                pagination: empty_pagination(),
+            },
+            bills: ParametersBills {
+                count_pars: ParametersBillsCount {
+                    start_inclusive: 0,
+                    end_exclusive: 0,
+                    scope_user_id: None,
+                },
+                pagination: empty_pagination(),
+            },
+            bill_detail_infos: ParametersBillDetails {
+                timestamp_from: None,
+                timestamp_to: None,
+            },
+            open_ffa_freebies: ParametersOpenFFAFreebies {
+                pagination: empty_pagination(),
+            },
+            top_personal_drinks: ParametersTopPersonalDrinks { user_id: 0, n: 0 },
+            personal_log: ParametersPurchaseLogPersonal {
+                count_pars: ParametersPurchaseLogPersonalCount {
+                    user_id: 0,
+                    millis_start: 0,
+                    millis_end: 0,
+                },
+                pagination: empty_pagination(),
+            },
+            incoming_freebies: ParametersIncomingFreebies {
+                count_pars: ParametersIncomingFreebiesCount { recipient_id: 0 },
+                pagination: empty_pagination(),
+            },
+            outgoing_freebies: ParametersOutgoingFreebies {
+                count_pars: ParametersOutgoingFreebiesCount { donor_id: 0 },
+                pagination: empty_pagination(),
+            },
+            personal_detail_infos: ParametersDetailInfoForUser { user_id: 0 },
+        };
+    }
+
+    fn url_with_state(config: &ServerConfig, path: &str) -> String {
+        let query = serde_json::to_string(&empty_app_state()).unwrap();
+
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &query)
+            .finish();
+
+        return format!(
+            "{}{}/api{}?{}",
+            HOST_WITHOUTPORT, config.server_port, path, encoded
+        );
+    }
+
+    #[test]
+    fn counting_users_works() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+
+        let count_all = ParametersAllUsersCount {
+            searchterm: "".to_string(),
+        };
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &serde_json::to_string(&count_all).unwrap())
+            .finish();
+        let httpbody = blocking_http_get_call(&format!(
+            "{}{}/api/users/count?{}",
+            HOST_WITHOUTPORT, config.server_port, encoded
+        )).unwrap();
+
+        let count_none = ParametersAllUsersCount {
+            searchterm: "no-user-is-called-like-this".to_string(),
+        };
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &serde_json::to_string(&count_none).unwrap())
+            .finish();
+        let httpbody_none = blocking_http_get_call(&format!(
+            "{}{}/api/users/count?{}",
+            HOST_WITHOUTPORT, config.server_port, encoded
+        )).unwrap();
+
+        server.close().unwrap();
+
+        let count: u32 = serde_json::from_str(&httpbody).unwrap();
+        assert_eq!(count, 53);
+        let count_none: u32 = serde_json::from_str(&httpbody_none).unwrap();
+        assert_eq!(count_none, 0);
+    }
+
+    #[test]
+    fn admin_routes_require_a_token() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+
+        let client = reqwest::Client::new();
+        let url = url_with_state(&config, "/users/delete");
+        let postjson = DeleteUser { user_id: 1 };
+
+        let rejected = client
+            .post(&url)
+            .body(serde_json::to_string(&postjson).unwrap())
+            .send()
+            .unwrap();
+        assert_eq!(rejected.status().as_u16(), 401);
+
+        let mut login = client
+            .post(&format!(
+                "{}{}/api/admin/login",
+                HOST_WITHOUTPORT, config.server_port
+            ))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        assert_eq!(login.status().as_u16(), 200);
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+
+        let accepted = client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", token.token))
+            .body(serde_json::to_string(&postjson).unwrap())
+            .send()
+            .unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(accepted.status().as_u16(), 200);
+    }
+    #[test]
+    fn purchases_are_pushed_to_event_stream_subscribers() {
+        use std::io::Read;
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        //a purchase that never shows up must fail the test instead of blocking it,
+        //keepalives arrive every 15 seconds, so reads alone would never time out
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(20))
+            .build()
+            .unwrap();
+        let mut stream = client
+            .get(&format!("{}{}/api/events", HOST_WITHOUTPORT, config.server_port))
+            .send()
+            .unwrap();
+        assert_eq!(stream.status().as_u16(), 200);
+
+        let purchase = client
+            .post(&url_with_state(&config, "/purchases"))
+            .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }).unwrap())
+            .send()
+            .unwrap();
+        assert_eq!(purchase.status().as_u16(), 200);
+
+        let mut received = String::new();
+        let mut buf = [0u8; 1024];
+        while !received.contains("PurchaseCreated") {
+            assert!(std::time::Instant::now() < deadline, "no purchase arrived on the stream in time");
+            let n = stream.read(&mut buf).expect("the stream stalled");
+            assert!(n > 0, "stream closed before the purchase arrived");
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+
+        server.close().unwrap();
+
+        assert!(received.contains("event: MakeSimplePurchase"));
+    }
+
+    #[test]
+    fn purchases_record_the_terminal_they_were_made_on() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let mut registered = client
+            .post(&format!("{}/devices/register", api))
+            .body(serde_json::to_string(&devices::RegisterDevice {
+                name: "Tap room".to_string(),
+                role: devices::DeviceRole::PurchaseTerminal,
+            }).unwrap())
+            .send()
+            .unwrap();
+        let info: devices::DeviceInfo = serde_json::from_str(&registered.text().unwrap()).unwrap();
+
+        let mut login = client
+            .post(&format!("{}/admin/login", api))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+        let mut approved = client
+            .post(&format!("{}/admin/devices/approve", api))
+            .header("Authorization", format!("Bearer {}", token.token))
+            .body(serde_json::to_string(&devices::ApproveDevice {
+                id: info.id,
+                role: devices::DeviceRole::PurchaseTerminal,
+            }).unwrap())
+            .send()
+            .unwrap();
+        let issued: devices::IssuedDeviceKey = serde_json::from_str(&approved.text().unwrap()).unwrap();
+
+        let purchase = client
+            .post(&url_with_state(&config, "/purchases"))
+            .header(devices::DEVICE_KEY_HEADER, issued.api_key.to_string())
+            .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }).unwrap())
+            .send()
+            .unwrap();
+        let forbidden = client
+            .post(&url_with_state(&config, "/users"))
+            .header(devices::DEVICE_KEY_HEADER, issued.api_key.to_string())
+            .body("{}")
+            .send()
+            .unwrap();
+        let unknown = client
+            .post(&url_with_state(&config, "/purchases"))
+            .header(devices::DEVICE_KEY_HEADER, "not-a-key")
+            .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }).unwrap())
+            .send()
+            .unwrap();
+
+        let log_query = ParametersPurchaseLogGlobal {
+            count_pars: ParametersPurchaseLogGlobalCount {
+                millis_start: 0,
+                millis_end: i64::max_value(),
+            },
+            pagination: ParametersPagination {
+                start_inclusive: 0,
+                end_exclusive: 10,
+            },
+        };
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &serde_json::to_string(&log_query).unwrap())
+            .finish();
+        let log = blocking_http_get_call(&format!("{}/purchases/global?{}", api, encoded)).unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(purchase.status().as_u16(), 200);
+        assert_eq!(forbidden.status().as_u16(), 401);
+        assert_eq!(unknown.status().as_u16(), 401);
+        let log: serde_json::Value = serde_json::from_str(&log).unwrap();
+        let names: Vec<String> = log["purchase_devices"]
+            .as_object()
+            .unwrap()
+            .values()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect();
+        assert_eq!(names, vec!["Tap room".to_string()]);
+    }
+
+    #[test]
+    fn members_who_opted_in_need_their_pin_to_purchase() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let mut login = client
+            .post(&format!("{}/admin/login", api))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+        let pin_set = client
+            .post(&format!("{}/admin/credentials/pin", api))
+            .header("Authorization", format!("Bearer {}", token.token))
+            .body(serde_json::to_string(&credentials::SetPin {
+                user_id: 1,
+                pin: Some("4711".to_string()),
+            }).unwrap())
+            .send()
+            .unwrap();
+        let pin = credentials::PurchaseCredential {
+            pin: Some("4711".to_string()),
+            card_uid: None,
+        };
+        let opted_in = client
+            .post(&format!("{}/credentials/required", api))
+            .body(serde_json::to_string(&credentials::SetCredentialRequired {
+                user_id: 1,
+                required: true,
+                credential: pin.clone(),
+            }).unwrap())
+            .send()
+            .unwrap();
+
+        let without_pin = client
+            .post(&url_with_state(&config, "/purchases"))
+            .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }).unwrap())
+            .send()
+            .unwrap();
+        let with_pin = client
+            .post(&url_with_state(&config, "/purchases"))
+            .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: Some(pin) }).unwrap())
+            .send()
+            .unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(pin_set.status().as_u16(), 200);
+        assert_eq!(opted_in.status().as_u16(), 200);
+        assert_eq!(without_pin.status().as_u16(), 401);
+        assert_eq!(with_pin.status().as_u16(), 200);
+    }
+
+    #[test]
+    fn items_can_be_found_by_their_barcode() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let mut login = client
+            .post(&format!("{}/admin/login", api))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+        let create = |name: &str| {
+            client
+                .post(&url_with_state(&config, "/items"))
+                .header("Authorization", format!("Bearer {}", token.token))
+                .body(serde_json::to_string(&CreateItem {
+                    name: name.to_string(),
+                    price_cents: 150,
+                    category: None,
+                    barcodes: vec!["4006381333931".to_string()],
+                }).unwrap())
+                .send()
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        let created = create("Scanned lemonade");
+        let duplicate = create("Other lemonade");
+
+        let found = blocking_http_get_call(&format!("{}/items/bybarcode?barcode=4006381333931", api)).unwrap();
+        let unknown = client
+            .get(&format!("{}/items/bybarcode?barcode=96385074", api))
+            .send()
+            .unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(created, 200);
+        assert_eq!(duplicate, 409);
+        let item: rustix_bl::datastore::Item = serde_json::from_str(&found).unwrap();
+        assert_eq!(item.name, "Scanned lemonade");
+        assert_eq!(unknown.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn retried_purchases_with_the_same_idempotency_key_are_booked_once() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let purchase = |item_id: u32| {
+            let mut response = client
+                .post(&url_with_state(&config, "/purchases"))
+                .header(idempotency::IDEMPOTENCY_KEY_HEADER, "3f2c9d1e-retry")
+                .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: item_id, credential: None }).unwrap())
+                .send()
+                .unwrap();
+            return (response.status().as_u16(), response.text().unwrap());
+        };
+
+        let before = global_purchase_count(&api);
+        let first = purchase(1);
+        let retried = purchase(1);
+        let after = global_purchase_count(&api);
+        let reused = purchase(2);
+
+        server.close().unwrap();
+
+        assert_eq!(first.0, 200);
+        assert_eq!(retried, first);
+        assert_eq!(after, before + 1);
+        assert_eq!(reused.0, 409);
+    }
+
+    #[test]
+    fn offline_purchases_are_synchronized_once_in_time_order() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let synchronize = |entries: Vec<sync::SyncEntry>| {
+            let mut response = client
+                .post(&url_with_state(&config, "/purchases/sync"))
+                .body(serde_json::to_string(&sync::SyncBatch { entries: entries }).unwrap())
+                .send()
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            let result: sync::SyncBatchResult = serde_json::from_str(&response.text().unwrap()).unwrap();
+            return result.results.into_iter().map(|r| (r.uuid, r.status)).collect::<Vec<(String, sync::SyncStatus)>>();
+        };
+
+        let now = current_time_millis();
+        let simple = sync::SyncEntry {
+            uuid: "kiosk-1".to_string(),
+            timestamp_epoch_millis: now - 60 * 1000,
+            simple: Some(MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }),
+            cart: None,
+            ffa: None,
+        };
+        let cart = sync::SyncEntry {
+            uuid: "kiosk-2".to_string(),
+            timestamp_epoch_millis: now - 120 * 1000,
+            simple: None,
+            cart: Some(MakeCartPurchase {
+                user_id: 2,
+                items: vec![KeyValue { key: 1, value: 1 }],
+                specials: vec![],
+                credential: None,
+            }),
+            ffa: None,
+        };
+        let empty = sync::SyncEntry {
+            uuid: "kiosk-3".to_string(),
+            timestamp_epoch_millis: now - 30 * 1000,
+            simple: None,
+            cart: None,
+            ffa: None,
+        };
+
+        let before = global_purchase_count(&api);
+        let first = synchronize(vec![simple.clone(), cart, empty]);
+        let after_first = global_purchase_count(&api);
+        let resent = synchronize(vec![simple]);
+        let after_resent = global_purchase_count(&api);
+
+        server.close().unwrap();
+
+        assert_eq!(
+            first,
+            vec![
+                ("kiosk-1".to_string(), sync::SyncStatus::Applied),
+                ("kiosk-2".to_string(), sync::SyncStatus::Applied),
+                ("kiosk-3".to_string(), sync::SyncStatus::Rejected),
+            ]
+        );
+        assert_eq!(after_first, before + 2);
+        assert_eq!(resent, vec![("kiosk-1".to_string(), sync::SyncStatus::AlreadyApplied)]);
+        assert_eq!(after_resent, after_first);
+    }
+
+    #[test]
+    fn undos_follow_the_policy_and_are_audited() {
+        let mut config = get_server_config();
+        config.undo.max_undos_per_day = Some(1);
+        let mut backend = rustix_bl::build_transient_backend();
+        fill_backend_with_medium_test_data(&mut backend);
+        let mut server = execute_cervisia_server(&live_config(config.clone()), None, Some(backend));
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let started = current_time_millis();
+        for _ in 0..2 {
+            client
+                .post(&url_with_state(&config, "/purchases"))
+                .body(serde_json::to_string(&MakeSimplePurchase { user_id: 1, item_id: 1, credential: None }).unwrap())
+                .send()
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        let log_query = ParametersPurchaseLogGlobal {
+            count_pars: ParametersPurchaseLogGlobalCount {
+                millis_start: started,
+                millis_end: i64::max_value(),
+            },
+            pagination: ParametersPagination {
+                start_inclusive: 0,
+                end_exclusive: 10,
+            },
+        };
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &serde_json::to_string(&log_query).unwrap())
+            .finish();
+        let log = blocking_http_get_call(&format!("{}/purchases/global?{}", api, encoded)).unwrap();
+        let log: PaginatedResult<manager::Purchase> = serde_json::from_str(&log).unwrap();
+        let purchase_ids: Vec<u64> = log
+            .results
+            .iter()
+            .map(|p| match p {
+                &manager::Purchase::SimplePurchase { unique_id, .. } => unique_id,
+                &manager::Purchase::SpecialPurchase { unique_id, .. } => unique_id,
+                &manager::Purchase::FFAPurchase { unique_id, .. } => unique_id,
+            })
+            .collect();
+
+        let undo_purchase = |path: &str, unique_id: u64, token: Option<&str>| {
+            let mut request = client.post(&url_with_state(&config, path));
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            let mut response = request
+                .body(serde_json::to_string(&UndoPurchase { unique_id: unique_id }).unwrap())
+                .send()
+                .unwrap();
+            let result: ServerWriteResult = serde_json::from_str(&response.text().unwrap()).unwrap();
+            return (response.status().as_u16(), result);
+        };
+        let first = undo_purchase("/purchases/undo/user", purchase_ids[0], None);
+        let second = undo_purchase("/purchases/undo/user", purchase_ids[1], None);
+
+        let mut login = client
+            .post(&format!("{}/admin/login", api))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+        let by_admin = undo_purchase("/purchases/undo/admin", purchase_ids[1], Some(&token.token));
+        let mut audit = client
+            .get(&format!("{}/admin/undos", api))
+            .header("Authorization", format!("Bearer {}", token.token))
+            .send()
+            .unwrap();
+        let records: Vec<undo::UndoRecord> = serde_json::from_str(&audit.text().unwrap()).unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(purchase_ids.len(), 2);
+        assert_eq!(first.0, 200);
+        assert_eq!(second.0, 429);
+        let refusal: undo::UndoRefusal = serde_json::from_value(second.1.error_details.unwrap()).unwrap();
+        assert_eq!(refusal.reason, undo::UndoRefusalReason::DailyLimitReached);
+        assert_eq!(refusal.undos_left_today, Some(0));
+        assert!(refusal.seconds_remaining > 0);
+        assert_eq!(by_admin.0, 200);
+        assert_eq!(
+            records.iter().map(|r| (r.purchase_id, r.undone_by)).collect::<Vec<(u64, undo::UndoActor)>>(),
+            vec![(purchase_ids[1], undo::UndoActor::Admin), (purchase_ids[0], undo::UndoActor::Member)]
+        );
+    }
+
+    #[test]
+    fn keys_which_are_not_persisted_cannot_be_rotated() {
+        let (server, config) = build_default_server(fill_not);
+        let mut server = server;
+        let client = reqwest::Client::new();
+        let api = format!("{}{}/api", HOST_WITHOUTPORT, config.server_port);
+
+        let mut login = client
+            .post(&format!("{}/admin/login", api))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+        let rotated = client
+            .post(&format!("{}/admin/jwt/rotate", api))
+            .header("Authorization", format!("Bearer {}", token.token))
+            .send()
+            .unwrap();
+
+        server.close().unwrap();
+
+        assert_eq!(rotated.status().as_u16(), 409);
+    }
+
+    const GARBAGE_ROUTES: &[(&str, &str)] = &[
+        ("GET", "/users/all"),
+        ("GET", "/users/top"),
+        ("GET", "/users/detail"),
+        ("GET", "/items/top"),
+        ("GET", "/items/all"),
+        ("GET", "/items/bybarcode"),
+        ("GET", "/purchases/global"),
+        ("GET", "/purchases/personal"),
+        ("GET", "/bills"),
+        ("GET", "/bills/detail"),
+        ("GET", "/giveout/ffa"),
+        ("GET", "/giveout/incoming"),
+        ("GET", "/giveout/outgoing"),
+        ("GET", "/users/count"),
+        ("GET", "/items/count"),
+        ("GET", "/purchases/global/count"),
+        ("GET", "/purchases/personal/count"),
+        ("GET", "/bills/count"),
+        ("GET", "/giveout/incoming/count"),
+        ("GET", "/giveout/outgoing/count"),
+        ("GET", "/bill/download/list"),
+        ("GET", "/bill/download"),
+        ("GET", "/bill/download/secure"),
+        ("GET", "/bill/download/requestjwt"),
+        ("GET", "/public/ticket"),
+        ("POST", "/users"),
+        ("POST", "/items"),
+        ("POST", "/users/update"),
+        ("POST", "/items/update"),
+        ("POST", "/users/delete"),
+        ("POST", "/items/delete"),
+        ("POST", "/purchases"),
+        ("POST", "/purchases/cart"),
+        ("POST", "/purchases/ffa"),
+        ("POST", "/purchases/sync"),
+        ("POST", "/purchases/undo/user"),
+        ("POST", "/purchases/undo/admin"),
+        ("POST", "/purchases/special/setprice"),
+        ("POST", "/bill/create"),
+        ("POST", "/bill/update"),
+        ("POST", "/bill/delete"),
+        ("POST", "/bill/finalize"),
+        ("POST", "/bill/export"),
+        ("POST", "/giveout/budget"),
+        ("POST", "/giveout/count"),
+        ("POST", "/giveout/ffa"),
+        ("POST", "/admin/checkpassword"),
+        ("POST", "/admin/login"),
+        ("GET", "/admin/mails"),
+        ("POST", "/admin/mails/resend"),
+        ("GET", "/admin/sepa/mandates"),
+        ("POST", "/admin/sepa/mandates"),
+        ("POST", "/admin/sepa/mandates/delete"),
+        ("GET", "/bill/download/sepa"),
+        ("GET", "/payments"),
+        ("POST", "/payments"),
+        ("POST", "/payments/update"),
+        ("POST", "/payments/delete"),
+        ("GET", "/payments/balances"),
+        ("GET", "/admin/dunning"),
+        ("POST", "/admin/dunning/contact"),
+        ("POST", "/admin/dunning/pause"),
+        ("GET", "/prepaid"),
+        ("POST", "/prepaid/topup"),
+        ("POST", "/prepaid/limit"),
+        ("GET", "/inventory"),
+        ("POST", "/inventory/restock"),
+        ("POST", "/inventory/stocktaking"),
+        ("POST", "/inventory/threshold"),
+        ("GET", "/inventory/movements"),
+        ("GET", "/inventory/report"),
+        ("GET", "/inventory/reorder"),
+        ("GET", "/notifications/preferences"),
+        ("POST", "/notifications/preferences"),
+        ("GET", "/admin/webhooks"),
+        ("POST", "/admin/webhooks"),
+        ("POST", "/admin/webhooks/delete"),
+        ("POST", "/admin/webhooks/test"),
+        ("GET", "/admin/webhooks/deliveries"),
+        ("POST", "/devices/register"),
+        ("GET", "/devices/current"),
+        ("GET", "/admin/devices"),
+        ("POST", "/admin/devices/approve"),
+        ("POST", "/admin/devices/revoke"),
+        ("POST", "/credentials/resolve"),
+        ("POST", "/credentials/required"),
+        ("GET", "/admin/credentials"),
+        ("GET", "/admin/undos"),
+        ("POST", "/admin/credentials/pin"),
+        ("POST", "/admin/credentials/cards"),
+        ("POST", "/admin/credentials/cards/delete"),
+        //GET /events is left out, it only answers once the client disconnects
+    ];
+
+    #[test]
+    fn garbage_requests_do_not_take_the_server_down() {
+        let (server, config) = build_default_server(fill_backend_with_medium_test_data);
+        let mut server = server;
+        let client = reqwest::Client::new();
+
+        let mut login = client
+            .post(&format!(
+                "{}{}/api/admin/login",
+                HOST_WITHOUTPORT, config.server_port
+            ))
+            .body(config.admin_password.to_string())
+            .send()
+            .unwrap();
+        let token: AdminToken = serde_json::from_str(&login.text().unwrap()).unwrap();
+
+        let queries = vec![
+            "",
+            "?query=definitely-not-json",
+            "?query=%7B%22users%22%3A42%7D",
+            "?from=abc&to=&jwt=xyz",
+        ];
+        let bodies = vec!["", "{{{", "{\"user_id\":\"seven\"}", "[1,2,3]"];
+
+        for &(method, path) in GARBAGE_ROUTES {
+            for query in &queries {
+                for body in &bodies {
+                    let url = format!(
+                        "{}{}/api{}{}",
+                        HOST_WITHOUTPORT, config.server_port, path, query
+                    );
+                    let request = if method == "GET" {
+                        client.get(&url)
+                    } else {
+                        client.post(&url).body(body.to_string())
+                    };
+                    let response = request
+                        .header("Authorization", format!("Bearer {}", token.token))
+                        .send()
+                        .unwrap();
+                    assert!(
+                        response.status().as_u16() < 500,
+                        "{} {}{} with body {:?} answered {}",
+                        method,
+                        path,
+                        query,
+                        body,
+                        response.status()
+                    );
+                }
+            }
+        }
+
+        //a parse error is reported as bad request with a machine readable code
+        let mut rejected = client
+            .post(&url_with_state(&config, "/users"))
+            .body("{{{")
+            .send()
+            .unwrap();
+        assert_eq!(rejected.status().as_u16(), 400);
+        let rejected_body: ServerWriteResult =
+            serde_json::from_str(&rejected.text().unwrap()).unwrap();
+        assert_eq!(rejected_body.error_code, Some("bad_request".to_string()));
+
+        //and the backend is still usable afterwards
+        let params = ParametersAllUsers {
+            count_pars: ParametersAllUsersCount {
+                searchterm: "".to_string(),
+            },
+            pagination: ParametersPagination {
+                start_inclusive: 0,
+                end_exclusive: 1_000_000,
+            },
+        };
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &serde_json::to_string(&params).unwrap())
+            .finish();
+        let httpbody = blocking_http_get_call(&format!(
+            "{}{}/api/users/all?{}",
+            HOST_WITHOUTPORT, config.server_port, encoded
+        )).unwrap();
+
+        server.close().unwrap();
+
+        let parsedjson: PaginatedResult<rustix_bl::datastore::User> =
+            serde_json::from_str(&httpbody).unwrap();
+        assert_eq!(parsedjson.results.len(), 53);
+    }
 }
@@ -0,0 +1,140 @@
+use chrono::Utc;
+use configuration::ServerConfig;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_json;
+use std;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+//state which rustix-bl does not know about is kept in small json files next to the persistence directory
+
+pub fn sidecar_path(config: &ServerConfig, name: &str) -> Option<PathBuf> {
+    if !config.use_persistence {
+        return None;
+    }
+    let base = config.persistence_file_path.trim_end_matches('/');
+    return Some(PathBuf::from(format!("{}.{}.json", base, name)));
+}
+
+//for logs which only grow, appending is cheap where rewriting a whole json file would not be
+pub fn sidecar_log_path(config: &ServerConfig, name: &str) -> Option<PathBuf> {
+    return sidecar_path(config, name).map(|path| path.with_extension("jsonl"));
+}
+
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, io::Error> {
+    //only a missing file means there is nothing yet, any other error must not lead to overwriting it
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let decoded: T = serde_json::from_str(&s)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    return Ok(Some(decoded));
+}
+
+//a file which cannot be read is moved aside before an empty one takes its place, so the next save does not
+//destroy it. if it cannot even be moved, starting would risk exactly that, so the server refuses to start
+pub fn load_json_or_default<T: DeserializeOwned + Default>(path: &Path, what: &str) -> T {
+    return match load_json(path) {
+        Ok(Some(value)) => value,
+        Ok(None) => T::default(),
+        Err(e) => {
+            move_aside(path, what, e);
+            T::default()
+        }
+    };
+}
+
+pub fn move_aside(path: &Path, what: &str, error: io::Error) {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".broken-{}", Utc::now().timestamp()));
+    let aside = PathBuf::from(aside);
+    match std::fs::rename(path, &aside) {
+        Ok(()) => error!(
+            "Could not read {} from {:?}, moved the file to {:?} and started without them: {:?}",
+            what, path, aside, error
+        ),
+        Err(move_error) => panic!(
+            "Could not read {} from {:?} ({:?}) and could not move the file aside ({:?}), refusing to overwrite it",
+            what, path, error, move_error
+        ),
+    }
+}
+
+//writes into a temporary file first, so a crash never leaves a half written file behind
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), io::Error> {
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    return write_atomically(path, &content);
+}
+
+fn write_atomically(path: &Path, content: &str) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    return Ok(());
+}
+
+//one json document per line, a half written last line (e.g. after a crash) is skipped
+pub fn load_json_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, io::Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let mut values: Vec<T> = Vec::new();
+    for line in s.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(value) => values.push(value),
+            Err(e) => warn!("Skipping unreadable line in {:?}: {}", path, e),
+        }
+    }
+    return Ok(values);
+}
+
+//replaces the whole log, e.g. to drop lines which are not needed anymore
+pub fn save_json_lines<T: Serialize>(path: &Path, values: &[T]) -> Result<(), io::Error> {
+    return write_atomically(path, &json_lines(values)?);
+}
+
+fn json_lines<T: Serialize>(values: &[T]) -> Result<String, io::Error> {
+    let mut content = String::new();
+    for value in values {
+        content.push_str(&serde_json::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+        content.push('\n');
+    }
+    return Ok(content);
+}
+
+//all lines go out in one write, so readers never see them interleaved with other appends
+pub fn append_json_lines<T: Serialize>(path: &Path, values: &[T]) -> Result<(), io::Error> {
+    let content = json_lines(values)?;
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_data()?;
+    return Ok(());
+}
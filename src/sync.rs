@@ -0,0 +1,220 @@
+use billformatter::DATE_FORMAT_STRING;
+use chrono::prelude::*;
+use errors::ServerError;
+use rustix_bl::datastore::{Bill, BillState};
+use server::responsehandlers::{MakeCartPurchase, MakeFFAPurchase, MakeSimplePurchase};
+use typescriptify::TypeScriptifyTrait;
+
+//configured in the [sync] table of the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncSettings {
+    pub max_entries: usize,
+    //kiosk clocks drift, purchases from further in the future are refused
+    pub max_clock_skew_seconds: i64,
+    //uuids are remembered this long, a kiosk may be offline for days and retries are only recognized within it
+    pub uuid_retention_seconds: i64,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        return SyncSettings {
+            max_entries: 200,
+            max_clock_skew_seconds: 5 * 60,
+            uuid_retention_seconds: 14 * 24 * 60 * 60,
+        };
+    }
+}
+
+impl SyncSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.max_entries < 1 {
+            problems.push("sync.max_entries must be at least 1".to_string());
+        }
+        if self.max_clock_skew_seconds < 0 {
+            problems.push("sync.max_clock_skew_seconds must not be negative".to_string());
+        }
+        if self.uuid_retention_seconds <= 0 {
+            problems.push("sync.uuid_retention_seconds must be positive".to_string());
+        }
+        return problems;
+    }
+}
+
+//exactly one of simple, cart and ffa is set, the uuid is generated by the kiosk when the purchase is made
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SyncEntry {
+    pub uuid: String,
+    pub timestamp_epoch_millis: i64,
+    #[serde(default)]
+    pub simple: Option<MakeSimplePurchase>,
+    #[serde(default)]
+    pub cart: Option<MakeCartPurchase>,
+    #[serde(default)]
+    pub ffa: Option<MakeFFAPurchase>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SyncBatch {
+    pub entries: Vec<SyncEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum SyncStatus {
+    Applied,
+    //sent before, e.g. when the answer to an earlier sync got lost
+    AlreadyApplied,
+    Conflict,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct SyncEntryResult {
+    pub uuid: String,
+    pub status: SyncStatus,
+    pub error_code: Option<String>,
+    pub error_message: Option<String>,
+}
+
+impl SyncEntryResult {
+    pub fn succeeded(uuid: String, status: SyncStatus) -> SyncEntryResult {
+        return SyncEntryResult {
+            uuid: uuid,
+            status: status,
+            error_code: None,
+            error_message: None,
+        };
+    }
+
+    //conflicts will not go away by retrying, the kiosk should show them to the staff
+    pub fn failed(uuid: String, err: &ServerError) -> SyncEntryResult {
+        let status = match *err {
+            ServerError::Conflict(_) => SyncStatus::Conflict,
+            _ => SyncStatus::Rejected,
+        };
+        return SyncEntryResult {
+            uuid: uuid,
+            status: status,
+            error_code: Some(err.error_code().to_string()),
+            error_message: Some(err.message().to_string()),
+        };
+    }
+}
+
+//results are listed in the order of the batch
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct SyncBatchResult {
+    pub results: Vec<SyncEntryResult>,
+}
+
+//indices of the entries sorted by purchase time, entries with the same time keep the order of the batch
+pub fn application_order(entries: &[SyncEntry]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].timestamp_epoch_millis);
+    return order;
+}
+
+//older entries could not be recognized as duplicates anymore, their uuid may already be forgotten
+pub fn validate_entry(
+    entry: &SyncEntry,
+    settings: &SyncSettings,
+    now_epoch_millis: i64,
+) -> Result<(), ServerError> {
+    let uuid = entry.uuid.trim();
+    if uuid.is_empty() || uuid.len() > 64 {
+        return Err(ServerError::BadRequest(
+            "Every entry needs a uuid of at most 64 characters".to_string(),
+        ));
+    }
+    if entry.timestamp_epoch_millis > now_epoch_millis + settings.max_clock_skew_seconds * 1000 {
+        return Err(ServerError::BadRequest(format!(
+            "Purchase {} lies in the future, please check the clock of the kiosk",
+            uuid
+        )));
+    }
+    if entry.timestamp_epoch_millis <= now_epoch_millis - settings.uuid_retention_seconds * 1000 {
+        return Err(ServerError::BadRequest(format!(
+            "Purchase {} is too old to be synchronized",
+            uuid
+        )));
+    }
+    return Ok(());
+}
+
+//purchases cannot be added to bills which were already finalized, their totals are fixed
+pub fn check_period_open(bills: &[Bill], timestamp_epoch_millis: i64) -> Result<(), ServerError> {
+    let finalized = bills.iter().find(|b| {
+        b.bill_state != BillState::Created
+            && b.timestamp_from <= timestamp_epoch_millis
+            && timestamp_epoch_millis <= b.timestamp_to
+    });
+    return match finalized {
+        Some(bill) => Err(ServerError::Conflict(format!(
+            "The purchase falls into the bill from {} to {}, which is already finalized",
+            format_day(bill.timestamp_from),
+            format_day(bill.timestamp_to)
+        ))),
+        None => Ok(()),
+    };
+}
+
+fn format_day(epoch_millis: i64) -> String {
+    return Utc
+        .timestamp(epoch_millis / 1000, 0)
+        .format(DATE_FORMAT_STRING)
+        .to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use billformatter::tests::simple_bill;
+    use rustix_bl::datastore::BillState;
+    use server::responsehandlers::MakeSimplePurchase;
+    use sync::*;
+
+    fn entry(uuid: &str, timestamp_epoch_millis: i64) -> SyncEntry {
+        return SyncEntry {
+            uuid: uuid.to_string(),
+            timestamp_epoch_millis: timestamp_epoch_millis,
+            simple: Some(MakeSimplePurchase {
+                user_id: 1,
+                item_id: 1,
+                credential: None,
+            }),
+            cart: None,
+            ffa: None,
+        };
+    }
+
+    #[test]
+    fn entries_are_applied_by_time_and_checked_against_the_clock() {
+        let settings = SyncSettings {
+            uuid_retention_seconds: 3600,
+            ..SyncSettings::default()
+        };
+        let entries = vec![entry("a", 3000), entry("b", 1000), entry("c", 3000), entry("d", 2000)];
+        assert_eq!(application_order(&entries), vec![1, 3, 0, 2]);
+
+        let now = 10_000_000;
+        assert!(validate_entry(&entry("a", now - 1000), &settings, now).is_ok());
+        assert!(validate_entry(&entry("", now - 1000), &settings, now).is_err());
+        assert!(validate_entry(&entry("a", now + 60 * 1000), &settings, now).is_ok());
+        assert!(validate_entry(&entry("a", now + 3600 * 1000), &settings, now).is_err());
+        assert!(validate_entry(&entry("a", now - 3600 * 1000), &settings, now).is_err());
+    }
+
+    #[test]
+    fn purchases_in_finalized_bills_are_conflicts() {
+        let mut bill = simple_bill();
+        let inside = bill.timestamp_from + 1;
+        let err = check_period_open(&vec![bill.clone()], inside).unwrap_err();
+        assert_eq!(err.error_code(), "conflict");
+        assert_eq!(SyncEntryResult::failed("a".to_string(), &err).status, SyncStatus::Conflict);
+        assert!(check_period_open(&vec![bill.clone()], bill.timestamp_to + 1).is_ok());
+
+        //bills which are only created still collect purchases
+        bill.bill_state = BillState::Created;
+        assert!(check_period_open(&vec![bill], inside).is_ok());
+    }
+}
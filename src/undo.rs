@@ -0,0 +1,393 @@
+use configuration::ServerConfig;
+use errors::ServerError;
+use iron;
+use iron::prelude::*;
+use rustix_bl::datastore::DatastoreQueries;
+use rustix_bl::datastore::Purchase;
+use serde_json;
+use server::{current_time_millis, Backend};
+use sidecar;
+use std;
+use std::path::PathBuf;
+use typescriptify::TypeScriptifyTrait;
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+//configured in the [undo] table of the config file, admins may always undo
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UndoSettings {
+    pub window_seconds: i64,
+    pub allow_cart_purchases: bool,
+    pub allow_ffa_purchases: bool,
+    pub allow_specials: bool,
+    //counted over the last 24 hours, None for no limit
+    pub max_undos_per_day: Option<u32>,
+}
+
+impl Default for UndoSettings {
+    fn default() -> Self {
+        return UndoSettings {
+            window_seconds: 60,
+            allow_cart_purchases: true,
+            allow_ffa_purchases: true,
+            allow_specials: true,
+            max_undos_per_day: None,
+        };
+    }
+}
+
+impl UndoSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.window_seconds < 0 {
+            problems.push("undo.window_seconds must not be negative".to_string());
+        }
+        return problems;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum UndoKind {
+    Simple,
+    //the backend does not mark carts, a cart is recognized by several purchases of a member sharing one timestamp
+    Cart,
+    Special,
+    FreeForAll,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum UndoActor {
+    Member,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UndoTarget {
+    pub purchase_id: u64,
+    pub kind: UndoKind,
+    //None for giveouts, nobody in particular drank those
+    pub user_id: Option<u32>,
+    pub description: String,
+    pub purchase_timestamp_epoch_millis: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct UndoRecord {
+    pub purchase_id: u64,
+    pub kind: UndoKind,
+    pub user_id: Option<u32>,
+    pub description: String,
+    pub purchase_timestamp_epoch_millis: i64,
+    pub undone_epoch_millis: i64,
+    pub undone_by: UndoActor,
+    pub device_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum UndoRefusalReason {
+    WindowExpired,
+    KindNotAllowed,
+    DailyLimitReached,
+}
+
+//sent as error_details of the ServerWriteResult, so kiosks can show a countdown instead of parsing the message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TypeScriptify)]
+pub struct UndoRefusal {
+    pub reason: UndoRefusalReason,
+    pub kind: UndoKind,
+    pub window_seconds: i64,
+    //left in the undo window, 0 once it has passed
+    pub seconds_remaining: i64,
+    pub undos_left_today: Option<u32>,
+    //only for the daily limit, when the oldest counted undo drops out
+    pub retry_after_seconds: Option<i64>,
+}
+
+impl UndoRefusal {
+    pub fn to_error(&self) -> ServerError {
+        return match self.reason {
+            UndoRefusalReason::WindowExpired => ServerError::Conflict(format!(
+                "Purchases can only be undone within {} seconds",
+                self.window_seconds
+            )),
+            UndoRefusalReason::KindNotAllowed => ServerError::Conflict(format!(
+                "Purchases of kind {:?} cannot be undone at the terminal, please ask an admin",
+                self.kind
+            )),
+            UndoRefusalReason::DailyLimitReached => ServerError::TooManyRequests(
+                "No more undos are allowed for this member today, please ask an admin".to_string(),
+            ),
+        };
+    }
+
+    pub fn to_response(&self) -> Response {
+        let err = self.to_error();
+        let mut result = err.to_write_result();
+        result.error_details = serde_json::to_value(self).ok();
+        let body = serde_json::to_string(&result).unwrap_or(String::new());
+        return Response::with((err.status(), body));
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UndoBook {
+    //only the last day, which the daily limit needs. every record is in the log
+    pub records: Vec<UndoRecord>,
+    //one line per undo, only ever appended to
+    pub path: Option<PathBuf>,
+}
+
+impl UndoBook {
+    pub fn load(config: &ServerConfig) -> UndoBook {
+        let path = match sidecar::sidecar_log_path(config, "undo-records") {
+            Some(path) => path,
+            None => return UndoBook::default(),
+        };
+        let mut book = UndoBook::default();
+        match sidecar::load_json_lines::<UndoRecord>(&path) {
+            Ok(records) => book.records = records,
+            Err(e) => sidecar::move_aside(&path, "undo records", e),
+        }
+        book.path = Some(path);
+        book.forget_old_records(current_time_millis());
+        return book;
+    }
+
+    fn forget_old_records(&mut self, now_epoch_millis: i64) {
+        self.records.retain(|r| r.undone_epoch_millis > now_epoch_millis - MILLIS_PER_DAY);
+    }
+
+    pub fn record(&mut self, target: UndoTarget, undone_by: UndoActor, device_id: Option<u64>, now_epoch_millis: i64) {
+        let record = UndoRecord {
+            purchase_id: target.purchase_id,
+            kind: target.kind,
+            user_id: target.user_id,
+            description: target.description,
+            purchase_timestamp_epoch_millis: target.purchase_timestamp_epoch_millis,
+            undone_epoch_millis: now_epoch_millis,
+            undone_by: undone_by,
+            device_id: device_id,
+        };
+        //appended, an undo never rewrites the whole audit
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::append_json_lines(path, &[record.clone()]) {
+                error!("Could not persist undo record to {:?}: {:?}", path, e);
+            }
+        }
+        self.records.push(record);
+        self.forget_old_records(now_epoch_millis);
+    }
+
+    //the whole audit is read from the log, callers do this without holding the lock
+    pub fn log_path(&self) -> Option<PathBuf> {
+        return self.path.clone();
+    }
+
+    //undos by admins do not count against the limit of the member
+    fn member_undos_since(&self, user_id: u32, since_epoch_millis: i64) -> Vec<&UndoRecord> {
+        return self
+            .records
+            .iter()
+            .filter(|r| r.undone_by == UndoActor::Member && r.user_id == Some(user_id))
+            .filter(|r| r.undone_epoch_millis > since_epoch_millis)
+            .collect();
+    }
+
+    pub fn check(&self, target: &UndoTarget, settings: &UndoSettings, now_epoch_millis: i64) -> Result<(), UndoRefusal> {
+        let elapsed_millis = now_epoch_millis - target.purchase_timestamp_epoch_millis;
+        let seconds_remaining = std::cmp::max(0, settings.window_seconds - elapsed_millis / 1000);
+        let undos_today = target
+            .user_id
+            .map(|user_id| self.member_undos_since(user_id, now_epoch_millis - MILLIS_PER_DAY))
+            .unwrap_or(Vec::new());
+        //giveouts are not booked for a member, so they are not limited per member either
+        let undos_left_today = match (settings.max_undos_per_day, target.user_id) {
+            (Some(max), Some(_)) => Some(max.saturating_sub(undos_today.len() as u32)),
+            _ => None,
+        };
+        let refusal = |reason: UndoRefusalReason, retry_after_seconds: Option<i64>| UndoRefusal {
+            reason: reason,
+            kind: target.kind,
+            window_seconds: settings.window_seconds,
+            seconds_remaining: seconds_remaining,
+            undos_left_today: undos_left_today,
+            retry_after_seconds: retry_after_seconds,
+        };
+
+        let allowed = match target.kind {
+            UndoKind::Simple => true,
+            UndoKind::Cart => settings.allow_cart_purchases,
+            UndoKind::Special => settings.allow_specials,
+            UndoKind::FreeForAll => settings.allow_ffa_purchases,
+        };
+        if !allowed {
+            return Err(refusal(UndoRefusalReason::KindNotAllowed, None));
+        }
+        if elapsed_millis > settings.window_seconds * 1000 {
+            return Err(refusal(UndoRefusalReason::WindowExpired, None));
+        }
+        if undos_left_today == Some(0) {
+            let retry_after_seconds = undos_today
+                .iter()
+                .map(|r| r.undone_epoch_millis)
+                .min()
+                .map(|oldest| std::cmp::max(0, (oldest + MILLIS_PER_DAY - now_epoch_millis) / 1000));
+            return Err(refusal(UndoRefusalReason::DailyLimitReached, retry_after_seconds));
+        }
+        return Ok(());
+    }
+}
+
+//None if the purchase is unknown, the backend rejects undoing those itself
+pub fn describe_purchase(backend: &Backend, unique_id: u64) -> Option<UndoTarget> {
+    let timestamp = backend.datastore.get_purchase_timestamp(unique_id)?;
+    let purchases = backend
+        .datastore
+        .global_log_filtered(timestamp - 1, timestamp + 1)
+        .to_vec();
+    let booked_together = |user_id: u32| {
+        purchases
+            .iter()
+            .filter(|p| *p.get_timestamp() == timestamp)
+            .filter(|p| match *p {
+                &Purchase::SimplePurchase { consumer_id, .. } => consumer_id == user_id,
+                &Purchase::SpecialPurchase { consumer_id, .. } => consumer_id == user_id,
+                &Purchase::FFAPurchase { .. } => false,
+            })
+            .count()
+    };
+    for purchase in &purchases {
+        let (id, kind, user_id, description) = match purchase {
+            &Purchase::SimplePurchase {
+                unique_id: id,
+                consumer_id,
+                item_id,
+                ..
+            } => {
+                let kind = if booked_together(consumer_id) > 1 { UndoKind::Cart } else { UndoKind::Simple };
+                let name = backend.datastore.items.get(&item_id).map(|i| i.name.to_string());
+                (id, kind, Some(consumer_id), name.unwrap_or(format!("item {}", item_id)))
+            }
+            &Purchase::SpecialPurchase {
+                unique_id: id,
+                consumer_id,
+                ref special_name,
+                ..
+            } => (id, UndoKind::Special, Some(consumer_id), special_name.to_string()),
+            &Purchase::FFAPurchase {
+                unique_id: id,
+                item_id,
+                ..
+            } => {
+                let name = backend.datastore.items.get(&item_id).map(|i| i.name.to_string());
+                (id, UndoKind::FreeForAll, None, name.unwrap_or(format!("item {}", item_id)))
+            }
+        };
+        if id == unique_id {
+            return Some(UndoTarget {
+                purchase_id: id,
+                kind: kind,
+                user_id: user_id,
+                description: description,
+                purchase_timestamp_epoch_millis: timestamp,
+            });
+        }
+    }
+    return None;
+}
+
+//every undo ever recorded, oldest first. without persistence only the recent ones in memory exist
+pub fn read_log(path: &Option<PathBuf>, recent: Vec<UndoRecord>) -> Result<Vec<UndoRecord>, ServerError> {
+    return match *path {
+        Some(ref path) => sidecar::load_json_lines(path).map_err(|e| {
+            ServerError::Internal(format!("Could not read undo records: {}", e))
+        }),
+        None => Ok(recent),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ServerConfig;
+    use std;
+    use undo::*;
+    use uuid::Uuid;
+
+    fn target(kind: UndoKind, purchase_timestamp_epoch_millis: i64) -> UndoTarget {
+        return UndoTarget {
+            purchase_id: 7,
+            kind: kind,
+            user_id: Some(1),
+            description: "Beer".to_string(),
+            purchase_timestamp_epoch_millis: purchase_timestamp_epoch_millis,
+        };
+    }
+
+    #[test]
+    fn the_window_and_kinds_follow_the_settings() {
+        let settings = UndoSettings {
+            allow_specials: false,
+            ..UndoSettings::default()
+        };
+        let book = UndoBook::default();
+        let now = 1_000_000;
+
+        assert!(book.check(&target(UndoKind::Simple, now - 20 * 1000), &settings, now).is_ok());
+        assert!(book.check(&target(UndoKind::Cart, now - 20 * 1000), &settings, now).is_ok());
+
+        let expired = book.check(&target(UndoKind::Simple, now - 90 * 1000), &settings, now).unwrap_err();
+        assert_eq!(expired.reason, UndoRefusalReason::WindowExpired);
+        assert_eq!(expired.seconds_remaining, 0);
+        assert_eq!(expired.to_error().error_code(), "conflict");
+
+        let special = book.check(&target(UndoKind::Special, now - 20 * 1000), &settings, now).unwrap_err();
+        assert_eq!(special.reason, UndoRefusalReason::KindNotAllowed);
+        assert_eq!(special.seconds_remaining, 40);
+    }
+
+    #[test]
+    fn members_may_only_undo_so_often_per_day() {
+        let settings = UndoSettings {
+            max_undos_per_day: Some(2),
+            ..UndoSettings::default()
+        };
+        let mut book = UndoBook::default();
+        let now = 10 * MILLIS_PER_DAY;
+        book.record(target(UndoKind::Simple, 0), UndoActor::Member, None, now - MILLIS_PER_DAY - 1000);
+        book.record(target(UndoKind::Simple, 0), UndoActor::Member, Some(3), now - 3600 * 1000);
+        //admins are not limited and do not use up the allowance of the member
+        book.record(target(UndoKind::Simple, 0), UndoActor::Admin, None, now - 60 * 1000);
+        assert!(book.check(&target(UndoKind::Simple, now - 1000), &settings, now).is_ok());
+
+        book.record(target(UndoKind::Simple, 0), UndoActor::Member, Some(3), now - 30 * 1000);
+        let refused = book.check(&target(UndoKind::Simple, now - 1000), &settings, now).unwrap_err();
+        assert_eq!(refused.reason, UndoRefusalReason::DailyLimitReached);
+        assert_eq!(refused.undos_left_today, Some(0));
+        assert_eq!(refused.retry_after_seconds, Some(23 * 3600));
+        assert_eq!(refused.to_error().error_code(), "too_many_requests");
+    }
+
+    #[test]
+    fn records_are_appended_to_a_log_and_only_the_last_day_is_kept_in_memory() {
+        let directory = std::env::temp_dir().join(format!("cervisia-undo-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let now = current_time_millis();
+        let mut book = UndoBook::load(&config);
+        book.record(target(UndoKind::Simple, 0), UndoActor::Member, None, now - 2 * MILLIS_PER_DAY);
+        book.record(target(UndoKind::Simple, 0), UndoActor::Admin, Some(3), now - 1000);
+        let in_memory = book.records.len();
+
+        let reloaded = UndoBook::load(&config);
+        let logged = read_log(&reloaded.log_path(), reloaded.records.clone()).unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(in_memory, 1);
+        assert_eq!(reloaded.records.len(), 1);
+        assert_eq!(reloaded.records[0].undone_by, UndoActor::Admin);
+        assert_eq!(logged.len(), 2);
+    }
+}
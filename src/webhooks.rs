@@ -0,0 +1,641 @@
+use chrono::prelude::*;
+use configuration::{current_config, LiveConfig, ServerConfig};
+use errors::ServerError;
+use manager;
+use notifier;
+use reqwest;
+use serde_json;
+use server::Backend;
+use sidecar;
+use std;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use typescriptify::TypeScriptifyTrait;
+use workers::Worker;
+
+const POLL_INTERVAL_SECONDS: u64 = 5;
+
+const MAX_BACKOFF_SECONDS: i64 = 6 * 60 * 60;
+
+//the delivery log is rewritten once it holds this many lines more than twice the kept deliveries
+const COMPACTION_SLACK: usize = 100;
+
+pub const EVENT_HEADER: &str = "X-Cervisia-Event";
+pub const DELIVERY_HEADER: &str = "X-Cervisia-Delivery";
+
+//fired by the test endpoint, never by the backend
+pub const TEST_EVENT: &str = "WebhookTest";
+
+//event fields which reference users or items, their names are added to the payload
+const USER_FIELDS: [&str; 4] = ["user_id", "donor", "recipient", "consumer_id"];
+const ITEM_FIELDS: [&str; 2] = ["item_id", "item_ids"];
+
+//configured in the [webhooks] table of the config file, subscriptions themselves are managed via the admin api
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    pub max_attempts: u32,
+    pub retry_base_seconds: i64,
+    //finished deliveries beyond this are dropped from the log, pending ones are always kept
+    pub delivery_log_size: usize,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        return WebhookSettings {
+            max_attempts: 8,
+            retry_base_seconds: 30,
+            delivery_log_size: 500,
+        };
+    }
+}
+
+impl WebhookSettings {
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.max_attempts < 1 {
+            problems.push("webhooks.max_attempts must be at least 1".to_string());
+        }
+        if self.retry_base_seconds <= 0 {
+            problems.push("webhooks.retry_base_seconds must be positive".to_string());
+        }
+        return problems;
+    }
+
+    pub fn backoff_seconds(&self, attempts: u32) -> i64 {
+        let exponent = std::cmp::min(attempts.saturating_sub(1), 20);
+        let seconds = self.retry_base_seconds.saturating_mul(1i64 << exponent);
+        return std::cmp::min(seconds, MAX_BACKOFF_SECONDS);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookSubscription {
+    pub id: u64,
+    pub url: String,
+    pub secret: Option<String>,
+    //names of BLEvents variants, empty means every event
+    pub events: Vec<String>,
+    pub created_epoch_seconds: i64,
+}
+
+impl WebhookSubscription {
+    pub fn wants(&self, event_type: &str) -> bool {
+        return event_type == TEST_EVENT
+            || self.events.is_empty()
+            || self.events.iter().any(|e| e == event_type);
+    }
+}
+
+//the secret never leaves the server again
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct WebhookSubscriptionInfo {
+    pub id: u64,
+    pub url: String,
+    pub has_secret: bool,
+    pub events: Vec<String>,
+    pub created_epoch_seconds: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct CreateWebhookSubscription {
+    pub url: String,
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct WebhookSubscriptionId {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum DeliveryStatus {
+    Queued,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TypeScriptify)]
+pub struct WebhookDelivery {
+    pub id: u64,
+    pub subscription_id: u64,
+    pub event_type: String,
+    //the exact body that is signed and sent
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub created_epoch_seconds: i64,
+    pub next_attempt_epoch_seconds: i64,
+    pub last_response: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookPayload {
+    pub event_type: String,
+    pub timestamp_epoch_millis: i64,
+    //the event as it was applied to the backend
+    pub event: serde_json::Value,
+    pub users: BTreeMap<u32, String>,
+    pub items: BTreeMap<u32, String>,
+    //purchases booked by this event, enriched like in the purchase log
+    pub purchases: Vec<manager::Purchase>,
+}
+
+//subscriptions are rewritten when an admin changes them, every change of a delivery is appended to the
+//delivery log instead, a later line for the same delivery replaces the earlier one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WebhookBook {
+    pub next_subscription_id: u64,
+    pub next_delivery_id: u64,
+    pub subscriptions: Vec<WebhookSubscription>,
+    #[serde(skip)]
+    pub deliveries: Vec<WebhookDelivery>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    #[serde(skip)]
+    deliveries_path: Option<PathBuf>,
+    //ids of deliveries changed since they were last appended
+    #[serde(skip)]
+    unlogged: BTreeSet<u64>,
+    #[serde(skip)]
+    logged_lines: usize,
+}
+
+impl WebhookBook {
+    pub fn load(config: &ServerConfig) -> WebhookBook {
+        let path = match sidecar::sidecar_path(config, "webhooks") {
+            Some(path) => path,
+            None => return WebhookBook::default(),
+        };
+        let mut book: WebhookBook = sidecar::load_json_or_default(&path, "webhook subscriptions");
+        book.path = Some(path);
+        let deliveries_path = match sidecar::sidecar_log_path(config, "webhook-deliveries") {
+            Some(path) => path,
+            None => return book,
+        };
+        match sidecar::load_json_lines::<WebhookDelivery>(&deliveries_path) {
+            Ok(lines) => {
+                book.logged_lines = lines.len();
+                let mut latest: BTreeMap<u64, WebhookDelivery> = BTreeMap::new();
+                for delivery in lines {
+                    book.next_delivery_id = std::cmp::max(book.next_delivery_id, delivery.id + 1);
+                    latest.insert(delivery.id, delivery);
+                }
+                book.deliveries = latest.into_iter().map(|(_, d)| d).collect();
+            }
+            Err(e) => sidecar::move_aside(&deliveries_path, "webhook deliveries", e),
+        }
+        book.deliveries_path = Some(deliveries_path);
+        let subscription_ids: Vec<u64> = book.subscriptions.iter().map(|s| s.id).collect();
+        book.deliveries
+            .retain(|d| d.status != DeliveryStatus::Queued || subscription_ids.contains(&d.subscription_id));
+        book.trim_log(config.webhooks.delivery_log_size);
+        return book;
+    }
+
+    pub fn save(&mut self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = sidecar::save_json(path, self) {
+                error!("Could not persist webhook subscriptions to {:?}: {:?}", path, e);
+            }
+        }
+        self.save_deliveries();
+    }
+
+    //appends the changed deliveries, the whole log is only rewritten once it holds mostly outdated lines
+    pub fn save_deliveries(&mut self) {
+        let changed = std::mem::replace(&mut self.unlogged, BTreeSet::new());
+        let path = match self.deliveries_path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let lines: Vec<&WebhookDelivery> = self.deliveries.iter().filter(|d| changed.contains(&d.id)).collect();
+        if !lines.is_empty() {
+            match sidecar::append_json_lines(&path, &lines) {
+                Ok(()) => self.logged_lines += lines.len(),
+                Err(e) => error!("Could not persist webhook deliveries to {:?}: {:?}", path, e),
+            }
+        }
+        if self.logged_lines > 2 * self.deliveries.len() + COMPACTION_SLACK {
+            match sidecar::save_json_lines(&path, &self.deliveries) {
+                Ok(()) => self.logged_lines = self.deliveries.len(),
+                Err(e) => error!("Could not compact webhook deliveries in {:?}: {:?}", path, e),
+            }
+        }
+    }
+
+    pub fn infos(&self) -> Vec<WebhookSubscriptionInfo> {
+        return self
+            .subscriptions
+            .iter()
+            .map(|s| WebhookSubscriptionInfo {
+                id: s.id,
+                url: s.url.to_string(),
+                has_secret: s.secret.is_some(),
+                events: s.events.clone(),
+                created_epoch_seconds: s.created_epoch_seconds,
+            })
+            .collect();
+    }
+
+    pub fn subscribe(
+        &mut self,
+        request: CreateWebhookSubscription,
+        now_epoch_seconds: i64,
+    ) -> Result<u64, ServerError> {
+        let url = request.url.trim().to_string();
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(ServerError::BadRequest("url must start with http:// or https://".to_string()));
+        }
+        let events: Vec<String> = request
+            .events
+            .iter()
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.subscriptions.push(WebhookSubscription {
+            id: id,
+            url: url,
+            secret: request.secret.filter(|s| !s.is_empty()),
+            events: events,
+            created_epoch_seconds: now_epoch_seconds,
+        });
+        return Ok(id);
+    }
+
+    //pending deliveries of the subscription are dropped as well, the log keeps the finished ones
+    pub fn unsubscribe(&mut self, id: u64) -> bool {
+        let before = self.subscriptions.len();
+        self.subscriptions.retain(|s| s.id != id);
+        self.deliveries
+            .retain(|d| d.subscription_id != id || d.status != DeliveryStatus::Queued);
+        return self.subscriptions.len() != before;
+    }
+
+    //returns how many subscriptions got a delivery
+    pub fn enqueue(&mut self, event_type: &str, payload: &str, now_epoch_seconds: i64) -> usize {
+        let subscription_ids: Vec<u64> = self
+            .subscriptions
+            .iter()
+            .filter(|s| s.wants(event_type))
+            .map(|s| s.id)
+            .collect();
+        for subscription_id in &subscription_ids {
+            self.enqueue_for(*subscription_id, event_type, payload, now_epoch_seconds);
+        }
+        return subscription_ids.len();
+    }
+
+    fn enqueue_for(&mut self, subscription_id: u64, event_type: &str, payload: &str, now_epoch_seconds: i64) -> u64 {
+        let id = self.next_delivery_id;
+        self.next_delivery_id += 1;
+        self.deliveries.push(WebhookDelivery {
+            id: id,
+            subscription_id: subscription_id,
+            event_type: event_type.to_string(),
+            payload: payload.to_string(),
+            status: DeliveryStatus::Queued,
+            attempts: 0,
+            created_epoch_seconds: now_epoch_seconds,
+            next_attempt_epoch_seconds: now_epoch_seconds,
+            last_response: None,
+        });
+        self.unlogged.insert(id);
+        return id;
+    }
+
+    pub fn due(&self, now_epoch_seconds: i64) -> Vec<(WebhookDelivery, WebhookSubscription)> {
+        return self
+            .deliveries
+            .iter()
+            .filter(|d| d.status == DeliveryStatus::Queued && d.next_attempt_epoch_seconds <= now_epoch_seconds)
+            .filter_map(|d| {
+                self.subscriptions
+                    .iter()
+                    .find(|s| s.id == d.subscription_id)
+                    .map(|s| (d.clone(), s.clone()))
+            })
+            .collect();
+    }
+
+    pub fn record_result(
+        &mut self,
+        id: u64,
+        result: Result<String, String>,
+        now_epoch_seconds: i64,
+        settings: &WebhookSettings,
+    ) {
+        if let Some(delivery) = self.deliveries.iter_mut().find(|d| d.id == id) {
+            self.unlogged.insert(id);
+            delivery.attempts += 1;
+            match result {
+                Ok(response) => {
+                    delivery.status = DeliveryStatus::Delivered;
+                    delivery.last_response = Some(response);
+                }
+                Err(response) => {
+                    delivery.last_response = Some(response);
+                    if delivery.attempts >= settings.max_attempts {
+                        delivery.status = DeliveryStatus::Failed;
+                    } else {
+                        delivery.next_attempt_epoch_seconds =
+                            now_epoch_seconds + settings.backoff_seconds(delivery.attempts);
+                    }
+                }
+            }
+        }
+        self.trim_log(settings.delivery_log_size);
+    }
+
+    fn trim_log(&mut self, keep_finished: usize) {
+        let finished = self
+            .deliveries
+            .iter()
+            .filter(|d| d.status != DeliveryStatus::Queued)
+            .count();
+        let mut to_drop = finished.saturating_sub(keep_finished);
+        self.deliveries.retain(|d| {
+            if to_drop > 0 && d.status != DeliveryStatus::Queued {
+                to_drop -= 1;
+                return false;
+            }
+            return true;
+        });
+    }
+
+    //newest first, like the mail queue
+    pub fn log(&self, subscription_id: Option<u64>) -> Vec<WebhookDelivery> {
+        return self
+            .deliveries
+            .iter()
+            .rev()
+            .filter(|d| subscription_id.map(|s| s == d.subscription_id).unwrap_or(true))
+            .cloned()
+            .collect();
+    }
+}
+
+//unit variants serialize as plain strings, all others as an object with the variant name as only key
+pub fn event_type(event: &serde_json::Value) -> Option<String> {
+    if let Some(name) = event.as_str() {
+        return Some(name.to_string());
+    }
+    return event
+        .as_object()
+        .and_then(|o| if o.len() == 1 { o.keys().next().cloned() } else { None });
+}
+
+fn referenced_ids(fields: &serde_json::Value, keys: &[&str]) -> Vec<u32> {
+    let mut ids: Vec<u32> = Vec::new();
+    for key in keys {
+        match fields.get(*key) {
+            Some(&serde_json::Value::Array(ref values)) => {
+                ids.extend(values.iter().filter_map(|v| v.as_u64()).map(|v| v as u32))
+            }
+            Some(value) => ids.extend(value.as_u64().map(|v| v as u32)),
+            None => {}
+        }
+    }
+    return ids;
+}
+
+//built after the event was applied, so names of newly created users and items resolve as well
+pub fn build_payload(backend: &Backend, event: serde_json::Value, now_epoch_millis: i64) -> Option<WebhookPayload> {
+    use rustix_bl::datastore::DatastoreQueries;
+    let event_type = event_type(&event)?;
+    let mut payload = WebhookPayload {
+        event_type: event_type.to_string(),
+        timestamp_epoch_millis: now_epoch_millis,
+        event: event.clone(),
+        users: BTreeMap::new(),
+        items: BTreeMap::new(),
+        purchases: Vec::new(),
+    };
+    let fields = match event.get(&event_type) {
+        Some(fields) => fields.clone(),
+        None => return Some(payload),
+    };
+    for user_id in referenced_ids(&fields, &USER_FIELDS) {
+        if let Some(user) = backend.datastore.users.get(&user_id) {
+            payload.users.insert(user_id, user.username.to_string());
+        }
+    }
+    for item_id in referenced_ids(&fields, &ITEM_FIELDS) {
+        if let Some(item) = backend.datastore.items.get(&item_id) {
+            payload.items.insert(item_id, item.name.to_string());
+        }
+    }
+    if event_type.starts_with("Make") && event_type.ends_with("Purchase") {
+        if let Some(timestamp) = fields.get("timestamp").and_then(|t| t.as_i64()) {
+            for purchase in backend.datastore.global_log_filtered(timestamp - 1, timestamp + 1).iter() {
+                if *purchase.get_timestamp() != timestamp {
+                    continue;
+                }
+                match manager::enrich_purchase(purchase, &backend.datastore) {
+                    Ok(enriched) => payload.purchases.push(enriched),
+                    Err(e) => warn!("Could not enrich purchase for webhook payload: {:?}", e),
+                }
+            }
+        }
+    }
+    return Some(payload);
+}
+
+pub fn deliver(delivery: &WebhookDelivery, subscription: &WebhookSubscription) -> Result<String, String> {
+    let client = reqwest::Client::new();
+    let mut request = client
+        .post(&subscription.url)
+        .header(EVENT_HEADER, delivery.event_type.to_string())
+        .header(DELIVERY_HEADER, delivery.id.to_string());
+    if let Some(ref secret) = subscription.secret {
+        request = request.header(notifier::SIGNATURE_HEADER, notifier::sign(&delivery.payload, secret)?);
+    }
+    let res = request
+        .body(delivery.payload.to_string())
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("webhook answered with status {}", res.status()));
+    }
+    return Ok(format!("{}", res.status()));
+}
+
+//a test delivery is sent right away and only once, the caller sees the outcome directly
+pub fn fire_test(book: &RwLock<WebhookBook>, subscription_id: u64, settings: &WebhookSettings) -> Result<WebhookDelivery, ServerError> {
+    let now = Utc::now();
+    let (delivery, subscription) = {
+        let mut book = book.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let subscription = book
+            .subscriptions
+            .iter()
+            .find(|s| s.id == subscription_id)
+            .cloned()
+            .ok_or(ServerError::NotFound(format!("No webhook subscription with id {}", subscription_id)))?;
+        let payload = WebhookPayload {
+            event_type: TEST_EVENT.to_string(),
+            timestamp_epoch_millis: now.timestamp() * 1000,
+            event: serde_json::Value::String(TEST_EVENT.to_string()),
+            users: BTreeMap::new(),
+            items: BTreeMap::new(),
+            purchases: Vec::new(),
+        };
+        let body = serde_json::to_string(&payload).map_err(|e| ServerError::Internal(e.to_string()))?;
+        let id = book.enqueue_for(subscription_id, TEST_EVENT, &body, now.timestamp());
+        let delivery = book.deliveries.iter_mut().find(|d| d.id == id).unwrap();
+        delivery.status = DeliveryStatus::Failed;
+        (delivery.clone(), subscription)
+    };
+    let result = deliver(&delivery, &subscription);
+    let mut book = book.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let recorded = book.deliveries.iter_mut().find(|d| d.id == delivery.id).map(|d| {
+        d.attempts = 1;
+        match result {
+            Ok(response) => {
+                d.status = DeliveryStatus::Delivered;
+                d.last_response = Some(response);
+            }
+            Err(response) => d.last_response = Some(response),
+        }
+        d.clone()
+    });
+    book.unlogged.insert(delivery.id);
+    book.trim_log(settings.delivery_log_size);
+    book.save_deliveries();
+    return Ok(recorded.unwrap_or(delivery));
+}
+
+//the lock is only held for bookkeeping, never while waiting for a subscriber
+pub fn process_due_deliveries(book: &RwLock<WebhookBook>, settings: &WebhookSettings) -> usize {
+    let due = {
+        let book = book.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        book.due(Utc::now().timestamp())
+    };
+
+    for &(ref delivery, ref subscription) in &due {
+        let result = deliver(delivery, subscription);
+        if let Err(ref e) = result {
+            warn!("Webhook delivery {} to {} failed: {}", delivery.id, subscription.url, e);
+        }
+        let mut book = book.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        book.record_result(delivery.id, result, Utc::now().timestamp(), settings);
+        book.save_deliveries();
+    }
+
+    return due.len();
+}
+
+pub fn start_webhook_worker(book: Arc<RwLock<WebhookBook>>, config: LiveConfig) -> Worker {
+    return Worker::spawn("webhooks", Duration::from_secs(POLL_INTERVAL_SECONDS), move || {
+        process_due_deliveries(&book, &current_config(&config).webhooks);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ServerConfig;
+    use serde_json;
+    use std;
+    use uuid::Uuid;
+    use webhooks::*;
+
+    fn subscribe(book: &mut WebhookBook, events: Vec<&str>) -> u64 {
+        return book
+            .subscribe(
+                CreateWebhookSubscription {
+                    url: "http://localhost:9999/hook".to_string(),
+                    secret: Some("s3cret".to_string()),
+                    events: events.iter().map(|e| e.to_string()).collect(),
+                },
+                100,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn events_are_filtered_per_subscription() {
+        let mut book = WebhookBook::default();
+        let all = subscribe(&mut book, vec![]);
+        let purchases = subscribe(&mut book, vec!["MakeSimplePurchase"]);
+
+        assert_eq!(book.enqueue("MakeSimplePurchase", "{}", 100), 2);
+        assert_eq!(book.enqueue("CreateUser", "{}", 100), 1);
+
+        assert_eq!(book.log(Some(all)).len(), 2);
+        assert_eq!(book.log(Some(purchases)).len(), 1);
+        assert!(book.subscribe(
+            CreateWebhookSubscription {
+                url: "ftp://localhost/hook".to_string(),
+                secret: None,
+                events: vec![],
+            },
+            100
+        ).is_err());
+        assert_eq!(
+            event_type(&serde_json::from_str("{\"CreateUser\":{\"username\":\"a\"}}").unwrap()),
+            Some("CreateUser".to_string())
+        );
+    }
+
+    #[test]
+    fn failed_deliveries_back_off_until_max_attempts() {
+        let settings = WebhookSettings {
+            max_attempts: 2,
+            retry_base_seconds: 10,
+            delivery_log_size: 1,
+        };
+        let mut book = WebhookBook::default();
+        subscribe(&mut book, vec![]);
+        book.enqueue("CreateUser", "{}", 100);
+        let id = book.due(100)[0].0.id;
+
+        book.record_result(id, Err("timeout".to_string()), 100, &settings);
+        assert!(book.due(105).is_empty());
+        assert_eq!(book.due(110).len(), 1);
+
+        book.record_result(id, Err("timeout".to_string()), 110, &settings);
+        assert_eq!(book.log(None)[0].status, DeliveryStatus::Failed);
+        assert!(book.due(10000).is_empty());
+
+        //only the newest finished delivery is kept
+        book.enqueue("CreateItem", "{}", 200);
+        let second = book.due(200)[0].0.id;
+        book.record_result(second, Ok("200 OK".to_string()), 200, &settings);
+        let log = book.log(None);
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, DeliveryStatus::Delivered);
+    }
+
+    #[test]
+    fn deliveries_are_appended_to_a_log_next_to_the_subscriptions() {
+        let directory = std::env::temp_dir().join(format!("cervisia-webhooks-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            use_persistence: true,
+            persistence_file_path: directory.join("db").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let mut book = WebhookBook::load(&config);
+        subscribe(&mut book, vec![]);
+        book.save();
+        book.enqueue("CreateUser", "{\"payload\":1}", 100);
+        book.enqueue("CreateItem", "{\"payload\":2}", 100);
+        book.save_deliveries();
+        let first = book.due(100)[0].0.id;
+        book.record_result(first, Ok("200 OK".to_string()), 101, &config.webhooks);
+        book.save_deliveries();
+
+        let subscriptions = std::fs::read_to_string(book.path.clone().unwrap()).unwrap();
+        let reloaded = WebhookBook::load(&config);
+        let _ = std::fs::remove_dir_all(&directory);
+        assert!(!subscriptions.contains("payload"));
+        assert_eq!(reloaded.subscriptions.len(), 1);
+        assert_eq!(reloaded.log(None).len(), 2);
+        assert_eq!(reloaded.due(200).len(), 1);
+        assert_eq!(reloaded.next_delivery_id, 2);
+    }
+}
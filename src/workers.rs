@@ -0,0 +1,108 @@
+use std;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//how often a waiting worker looks whether it should stop
+const STOP_CHECK_MILLIS: u64 = 100;
+
+//a background loop belonging to one server, closing the server stops and joins it
+pub struct Worker {
+    name: String,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Worker {
+    //runs round right away and then every interval until stopped, a running round is always finished
+    pub fn spawn<F>(name: &str, interval: Duration, mut round: F) -> Worker
+    where
+        F: FnMut() + Send + 'static,
+    {
+        return Worker::spawn_paced(name, move || {
+            round();
+            return interval;
+        });
+    }
+
+    //like spawn, but every round returns how long to wait before the next one, e.g. read from the live config
+    pub fn spawn_paced<F>(name: &str, mut round: F) -> Worker
+    where
+        F: FnMut() -> Duration + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    let interval = round();
+                    let waiting_since = Instant::now();
+                    while waiting_since.elapsed() < interval && !stopped.load(Ordering::SeqCst) {
+                        thread::sleep(std::cmp::min(interval, Duration::from_millis(STOP_CHECK_MILLIS)));
+                    }
+                }
+            })
+            .unwrap();
+        return Worker {
+            name: name.to_string(),
+            stop: stop,
+            handle: handle,
+        };
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn join(self) {
+        self.stop();
+        if self.handle.join().is_err() {
+            error!("Worker {} panicked", self.name);
+        }
+    }
+}
+
+//all are told to stop first, so slow rounds of different workers finish in parallel
+pub fn stop_all(workers: Vec<Worker>) {
+    for worker in &workers {
+        worker.stop();
+    }
+    for worker in workers {
+        worker.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use workers::*;
+
+    #[test]
+    fn workers_run_rounds_until_they_are_stopped() {
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let counted = rounds.clone();
+        let worker = Worker::spawn("counter", Duration::from_millis(10), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(200));
+        stop_all(vec![worker]);
+
+        let after_stop = rounds.load(Ordering::SeqCst);
+        assert!(after_stop >= 2);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rounds.load(Ordering::SeqCst), after_stop);
+    }
+
+    #[test]
+    fn long_intervals_do_not_delay_stopping() {
+        let worker = Worker::spawn("sleeper", Duration::from_secs(3600), || {});
+        let started = Instant::now();
+        worker.join();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}